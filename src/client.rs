@@ -0,0 +1,331 @@
+//! Off-chain helpers for building escrow transactions, enabled with the `client` feature.
+
+use solana_program::{
+    instruction::Instruction, program_error::ProgramError, program_option::COption,
+    program_pack::Pack, pubkey::Pubkey, rent::Rent, system_instruction,
+};
+use spl_token::state::Account as TokenAccount;
+
+use crate::{
+    error::EscrowError,
+    instruction::{self, ExchangeAccount},
+    state::{AuditEntry, AuditLog, Config, Escrow, EscrowStatus, ReferenceMode, RoundingMode},
+};
+
+/// Builds the full init flow: creating the temp token account and escrow account, moving
+/// `deposit_amount` from the initializer's `send_token_account` into the temp account and
+/// finally `InitEscrow`, in that order. The escrow has no oracle, no fill limit, cancel
+/// penalty or cancel window, can be filled right away, expires after `DEFAULT_EXPIRY_SLOTS` and
+/// rounds in the initializer's favor; for anything else, swap the last instruction for one built
+/// with `instruction::init_escrow`.
+///
+/// Required signers: the initializer (fee payer, funds the new accounts and owns
+/// `send_token_account`), `temp_token_account` and `escrow_account` (both new keypairs).
+///
+/// `treasury` must be the treasury set in the program's config.
+///
+/// The resulting transaction has 3 signatures and 13 unique accounts, around 850 of the 1232
+/// bytes a transaction can take.
+#[allow(clippy::too_many_arguments)]
+pub fn init_escrow_with_temp_account(
+    program_id: &Pubkey,
+    initializer: &Pubkey,
+    send_token_account: &Pubkey,
+    send_mint: &Pubkey,
+    temp_token_account: &Pubkey,
+    receive_token_account: &Pubkey,
+    receive_mint: &Pubkey,
+    escrow_account: &Pubkey,
+    treasury: &Pubkey,
+    deposit_amount: u64,
+    expected_amount: u64,
+) -> Result<Vec<Instruction>, ProgramError> {
+    let rent = Rent::default();
+    Ok(vec![
+        system_instruction::create_account(
+            initializer,
+            temp_token_account,
+            temp_account_rent_exempt_minimum(),
+            TokenAccount::LEN as u64,
+            &spl_token::id(),
+        ),
+        spl_token::instruction::initialize_account3(
+            &spl_token::id(),
+            temp_token_account,
+            send_mint,
+            initializer,
+        )?,
+        spl_token::instruction::transfer(
+            &spl_token::id(),
+            send_token_account,
+            temp_token_account,
+            initializer,
+            &[],
+            deposit_amount,
+        )?,
+        system_instruction::create_account(
+            initializer,
+            escrow_account,
+            rent.minimum_balance(Escrow::LEN),
+            Escrow::LEN as u64,
+            program_id,
+        ),
+        instruction::init_escrow(
+            program_id,
+            initializer,
+            temp_token_account,
+            receive_token_account,
+            receive_mint,
+            escrow_account,
+            treasury,
+            expected_amount,
+            COption::None,
+            0,
+            0,
+            RoundingMode::FloorToTaker,
+            vec![],
+            vec![],
+            ReferenceMode::Plain,
+            0,
+            0,
+            0,
+            0,
+            0,
+            [0; 32],
+            false,
+            0,
+            0,
+            COption::None,
+            vec![],
+        )?,
+    ])
+}
+
+/// Lamports the temp token account needs to be rent exempt, on top of which a wrapped SOL temp
+/// account holds its deposit.
+pub fn temp_account_rent_exempt_minimum() -> u64 {
+    Rent::default().minimum_balance(TokenAccount::LEN)
+}
+
+/// Most unique accounts a transaction holding just a full `Exchange` references: every account of
+/// `ExchangeAccount` (including the oracle) plus the program itself. Each costs 32 bytes of the
+/// message plus a byte in the instruction, so with the taker as sole signer and fee payer and no
+/// reference preimage the transaction is around 650 of the 1232 bytes a transaction can take.
+/// Integrators bundling more instructions should consider address lookup tables past that, see
+/// `lookup_table_keys`.
+pub fn exchange_tx_account_count() -> usize {
+    ExchangeAccount::COUNT + 1
+}
+
+/// How the accounts of a transaction split up once it uses an address lookup table, see
+/// `lookup_table_keys`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LookupTableKeys {
+    /// Keys that have to stay in the message itself: the invoked programs and then the signers,
+    /// each once.
+    pub static_keys: Vec<Pubkey>,
+    /// Every other key, each once, in the order it first appears. These go into the lookup table.
+    pub lookup_keys: Vec<Pubkey>,
+}
+
+impl LookupTableKeys {
+    /// Index a v0 message references `key` by in the lookup table holding `lookup_keys`.
+    pub fn lookup_index(&self, key: &Pubkey) -> Option<u8> {
+        let index = self
+            .lookup_keys
+            .iter()
+            .position(|lookup_key| lookup_key == key)?;
+        u8::try_from(index).ok()
+    }
+}
+
+/// Splits the accounts of `instructions` into the keys the message has to carry and the ones an
+/// address lookup table can hold, for composite transactions (e.g. several exchanges) that don't
+/// fit the 1232 bytes otherwise.
+///
+/// This crate's `solana-program` predates v0 messages, so setting the table up is left to a
+/// newer `solana-sdk` on the client: create a table with `create_lookup_table`, add
+/// `lookup_keys` with `extend_lookup_table` (at most 256 keys per table, around 20 per
+/// transaction), wait a slot for the table to activate and compile the message with
+/// `v0::Message::try_compile` against it. The fee payer always stays in the message, whether or
+/// not it's among `static_keys`.
+pub fn lookup_table_keys(instructions: &[Instruction]) -> LookupTableKeys {
+    let mut keys = LookupTableKeys::default();
+    for instruction in instructions {
+        if !keys.static_keys.contains(&instruction.program_id) {
+            keys.static_keys.push(instruction.program_id);
+        }
+    }
+    for account in instructions.iter().flat_map(|ix| &ix.accounts) {
+        if account.is_signer && !keys.static_keys.contains(&account.pubkey) {
+            keys.static_keys.push(account.pubkey);
+        }
+    }
+    for account in instructions.iter().flat_map(|ix| &ix.accounts) {
+        if !keys.static_keys.contains(&account.pubkey)
+            && !keys.lookup_keys.contains(&account.pubkey)
+        {
+            keys.lookup_keys.push(account.pubkey);
+        }
+    }
+    keys
+}
+
+/// Whether `escrow` sells `sell_mint` for `buy_mint`, either its expected mint or one of its
+/// other accepted mints. Lets matchers filter escrows without fetching their token accounts.
+pub fn escrow_matches(escrow: &Escrow, sell_mint: &Pubkey, buy_mint: &Pubkey) -> bool {
+    escrow.deposit_mint == *sell_mint
+        && (escrow.expected_mint == *buy_mint || escrow.accepted_mints.contains(buy_mint))
+}
+
+/// How much of `escrow`'s deposit takers can still fill, given its temp account's `temp_balance`:
+/// all of it while the escrow is live and has fills left, nothing once it's closed or has used up
+/// its max fills. Slot-dependent states aren't considered, so an escrow that hasn't opened yet,
+/// has expired or is reserved by a prepared settlement shows what's left in it.
+pub fn remaining_fillable(escrow: &Escrow, temp_balance: u64) -> u64 {
+    match escrow.status {
+        EscrowStatus::Uninitialized | EscrowStatus::Closed => 0,
+        _ if escrow.fills_exhausted() => 0,
+        _ => temp_balance,
+    }
+}
+
+/// What a full `Exchange` of an escrow would move, see `preview_exchange`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ExchangePreview {
+    /// Tokens the taker pays in total: the payment plus `fee`.
+    pub amount_in: u64,
+    /// Deposited tokens the taker receives.
+    pub amount_out: u64,
+    /// Part of `amount_in` that goes to the treasury.
+    pub fee: u64,
+    /// Whether the exchange closes the escrow, always so for a full one.
+    pub escrow_will_close: bool,
+}
+
+/// Works out a full `Exchange` of `escrow` the way the program does, given its temp account's
+/// `deposit` and the program's `config`, for a taker paying in `payment_mint` from an account
+/// holding `taker_source_balance`. Fails like the exchange would if that balance falls short.
+pub fn preview_exchange(
+    escrow: &Escrow,
+    config: &Config,
+    deposit: u64,
+    payment_mint: &Pubkey,
+    taker_source_balance: u64,
+) -> Result<ExchangePreview, EscrowError> {
+    let payment = escrow
+        .fill_payment(deposit, deposit)
+        .map_err(|_| EscrowError::Overflow)?;
+    let fee = config
+        .fee_for(payment, payment_mint)
+        .map_err(|_| EscrowError::Overflow)?;
+    let amount_in = payment.checked_add(fee).ok_or(EscrowError::Overflow)?;
+    if taker_source_balance < amount_in {
+        return Err(EscrowError::InsufficientTakerBalance);
+    }
+    Ok(ExchangePreview {
+        amount_in,
+        amount_out: deposit,
+        fee,
+        escrow_will_close: true,
+    })
+}
+
+/// Decodes the data of the `AuditLog` account into the fills it recorded, oldest first.
+pub fn decode_audit_log(data: &[u8]) -> Result<Vec<AuditEntry>, ProgramError> {
+    AuditLog::entries(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::collections::HashSet};
+
+    fn escrow(status: EscrowStatus, max_fills: u8, fills_done: u8) -> Escrow {
+        let mut escrow = Escrow::unpack_unchecked(&[0; Escrow::LEN]).unwrap();
+        escrow.status = status;
+        escrow.max_fills = max_fills;
+        escrow.fills_done = fills_done;
+        escrow
+    }
+
+    #[test]
+    fn lookup_table_keys_cover_every_account_once() {
+        let program_id = Pubkey::new_unique();
+        let taker = Pubkey::new_unique();
+        let temp_token_account = Pubkey::new_unique();
+        let initializer = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+        // Two exchanges sharing the taker, its token accounts and the program's PDAs.
+        let instructions = [Pubkey::new_unique(), Pubkey::new_unique()]
+            .iter()
+            .map(|escrow_account| {
+                instruction::exchange(
+                    &program_id,
+                    &taker,
+                    &Pubkey::new_unique(),
+                    &mint,
+                    &Pubkey::new_unique(),
+                    &temp_token_account,
+                    &initializer,
+                    &Pubkey::new_unique(),
+                    escrow_account,
+                    &Pubkey::new_unique(),
+                    &Pubkey::new_unique(),
+                    None,
+                    1_000,
+                    &[],
+                    u64::MAX,
+                )
+                .unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        let keys = lookup_table_keys(&instructions);
+
+        let static_keys = keys.static_keys.iter().collect::<HashSet<_>>();
+        let lookup_keys = keys.lookup_keys.iter().collect::<HashSet<_>>();
+        assert_eq!(static_keys.len(), keys.static_keys.len());
+        assert_eq!(lookup_keys.len(), keys.lookup_keys.len());
+        assert!(static_keys.is_disjoint(&lookup_keys));
+        assert_eq!(keys.static_keys, vec![program_id, taker]);
+        for instruction in &instructions {
+            for account in &instruction.accounts {
+                assert!(
+                    static_keys.contains(&account.pubkey)
+                        || keys.lookup_index(&account.pubkey).is_some()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn remaining_fillable_follows_fills_and_status() {
+        // Never filled, with and without a fill cap.
+        assert_eq!(
+            remaining_fillable(&escrow(EscrowStatus::Open, 0, 0), 1_000),
+            1_000
+        );
+        assert_eq!(
+            remaining_fillable(&escrow(EscrowStatus::Open, 3, 0), 1_000),
+            1_000
+        );
+        // Filled in part, what's left in the temp account.
+        assert_eq!(
+            remaining_fillable(&escrow(EscrowStatus::PartiallyFilled, 3, 2), 400),
+            400
+        );
+        assert_eq!(
+            remaining_fillable(&escrow(EscrowStatus::PartiallyFilled, 0, 10), 400),
+            400
+        );
+        // Out of fills or closed, nothing.
+        assert_eq!(
+            remaining_fillable(&escrow(EscrowStatus::PartiallyFilled, 3, 3), 400),
+            0
+        );
+        assert_eq!(
+            remaining_fillable(&escrow(EscrowStatus::Closed, 0, 1), 0),
+            0
+        );
+    }
+}
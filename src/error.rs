@@ -12,8 +12,134 @@ pub enum EscrowError {
     #[error("Expected amount does not match actual amount")]
     ExpectedAmountMismatch,
 
-    #[error("Overflow when returning rent amount")]
+    #[error("Arithmetic overflow")]
     Overflow,
+
+    #[error("Token account mint does not match expected mint")]
+    MintMismatch,
+
+    #[error("Token account owner does not match expected owner")]
+    OwnerMismatch,
+
+    #[error("Escrow price deviates too far from the oracle price")]
+    PriceOutOfBounds,
+
+    #[error("Signer is not authorized for this instruction")]
+    Unauthorized,
+
+    #[error("Account is an active escrow")]
+    ActiveEscrow,
+
+    #[error("PDA account does not match the escrow authority")]
+    InvalidPdaAccount,
+
+    #[error("Payment mint decimals do not match the escrow's expected decimals")]
+    DecimalsMismatch,
+
+    #[error("Escrow has reached its max number of fills")]
+    MaxFillsReached,
+
+    #[error("Insufficient funds")]
+    InsufficientFunds,
+
+    #[error("Escrow's temp token account still holds tokens")]
+    EscrowNotEmpty,
+
+    #[error("Temp token account is not owned by the escrow authority")]
+    AuthorityNotTransferred,
+
+    #[error("Escrow is reserved by a prepared settlement")]
+    SettlementPending,
+
+    #[error("Escrow has no prepared settlement")]
+    SettlementNotPrepared,
+
+    #[error("Prepared settlement has expired")]
+    SettlementExpired,
+
+    #[error("Destination token account is not owned by the initializer")]
+    DestNotOwnedByInitializer,
+
+    #[error("Expected amount is below the minimum")]
+    AmountTooSmall,
+
+    #[error("Initializer's destination token account can no longer receive tokens")]
+    InitializerDestUnavailable,
+
+    #[error("Payment mint is not accepted by the escrow")]
+    MintNotAccepted,
+
+    #[error("Reference preimage does not match the escrow's reference")]
+    ReferenceMismatch,
+
+    #[error("Taker's source token account can't cover the payment")]
+    InsufficientTakerBalance,
+
+    #[error("Program is paused")]
+    Paused,
+
+    #[error("Expected a token account but got a mint")]
+    ExpectedTokenAccountGotMint,
+
+    #[error("Fill costs more than the taker's max amount in")]
+    AmountInExceedsMax,
+
+    #[error("Escrow can't be filled before its start slot")]
+    EscrowNotYetActive,
+
+    #[error("Clock sysvar is unavailable")]
+    ClockUnavailable,
+
+    #[error("List has more entries than an escrow can store")]
+    ListTooLong,
+
+    #[error("Escrow has expired")]
+    EscrowExpired,
+
+    #[error("Escrow account isn't sized for an escrow")]
+    InvalidAccountSize,
+
+    #[error("Escrow is closed")]
+    EscrowClosed,
+
+    #[error("Temp token account doesn't hold the escrow's deposit mint")]
+    TempMintMismatch,
+
+    #[error("Escrow address was closed too recently to be reused")]
+    AddressCooldown,
+
+    #[error("Escrow has nothing to pay out or nothing to be paid")]
+    InvalidEscrowState,
+
+    #[error("Escrow can't be cancelled yet")]
+    TooEarlyToCancel,
+
+    #[error("Escrow's temp token account was closed")]
+    TempAccountClosed,
+
+    #[error("Token account is frozen")]
+    AccountFrozen,
+
+    #[error("Taker isn't in the escrow's allow-list")]
+    NotInAllowlist,
+
+    #[error("Instruction was passed fewer accounts than it requires")]
+    NotEnoughAccounts,
+
+    #[error("Expected a mint but got a token account")]
+    ExpectedMintGotTokenAccount,
+
+    #[error("Partial fill is below the escrow's minimum fill")]
+    FillTooSmall,
+
+    #[error("Fill lacks the maker's signature over its terms")]
+    MissingMakerSignature,
+
+    #[error("Temp token account's authority didn't pass to the escrow authority")]
+    AuthorityTransferFailed,
+
+    #[error("Settlement must be a transaction's own instruction, not invoked through a CPI")]
+    NotTopLevelInstruction,
 }
 
 impl From<EscrowError> for ProgramError {
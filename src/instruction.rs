@@ -1,20 +1,106 @@
-use solana_program::program_error::ProgramError;
+use std::mem::size_of;
 
-use crate::error::EscrowError::InvalidInstruction;
+#[cfg(feature = "test-harness")]
+use solana_program::program_pack::Pack;
+use solana_program::{
+    bpf_loader_upgradeable, ed25519_program,
+    instruction::{AccountMeta, Instruction},
+    msg,
+    program_error::ProgramError,
+    program_option::COption,
+    pubkey::Pubkey,
+    system_program, sysvar,
+};
+
+#[cfg(feature = "test-harness")]
+use crate::state::Escrow;
+use crate::{
+    error::EscrowError::InvalidInstruction,
+    state::{
+        find_audit_log_address, find_authority_cache_address, find_config_address,
+        find_escrow_authority_address, find_escrow_index_address, find_native_sol_wrapper_address,
+        find_split_escrow_address, find_split_temp_address, FeeOverride, ReferenceMode,
+        RoundingMode, MAX_REFERENCE_PREIMAGE_LEN, MAX_TAKER_PROOF_LEN,
+    },
+};
+
+/// Format of the instruction data, its first byte. Bumped whenever the layout after it changes,
+/// so `unpack` can tell which layout a client used.
+pub const INSTRUCTION_FORMAT_VERSION: u8 = 1;
+
+/// Longest instruction data `unpack` accepts, what a whole transaction can carry. Only a CPI could
+/// pass more, and no instruction needs it.
+pub const MAX_INSTRUCTION_DATA_LEN: usize = 1232;
 
 pub enum EscrowInstruction {
     /// Starts the trade by creating + populating an escrow account (transfer ownership of given temp token account to PDA)
     ///
+    /// The initializer can be a PDA of another program for program-controlled escrows, with that
+    /// program invoking this one through `invoke_signed`: the runtime marks the PDA as a signer of
+    /// the inner instruction, so it needs no flag or special casing here. As it pays the index
+    /// rent and init fee through the system program it has to be a system account without data,
+    /// and its program has to sign the same way for every later instruction that needs the
+    /// initializer's signature.
+    ///
     /// Accounts expected:
     //
-    /// 0. `[signer]` Account of person who initializes escrow
+    /// 0. `[signer, writable]` Account of person who initializes escrow, pays for the escrow index, init fee and stake
     /// 1. `[writable]` Temp token account which should be created prior to instruction and owned by initializer
-    /// 2. `[]` Initializer's token account for the token they receive should trade go through
-    /// 3. `[writable]` Escrow account, hold all necessary info about the trade
+    /// 2. `[]` Initializer's token account for the token they receive should trade go through, or with `deliver_to_ata` their associated token account for the mint from 5., which doesn't have to exist yet
+    /// 3. `[writable]` Escrow account, hold all necessary info about the trade, and the stake
     /// 4. `[]` Token program
+    /// 5. `[]` Mint of the initializer's token account from 2.
+    /// 6. `[writable]` Initializer's escrow index PDA, created if it doesn't exist yet
+    /// 7. `[]` System program
+    /// 8. `[writable]` Treasury set in the config, receives the init fee
+    /// 9. `[]` Config PDA
+    /// 10. `[writable]` The temp token accounts of `basket`, in its order, owned by the initializer
+    ///     like 1., then `[]` optionally the rent beneficiary (see `with_rent_beneficiary`)
     InitEscrow {
         // Amount party A expects to receive of token Y
         amount: u64,
+        // Price feed to check the exchange price against (requires the `oracle` feature)
+        oracle: COption<Pubkey>,
+        // Max deviation from the oracle price in basis points, ignored without an oracle
+        max_deviation_bps: u16,
+        // Max number of fills the escrow can be filled in, 0 for no limit
+        max_fills: u8,
+        // Who absorbs the rounding dust of partial fills
+        rounding_mode: RoundingMode,
+        // Other mints (same decimals as the one of 2.) accepted as payment, at most `MAX_LIST_LEN`
+        accepted_mints: Vec<Pubkey>,
+        // Off-chain reference of the trade (e.g. an order id): at most 32 bytes stored as is, or a
+        // preimage of at most `MAX_REFERENCE_PREIMAGE_LEN` bytes of which only the hash is stored
+        reference: Vec<u8>,
+        reference_mode: ReferenceMode,
+        // First slot the escrow can be filled in, 0 if it can be right away
+        start_slot: u64,
+        // Share of the deposit in basis points a cancel sends to the treasury, at most 10000
+        cancel_penalty_bps: u16,
+        // First slot the escrow can't be filled in anymore, 0 for `DEFAULT_EXPIRY_SLOTS` after it
+        // opens
+        expiry_slot: u64,
+        // Slots after init before the initializer can cancel, 0 to allow it right away
+        min_open_slots: u64,
+        // Kind of trade for clients to filter by, see `Escrow::category`
+        category: u8,
+        // Root of the merkle tree of takers allowed to fill the escrow, all zeroes to allow anyone
+        // (see `Escrow::allows_taker`)
+        taker_merkle_root: [u8; 32],
+        // Whether 2. is the initializer's associated token account, which the first full exchange
+        // creates if it doesn't exist by then (see `with_ata_creation`)
+        deliver_to_ata: bool,
+        // Fewest deposited tokens a partial fill can take, except the one closing the escrow, 0
+        // for no minimum
+        min_fill: u64,
+        // Lamports the initializer stakes against the escrow expiring unfilled, 0 for none (see
+        // `Escrow::stake_lamports`)
+        stake: u64,
+        // Key that has to sign off on every fill, see `Escrow::maker`
+        maker: COption<Pubkey>,
+        // Temp token accounts of other mints deposited along with 1., all paid out by a full
+        // fill, at most `MAX_LIST_LEN` (see `Escrow::basket`)
+        basket: Vec<Pubkey>,
     },
 
     /// Accepts a trade
@@ -22,42 +108,1918 @@ pub enum EscrowInstruction {
     /// Accounts expected:
     //
     /// 0. `[signer]` Account of person who takes the trader
-    /// 1. `[writable]` The taker's token account for the token they send
+    /// 1. `[writable]` The taker's token account for the token they send, or their native SOL wrapper when paying wrapped SOL from their native SOL (see `with_native_sol_payment`)
     /// 2. `[writable]` The taker's token account for the token they will receive should trade go through
     /// 3. `[writable]` PDA's temp account to get tokens from and eventually close... TODO: isn't this saved already?
     /// 4. `[writable]` Initializer's main account to send rent fees to... TODO: isn't this saved already?
-    /// 5. `[writable]` Initializer's token account that will receive tokens
+    /// 5. `[writable]` Initializer's token account that will receive tokens, or when paying in one of the escrow's other accepted mints, an initializer-owned account of that mint
     /// 6. `[writable]` Escrow account holding escrow info
     /// 7. `[]` Token program
     /// 8. `[]` PDA account
+    /// 9. `[]` Mint of the taker's token account from 1.
+    /// 10. `[writable]` Initializer's escrow index PDA
+    /// 11. `[]` Config PDA
+    /// 12. `[writable]` Token account of the config's treasury for the mint from 9., receives the fee less the secondary treasury's share (unchecked while that's 0)
+    /// 13. `[writable]` Initializer's token account for the deposited mint, receives whatever the closing fill leaves in 3. (unchecked while that's nothing)
+    /// 14. `[writable]` Token account of the config's secondary treasury for the mint from 9., receives its share of the fee (unchecked while that's 0, see `with_secondary_treasury`)
+    /// 15. `[]` Oracle price account, only if the escrow was initialized with one
+    /// 15. (16. with an oracle) onwards, the accounts below that apply, in any order except that
+    ///     each basket account comes right before its destination:
+    ///     - `[]` The authority cache (see `with_authority_cache`)
+    ///     - `[signer]` A relayer paying the transaction fee for the taker (see `with_relayer`)
+    ///     - `[writable]` The audit log (see `with_audit_log`)
+    ///     - `[writable]` The escrow's rent beneficiary, if it has one (see
+    ///       `with_rent_beneficiary`)
+    ///     - `[]` The system program, to create 5. if the escrow delivers to an associated token
+    ///       account that doesn't exist yet (see `with_ata_creation`, 0. then has to be writable to
+    ///       pay for it), or the native SOL wrapper from 1. (see `with_native_sol_payment`)
+    ///     - `[]` The associated token account program, to create 5. (see `with_ata_creation`)
+    ///     - `[]` The rent sysvar, to create 5. (see `with_ata_creation`)
+    ///     - `[]` The instructions sysvar, if the escrow has a maker (see `with_maker_approval`)
+    ///     - `[writable]` Each of the escrow's basket temp accounts, if it has any, followed by
+    ///       `[writable]` the taker's token account of the same mint to pay it out to (see
+    ///       `with_basket_accounts`)
     Exchange {
         // Amount taker expects to be paid in the other token, as u64 because that's the max possible supply of token
         // TODO: add expected send amount so taker can't be front-run by initializer w/ a cancel + re-initialize with higher amount.
         amount: u64,
+        // Preimage of the escrow's reference, required iff its reference mode is `HashedRevealOnFill`
+        reference_preimage: Vec<u8>,
+        // Most the taker pays in total (payment plus fee), `u64::MAX` for no cap
+        max_amount_in: u64,
+        // Proof the taker is in the escrow's allow-list, at most `MAX_TAKER_PROOF_LEN` hashes,
+        // empty if it has none (see `with_taker_proof`)
+        taker_proof: Vec<[u8; 32]>,
+    },
+
+    /// Moves any lamports above the rent-exempt minimum from an escrow-related account (the PDA
+    /// or a stale program-owned account) to the treasury. Active escrows are never swept.
+    ///
+    /// Accounts expected:
+    //
+    /// 0. `[signer]` Admin set in the config
+    /// 1. `[writable]` Account to sweep
+    /// 2. `[writable]` Treasury set in the config
+    /// 3. `[]` System program
+    /// 4. `[]` Config PDA
+    SweepDust,
+
+    /// Cancels the trade, returning the deposited tokens to the initializer and closing both the
+    /// temp token account and the escrow account. If the escrow has a cancel penalty, that share
+    /// of the deposit goes to the treasury instead.
+    ///
+    /// Accounts expected:
+    //
+    /// 0. `[signer, writable]` Initializer of the escrow, receives the rent of the closed accounts
+    /// 1. `[writable]` PDA's temp account holding the deposited tokens
+    /// 2. `[writable]` Initializer's token account to return the deposited tokens to
+    /// 3. `[writable]` Escrow account holding escrow info
+    /// 4. `[]` Token program
+    /// 5. `[]` PDA account
+    /// 6. `[writable]` Initializer's escrow index PDA
+    /// 7. `[]` Config PDA, only read if the escrow has a cancel penalty
+    /// 8. `[writable]` Token account of the config's treasury for the deposited mint, receives the penalty (unchecked without one)
+    /// 9. `[writable]` The escrow's basket accounts if it has any, in the order of
+    ///    `Escrow::basket`, each followed by the initializer's token account to return it to (see
+    ///    `with_basket_accounts`)
+    CancelEscrow,
+
+    /// Merges the second escrow into the first: moves its deposited tokens into the first's temp
+    /// account, adds its expected amount and stake to the first's and closes it. Both escrows need
+    /// the same initializer, deposit mint and receiving token account, and the same rounding mode,
+    /// accepted mints, cancel penalty, expiry, oracle, taker allowlist, maker, minimum fill, maximum
+    /// fills and ATA delivery. Neither can be a basket escrow.
+    ///
+    /// Accounts expected:
+    //
+    /// 0. `[signer, writable]` Initializer of both escrows, receives the rent of the closed accounts
+    /// 1. `[writable]` Escrow account to merge into
+    /// 2. `[writable]` PDA's temp account of the escrow from 1.
+    /// 3. `[writable]` Escrow account to merge and close
+    /// 4. `[writable]` PDA's temp account of the escrow from 3., closed
+    /// 5. `[]` Token program
+    /// 6. `[]` PDA account
+    /// 7. `[writable]` Initializer's escrow index PDA
+    MergeEscrows,
+
+    /// Accepts part of a trade: the taker receives `amount` of the deposited tokens and pays the
+    /// proportional part of the expected amount. Filling the remaining deposit closes the escrow
+    /// like `Exchange` does. Once the escrow's max fills are used up it can only be cancelled.
+    /// Basket escrows can only be filled in full.
+    ///
+    /// Accounts expected are the same as for `Exchange`.
+    PartialExchange {
+        // Amount of the deposited token the taker receives
+        amount: u64,
+        // See `Exchange`
+        reference_preimage: Vec<u8>,
+        // See `Exchange`
+        max_amount_in: u64,
+        // See `Exchange`
+        taker_proof: Vec<[u8; 32]>,
+    },
+
+    /// Logs the program version and returns it (as little-endian u32) via return data.
+    ///
+    /// No accounts expected.
+    GetVersion,
+
+    /// First half of a full exchange split over two transactions: runs all of `Exchange`'s checks
+    /// and reserves the escrow for the taker for `SETTLEMENT_EXPIRY_SLOTS`, without moving tokens.
+    /// The fee is quoted here and stored with `max_amount_in` for the finalize to charge.
+    ///
+    /// Accounts expected are the same as for `Exchange`.
+    PrepareSettlement {
+        // Amount taker expects to be paid in the other token, the whole deposit
+        amount: u64,
+        // See `Exchange`
+        reference_preimage: Vec<u8>,
+        // See `Exchange`
+        max_amount_in: u64,
+        // See `Exchange`
+        taker_proof: Vec<[u8; 32]>,
+    },
+
+    /// Second half of a split exchange: moves the tokens and closes the escrow like `Exchange`,
+    /// if the same taker prepared it and the preparation hasn't expired. The taker pays the fee
+    /// quoted at prepare, never more than its `max_amount_in` allowed.
+    ///
+    /// Only runs as a transaction's own instruction, refused with `NotTopLevelInstruction` when
+    /// invoked through a CPI, which it tells from the instructions sysvar. This keeps chained
+    /// escrows from settling each other.
+    ///
+    /// Accounts expected are the same as for `Exchange`, without the oracle, plus the
+    /// instructions sysvar among the trailing ones (`finalize_settlement` appends it).
+    FinalizeSettlement,
+
+    /// Describes the accounts an `Exchange` of the given escrow needs, via return data: for each
+    /// account in order, its 32-byte key (all zeroes if the escrow doesn't determine it, e.g. the
+    /// taker's accounts) followed by a flags byte, `EXCHANGE_ACCOUNT_SIGNER |
+    /// EXCHANGE_ACCOUNT_WRITABLE` as applicable.
+    ///
+    /// Accounts expected:
+    //
+    /// 0. `[]` Escrow account
+    DescribeExchange,
+
+    /// Creates the program's config. Can only be called once, by the program's upgrade authority.
+    ///
+    /// Accounts expected:
+    //
+    /// 0. `[signer, writable]` Deployer, the upgrade authority, pays for the config account
+    /// 1. `[writable]` Config PDA
+    /// 2. `[]` System program
+    /// 3. `[]` The program's ProgramData account of the upgradeable loader
+    InitConfig(ConfigArgs),
+
+    /// Replaces the program's config. The admin stays the same, it can only change hands through
+    /// `ProposeAdmin` and `AcceptAdmin`.
+    ///
+    /// Accounts expected:
+    //
+    /// 0. `[signer]` Admin set in the config
+    /// 1. `[writable]` Config PDA
+    UpdateConfig(ConfigArgs),
+
+    /// Splits an escrow into `count` children at PDAs (see `find_split_escrow_address` and
+    /// `find_split_temp_address`), each with an equal share of the deposit and the stake and the
+    /// proportional part of the expected amount. Rounding leftovers go to the last child. The split
+    /// escrow is closed. Basket escrows and partially filled ones can't be split.
+    ///
+    /// Accounts expected:
+    //
+    /// 0. `[signer, writable]` Initializer of the escrow, pays for the children and receives the rent of the closed accounts
+    /// 1. `[writable]` Escrow account to split
+    /// 2. `[writable]` PDA's temp account of the escrow from 1.
+    /// 3. `[]` Mint of the temp account from 2.
+    /// 4. `[]` Token program
+    /// 5. `[]` PDA account
+    /// 6. `[writable]` Initializer's escrow index PDA
+    /// 7. `[]` System program
+    /// 8. + 2 * i. `[writable]` Escrow account of child i
+    /// 9. + 2 * i. `[writable]` Temp token account of child i
+    SplitEscrow {
+        // Number of children, at least 2
+        count: u8,
+    },
+
+    /// Checks the escrow's stored bump and version against what they should be, and that the
+    /// temp account is still owned by the escrow authority PDA, logging every mismatch. Returns
+    /// the mismatches found as a `VERIFY_*` bitmask (one byte) via return data. Never moves tokens.
+    ///
+    /// With `repair`, the admin set in the config rewrites a wrong bump or version. A wrong temp
+    /// account owner can't be repaired here.
+    ///
+    /// Accounts expected:
+    //
+    /// 0. `[writable]` Escrow account
+    /// 1. `[]` Temp token account of the escrow
+    /// 2. `[]` Config PDA
+    /// 3. `[signer]` (optional) Admin set in the config, required with `repair`
+    Verify {
+        // Whether to rewrite the bump and version
+        repair: bool,
+    },
+
+    /// Overwrites the escrow's state as given, so tests can set up escrows that are expired,
+    /// reserved or partially filled without replaying the flow that gets them there. Only built
+    /// with the `test-harness` feature, which can't be built for BPF.
+    ///
+    /// Accounts expected:
+    //
+    /// 0. `[writable]` Escrow account
+    #[cfg(feature = "test-harness")]
+    SetEscrowStateForTesting(Box<Escrow>),
+
+    /// Proposes a new admin for the config, who takes over once they sign `AcceptAdmin`. A
+    /// later proposal replaces an earlier one.
+    ///
+    /// Accounts expected:
+    //
+    /// 0. `[signer]` Admin set in the config
+    /// 1. `[writable]` Config PDA
+    ProposeAdmin {
+        // Admin to hand over to
+        new_admin: Pubkey,
+    },
+
+    /// Makes the proposed admin the config's admin.
+    ///
+    /// Accounts expected:
+    //
+    /// 0. `[signer]` Admin proposed by `ProposeAdmin`
+    /// 1. `[writable]` Config PDA
+    AcceptAdmin,
+
+    /// Reclaims expired escrows for their initializers, like a `CancelEscrow` without penalty
+    /// anyone can send, except that their stakes are forfeited to the treasury. Escrows that
+    /// haven't expired, are reserved by a prepared settlement or are already closed are skipped,
+    /// as are basket escrows, which only their initializer can cancel. At most
+    /// `MAX_RECLAIM_BATCH` escrows per call.
+    ///
+    /// Accounts expected:
+    //
+    /// 0. `[]` Token program
+    /// 1. `[]` PDA account
+    /// 2. + 5 * i. `[writable]` Escrow account i
+    /// 3. + 5 * i. `[writable]` PDA's temp account of escrow i
+    /// 4. + 5 * i. `[writable]` Initializer of escrow i, receives the rent of the closed accounts
+    /// 5. + 5 * i. `[writable]` Initializer's token account to return the deposit of escrow i to
+    /// 6. + 5 * i. `[writable]` Escrow index PDA of the initializer of escrow i
+    /// 2. + 5 * n. `[]` Config PDA, only read if an escrow of the batch has a stake
+    /// 3. + 5 * n. `[writable]` Treasury set in the config, receives the stakes
+    ReclaimExpiredBatch {
+        // Number of escrows in the batch
+        count: u8,
+    },
+
+    /// Creates the `AuthorityCache` PDA holding the escrow authority and its bump. Anyone can
+    /// pay for it, once. The fill instructions read it instead of deriving the authority when it's
+    /// passed after their fixed accounts (see `with_authority_cache`).
+    ///
+    /// Accounts expected:
+    //
+    /// 0. `[signer, writable]` Payer of the cache account
+    /// 1. `[writable]` Authority cache PDA
+    /// 2. `[]` System program
+    CacheAuthority,
+
+    /// Works out what filling `amount` of the escrow's deposit costs at the current slot and
+    /// config, without moving anything: returns `amount_in` (payment plus fee, what
+    /// `max_amount_in` has to cover) and `amount_out` (tokens the taker receives) via return data,
+    /// as two little-endian u64s. Fails with the error the fill would fail with for an escrow that
+    /// isn't open, an `amount` it can't fill or a mint it doesn't accept.
+    ///
+    /// Accounts expected:
+    //
+    /// 0. `[]` Escrow account
+    /// 1. `[]` PDA's temp account of the escrow
+    /// 2. `[]` Config PDA
+    /// 3. `[]` Mint the taker would pay in
+    QuoteExchange {
+        // Deposited tokens the taker would receive, the whole deposit for a full exchange
+        amount: u64,
+    },
+
+    /// Creates the `AuditLog` PDA, empty, which fills sent along with it are then recorded in.
+    ///
+    /// Accounts expected:
+    //
+    /// 0. `[signer, writable]` Admin set in the config, pays for the audit log
+    /// 1. `[writable]` Audit log PDA
+    /// 2. `[]` System program
+    /// 3. `[]` Config PDA
+    InitAuditLog,
+
+    /// Adds `amount` tokens to an open escrow's deposit, raising its expected amount in
+    /// proportion so the price stays the same (rounded the way fills round the payment).
+    ///
+    /// Accounts expected:
+    //
+    /// 0. `[signer]` The account of the person who initialized the escrow
+    /// 1. `[writable]` Escrow account
+    /// 2. `[writable]` The initializer's token account of the deposit mint to take the tokens from
+    /// 3. `[writable]` PDA's temp account of the escrow
+    /// 4. `[]` The token program
+    /// 5. `[]` Config PDA
+    TopUpEscrow {
+        // Tokens added to the deposit
+        amount: u64,
+    },
+
+    /// Reprices an escrow by replacing it: cancels it like `CancelEscrow`, then initializes a new
+    /// escrow like `InitEscrow` with the same terms but `new_expected_amount`, all in one
+    /// instruction so no taker can fill either offer in between. Every check of both applies,
+    /// e.g. the cancel window and penalty, the address cooldown and pausing. Refused while a
+    /// settlement is prepared, since the prepared taker agreed to the old price, once the escrow
+    /// expired, and for basket escrows. The new escrow has no cancel window of its own: the old
+    /// one's had to be over to cancel it, and isn't restarted. See `replace_escrow`.
+    ///
+    /// Accounts expected:
+    //
+    /// 0. - 8. The accounts of `CancelEscrow`, returning the deposit to the new temp account
+    /// 9. - 18. The accounts of `InitEscrow` for the new escrow, by the same initializer
+    /// 19. `[writable]` Optionally, the rent beneficiary of both escrows
+    ReplaceEscrow {
+        // What the initializer now expects for the whole remaining deposit
+        new_expected_amount: u64,
+    },
+
+    /// Sends an escrow account whatever lamports it's missing to be rent exempt under the
+    /// cluster's current rent, e.g. after a rent increase. Anyone can pay. Does nothing if it's
+    /// exempt already.
+    ///
+    /// Accounts expected:
+    //
+    /// 0. `[signer, writable]` Payer of the missing rent
+    /// 1. `[writable]` Escrow account
+    /// 2. `[]` System program
+    TopUpRent,
+
+    /// Reports whether `taker` may fill the escrow as far as its allow-list goes (see
+    /// `Escrow::allows_taker`), given the proof they'd fill with, without filling anything:
+    /// returns one byte via return data, 1 if they may and 0 if not. For UIs to tell takers up
+    /// front rather than have their fill fail with `NotInAllowlist`.
+    ///
+    /// Accounts expected:
+    //
+    /// 0. `[]` Escrow account
+    CheckEligibility {
+        // Candidate taker, who doesn't have to sign
+        taker: Pubkey,
+        // Proof the taker is in the escrow's allow-list, as a fill would pass it
+        taker_proof: Vec<[u8; 32]>,
     },
 }
 
+/// `Verify` mismatch: the stored bump isn't the escrow authority PDA's.
+pub const VERIFY_BUMP_MISMATCH: u8 = 1;
+/// `Verify` mismatch: the stored version isn't `PROGRAM_VERSION`.
+pub const VERIFY_VERSION_MISMATCH: u8 = 1 << 1;
+/// `Verify` mismatch: the temp account isn't owned by the escrow authority PDA.
+pub const VERIFY_TEMP_OWNER_MISMATCH: u8 = 1 << 2;
+
+/// Values of the config set by `InitConfig` and `UpdateConfig`, see `state::Config`.
+pub struct ConfigArgs {
+    pub admin: Pubkey,
+    pub fee_bps: u16,
+    pub paused: bool,
+    pub treasury: Pubkey,
+    pub init_fee: u64,
+    pub fee_overrides: Vec<FeeOverride>,
+    pub allowed_relayer: COption<Pubkey>,
+    pub address_cooldown_slots: u64,
+    pub secondary_treasury: Pubkey,
+    pub secondary_treasury_bps: u16,
+}
+
+/// Position of the first basket account among the accounts `InitEscrow` expects.
+pub const INIT_ESCROW_BASKET_OFFSET: usize = 10;
+
+/// Position of the first basket account among the accounts `CancelEscrow` expects.
+pub const CANCEL_ESCROW_BASKET_OFFSET: usize = 9;
+
+/// Position of the first `InitEscrow` account among the accounts `ReplaceEscrow` expects, right
+/// after the `CancelEscrow` ones.
+pub const REPLACE_ESCROW_INIT_OFFSET: usize = 9;
+
+/// Flag of an account `DescribeExchange` describes that must sign.
+pub const EXCHANGE_ACCOUNT_SIGNER: u8 = 1;
+/// Flag of an account `DescribeExchange` describes that must be writable.
+pub const EXCHANGE_ACCOUNT_WRITABLE: u8 = 1 << 1;
+
+/// Positions of the accounts `Exchange`, `PartialExchange` and the settlement instructions
+/// expect, in the order documented on `EscrowInstruction::Exchange`.
+#[repr(usize)]
+#[derive(Clone, Copy, Debug)]
+pub enum ExchangeAccount {
+    Taker = 0,
+    TakerSourceTokenAccount = 1,
+    TakerDestTokenAccount = 2,
+    TempTokenAccount = 3,
+    Initializer = 4,
+    InitializerDestTokenAccount = 5,
+    EscrowAccount = 6,
+    TokenProgram = 7,
+    PdaAccount = 8,
+    TakerSourceMint = 9,
+    EscrowIndex = 10,
+    Config = 11,
+    TreasuryTokenAccount = 12,
+    InitializerRefundTokenAccount = 13,
+    SecondaryTreasuryTokenAccount = 14,
+    /// Only passed if the escrow has an oracle, so always last.
+    Oracle = 15,
+}
+
+impl ExchangeAccount {
+    /// Number of accounts, including the optional oracle.
+    pub const COUNT: usize = ExchangeAccount::Oracle as usize + 1;
+}
+
 impl EscrowInstruction {
     pub fn unpack(input: &[u8]) -> Result<Self, ProgramError> {
+        // Checked before anything is allocated for the lists it may hold.
+        if input.len() > MAX_INSTRUCTION_DATA_LEN {
+            msg!(
+                "Instruction data is {} bytes, at most {} allowed",
+                input.len(),
+                MAX_INSTRUCTION_DATA_LEN
+            );
+            return Err(InvalidInstruction.into());
+        }
+        let (&format_version, rest) = input.split_first().ok_or(InvalidInstruction)?;
+        match format_version {
+            INSTRUCTION_FORMAT_VERSION => Self::unpack_tagged(rest),
+            _ => {
+                msg!("Unknown instruction format version {}", format_version);
+                Err(InvalidInstruction.into())
+            }
+        }
+    }
+
+    /// Unpacks the tag and fields that follow the format version in the current format.
+    fn unpack_tagged(input: &[u8]) -> Result<Self, ProgramError> {
         let (&tag, rest) = input.split_first().ok_or(InvalidInstruction)?;
 
         Ok(match tag {
-            0 => Self::InitEscrow {
-                amount: Self::unpack_amount(rest)?,
+            0 => {
+                let (amount, rest) = Self::unpack_u64(rest)?;
+                let (oracle, rest) = Self::unpack_pubkey_option(rest)?;
+                let (max_deviation_bps, rest) = Self::unpack_u16(rest)?;
+                let (&max_fills, rest) = rest.split_first().ok_or(InvalidInstruction)?;
+                let (rounding_mode, rest) = rest
+                    .split_first()
+                    .and_then(|(&mode, rest)| Some((RoundingMode::from_u8(mode)?, rest)))
+                    .ok_or(InvalidInstruction)?;
+                let (accepted_mints, rest) = Self::unpack_pubkeys(rest, u8::MAX as usize)?;
+                let (reference, rest) = Self::unpack_bytes(rest, MAX_REFERENCE_PREIMAGE_LEN)?;
+                let (reference_mode, rest) = rest
+                    .split_first()
+                    .and_then(|(&mode, rest)| Some((ReferenceMode::from_u8(mode)?, rest)))
+                    .ok_or(InvalidInstruction)?;
+                let (start_slot, rest) = Self::unpack_u64(rest)?;
+                let (cancel_penalty_bps, rest) = Self::unpack_u16(rest)?;
+                let (expiry_slot, rest) = Self::unpack_u64(rest)?;
+                let (min_open_slots, rest) = Self::unpack_u64(rest)?;
+                let (&category, rest) = rest.split_first().ok_or(InvalidInstruction)?;
+                let (taker_merkle_root, rest) = Self::unpack_pubkey(rest)?;
+                let (deliver_to_ata, rest) = match rest.split_first() {
+                    Some((&0, rest)) => (false, rest),
+                    Some((&1, rest)) => (true, rest),
+                    _ => return Err(InvalidInstruction.into()),
+                };
+                let (min_fill, rest) = Self::unpack_u64(rest)?;
+                let (stake, rest) = Self::unpack_u64(rest)?;
+                let (maker, rest) = Self::unpack_pubkey_option(rest)?;
+                let (basket, _rest) = Self::unpack_pubkeys(rest, u8::MAX as usize)?;
+                Self::InitEscrow {
+                    amount,
+                    oracle,
+                    max_deviation_bps,
+                    max_fills,
+                    rounding_mode,
+                    accepted_mints,
+                    reference,
+                    reference_mode,
+                    start_slot,
+                    cancel_penalty_bps,
+                    expiry_slot,
+                    min_open_slots,
+                    category,
+                    taker_merkle_root: taker_merkle_root.to_bytes(),
+                    deliver_to_ata,
+                    min_fill,
+                    stake,
+                    maker,
+                    basket,
+                }
+            }
+            1 => {
+                let (amount, reference_preimage, max_amount_in, taker_proof) =
+                    Self::unpack_fill(rest)?;
+                Self::Exchange {
+                    amount,
+                    reference_preimage,
+                    max_amount_in,
+                    taker_proof,
+                }
+            }
+            2 => Self::SweepDust,
+            3 => Self::CancelEscrow,
+            4 => Self::MergeEscrows,
+            5 => {
+                let (amount, reference_preimage, max_amount_in, taker_proof) =
+                    Self::unpack_fill(rest)?;
+                Self::PartialExchange {
+                    amount,
+                    reference_preimage,
+                    max_amount_in,
+                    taker_proof,
+                }
+            }
+            6 => Self::GetVersion,
+            7 => {
+                let (amount, reference_preimage, max_amount_in, taker_proof) =
+                    Self::unpack_fill(rest)?;
+                Self::PrepareSettlement {
+                    amount,
+                    reference_preimage,
+                    max_amount_in,
+                    taker_proof,
+                }
+            }
+            8 => Self::FinalizeSettlement,
+            9 => Self::DescribeExchange,
+            10 => Self::InitConfig(Self::unpack_config_args(rest)?),
+            11 => Self::UpdateConfig(Self::unpack_config_args(rest)?),
+            12 => Self::SplitEscrow {
+                count: *rest.first().ok_or(InvalidInstruction)?,
+            },
+            13 => Self::Verify {
+                repair: match rest.first() {
+                    Some(0) => false,
+                    Some(1) => true,
+                    _ => return Err(InvalidInstruction.into()),
+                },
+            },
+            #[cfg(feature = "test-harness")]
+            14 => Self::SetEscrowStateForTesting(Box::new(
+                Escrow::unpack_from_slice(rest.get(..Escrow::LEN).ok_or(InvalidInstruction)?)
+                    .map_err(|_| InvalidInstruction)?,
+            )),
+            15 => Self::ProposeAdmin {
+                new_admin: Self::unpack_pubkey(rest)?.0,
+            },
+            16 => Self::AcceptAdmin,
+            17 => Self::ReclaimExpiredBatch {
+                count: *rest.first().ok_or(InvalidInstruction)?,
             },
-            1 => Self::Exchange {
-                amount: Self::unpack_amount(rest)?,
+            18 => Self::CacheAuthority,
+            19 => Self::QuoteExchange {
+                amount: Self::unpack_u64(rest)?.0,
             },
+            20 => Self::InitAuditLog,
+            21 => Self::TopUpEscrow {
+                amount: Self::unpack_u64(rest)?.0,
+            },
+            22 => Self::ReplaceEscrow {
+                new_expected_amount: Self::unpack_u64(rest)?.0,
+            },
+            23 => Self::TopUpRent,
+            24 => {
+                let (taker, rest) = Self::unpack_pubkey(rest)?;
+                let (taker_proof, _rest) = Self::unpack_pubkeys(rest, MAX_TAKER_PROOF_LEN)?;
+                Self::CheckEligibility {
+                    taker,
+                    taker_proof: taker_proof.iter().map(|key| key.to_bytes()).collect(),
+                }
+            }
             _ => return Err(InvalidInstruction.into()),
         })
     }
 
-    fn unpack_amount(input: &[u8]) -> Result<u64, ProgramError> {
-        let amount = input
+    pub fn pack(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(size_of::<Self>());
+        buf.push(INSTRUCTION_FORMAT_VERSION);
+        match self {
+            Self::InitEscrow {
+                amount,
+                oracle,
+                max_deviation_bps,
+                max_fills,
+                rounding_mode,
+                accepted_mints,
+                reference,
+                reference_mode,
+                start_slot,
+                cancel_penalty_bps,
+                expiry_slot,
+                min_open_slots,
+                category,
+                taker_merkle_root,
+                deliver_to_ata,
+                min_fill,
+                stake,
+                maker,
+                basket,
+            } => {
+                buf.push(0);
+                buf.extend_from_slice(&amount.to_le_bytes());
+                Self::pack_pubkey_option(oracle, &mut buf);
+                buf.extend_from_slice(&max_deviation_bps.to_le_bytes());
+                buf.push(*max_fills);
+                buf.push(*rounding_mode as u8);
+                buf.push(accepted_mints.len() as u8);
+                for mint in accepted_mints {
+                    buf.extend_from_slice(mint.as_ref());
+                }
+                Self::pack_bytes(reference, &mut buf);
+                buf.push(*reference_mode as u8);
+                buf.extend_from_slice(&start_slot.to_le_bytes());
+                buf.extend_from_slice(&cancel_penalty_bps.to_le_bytes());
+                buf.extend_from_slice(&expiry_slot.to_le_bytes());
+                buf.extend_from_slice(&min_open_slots.to_le_bytes());
+                buf.push(*category);
+                buf.extend_from_slice(taker_merkle_root);
+                buf.push(*deliver_to_ata as u8);
+                buf.extend_from_slice(&min_fill.to_le_bytes());
+                buf.extend_from_slice(&stake.to_le_bytes());
+                Self::pack_pubkey_option(maker, &mut buf);
+                buf.push(basket.len() as u8);
+                for temp_token_account in basket {
+                    buf.extend_from_slice(temp_token_account.as_ref());
+                }
+            }
+            Self::Exchange {
+                amount,
+                reference_preimage,
+                max_amount_in,
+                taker_proof,
+            } => {
+                buf.push(1);
+                buf.extend_from_slice(&amount.to_le_bytes());
+                Self::pack_bytes(reference_preimage, &mut buf);
+                buf.extend_from_slice(&max_amount_in.to_le_bytes());
+                Self::pack_taker_proof(taker_proof, &mut buf);
+            }
+            Self::SweepDust => buf.push(2),
+            Self::CancelEscrow => buf.push(3),
+            Self::MergeEscrows => buf.push(4),
+            Self::PartialExchange {
+                amount,
+                reference_preimage,
+                max_amount_in,
+                taker_proof,
+            } => {
+                buf.push(5);
+                buf.extend_from_slice(&amount.to_le_bytes());
+                Self::pack_bytes(reference_preimage, &mut buf);
+                buf.extend_from_slice(&max_amount_in.to_le_bytes());
+                Self::pack_taker_proof(taker_proof, &mut buf);
+            }
+            Self::GetVersion => buf.push(6),
+            Self::PrepareSettlement {
+                amount,
+                reference_preimage,
+                max_amount_in,
+                taker_proof,
+            } => {
+                buf.push(7);
+                buf.extend_from_slice(&amount.to_le_bytes());
+                Self::pack_bytes(reference_preimage, &mut buf);
+                buf.extend_from_slice(&max_amount_in.to_le_bytes());
+                Self::pack_taker_proof(taker_proof, &mut buf);
+            }
+            Self::FinalizeSettlement => buf.push(8),
+            Self::DescribeExchange => buf.push(9),
+            Self::InitConfig(args) => {
+                buf.push(10);
+                Self::pack_config_args(args, &mut buf);
+            }
+            Self::UpdateConfig(args) => {
+                buf.push(11);
+                Self::pack_config_args(args, &mut buf);
+            }
+            Self::SplitEscrow { count } => {
+                buf.push(12);
+                buf.push(*count);
+            }
+            Self::Verify { repair } => {
+                buf.push(13);
+                buf.push(*repair as u8);
+            }
+            #[cfg(feature = "test-harness")]
+            Self::SetEscrowStateForTesting(escrow) => {
+                buf.push(14);
+                let start = buf.len();
+                buf.resize(start + Escrow::LEN, 0);
+                escrow.pack_into_slice(&mut buf[start..]);
+            }
+            Self::ProposeAdmin { new_admin } => {
+                buf.push(15);
+                buf.extend_from_slice(new_admin.as_ref());
+            }
+            Self::AcceptAdmin => buf.push(16),
+            Self::ReclaimExpiredBatch { count } => {
+                buf.push(17);
+                buf.push(*count);
+            }
+            Self::CacheAuthority => buf.push(18),
+            Self::QuoteExchange { amount } => {
+                buf.push(19);
+                buf.extend_from_slice(&amount.to_le_bytes());
+            }
+            Self::InitAuditLog => buf.push(20),
+            Self::TopUpEscrow { amount } => {
+                buf.push(21);
+                buf.extend_from_slice(&amount.to_le_bytes());
+            }
+            Self::ReplaceEscrow {
+                new_expected_amount,
+            } => {
+                buf.push(22);
+                buf.extend_from_slice(&new_expected_amount.to_le_bytes());
+            }
+            Self::TopUpRent => buf.push(23),
+            Self::CheckEligibility { taker, taker_proof } => {
+                buf.push(24);
+                buf.extend_from_slice(taker.as_ref());
+                Self::pack_taker_proof(taker_proof, &mut buf);
+            }
+        }
+        buf
+    }
+
+    /// Fewest accounts the instruction can be passed: the ones documented as required, not
+    /// counting optional or trailing ones.
+    pub fn required_account_count(&self) -> usize {
+        match self {
+            Self::InitEscrow { basket, .. } => INIT_ESCROW_BASKET_OFFSET + basket.len(),
+            Self::Exchange { .. }
+            | Self::PartialExchange { .. }
+            | Self::PrepareSettlement { .. }
+            | Self::FinalizeSettlement => ExchangeAccount::Oracle as usize,
+            Self::SweepDust => 5,
+            Self::CancelEscrow => 7,
+            Self::MergeEscrows => 8,
+            Self::GetVersion => 0,
+            Self::DescribeExchange => 1,
+            Self::InitConfig(_) => 4,
+            Self::UpdateConfig(_) => 2,
+            Self::SplitEscrow { count } => 8 + 2 * *count as usize,
+            Self::Verify { .. } => 3,
+            #[cfg(feature = "test-harness")]
+            Self::SetEscrowStateForTesting(_) => 1,
+            Self::ProposeAdmin { .. } | Self::AcceptAdmin => 2,
+            Self::ReclaimExpiredBatch { count } => 2 + 5 * *count as usize,
+            Self::CacheAuthority => 3,
+            Self::QuoteExchange { .. } => 4,
+            Self::InitAuditLog => 4,
+            Self::TopUpEscrow { .. } => 6,
+            Self::ReplaceEscrow { .. } => REPLACE_ESCROW_INIT_OFFSET + 10,
+            Self::TopUpRent => 3,
+            Self::CheckEligibility { .. } => 1,
+        }
+    }
+
+    fn unpack_u64(input: &[u8]) -> Result<(u64, &[u8]), ProgramError> {
+        let value = input
             .get(..8)
             .and_then(|slice| slice.try_into().ok())
             .map(u64::from_le_bytes)
             .ok_or(InvalidInstruction)?;
-        Ok(amount)
+        Ok((value, &input[8..]))
+    }
+
+    fn unpack_u16(input: &[u8]) -> Result<(u16, &[u8]), ProgramError> {
+        let value = input
+            .get(..2)
+            .and_then(|slice| slice.try_into().ok())
+            .map(u16::from_le_bytes)
+            .ok_or(InvalidInstruction)?;
+        Ok((value, &input[2..]))
+    }
+
+    fn unpack_pubkey_option(input: &[u8]) -> Result<(COption<Pubkey>, &[u8]), ProgramError> {
+        match input.split_first() {
+            Some((&0, rest)) => Ok((COption::None, rest)),
+            Some((&1, rest)) if rest.len() >= 32 => {
+                let (key, rest) = rest.split_at(32);
+                Ok((COption::Some(Pubkey::new(key)), rest))
+            }
+            _ => Err(InvalidInstruction.into()),
+        }
+    }
+
+    /// Unpacks a count byte followed by that many keys, at most `max`.
+    fn unpack_pubkeys(input: &[u8], max: usize) -> Result<(Vec<Pubkey>, &[u8]), ProgramError> {
+        let (&count, rest) = input.split_first().ok_or(InvalidInstruction)?;
+        let count = count as usize;
+        if count > max || rest.len() < count * 32 {
+            return Err(InvalidInstruction.into());
+        }
+        let (keys, rest) = rest.split_at(count * 32);
+        Ok((keys.chunks_exact(32).map(Pubkey::new).collect(), rest))
+    }
+
+    /// Unpacks a count byte followed by that many mints with their fee. The processor bounds the
+    /// count, like it does for key lists.
+    fn unpack_fee_overrides(input: &[u8]) -> Result<(Vec<FeeOverride>, &[u8]), ProgramError> {
+        let (&count, mut rest) = input.split_first().ok_or(InvalidInstruction)?;
+        let mut fee_overrides = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let (mint, after_mint) = Self::unpack_pubkey(rest)?;
+            let (fee_bps, after_fee) = Self::unpack_u16(after_mint)?;
+            fee_overrides.push(FeeOverride { mint, fee_bps });
+            rest = after_fee;
+        }
+        Ok((fee_overrides, rest))
+    }
+
+    /// Unpacks a length byte followed by that many bytes, at most `max`.
+    fn unpack_bytes(input: &[u8], max: usize) -> Result<(Vec<u8>, &[u8]), ProgramError> {
+        let (&len, rest) = input.split_first().ok_or(InvalidInstruction)?;
+        let len = len as usize;
+        if len > max || rest.len() < len {
+            return Err(InvalidInstruction.into());
+        }
+        let (bytes, rest) = rest.split_at(len);
+        Ok((bytes.to_vec(), rest))
+    }
+
+    fn pack_bytes(value: &[u8], buf: &mut Vec<u8>) {
+        buf.push(value.len() as u8);
+        buf.extend_from_slice(value);
+    }
+
+    /// Unpacks the data of the fill instructions, where older clients may leave out the preimage,
+    /// the max amount in and the taker proof.
+    #[allow(clippy::type_complexity)]
+    fn unpack_fill(input: &[u8]) -> Result<(u64, Vec<u8>, u64, Vec<[u8; 32]>), ProgramError> {
+        let (amount, rest) = Self::unpack_u64(input)?;
+        if rest.is_empty() {
+            return Ok((amount, vec![], u64::MAX, vec![]));
+        }
+        let (reference_preimage, rest) = Self::unpack_bytes(rest, MAX_REFERENCE_PREIMAGE_LEN)?;
+        if rest.is_empty() {
+            return Ok((amount, reference_preimage, u64::MAX, vec![]));
+        }
+        let (max_amount_in, rest) = Self::unpack_u64(rest)?;
+        let taker_proof = if rest.is_empty() {
+            vec![]
+        } else {
+            Self::unpack_pubkeys(rest, MAX_TAKER_PROOF_LEN)?
+                .0
+                .iter()
+                .map(|key| key.to_bytes())
+                .collect()
+        };
+        Ok((amount, reference_preimage, max_amount_in, taker_proof))
+    }
+
+    fn pack_taker_proof(taker_proof: &[[u8; 32]], buf: &mut Vec<u8>) {
+        buf.push(taker_proof.len() as u8);
+        for hash in taker_proof {
+            buf.extend_from_slice(hash);
+        }
+    }
+
+    fn unpack_config_args(input: &[u8]) -> Result<ConfigArgs, ProgramError> {
+        let (admin, rest) = Self::unpack_pubkey(input)?;
+        let (fee_bps, rest) = Self::unpack_u16(rest)?;
+        let (paused, rest) = match rest.split_first() {
+            Some((&0, rest)) => (false, rest),
+            Some((&1, rest)) => (true, rest),
+            _ => return Err(InvalidInstruction.into()),
+        };
+        let (treasury, rest) = Self::unpack_pubkey(rest)?;
+        let (init_fee, rest) = Self::unpack_u64(rest)?;
+        // Optional so clients predating them keep working, as without any overrides, relayer,
+        // cooldown or secondary treasury.
+        let (fee_overrides, rest) = if rest.is_empty() {
+            (vec![], rest)
+        } else {
+            Self::unpack_fee_overrides(rest)?
+        };
+        let (allowed_relayer, rest) = if rest.is_empty() {
+            (COption::None, rest)
+        } else {
+            Self::unpack_pubkey_option(rest)?
+        };
+        let (address_cooldown_slots, rest) = if rest.is_empty() {
+            (0, rest)
+        } else {
+            Self::unpack_u64(rest)?
+        };
+        let (secondary_treasury, secondary_treasury_bps) = if rest.is_empty() {
+            (Pubkey::default(), 0)
+        } else {
+            let (secondary_treasury, rest) = Self::unpack_pubkey(rest)?;
+            (secondary_treasury, Self::unpack_u16(rest)?.0)
+        };
+        Ok(ConfigArgs {
+            admin,
+            fee_bps,
+            paused,
+            treasury,
+            init_fee,
+            fee_overrides,
+            allowed_relayer,
+            address_cooldown_slots,
+            secondary_treasury,
+            secondary_treasury_bps,
+        })
+    }
+
+    fn pack_config_args(args: &ConfigArgs, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(args.admin.as_ref());
+        buf.extend_from_slice(&args.fee_bps.to_le_bytes());
+        buf.push(args.paused as u8);
+        buf.extend_from_slice(args.treasury.as_ref());
+        buf.extend_from_slice(&args.init_fee.to_le_bytes());
+        buf.push(args.fee_overrides.len() as u8);
+        for fee_override in &args.fee_overrides {
+            buf.extend_from_slice(fee_override.mint.as_ref());
+            buf.extend_from_slice(&fee_override.fee_bps.to_le_bytes());
+        }
+        Self::pack_pubkey_option(&args.allowed_relayer, buf);
+        buf.extend_from_slice(&args.address_cooldown_slots.to_le_bytes());
+        buf.extend_from_slice(args.secondary_treasury.as_ref());
+        buf.extend_from_slice(&args.secondary_treasury_bps.to_le_bytes());
+    }
+
+    fn unpack_pubkey(input: &[u8]) -> Result<(Pubkey, &[u8]), ProgramError> {
+        if input.len() < 32 {
+            return Err(InvalidInstruction.into());
+        }
+        let (key, rest) = input.split_at(32);
+        Ok((Pubkey::new(key), rest))
+    }
+
+    fn pack_pubkey_option(value: &COption<Pubkey>, buf: &mut Vec<u8>) {
+        match value {
+            COption::Some(key) => {
+                buf.push(1);
+                buf.extend_from_slice(key.as_ref());
+            }
+            COption::None => buf.push(0),
+        }
+    }
+}
+
+/// Creates an `InitEscrow` instruction.
+#[allow(clippy::too_many_arguments)]
+pub fn init_escrow(
+    program_id: &Pubkey,
+    initializer: &Pubkey,
+    temp_token_account: &Pubkey,
+    initializer_receive_token_account: &Pubkey,
+    initializer_receive_mint: &Pubkey,
+    escrow_account: &Pubkey,
+    treasury: &Pubkey,
+    amount: u64,
+    oracle: COption<Pubkey>,
+    max_deviation_bps: u16,
+    max_fills: u8,
+    rounding_mode: RoundingMode,
+    accepted_mints: Vec<Pubkey>,
+    reference: Vec<u8>,
+    reference_mode: ReferenceMode,
+    start_slot: u64,
+    cancel_penalty_bps: u16,
+    expiry_slot: u64,
+    min_open_slots: u64,
+    category: u8,
+    taker_merkle_root: [u8; 32],
+    deliver_to_ata: bool,
+    min_fill: u64,
+    stake: u64,
+    maker: COption<Pubkey>,
+    basket: Vec<Pubkey>,
+) -> Result<Instruction, ProgramError> {
+    let data = EscrowInstruction::InitEscrow {
+        amount,
+        oracle,
+        max_deviation_bps,
+        max_fills,
+        rounding_mode,
+        accepted_mints,
+        reference,
+        reference_mode,
+        start_slot,
+        cancel_penalty_bps,
+        expiry_slot,
+        min_open_slots,
+        category,
+        taker_merkle_root,
+        deliver_to_ata,
+        min_fill,
+        stake,
+        maker,
+        basket: basket.clone(),
+    }
+    .pack();
+
+    let mut accounts = vec![
+        AccountMeta::new(*initializer, true),
+        AccountMeta::new(*temp_token_account, false),
+        AccountMeta::new_readonly(*initializer_receive_token_account, false),
+        AccountMeta::new(*escrow_account, false),
+        AccountMeta::new_readonly(spl_token::id(), false),
+        AccountMeta::new_readonly(*initializer_receive_mint, false),
+        AccountMeta::new(find_escrow_index_address(program_id, initializer).0, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new(*treasury, false),
+        AccountMeta::new_readonly(find_config_address(program_id).0, false),
+    ];
+    accounts.extend(
+        basket
+            .iter()
+            .map(|temp_token_account| AccountMeta::new(*temp_token_account, false)),
+    );
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Creates an `Exchange` instruction. `oracle` must be given iff the escrow has one.
+#[allow(clippy::too_many_arguments)]
+pub fn exchange(
+    program_id: &Pubkey,
+    taker: &Pubkey,
+    taker_send_token_account: &Pubkey,
+    taker_send_mint: &Pubkey,
+    taker_receive_token_account: &Pubkey,
+    temp_token_account: &Pubkey,
+    initializer: &Pubkey,
+    initializer_receive_token_account: &Pubkey,
+    escrow_account: &Pubkey,
+    treasury_token_account: &Pubkey,
+    initializer_refund_token_account: &Pubkey,
+    oracle: Option<&Pubkey>,
+    amount: u64,
+    reference_preimage: &[u8],
+    max_amount_in: u64,
+) -> Result<Instruction, ProgramError> {
+    let data = EscrowInstruction::Exchange {
+        amount,
+        reference_preimage: reference_preimage.to_vec(),
+        max_amount_in,
+        taker_proof: vec![],
+    }
+    .pack();
+
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*taker, true),
+        AccountMeta::new(*taker_send_token_account, false),
+        AccountMeta::new(*taker_receive_token_account, false),
+        AccountMeta::new(*temp_token_account, false),
+        AccountMeta::new(*initializer, false),
+        AccountMeta::new(*initializer_receive_token_account, false),
+        AccountMeta::new(*escrow_account, false),
+        AccountMeta::new_readonly(spl_token::id(), false),
+        AccountMeta::new_readonly(find_escrow_authority_address(program_id).0, false),
+        AccountMeta::new_readonly(*taker_send_mint, false),
+        AccountMeta::new(find_escrow_index_address(program_id, initializer).0, false),
+        AccountMeta::new_readonly(find_config_address(program_id).0, false),
+        AccountMeta::new(*treasury_token_account, false),
+        AccountMeta::new(*initializer_refund_token_account, false),
+        // Stands in for the secondary treasury's token account until `with_secondary_treasury`.
+        AccountMeta::new(*treasury_token_account, false),
+    ];
+    debug_assert_eq!(accounts.len(), ExchangeAccount::Oracle as usize);
+    if let Some(oracle) = oracle {
+        accounts.push(AccountMeta::new_readonly(*oracle, false));
+    }
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a `PartialExchange` instruction, taking the same accounts as `exchange`.
+#[allow(clippy::too_many_arguments)]
+pub fn partial_exchange(
+    program_id: &Pubkey,
+    taker: &Pubkey,
+    taker_send_token_account: &Pubkey,
+    taker_send_mint: &Pubkey,
+    taker_receive_token_account: &Pubkey,
+    temp_token_account: &Pubkey,
+    initializer: &Pubkey,
+    initializer_receive_token_account: &Pubkey,
+    escrow_account: &Pubkey,
+    treasury_token_account: &Pubkey,
+    initializer_refund_token_account: &Pubkey,
+    oracle: Option<&Pubkey>,
+    amount: u64,
+    reference_preimage: &[u8],
+    max_amount_in: u64,
+) -> Result<Instruction, ProgramError> {
+    let mut instruction = exchange(
+        program_id,
+        taker,
+        taker_send_token_account,
+        taker_send_mint,
+        taker_receive_token_account,
+        temp_token_account,
+        initializer,
+        initializer_receive_token_account,
+        escrow_account,
+        treasury_token_account,
+        initializer_refund_token_account,
+        oracle,
+        amount,
+        reference_preimage,
+        max_amount_in,
+    )?;
+    instruction.data = EscrowInstruction::PartialExchange {
+        amount,
+        reference_preimage: reference_preimage.to_vec(),
+        max_amount_in,
+        taker_proof: vec![],
+    }
+    .pack();
+    Ok(instruction)
+}
+
+/// Creates a `PrepareSettlement` instruction, taking the same accounts as `exchange`.
+#[allow(clippy::too_many_arguments)]
+pub fn prepare_settlement(
+    program_id: &Pubkey,
+    taker: &Pubkey,
+    taker_send_token_account: &Pubkey,
+    taker_send_mint: &Pubkey,
+    taker_receive_token_account: &Pubkey,
+    temp_token_account: &Pubkey,
+    initializer: &Pubkey,
+    initializer_receive_token_account: &Pubkey,
+    escrow_account: &Pubkey,
+    treasury_token_account: &Pubkey,
+    initializer_refund_token_account: &Pubkey,
+    oracle: Option<&Pubkey>,
+    amount: u64,
+    reference_preimage: &[u8],
+    max_amount_in: u64,
+) -> Result<Instruction, ProgramError> {
+    let mut instruction = exchange(
+        program_id,
+        taker,
+        taker_send_token_account,
+        taker_send_mint,
+        taker_receive_token_account,
+        temp_token_account,
+        initializer,
+        initializer_receive_token_account,
+        escrow_account,
+        treasury_token_account,
+        initializer_refund_token_account,
+        oracle,
+        amount,
+        reference_preimage,
+        max_amount_in,
+    )?;
+    instruction.data = EscrowInstruction::PrepareSettlement {
+        amount,
+        reference_preimage: reference_preimage.to_vec(),
+        max_amount_in,
+        taker_proof: vec![],
+    }
+    .pack();
+    Ok(instruction)
+}
+
+/// Creates a `FinalizeSettlement` instruction, taking the same accounts as `exchange` minus the
+/// oracle, plus the instructions sysvar.
+#[allow(clippy::too_many_arguments)]
+pub fn finalize_settlement(
+    program_id: &Pubkey,
+    taker: &Pubkey,
+    taker_send_token_account: &Pubkey,
+    taker_send_mint: &Pubkey,
+    taker_receive_token_account: &Pubkey,
+    temp_token_account: &Pubkey,
+    initializer: &Pubkey,
+    initializer_receive_token_account: &Pubkey,
+    escrow_account: &Pubkey,
+    treasury_token_account: &Pubkey,
+    initializer_refund_token_account: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let mut instruction = exchange(
+        program_id,
+        taker,
+        taker_send_token_account,
+        taker_send_mint,
+        taker_receive_token_account,
+        temp_token_account,
+        initializer,
+        initializer_receive_token_account,
+        escrow_account,
+        treasury_token_account,
+        initializer_refund_token_account,
+        None,
+        0,
+        &[],
+        u64::MAX,
+    )?;
+    instruction.data = EscrowInstruction::FinalizeSettlement.pack();
+    instruction
+        .accounts
+        .push(AccountMeta::new_readonly(sysvar::instructions::id(), false));
+    Ok(instruction)
+}
+
+/// Creates a `SweepDust` instruction.
+pub fn sweep_dust(
+    program_id: &Pubkey,
+    admin: &Pubkey,
+    dust_account: &Pubkey,
+    treasury: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let data = EscrowInstruction::SweepDust.pack();
+
+    let accounts = vec![
+        AccountMeta::new_readonly(*admin, true),
+        AccountMeta::new(*dust_account, false),
+        AccountMeta::new(*treasury, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(find_config_address(program_id).0, false),
+    ];
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a `CancelEscrow` instruction.
+pub fn cancel_escrow(
+    program_id: &Pubkey,
+    initializer: &Pubkey,
+    temp_token_account: &Pubkey,
+    initializer_refund_token_account: &Pubkey,
+    escrow_account: &Pubkey,
+    treasury_token_account: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let data = EscrowInstruction::CancelEscrow.pack();
+
+    let accounts = vec![
+        AccountMeta::new(*initializer, true),
+        AccountMeta::new(*temp_token_account, false),
+        AccountMeta::new(*initializer_refund_token_account, false),
+        AccountMeta::new(*escrow_account, false),
+        AccountMeta::new_readonly(spl_token::id(), false),
+        AccountMeta::new_readonly(find_escrow_authority_address(program_id).0, false),
+        AccountMeta::new(find_escrow_index_address(program_id, initializer).0, false),
+        AccountMeta::new_readonly(find_config_address(program_id).0, false),
+        AccountMeta::new(*treasury_token_account, false),
+    ];
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a `MergeEscrows` instruction.
+pub fn merge_escrows(
+    program_id: &Pubkey,
+    initializer: &Pubkey,
+    escrow_account: &Pubkey,
+    temp_token_account: &Pubkey,
+    merged_escrow_account: &Pubkey,
+    merged_temp_token_account: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let data = EscrowInstruction::MergeEscrows.pack();
+
+    let accounts = vec![
+        AccountMeta::new(*initializer, true),
+        AccountMeta::new(*escrow_account, false),
+        AccountMeta::new(*temp_token_account, false),
+        AccountMeta::new(*merged_escrow_account, false),
+        AccountMeta::new(*merged_temp_token_account, false),
+        AccountMeta::new_readonly(spl_token::id(), false),
+        AccountMeta::new_readonly(find_escrow_authority_address(program_id).0, false),
+        AccountMeta::new(find_escrow_index_address(program_id, initializer).0, false),
+    ];
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a `DescribeExchange` instruction.
+pub fn describe_exchange(
+    program_id: &Pubkey,
+    escrow_account: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts: vec![AccountMeta::new_readonly(*escrow_account, false)],
+        data: EscrowInstruction::DescribeExchange.pack(),
+    })
+}
+
+/// Creates a `QuoteExchange` instruction.
+pub fn quote_exchange(
+    program_id: &Pubkey,
+    escrow_account: &Pubkey,
+    temp_token_account: &Pubkey,
+    payment_mint: &Pubkey,
+    amount: u64,
+) -> Result<Instruction, ProgramError> {
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new_readonly(*escrow_account, false),
+            AccountMeta::new_readonly(*temp_token_account, false),
+            AccountMeta::new_readonly(find_config_address(program_id).0, false),
+            AccountMeta::new_readonly(*payment_mint, false),
+        ],
+        data: EscrowInstruction::QuoteExchange { amount }.pack(),
+    })
+}
+
+/// Creates a `CheckEligibility` instruction.
+pub fn check_eligibility(
+    program_id: &Pubkey,
+    escrow_account: &Pubkey,
+    taker: &Pubkey,
+    taker_proof: Vec<[u8; 32]>,
+) -> Result<Instruction, ProgramError> {
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts: vec![AccountMeta::new_readonly(*escrow_account, false)],
+        data: EscrowInstruction::CheckEligibility {
+            taker: *taker,
+            taker_proof,
+        }
+        .pack(),
+    })
+}
+
+/// Creates an `InitConfig` instruction.
+pub fn init_config(
+    program_id: &Pubkey,
+    deployer: &Pubkey,
+    args: ConfigArgs,
+) -> Result<Instruction, ProgramError> {
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new(*deployer, true),
+            AccountMeta::new(find_config_address(program_id).0, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(
+                Pubkey::find_program_address(&[program_id.as_ref()], &bpf_loader_upgradeable::id())
+                    .0,
+                false,
+            ),
+        ],
+        data: EscrowInstruction::InitConfig(args).pack(),
+    })
+}
+
+/// Creates an `UpdateConfig` instruction.
+pub fn update_config(
+    program_id: &Pubkey,
+    admin: &Pubkey,
+    args: ConfigArgs,
+) -> Result<Instruction, ProgramError> {
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new_readonly(*admin, true),
+            AccountMeta::new(find_config_address(program_id).0, false),
+        ],
+        data: EscrowInstruction::UpdateConfig(args).pack(),
+    })
+}
+
+/// Creates a `ProposeAdmin` instruction.
+pub fn propose_admin(
+    program_id: &Pubkey,
+    admin: &Pubkey,
+    new_admin: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new_readonly(*admin, true),
+            AccountMeta::new(find_config_address(program_id).0, false),
+        ],
+        data: EscrowInstruction::ProposeAdmin {
+            new_admin: *new_admin,
+        }
+        .pack(),
+    })
+}
+
+/// Creates an `AcceptAdmin` instruction.
+pub fn accept_admin(program_id: &Pubkey, new_admin: &Pubkey) -> Result<Instruction, ProgramError> {
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new_readonly(*new_admin, true),
+            AccountMeta::new(find_config_address(program_id).0, false),
+        ],
+        data: EscrowInstruction::AcceptAdmin.pack(),
+    })
+}
+
+/// Creates a `SplitEscrow` instruction.
+pub fn split_escrow(
+    program_id: &Pubkey,
+    initializer: &Pubkey,
+    escrow_account: &Pubkey,
+    temp_token_account: &Pubkey,
+    temp_mint: &Pubkey,
+    count: u8,
+) -> Result<Instruction, ProgramError> {
+    let mut accounts = vec![
+        AccountMeta::new(*initializer, true),
+        AccountMeta::new(*escrow_account, false),
+        AccountMeta::new(*temp_token_account, false),
+        AccountMeta::new_readonly(*temp_mint, false),
+        AccountMeta::new_readonly(spl_token::id(), false),
+        AccountMeta::new_readonly(find_escrow_authority_address(program_id).0, false),
+        AccountMeta::new(find_escrow_index_address(program_id, initializer).0, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+    ];
+    for index in 0..count {
+        let child = find_split_escrow_address(program_id, escrow_account, index).0;
+        let child_temp = find_split_temp_address(program_id, escrow_account, index).0;
+        accounts.push(AccountMeta::new(child, false));
+        accounts.push(AccountMeta::new(child_temp, false));
+    }
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::SplitEscrow { count }.pack(),
+    })
+}
+
+/// Creates a `Verify` instruction, a repairing one if `admin` is given.
+pub fn verify(
+    program_id: &Pubkey,
+    escrow_account: &Pubkey,
+    temp_token_account: &Pubkey,
+    admin: Option<&Pubkey>,
+) -> Result<Instruction, ProgramError> {
+    let mut accounts = vec![
+        AccountMeta::new(*escrow_account, false),
+        AccountMeta::new_readonly(*temp_token_account, false),
+        AccountMeta::new_readonly(find_config_address(program_id).0, false),
+    ];
+    if let Some(admin) = admin {
+        accounts.push(AccountMeta::new_readonly(*admin, true));
+    }
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::Verify {
+            repair: admin.is_some(),
+        }
+        .pack(),
+    })
+}
+
+/// Creates a `SetEscrowStateForTesting` instruction.
+#[cfg(feature = "test-harness")]
+pub fn set_escrow_state_for_testing(
+    program_id: &Pubkey,
+    escrow_account: &Pubkey,
+    escrow: Escrow,
+) -> Result<Instruction, ProgramError> {
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts: vec![AccountMeta::new(*escrow_account, false)],
+        data: EscrowInstruction::SetEscrowStateForTesting(Box::new(escrow)).pack(),
+    })
+}
+
+/// Accounts of one escrow of a `ReclaimExpiredBatch`.
+pub struct ExpiredEscrow {
+    pub escrow_account: Pubkey,
+    pub temp_token_account: Pubkey,
+    pub initializer: Pubkey,
+    pub initializer_refund_token_account: Pubkey,
+}
+
+/// Creates a `ReclaimExpiredBatch` instruction. `treasury` is the treasury set in the config.
+pub fn reclaim_expired_batch(
+    program_id: &Pubkey,
+    escrows: &[ExpiredEscrow],
+    treasury: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let count = u8::try_from(escrows.len()).map_err(|_| ProgramError::InvalidArgument)?;
+    let mut accounts = vec![
+        AccountMeta::new_readonly(spl_token::id(), false),
+        AccountMeta::new_readonly(find_escrow_authority_address(program_id).0, false),
+    ];
+    for escrow in escrows {
+        accounts.push(AccountMeta::new(escrow.escrow_account, false));
+        accounts.push(AccountMeta::new(escrow.temp_token_account, false));
+        accounts.push(AccountMeta::new(escrow.initializer, false));
+        accounts.push(AccountMeta::new(
+            escrow.initializer_refund_token_account,
+            false,
+        ));
+        accounts.push(AccountMeta::new(
+            find_escrow_index_address(program_id, &escrow.initializer).0,
+            false,
+        ));
+    }
+    accounts.push(AccountMeta::new_readonly(
+        find_config_address(program_id).0,
+        false,
+    ));
+    accounts.push(AccountMeta::new(*treasury, false));
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::ReclaimExpiredBatch { count }.pack(),
+    })
+}
+
+/// Creates a `CacheAuthority` instruction.
+pub fn cache_authority(program_id: &Pubkey, payer: &Pubkey) -> Result<Instruction, ProgramError> {
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new(*payer, true),
+            AccountMeta::new(find_authority_cache_address(program_id).0, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+        data: EscrowInstruction::CacheAuthority.pack(),
+    })
+}
+
+/// Appends the `AuthorityCache` to an `Exchange`, `PartialExchange`, `PrepareSettlement` or
+/// `FinalizeSettlement` instruction, so the program reads the escrow authority instead of
+/// deriving it. Deriving costs 1500 compute units for every bump tried, the cache only the 32
+/// bytes of its key in the transaction. Combines with `with_relayer` in either order.
+pub fn with_authority_cache(mut instruction: Instruction, program_id: &Pubkey) -> Instruction {
+    instruction.accounts.push(AccountMeta::new_readonly(
+        find_authority_cache_address(program_id).0,
+        false,
+    ));
+    instruction
+}
+
+/// Appends `relayer` as a signer to an `Exchange`, `PartialExchange`, `PrepareSettlement` or
+/// `FinalizeSettlement` instruction, so it can be sent with the relayer as fee payer while the taker
+/// only signs for their tokens. If the config has an allowed relayer, it must be that one.
+pub fn with_relayer(mut instruction: Instruction, relayer: &Pubkey) -> Instruction {
+    instruction
+        .accounts
+        .push(AccountMeta::new_readonly(*relayer, true));
+    instruction
+}
+
+/// Sets the secondary treasury's token account of the payment mint of an `Exchange`,
+/// `PartialExchange` or `FinalizeSettlement` instruction, which the builders fill with the
+/// treasury's token account. Required whenever the config sends part of the fee to a secondary
+/// treasury and the fill's share of it isn't 0.
+pub fn with_secondary_treasury(
+    mut instruction: Instruction,
+    secondary_treasury_token_account: &Pubkey,
+) -> Instruction {
+    instruction.accounts[ExchangeAccount::SecondaryTreasuryTokenAccount as usize] =
+        AccountMeta::new(*secondary_treasury_token_account, false);
+    instruction
+}
+
+/// Creates an `InitAuditLog` instruction.
+pub fn init_audit_log(program_id: &Pubkey, admin: &Pubkey) -> Result<Instruction, ProgramError> {
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new(*admin, true),
+            AccountMeta::new(find_audit_log_address(program_id).0, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(find_config_address(program_id).0, false),
+        ],
+        data: EscrowInstruction::InitAuditLog.pack(),
+    })
+}
+
+/// Creates a `TopUpEscrow` instruction.
+pub fn top_up_escrow(
+    program_id: &Pubkey,
+    initializer: &Pubkey,
+    escrow_account: &Pubkey,
+    source_token_account: &Pubkey,
+    temp_token_account: &Pubkey,
+    amount: u64,
+) -> Result<Instruction, ProgramError> {
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new_readonly(*initializer, true),
+            AccountMeta::new(*escrow_account, false),
+            AccountMeta::new(*source_token_account, false),
+            AccountMeta::new(*temp_token_account, false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(find_config_address(program_id).0, false),
+        ],
+        data: EscrowInstruction::TopUpEscrow { amount }.pack(),
+    })
+}
+
+/// Creates a `ReplaceEscrow` instruction out of the `CancelEscrow` of the escrow (without basket
+/// accounts) and the `InitEscrow` of the new one, whose data is ignored. The cancel's refund
+/// account should be the new temp account, which the new escrow then takes the deposit in.
+///
+/// Even a pure reprice pays the escrow's cancel penalty out of the deposit, leaving the new escrow
+/// that much less, so with a penalty `cancel_escrow` needs the actual treasury token account.
+pub fn replace_escrow(
+    program_id: &Pubkey,
+    cancel_escrow: Instruction,
+    init_escrow: Instruction,
+    new_expected_amount: u64,
+) -> Result<Instruction, ProgramError> {
+    if cancel_escrow.accounts.len() != REPLACE_ESCROW_INIT_OFFSET {
+        return Err(ProgramError::InvalidArgument);
+    }
+    let mut accounts = cancel_escrow.accounts;
+    accounts.extend(init_escrow.accounts);
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::ReplaceEscrow {
+            new_expected_amount,
+        }
+        .pack(),
+    })
+}
+
+/// Creates a `TopUpRent` instruction.
+pub fn top_up_rent(
+    program_id: &Pubkey,
+    payer: &Pubkey,
+    escrow_account: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new(*payer, true),
+            AccountMeta::new(*escrow_account, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+        data: EscrowInstruction::TopUpRent.pack(),
+    })
+}
+
+/// Appends `rent_beneficiary` to an `InitEscrow` instruction, so the escrow account's rent goes to
+/// it rather than the initializer when the escrow closes. Every instruction that then closes the
+/// escrow (a closing `Exchange`, `PartialExchange` or `FinalizeSettlement`, `CancelEscrow`,
+/// `ReclaimExpiredBatch`, `SplitEscrow` and `MergeEscrows` for the merged escrow) needs it
+/// appended too.
+pub fn with_rent_beneficiary(
+    mut instruction: Instruction,
+    rent_beneficiary: &Pubkey,
+) -> Instruction {
+    instruction
+        .accounts
+        .push(AccountMeta::new(*rent_beneficiary, false));
+    instruction
+}
+
+/// Appends the temp token accounts of a basket escrow (see `Escrow::basket`) to a full `Exchange`
+/// or `FinalizeSettlement`, or to a `CancelEscrow`, each followed by the token account of the same
+/// mint it's emptied into: the taker's, or on cancel the initializer's. A `CancelEscrow` expects
+/// them in the order of `Escrow::basket`, right after its own accounts.
+pub fn with_basket_accounts(
+    mut instruction: Instruction,
+    basket: &[(Pubkey, Pubkey)],
+) -> Instruction {
+    for (temp_token_account, destination_token_account) in basket {
+        instruction
+            .accounts
+            .push(AccountMeta::new(*temp_token_account, false));
+        instruction
+            .accounts
+            .push(AccountMeta::new(*destination_token_account, false));
+    }
+    instruction
+}
+
+/// Makes an `Exchange` create the initializer's associated token account it pays into if that
+/// doesn't exist yet, for an escrow initialized with `deliver_to_ata`: marks the taker writable to
+/// fund its rent and appends the accounts the associated token account program needs.
+pub fn with_ata_creation(mut instruction: Instruction) -> Instruction {
+    if let Some(taker) = instruction
+        .accounts
+        .get_mut(ExchangeAccount::Taker as usize)
+    {
+        taker.is_writable = true;
+    }
+    instruction.accounts.extend([
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(spl_associated_token_account::id(), false),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+    ]);
+    instruction
+}
+
+/// Makes an `Exchange` or `PartialExchange` of an escrow expecting wrapped SOL take the payment
+/// from the taker's native SOL: the program funds a wrapped SOL account at the taker's
+/// `find_native_sol_wrapper_address` with `max_amount_in` lamports (so pass the fill's actual
+/// cost, e.g. `client::preview_exchange`'s `amount_in`, not `u64::MAX`), pays from it and closes
+/// it again, returning its rent and whatever wasn't spent to the taker. Replaces the taker's send
+/// token account, marks the taker writable to fund the wrapper and appends the system program.
+pub fn with_native_sol_payment(mut instruction: Instruction, program_id: &Pubkey) -> Instruction {
+    let taker = &mut instruction.accounts[ExchangeAccount::Taker as usize];
+    taker.is_writable = true;
+    let wrapper = find_native_sol_wrapper_address(program_id, &taker.pubkey).0;
+    instruction.accounts[ExchangeAccount::TakerSourceTokenAccount as usize] =
+        AccountMeta::new(wrapper, false);
+    instruction
+        .accounts
+        .push(AccountMeta::new_readonly(system_program::id(), false));
+    instruction
+}
+
+/// What an escrow's maker signs to approve a fill (see `Escrow::maker`): the escrow account, the
+/// taker, the amount of the deposit the taker fills, and the escrow's `fills_done` and
+/// `expected_amount` before the fill. The last two make an approval good for one fill at one
+/// price, so it can't be replayed on the next partial fill or after a `ReplaceEscrow`.
+pub fn maker_approval_message(
+    escrow_account: &Pubkey,
+    taker: &Pubkey,
+    amount: u64,
+    fills_done: u8,
+    expected_amount: u64,
+) -> Vec<u8> {
+    let mut message = Vec::with_capacity(81);
+    message.extend_from_slice(escrow_account.as_ref());
+    message.extend_from_slice(taker.as_ref());
+    message.extend_from_slice(&amount.to_le_bytes());
+    message.push(fills_done);
+    message.extend_from_slice(&expected_amount.to_le_bytes());
+    message
+}
+
+/// Where the ed25519 program instruction `verify_maker_approval` builds keeps each part of its
+/// data: its header (one signature), then the key, the signature and the message.
+pub const ED25519_PUBKEY_OFFSET: usize = 16;
+pub const ED25519_SIGNATURE_OFFSET: usize = ED25519_PUBKEY_OFFSET + 32;
+pub const ED25519_MESSAGE_OFFSET: usize = ED25519_SIGNATURE_OFFSET + 64;
+
+/// Creates the ed25519 program instruction verifying the maker's `signature` over `message` (see
+/// `maker_approval_message`), to go right before the fill it approves.
+pub fn verify_maker_approval(maker: &Pubkey, signature: &[u8; 64], message: &[u8]) -> Instruction {
+    let mut data = Vec::with_capacity(ED25519_MESSAGE_OFFSET + message.len());
+    data.extend_from_slice(&[1, 0]);
+    // Offsets into this same instruction's data (instruction index `u16::MAX`).
+    for value in [
+        ED25519_SIGNATURE_OFFSET as u16,
+        u16::MAX,
+        ED25519_PUBKEY_OFFSET as u16,
+        u16::MAX,
+        ED25519_MESSAGE_OFFSET as u16,
+        message.len() as u16,
+        u16::MAX,
+    ] {
+        data.extend_from_slice(&value.to_le_bytes());
+    }
+    data.extend_from_slice(maker.as_ref());
+    data.extend_from_slice(signature);
+    data.extend_from_slice(message);
+    Instruction {
+        program_id: ed25519_program::id(),
+        accounts: vec![],
+        data,
+    }
+}
+
+/// Appends the instructions sysvar to an `Exchange`, `PartialExchange` or `PrepareSettlement` of
+/// an escrow with a maker, so the program can find the `verify_maker_approval` before it.
+pub fn with_maker_approval(mut instruction: Instruction) -> Instruction {
+    instruction
+        .accounts
+        .push(AccountMeta::new_readonly(sysvar::instructions::id(), false));
+    instruction
+}
+
+/// Appends the `AuditLog` to an `Exchange`, `PartialExchange` or `FinalizeSettlement`
+/// instruction, so the fill gets recorded in it.
+pub fn with_audit_log(mut instruction: Instruction, program_id: &Pubkey) -> Instruction {
+    instruction.accounts.push(AccountMeta::new(
+        find_audit_log_address(program_id).0,
+        false,
+    ));
+    instruction
+}
+
+/// Sets the proof that the taker is in the escrow's allow-list (see `Escrow::allows_taker`) on an
+/// `Exchange`, `PartialExchange` or `PrepareSettlement` instruction.
+pub fn with_taker_proof(
+    mut instruction: Instruction,
+    proof: Vec<[u8; 32]>,
+) -> Result<Instruction, ProgramError> {
+    instruction.data = match EscrowInstruction::unpack(&instruction.data)? {
+        EscrowInstruction::Exchange {
+            amount,
+            reference_preimage,
+            max_amount_in,
+            ..
+        } => EscrowInstruction::Exchange {
+            amount,
+            reference_preimage,
+            max_amount_in,
+            taker_proof: proof,
+        },
+        EscrowInstruction::PartialExchange {
+            amount,
+            reference_preimage,
+            max_amount_in,
+            ..
+        } => EscrowInstruction::PartialExchange {
+            amount,
+            reference_preimage,
+            max_amount_in,
+            taker_proof: proof,
+        },
+        EscrowInstruction::PrepareSettlement {
+            amount,
+            reference_preimage,
+            max_amount_in,
+            ..
+        } => EscrowInstruction::PrepareSettlement {
+            amount,
+            reference_preimage,
+            max_amount_in,
+            taker_proof: proof,
+        },
+        _ => return Err(ProgramError::InvalidArgument),
+    }
+    .pack();
+    Ok(instruction)
+}
+
+/// Creates a `GetVersion` instruction.
+pub fn get_version(program_id: &Pubkey) -> Result<Instruction, ProgramError> {
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts: vec![],
+        data: EscrowInstruction::GetVersion.pack(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::error::EscrowError};
+
+    #[test]
+    fn unpack_accepts_current_format_version() {
+        let data = EscrowInstruction::GetVersion.pack();
+        assert_eq!(data[0], INSTRUCTION_FORMAT_VERSION);
+        assert!(matches!(
+            EscrowInstruction::unpack(&data),
+            Ok(EscrowInstruction::GetVersion)
+        ));
+    }
+
+    #[test]
+    fn unpack_rejects_unknown_format_version() {
+        let mut data = EscrowInstruction::GetVersion.pack();
+        for format_version in [0, INSTRUCTION_FORMAT_VERSION + 1, u8::MAX] {
+            data[0] = format_version;
+            assert!(matches!(
+                EscrowInstruction::unpack(&data),
+                Err(error) if error == EscrowError::InvalidInstruction.into()
+            ));
+        }
+    }
+
+    #[test]
+    fn unpack_rejects_oversized_data() {
+        // Bytes after an instruction's fields are ignored, up to the limit.
+        let mut data = EscrowInstruction::GetVersion.pack();
+        data.resize(MAX_INSTRUCTION_DATA_LEN, 0);
+        assert!(matches!(
+            EscrowInstruction::unpack(&data),
+            Ok(EscrowInstruction::GetVersion)
+        ));
+        data.push(0);
+        assert!(matches!(
+            EscrowInstruction::unpack(&data),
+            Err(error) if error == EscrowError::InvalidInstruction.into()
+        ));
     }
 }
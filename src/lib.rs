@@ -1,7 +1,34 @@
+#[cfg(feature = "client")]
+pub mod client;
 pub mod error;
 pub mod instruction;
+pub mod logging;
+pub mod math;
+#[cfg(feature = "oracle")]
+pub mod oracle;
 pub mod processor;
 pub mod state;
+pub mod token;
+pub mod utils;
 
 #[cfg(not(feature = "no-entrypoint"))]
 mod entrypoint;
+
+#[cfg(all(feature = "test-harness", target_arch = "bpf"))]
+compile_error!("the `test-harness` feature is for native tests only and must not be deployed");
+
+/// Smallest `expected_amount` an escrow can be created with, 0 for no floor.
+pub const MIN_EXPECTED_AMOUNT: u64 = 0;
+
+/// Slots a `PrepareSettlement` reserves the escrow for before it goes stale.
+pub const SETTLEMENT_EXPIRY_SLOTS: u64 = 150;
+
+/// Slots an escrow initialized without an explicit expiry stays fillable for, counted from when
+/// it opens: about 30 days at 400ms slots.
+pub const DEFAULT_EXPIRY_SLOTS: u64 = 6_480_000;
+
+/// Most escrows a single `ReclaimExpiredBatch` reclaims, to stay within its compute budget.
+pub const MAX_RECLAIM_BATCH: usize = 8;
+
+/// Version of the deployed program, bumped whenever instructions or their behavior change.
+pub const PROGRAM_VERSION: u32 = 1;
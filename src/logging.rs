@@ -0,0 +1,59 @@
+/// How much the program logs. Failures are always logged with `msg!`, as they revert anyway;
+/// everything else goes through `log_info!` or `log_debug!`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogLevel {
+    /// Which instruction ran and what it decided, e.g. skipping an escrow of a batch.
+    Info,
+    /// Step by step progress, e.g. each CPI about to be made.
+    Debug,
+}
+
+/// Level the program logs at: `Debug` unless built with the `quiet-logs` feature, which
+/// production deployments can use to save the compute the debug logs cost.
+pub const LOG_LEVEL: LogLevel = if cfg!(feature = "quiet-logs") {
+    LogLevel::Info
+} else {
+    LogLevel::Debug
+};
+
+/// Whether messages at `level` are logged at `LOG_LEVEL`.
+pub const fn enabled(level: LogLevel) -> bool {
+    level as u8 <= LOG_LEVEL as u8
+}
+
+/// `msg!` at `LogLevel::Info`.
+macro_rules! log_info {
+    ($($arg:tt)*) => {
+        if $crate::logging::enabled($crate::logging::LogLevel::Info) {
+            solana_program::msg!($($arg)*);
+        }
+    };
+}
+
+/// `msg!` at `LogLevel::Debug`.
+macro_rules! log_debug {
+    ($($arg:tt)*) => {
+        if $crate::logging::enabled($crate::logging::LogLevel::Debug) {
+            solana_program::msg!($($arg)*);
+        }
+    };
+}
+
+pub(crate) use {log_debug, log_info};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quiet_logs_suppress_debug_logs_only() {
+        if cfg!(feature = "quiet-logs") {
+            assert_eq!(LOG_LEVEL, LogLevel::Info);
+            assert!(!enabled(LogLevel::Debug));
+        } else {
+            assert_eq!(LOG_LEVEL, LogLevel::Debug);
+            assert!(enabled(LogLevel::Debug));
+        }
+        assert!(enabled(LogLevel::Info));
+    }
+}
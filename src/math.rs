@@ -0,0 +1,35 @@
+use solana_program::program_error::ProgramError;
+
+use crate::error::EscrowError;
+
+/// Direction to round a non-exact division in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Rounding {
+    Up,
+    Down,
+}
+
+/// Computes `amount * numerator / denominator`, rounded as given.
+pub fn proportional_amount(
+    amount: u64,
+    numerator: u64,
+    denominator: u64,
+    rounding: Rounding,
+) -> Result<u64, ProgramError> {
+    if denominator == 0 {
+        return Err(EscrowError::Overflow.into());
+    }
+    let product = (amount as u128)
+        .checked_mul(numerator as u128)
+        .ok_or(EscrowError::Overflow)?;
+    let result = match rounding {
+        Rounding::Up => {
+            product
+                .checked_add(denominator as u128 - 1)
+                .ok_or(EscrowError::Overflow)?
+                / denominator as u128
+        }
+        Rounding::Down => product / denominator as u128,
+    };
+    u64::try_from(result).map_err(|_| EscrowError::Overflow.into())
+}
@@ -0,0 +1,94 @@
+//! Minimal Pyth-style price feed decoding, enabled with the `oracle` feature.
+
+use arrayref::{array_mut_ref, array_ref, array_refs, mut_array_refs};
+use solana_program::{
+    program_error::ProgramError,
+    program_pack::{IsInitialized, Pack, Sealed},
+};
+
+use crate::error::EscrowError;
+
+/// Magic number at the start of every price account.
+pub const PRICE_FEED_MAGIC: u32 = 0xa1b2_c3d4;
+
+/// Largest exponent magnitude we accept, so `10^expo` fits comfortably in a u128.
+const MAX_EXPO: u32 = 18;
+
+/// Price of one (base unit of the) escrowed token in base units of the token the initializer
+/// receives, i.e. `price * 10^expo`.
+pub struct PriceFeed {
+    pub magic: u32,
+    pub price: i64,
+    pub expo: i32,
+}
+
+impl Sealed for PriceFeed {}
+impl IsInitialized for PriceFeed {
+    fn is_initialized(&self) -> bool {
+        self.magic == PRICE_FEED_MAGIC
+    }
+}
+
+impl Pack for PriceFeed {
+    const LEN: usize = 16;
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let dst = array_mut_ref![dst, 0, PriceFeed::LEN];
+        let (magic_dst, price_dst, expo_dst) = mut_array_refs![dst, 4, 8, 4];
+        *magic_dst = self.magic.to_le_bytes();
+        *price_dst = self.price.to_le_bytes();
+        *expo_dst = self.expo.to_le_bytes();
+    }
+
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        let src = array_ref![src, 0, PriceFeed::LEN];
+        let (magic, price, expo) = array_refs![src, 4, 8, 4];
+        Ok(PriceFeed {
+            magic: u32::from_le_bytes(*magic),
+            price: i64::from_le_bytes(*price),
+            expo: i32::from_le_bytes(*expo),
+        })
+    }
+}
+
+/// Checks that paying `expected_amount` for `deposit_amount` is within `max_deviation_bps` of the
+/// price the feed implies.
+pub fn check_price_deviation(
+    feed: &PriceFeed,
+    deposit_amount: u64,
+    expected_amount: u64,
+    max_deviation_bps: u16,
+) -> Result<(), ProgramError> {
+    if feed.price <= 0 || feed.expo.unsigned_abs() > MAX_EXPO {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    let scale = 10u128.pow(feed.expo.unsigned_abs());
+
+    // Compare `expected / deposit` against `price * 10^expo` by cross-multiplying, so the
+    // fair amount is `fair_num / fair_den`.
+    let value = (deposit_amount as u128)
+        .checked_mul(feed.price as u128)
+        .ok_or(EscrowError::Overflow)?;
+    let (fair_num, fair_den) = if feed.expo >= 0 {
+        (value.checked_mul(scale).ok_or(EscrowError::Overflow)?, 1)
+    } else {
+        (value, scale)
+    };
+    let expected = (expected_amount as u128)
+        .checked_mul(fair_den)
+        .ok_or(EscrowError::Overflow)?;
+
+    let deviation = expected
+        .max(fair_num)
+        .checked_sub(expected.min(fair_num))
+        .ok_or(EscrowError::Overflow)?;
+    let lhs = deviation.checked_mul(10_000).ok_or(EscrowError::Overflow)?;
+    let rhs = fair_num
+        .checked_mul(max_deviation_bps as u128)
+        .ok_or(EscrowError::Overflow)?;
+    if lhs > rhs {
+        return Err(EscrowError::PriceOutOfBounds.into());
+    }
+
+    Ok(())
+}
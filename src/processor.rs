@@ -1,101 +1,551 @@
 use solana_program::{
-    account_info::{next_account_info, AccountInfo},
+    account_info::AccountInfo,
+    bpf_loader_upgradeable, ed25519_program,
     entrypoint::ProgramResult,
-    msg,
-    program::{invoke, invoke_signed},
+    keccak, msg,
+    program::{invoke, invoke_signed, set_return_data},
     program_error::ProgramError,
+    program_option::COption,
     program_pack::{IsInitialized, Pack},
     pubkey::Pubkey,
-    sysvar::rent::Rent,
+    system_instruction, system_program,
+    sysvar::{
+        self,
+        clock::Clock,
+        instructions::{load_current_index_checked, load_instruction_at_checked},
+        rent::Rent,
+        Sysvar,
+    },
 };
+
+use spl_associated_token_account::{create_associated_token_account, get_associated_token_address};
 use spl_token::state::Account as TokenAccount;
 
-use crate::{error::EscrowError, instruction::EscrowInstruction, state::Escrow};
+#[cfg(feature = "oracle")]
+use crate::oracle::{check_price_deviation, PriceFeed};
+use crate::{
+    error::EscrowError,
+    instruction::{
+        self, ConfigArgs, EscrowInstruction, ExchangeAccount, CANCEL_ESCROW_BASKET_OFFSET,
+        EXCHANGE_ACCOUNT_SIGNER, EXCHANGE_ACCOUNT_WRITABLE, INIT_ESCROW_BASKET_OFFSET,
+        REPLACE_ESCROW_INIT_OFFSET, VERIFY_BUMP_MISMATCH, VERIFY_TEMP_OWNER_MISMATCH,
+        VERIFY_VERSION_MISMATCH,
+    },
+    logging::{log_debug, log_info},
+    math::{proportional_amount, Rounding},
+    state::{
+        escrow_signer_seeds, find_audit_log_address, find_authority_cache_address,
+        find_config_address, find_escrow_authority_address, find_escrow_index_address,
+        find_native_sol_wrapper_address, find_split_escrow_address, find_split_temp_address,
+        verify_escrow_pda, AuditEntry, AuditLog, AuthorityCache, CloseReason, Config, Escrow,
+        EscrowIndex, EscrowStatus, ReferenceMode, RoundingMode, AUDIT_LOG_SEED,
+        AUTHORITY_CACHE_SEED, CONFIG_SEED, ESCROW_INDEX_SEED, MAX_FEE_OVERRIDES, MAX_LIST_LEN,
+        NATIVE_SOL_WRAPPER_SEED, SPLIT_ESCROW_SEED, SPLIT_TEMP_SEED,
+    },
+    token::{assert_mint, assert_token_account, assert_token_program, can_receive_tokens},
+    utils::{named_account_at, next_named_account_info, require_account_count, require_signer},
+    DEFAULT_EXPIRY_SLOTS, MAX_RECLAIM_BATCH, MIN_EXPECTED_AMOUNT, PROGRAM_VERSION,
+};
+
+/// Index of the `ProgramData` variant of the upgradeable loader's account state.
+const PROGRAM_DATA_VARIANT: u32 = 3;
+/// Length of a ProgramData account's header: the variant, the slot and an optional authority.
+const PROGRAM_DATA_HEADER_LEN: usize = 4 + 8 + 1 + 32;
 
 pub struct Processor {}
 
-impl Processor {
-    pub fn process(program_id: &Pubkey, accounts: &[AccountInfo], input: &[u8]) -> ProgramResult {
-        let instruction = EscrowInstruction::unpack(input)?;
-        match instruction {
-            EscrowInstruction::InitEscrow { amount } => {
-                msg!("Instruction: InitEscrow");
-                Self::process_init_escrow(accounts, amount, program_id)
+/// Tokens moved by a single fill of an escrow.
+struct Fill {
+    /// Deposited tokens the taker receives.
+    amount: u64,
+    /// Tokens the taker pays the initializer.
+    payment: u64,
+    /// Tokens the taker pays the treasuries on top of `payment`.
+    fee: u64,
+    /// Part of `fee` going to the secondary treasury, the rest goes to the main one.
+    secondary_fee: u64,
+    closes_escrow: bool,
+}
+
+impl EscrowInstruction {
+    /// Routes the instruction to its `Processor` handler, the one place a new instruction has to
+    /// be hooked up.
+    pub fn execute(self, program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        require_account_count(accounts, self.required_account_count())?;
+        match self {
+            EscrowInstruction::InitEscrow {
+                amount,
+                oracle,
+                max_deviation_bps,
+                max_fills,
+                rounding_mode,
+                accepted_mints,
+                reference,
+                reference_mode,
+                start_slot,
+                cancel_penalty_bps,
+                expiry_slot,
+                min_open_slots,
+                category,
+                taker_merkle_root,
+                deliver_to_ata,
+                min_fill,
+                stake,
+                maker,
+                basket,
+            } => {
+                log_info!("Instruction: InitEscrow");
+                Processor::process_init_escrow(
+                    accounts,
+                    amount,
+                    oracle,
+                    max_deviation_bps,
+                    max_fills,
+                    rounding_mode,
+                    accepted_mints,
+                    Processor::reference_commitment(&reference, reference_mode)?,
+                    reference_mode,
+                    start_slot,
+                    cancel_penalty_bps,
+                    expiry_slot,
+                    min_open_slots,
+                    category,
+                    taker_merkle_root,
+                    deliver_to_ata,
+                    min_fill,
+                    stake,
+                    maker,
+                    basket,
+                    program_id,
+                )
+            }
+            EscrowInstruction::Exchange {
+                amount,
+                reference_preimage,
+                max_amount_in,
+                taker_proof,
+            } => {
+                log_info!("Instruction: Exchange");
+                Processor::process_exchange(
+                    accounts,
+                    amount,
+                    false,
+                    &reference_preimage,
+                    max_amount_in,
+                    &taker_proof,
+                    program_id,
+                )
+            }
+            EscrowInstruction::PartialExchange {
+                amount,
+                reference_preimage,
+                max_amount_in,
+                taker_proof,
+            } => {
+                log_info!("Instruction: PartialExchange");
+                Processor::process_exchange(
+                    accounts,
+                    amount,
+                    true,
+                    &reference_preimage,
+                    max_amount_in,
+                    &taker_proof,
+                    program_id,
+                )
+            }
+            EscrowInstruction::PrepareSettlement {
+                amount,
+                reference_preimage,
+                max_amount_in,
+                taker_proof,
+            } => {
+                log_info!("Instruction: PrepareSettlement");
+                Processor::process_prepare_settlement(
+                    accounts,
+                    amount,
+                    &reference_preimage,
+                    max_amount_in,
+                    &taker_proof,
+                    program_id,
+                )
+            }
+            EscrowInstruction::FinalizeSettlement => {
+                log_info!("Instruction: FinalizeSettlement");
+                Processor::process_finalize_settlement(accounts, program_id)
+            }
+            EscrowInstruction::DescribeExchange => {
+                log_info!("Instruction: DescribeExchange");
+                Processor::process_describe_exchange(accounts, program_id)
+            }
+            EscrowInstruction::InitConfig(args) => {
+                log_info!("Instruction: InitConfig");
+                Processor::process_init_config(accounts, args, program_id)
+            }
+            EscrowInstruction::UpdateConfig(args) => {
+                log_info!("Instruction: UpdateConfig");
+                Processor::process_update_config(accounts, args, program_id)
+            }
+            EscrowInstruction::ProposeAdmin { new_admin } => {
+                log_info!("Instruction: ProposeAdmin");
+                Processor::process_propose_admin(accounts, new_admin, program_id)
+            }
+            EscrowInstruction::AcceptAdmin => {
+                log_info!("Instruction: AcceptAdmin");
+                Processor::process_accept_admin(accounts, program_id)
             }
-            EscrowInstruction::Exchange { amount } => {
-                msg!("Instruction: Exchange");
-                Self::process_exchange(accounts, amount, program_id)
+            EscrowInstruction::CancelEscrow => {
+                log_info!("Instruction: CancelEscrow");
+                Processor::process_cancel_escrow(accounts, program_id)
+            }
+            EscrowInstruction::ReclaimExpiredBatch { count } => {
+                log_info!("Instruction: ReclaimExpiredBatch");
+                Processor::process_reclaim_expired_batch(accounts, count, program_id)
+            }
+            EscrowInstruction::CacheAuthority => {
+                log_info!("Instruction: CacheAuthority");
+                Processor::process_cache_authority(accounts, program_id)
+            }
+            EscrowInstruction::QuoteExchange { amount } => {
+                log_info!("Instruction: QuoteExchange");
+                Processor::process_quote_exchange(accounts, amount, program_id)
+            }
+            EscrowInstruction::InitAuditLog => {
+                log_info!("Instruction: InitAuditLog");
+                Processor::process_init_audit_log(accounts, program_id)
+            }
+            EscrowInstruction::TopUpEscrow { amount } => {
+                log_info!("Instruction: TopUpEscrow");
+                Processor::process_top_up_escrow(accounts, amount, program_id)
+            }
+            EscrowInstruction::ReplaceEscrow {
+                new_expected_amount,
+            } => {
+                log_info!("Instruction: ReplaceEscrow");
+                Processor::process_replace_escrow(accounts, new_expected_amount, program_id)
+            }
+            EscrowInstruction::TopUpRent => {
+                log_info!("Instruction: TopUpRent");
+                Processor::process_top_up_rent(accounts, program_id)
+            }
+            EscrowInstruction::CheckEligibility { taker, taker_proof } => {
+                log_info!("Instruction: CheckEligibility");
+                Processor::process_check_eligibility(accounts, &taker, &taker_proof, program_id)
+            }
+            EscrowInstruction::MergeEscrows => {
+                log_info!("Instruction: MergeEscrows");
+                Processor::process_merge_escrows(accounts, program_id)
+            }
+            EscrowInstruction::SplitEscrow { count } => {
+                log_info!("Instruction: SplitEscrow");
+                Processor::process_split_escrow(accounts, count, program_id)
+            }
+            EscrowInstruction::Verify { repair } => {
+                log_info!("Instruction: Verify");
+                Processor::process_verify(accounts, repair, program_id)
+            }
+            #[cfg(feature = "test-harness")]
+            EscrowInstruction::SetEscrowStateForTesting(escrow) => {
+                log_info!("Instruction: SetEscrowStateForTesting");
+                Processor::process_set_escrow_state_for_testing(accounts, *escrow, program_id)
+            }
+            EscrowInstruction::GetVersion => {
+                log_info!("Instruction: GetVersion");
+                Processor::process_get_version()
+            }
+            EscrowInstruction::SweepDust => {
+                log_info!("Instruction: SweepDust");
+                Processor::process_sweep_dust(accounts, program_id)
             }
         }
     }
+}
+
+impl Processor {
+    pub fn process(program_id: &Pubkey, accounts: &[AccountInfo], input: &[u8]) -> ProgramResult {
+        EscrowInstruction::unpack(input)?.execute(program_id, accounts)
+    }
 
+    #[allow(clippy::too_many_arguments)]
     fn process_init_escrow(
         accounts: &[AccountInfo],
         amount: u64,
+        oracle: COption<Pubkey>,
+        max_deviation_bps: u16,
+        max_fills: u8,
+        rounding_mode: RoundingMode,
+        accepted_mints: Vec<Pubkey>,
+        reference: [u8; 32],
+        reference_mode: ReferenceMode,
+        start_slot: u64,
+        cancel_penalty_bps: u16,
+        expiry_slot: u64,
+        min_open_slots: u64,
+        category: u8,
+        taker_merkle_root: [u8; 32],
+        deliver_to_ata: bool,
+        min_fill: u64,
+        stake: u64,
+        maker: COption<Pubkey>,
+        basket: Vec<Pubkey>,
         program_id: &Pubkey,
     ) -> ProgramResult {
         let account_info_iter = &mut accounts.iter();
-        let initializer = next_account_info(account_info_iter)?;
+        let initializer = next_named_account_info(account_info_iter, "initializer")?;
 
-        if !initializer.is_signer {
-            return Err(ProgramError::MissingRequiredSignature);
-        }
+        require_signer(initializer, "initializer")?;
 
         // No need to add check for owner since the authority transfer will check for us.
-        let temp_token_account = next_account_info(account_info_iter)?;
+        let temp_token_account = next_named_account_info(account_info_iter, "temp_token_account")?;
+        let temp_token_account_info = assert_token_account(temp_token_account, None, None)?;
 
-        let dest_token_account = next_account_info(account_info_iter)?;
-        if *dest_token_account.owner != spl_token::id() {
-            return Err(ProgramError::IncorrectProgramId);
+        let dest_token_account = next_named_account_info(account_info_iter, "dest_token_account")?;
+        // Exchange would pay into the very account it drains the deposit from.
+        if dest_token_account.key == temp_token_account.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        // Also need to check this is a token account (owned by the token program) by unpacking it,
+        // unless it's an associated token account the first exchange still has to create.
+        let dest_token_account_info = if deliver_to_ata && dest_token_account.data_is_empty() {
+            None
+        } else {
+            Some(assert_token_account(dest_token_account, None, None)?)
+        };
+        // Sending proceeds to someone else's account is allowed unless the deployment opts out.
+        #[cfg(feature = "enforce-dest-owner")]
+        if let Some(dest_token_account_info) = &dest_token_account_info {
+            if dest_token_account_info.owner != *initializer.key {
+                return Err(EscrowError::DestNotOwnedByInitializer.into());
+            }
         }
-        // Also need to check if this is a token account by unpacking it
-        TokenAccount::unpack(&dest_token_account.try_borrow_data()?)?;
 
         // We initialize our escrow account data here.
 
-        let escrow_account = next_account_info(account_info_iter)?;
+        let escrow_account = next_named_account_info(account_info_iter, "escrow_account")?;
+        // One buffer can't be both the escrow state and the deposit.
+        if escrow_account.key == temp_token_account.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
         // Old way of doing things (w/ sysvar rent account as input).
         // let rent = &Rent::from_account_info(next_account_info(account_info_iter)?)?;
         // if !rent.is_exempt(escrow_account.lamports(), escrow_account.data_len()) {
         //     return Err(EscrowError::NotRentExempt.into());
         // }
 
-        // New way of doing things.
-        if !Rent::is_exempt(
-            &Rent::default(),
-            escrow_account.lamports(),
-            escrow_account.data_len(),
-        ) {
+        // An account that was never funded fails the rent check too, but is almost always a
+        // client that forgot to create it rather than one that underfunded it.
+        if escrow_account.lamports() == 0 {
+            msg!("Escrow account {} wasn't funded", escrow_account.key);
+            return Err(EscrowError::NotRentExempt.into());
+        }
+        // New way of doing things (w/ the rent sysvar syscall).
+        let rent = Self::rent();
+        if !rent.is_exempt(escrow_account.lamports(), escrow_account.data_len()) {
+            return Err(EscrowError::NotRentExempt.into());
+        }
+        // The temp account holds the deposit for as long as the escrow lives, so it mustn't be
+        // collectable either.
+        if !rent.is_exempt(temp_token_account.lamports(), temp_token_account.data_len()) {
             return Err(EscrowError::NotRentExempt.into());
         }
+        // Escrows too small to be worth their rent just clutter indexers.
+        Self::assert_min_expected_amount(amount, MIN_EXPECTED_AMOUNT)?;
 
+        let token_program = next_named_account_info(account_info_iter, "token_program")?;
+        assert_token_program(token_program)?;
+        let dest_mint_account = next_named_account_info(account_info_iter, "dest_mint_account")?;
+        if let Some(dest_token_account_info) = &dest_token_account_info {
+            if *dest_mint_account.key != dest_token_account_info.mint {
+                return Err(EscrowError::MintMismatch.into());
+            }
+        }
+        let dest_mint = assert_mint(dest_mint_account)?;
+        if deliver_to_ata
+            && *dest_token_account.key
+                != get_associated_token_address(initializer.key, dest_mint_account.key)
+        {
+            msg!("Dest token account isn't the initializer's associated token account");
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let escrow_index_account =
+            next_named_account_info(account_info_iter, "escrow_index_account")?;
+        let system_program = next_named_account_info(account_info_iter, "system_program")?;
+        let treasury = next_named_account_info(account_info_iter, "treasury")?;
+        let config_account = next_named_account_info(account_info_iter, "config_account")?;
+        let config = Self::load_config(config_account, program_id)?;
+        if config.paused {
+            return Err(EscrowError::Paused.into());
+        }
+        // The initializer's signature above is what consents to the rent going elsewhere.
+        let rent_beneficiary = match accounts.get(INIT_ESCROW_BASKET_OFFSET + basket.len()) {
+            Some(rent_beneficiary) => COption::Some(*rent_beneficiary.key),
+            None => COption::None,
+        };
+
+        // No slack either way: the escrow only ever uses its first `Escrow::LEN` bytes, and any
+        // more would just lock up the initializer's rent and trip up scanners going by size.
+        if escrow_account.data_len() != Escrow::LEN {
+            msg!(
+                "Escrow account is {} bytes, should be {}",
+                escrow_account.data_len(),
+                Escrow::LEN
+            );
+            return Err(EscrowError::InvalidAccountSize.into());
+        }
         let mut escrow_info = Escrow::unpack_unchecked(&escrow_account.try_borrow_data()?)?;
         if escrow_info.is_initialized() {
             return Err(ProgramError::AccountAlreadyInitialized);
         }
 
-        escrow_info.is_initialized = true;
+        // Without oracle decoding compiled in we'd never be able to check the price, so refuse
+        // to create an escrow that depends on it.
+        if oracle.is_some() && !cfg!(feature = "oracle") {
+            msg!("Oracle-gated escrows require the `oracle` feature");
+            return Err(ProgramError::InvalidArgument);
+        }
+        Self::assert_list_len(accepted_mints.len(), "accepted mints")?;
+        Self::assert_list_len(basket.len(), "basket accounts")?;
+        if cancel_penalty_bps > 10_000 {
+            return Err(ProgramError::InvalidArgument);
+        }
+        let slot = Self::current_slot()?;
+        // No escrow should live forever just because its initializer didn't pick an expiry.
+        let expiry_slot = if expiry_slot == 0 {
+            slot.max(start_slot)
+                .checked_add(DEFAULT_EXPIRY_SLOTS)
+                .ok_or(EscrowError::Overflow)?
+        } else if expiry_slot <= start_slot {
+            msg!("Escrow would expire before it opens");
+            return Err(ProgramError::InvalidArgument);
+        } else {
+            expiry_slot
+        };
+
+        Self::collect_init_fee(
+            initializer,
+            treasury,
+            system_program,
+            config.init_fee,
+            &config.treasury,
+            &rent,
+        )?;
+        Self::collect_stake(initializer, escrow_account, system_program, stake)?;
+
+        escrow_info.status = EscrowStatus::Open;
         escrow_info.initializer_pubkey = *initializer.key;
         escrow_info.temp_token_account_pubkey = *temp_token_account.key;
         escrow_info.initializer_dest_token_account_pubkey = *dest_token_account.key;
         escrow_info.expected_amount = amount;
+        escrow_info.expected_amount_decimals = dest_mint.decimals;
+        escrow_info.oracle = oracle;
+        escrow_info.max_deviation_bps = max_deviation_bps;
+        escrow_info.max_fills = max_fills;
+        escrow_info.fills_done = 0;
+        escrow_info.rounding_mode = rounding_mode;
+        escrow_info.accepted_mints = accepted_mints;
+        escrow_info.reference = reference;
+        escrow_info.reference_mode = reference_mode;
+        escrow_info.start_slot = start_slot;
+        escrow_info.cancel_penalty_bps = cancel_penalty_bps;
+        escrow_info.expiry_slot = expiry_slot;
+        escrow_info.deposit_mint = temp_token_account_info.mint;
+        escrow_info.expected_mint = *dest_mint_account.key;
+        escrow_info.cancellable_slot = if min_open_slots == 0 {
+            0
+        } else {
+            slot.checked_add(min_open_slots)
+                .ok_or(EscrowError::Overflow)?
+        };
+        escrow_info.category = category;
+        escrow_info.taker_merkle_root = taker_merkle_root;
+        escrow_info.delivers_to_ata = deliver_to_ata;
+        escrow_info.min_fill = min_fill;
+        escrow_info.stake_lamports = stake;
+        escrow_info.maker = maker;
+        escrow_info.basket = basket.clone();
+        escrow_info.rent_beneficiary = rent_beneficiary;
+
+        let (pda, bump_seed) = find_escrow_authority_address(program_id);
+        escrow_info.bump_seed = bump_seed;
+        escrow_info.version = PROGRAM_VERSION;
 
         Escrow::pack(escrow_info, &mut escrow_account.try_borrow_mut_data()?)?;
 
         // Transfer ownership of temp token account to Escrow program.
+        Self::transfer_temp_authority(token_program, temp_token_account, initializer, &pda)?;
+        for (index, basket_key) in basket.iter().enumerate() {
+            let basket_account = Self::init_basket_account(
+                accounts,
+                INIT_ESCROW_BASKET_OFFSET + index,
+                basket_key,
+                temp_token_account,
+                &temp_token_account_info,
+                escrow_account,
+                &rent,
+            )?;
+            Self::transfer_temp_authority(token_program, basket_account, initializer, &pda)?;
+        }
+
+        Self::add_to_escrow_index(
+            escrow_index_account,
+            initializer,
+            system_program,
+            escrow_account.key,
+            config.address_cooldown_slots,
+            program_id,
+        )?;
 
-        let (pda, _bump_seed) = Pubkey::find_program_address(&[b"escrow"], program_id);
-        let token_program = next_account_info(account_info_iter)?;
+        Ok(())
+    }
+
+    /// Takes the basket temp token account `basket_key` from `index` of an `InitEscrow`'s
+    /// `accounts` and checks it can hold a deposit next to the main one: a rent exempt token
+    /// account of another mint than the main deposit's, and not the escrow account.
+    fn init_basket_account<'a, 'b>(
+        accounts: &'a [AccountInfo<'b>],
+        index: usize,
+        basket_key: &Pubkey,
+        temp_token_account: &AccountInfo,
+        temp_token_account_info: &TokenAccount,
+        escrow_account: &AccountInfo,
+        rent: &Rent,
+    ) -> Result<&'a AccountInfo<'b>, ProgramError> {
+        let basket_account = Self::basket_account_at(accounts, index, basket_key)?;
+        if basket_account.key == temp_token_account.key || basket_account.key == escrow_account.key
+        {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        // A basket is of other tokens, more of the main one is what `TopUpEscrow` is for.
+        let basket_account_info = assert_token_account(basket_account, None, None)?;
+        if basket_account_info.mint == temp_token_account_info.mint {
+            msg!(
+                "Basket account {} holds the main deposit's mint",
+                basket_key
+            );
+            return Err(EscrowError::MintMismatch.into());
+        }
+        if !rent.is_exempt(basket_account.lamports(), basket_account.data_len()) {
+            return Err(EscrowError::NotRentExempt.into());
+        }
+        Ok(basket_account)
+    }
+
+    /// Hands the initializer's `temp_token_account` over to the escrow authority `pda`.
+    fn transfer_temp_authority<'a>(
+        token_program: &AccountInfo<'a>,
+        temp_token_account: &AccountInfo<'a>,
+        initializer: &AccountInfo<'a>,
+        pda: &Pubkey,
+    ) -> ProgramResult {
         let owner_change_ix = spl_token::instruction::set_authority(
             token_program.key,
             temp_token_account.key,
-            Some(&pda),
+            Some(pda),
             spl_token::instruction::AuthorityType::AccountOwner,
             initializer.key,
             &[initializer.key],
         )?;
 
-        msg!("Calling token program to transfer token account ownership...");
+        log_debug!("Calling token program to transfer token account ownership...");
         invoke(
             &owner_change_ix,
             &[
@@ -104,34 +554,358 @@ impl Processor {
                 token_program.clone(),
             ],
         )?;
-
+        // Everything after init trusts the PDA to hold the deposit, so make sure the token
+        // program actually handed it over rather than taking its success for it.
+        let temp_owner = assert_token_account(temp_token_account, None, None)?.owner;
+        if temp_owner != *pda {
+            msg!(
+                "Temp account is owned by {} after the authority transfer, not {}",
+                temp_owner,
+                pda
+            );
+            return Err(EscrowError::AuthorityTransferFailed.into());
+        }
         Ok(())
     }
 
+    /// Fills the escrow, in full or (if `partial`) only `amount` of the deposited tokens.
     fn process_exchange(
         accounts: &[AccountInfo],
         amount: u64,
+        partial: bool,
+        reference_preimage: &[u8],
+        max_amount_in: u64,
+        taker_proof: &[[u8; 32]],
         program_id: &Pubkey,
     ) -> ProgramResult {
-        let account_info_iter = &mut accounts.iter();
-
-        let taker = next_account_info(account_info_iter)?;
-        let taker_source_token_account = next_account_info(account_info_iter)?;
-        let taker_dest_token_account = next_account_info(account_info_iter)?;
-        let temp_token_account = next_account_info(account_info_iter)?;
-        let initializer = next_account_info(account_info_iter)?;
-        let initializer_dest_token_account = next_account_info(account_info_iter)?;
-        let escrow_account = next_account_info(account_info_iter)?;
-        let token_program = next_account_info(account_info_iter)?;
-        let pda_account = next_account_info(account_info_iter)?;
+        let escrow_account =
+            Self::exchange_account(accounts, ExchangeAccount::EscrowAccount, "escrow_account")?;
         // No need to check for ownership since we'll write to it later.
-        let escrow = Escrow::unpack(&escrow_account.try_borrow_data()?)?;
+        let escrow = Self::load_escrow(escrow_account)?;
+        if escrow.is_settlement_pending(Self::current_slot()?) {
+            return Err(EscrowError::SettlementPending.into());
+        }
+
+        if escrow.delivers_to_ata {
+            Self::create_initializer_ata(accounts, &escrow)?;
+        }
+
+        let native_sol_wrapper_bump = Self::native_sol_wrapper_bump(accounts, program_id)?;
+        if let Some(wrapper_bump) = native_sol_wrapper_bump {
+            Self::wrap_native_sol(accounts, max_amount_in, wrapper_bump)?;
+        }
+
+        let (pda, bump_seed) = Self::escrow_authority(accounts, program_id)?;
+        let fill = Self::validate_exchange(
+            accounts,
+            &escrow,
+            amount,
+            partial,
+            reference_preimage,
+            max_amount_in,
+            taker_proof,
+            &pda,
+            program_id,
+        )?;
+        Self::settle_exchange(
+            accounts,
+            escrow,
+            fill,
+            native_sol_wrapper_bump.is_some(),
+            &pda,
+            bump_seed,
+            program_id,
+        )
+    }
+
+    /// The bump seed of the taker's native SOL wrapper if the exchange pays from it (see
+    /// `instruction::with_native_sol_payment`): the payment mint is the native mint and the
+    /// taker's send token account doesn't exist yet, so it has to be the wrapper. Only then is
+    /// the address derived, sparing every other exchange the compute.
+    fn native_sol_wrapper_bump(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+    ) -> Result<Option<u8>, ProgramError> {
+        let taker = Self::exchange_account(accounts, ExchangeAccount::Taker, "taker")?;
+        let taker_source_token_account = Self::exchange_account(
+            accounts,
+            ExchangeAccount::TakerSourceTokenAccount,
+            "taker_source_token_account",
+        )?;
+        let taker_source_mint_account = Self::exchange_account(
+            accounts,
+            ExchangeAccount::TakerSourceMint,
+            "taker_source_mint_account",
+        )?;
+        if *taker_source_mint_account.key != spl_token::native_mint::id()
+            || !taker_source_token_account.data_is_empty()
+        {
+            return Ok(None);
+        }
+        let (wrapper, bump_seed) = find_native_sol_wrapper_address(program_id, taker.key);
+        if *taker_source_token_account.key != wrapper {
+            return Ok(None);
+        }
+        Ok(Some(bump_seed))
+    }
+
+    /// Creates the taker's native SOL wrapper as a wrapped SOL account of the taker holding
+    /// `lamports` of their native SOL, for the exchange to pay from.
+    fn wrap_native_sol(accounts: &[AccountInfo], lamports: u64, bump_seed: u8) -> ProgramResult {
+        let taker = Self::exchange_account(accounts, ExchangeAccount::Taker, "taker")?;
+        let wrapper = Self::exchange_account(
+            accounts,
+            ExchangeAccount::TakerSourceTokenAccount,
+            "taker_source_token_account",
+        )?;
+        let native_mint = Self::exchange_account(
+            accounts,
+            ExchangeAccount::TakerSourceMint,
+            "taker_source_mint_account",
+        )?;
+        let token_program =
+            Self::exchange_account(accounts, ExchangeAccount::TokenProgram, "token_program")?;
+        assert_token_program(token_program)?;
+        let system_program = Self::trailing_exchange_accounts(accounts)
+            .iter()
+            .find(|info| *info.key == system_program::id())
+            .ok_or_else(|| {
+                msg!("Missing the system program to wrap the taker's SOL");
+                ProgramError::from(EscrowError::NotEnoughAccounts)
+            })?;
+
+        log_debug!("Calling system program to create the native SOL wrapper...");
+        Self::create_pda_account(
+            wrapper,
+            taker,
+            system_program,
+            TokenAccount::LEN,
+            &[NATIVE_SOL_WRAPPER_SEED, taker.key.as_ref(), &[bump_seed]],
+            token_program.key,
+        )?;
+        invoke(
+            &system_instruction::transfer(taker.key, wrapper.key, lamports),
+            &[taker.clone(), wrapper.clone(), system_program.clone()],
+        )?;
+        log_debug!("Calling token program to wrap {} lamports...", lamports);
+        invoke(
+            &spl_token::instruction::initialize_account3(
+                token_program.key,
+                wrapper.key,
+                native_mint.key,
+                taker.key,
+            )?,
+            &[wrapper.clone(), native_mint.clone()],
+        )
+    }
+
+    /// Closes the taker's native SOL wrapper, returning its rent and the lamports the exchange
+    /// didn't spend to the taker.
+    fn unwrap_native_sol(accounts: &[AccountInfo]) -> ProgramResult {
+        let taker = Self::exchange_account(accounts, ExchangeAccount::Taker, "taker")?;
+        let wrapper = Self::exchange_account(
+            accounts,
+            ExchangeAccount::TakerSourceTokenAccount,
+            "taker_source_token_account",
+        )?;
+        let token_program =
+            Self::exchange_account(accounts, ExchangeAccount::TokenProgram, "token_program")?;
+        log_debug!("Calling token program to close the native SOL wrapper...");
+        invoke(
+            &spl_token::instruction::close_account(
+                token_program.key,
+                wrapper.key,
+                taker.key,
+                taker.key,
+                &[],
+            )?,
+            &[wrapper.clone(), taker.clone()],
+        )
+    }
+
+    /// Runs every check of a full exchange and reserves the escrow for the taker, so a later
+    /// `FinalizeSettlement` only has to move the tokens.
+    fn process_prepare_settlement(
+        accounts: &[AccountInfo],
+        amount: u64,
+        reference_preimage: &[u8],
+        max_amount_in: u64,
+        taker_proof: &[[u8; 32]],
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let taker = Self::exchange_account(accounts, ExchangeAccount::Taker, "taker")?;
+        let escrow_account =
+            Self::exchange_account(accounts, ExchangeAccount::EscrowAccount, "escrow_account")?;
+        let mut escrow = Self::load_escrow(escrow_account)?;
+        // A stale preparation of someone else's is simply replaced.
+        let slot = Self::current_slot()?;
+        if escrow.is_settlement_pending(slot) {
+            return Err(EscrowError::SettlementPending.into());
+        }
+
+        let (pda, _bump_seed) = Self::escrow_authority(accounts, program_id)?;
+        let fill = Self::validate_exchange(
+            accounts,
+            &escrow,
+            amount,
+            false,
+            reference_preimage,
+            max_amount_in,
+            taker_proof,
+            &pda,
+            program_id,
+        )?;
+
+        escrow.status = EscrowStatus::Reserved;
+        escrow.prepared_taker = *taker.key;
+        escrow.prepared_slot = slot;
+        escrow.prepared_fee = fill.fee;
+        escrow.prepared_secondary_fee = fill.secondary_fee;
+        escrow.prepared_max_amount_in = max_amount_in;
+        Escrow::pack(escrow, &mut escrow_account.try_borrow_mut_data()?)?;
+
+        Ok(())
+    }
+
+    /// Settles an escrow reserved by `PrepareSettlement`, re-checking only what's needed to move
+    /// the tokens safely.
+    fn process_finalize_settlement(accounts: &[AccountInfo], program_id: &Pubkey) -> ProgramResult {
+        let taker = Self::exchange_account(accounts, ExchangeAccount::Taker, "taker")?;
+        let taker_source_token_account = Self::exchange_account(
+            accounts,
+            ExchangeAccount::TakerSourceTokenAccount,
+            "taker_source_token_account",
+        )?;
+        let temp_token_account = Self::exchange_account(
+            accounts,
+            ExchangeAccount::TempTokenAccount,
+            "temp_token_account",
+        )?;
+        let initializer =
+            Self::exchange_account(accounts, ExchangeAccount::Initializer, "initializer")?;
+        let initializer_dest_token_account = Self::exchange_account(
+            accounts,
+            ExchangeAccount::InitializerDestTokenAccount,
+            "initializer_dest_token_account",
+        )?;
+        let escrow_account =
+            Self::exchange_account(accounts, ExchangeAccount::EscrowAccount, "escrow_account")?;
+        let pda_account =
+            Self::exchange_account(accounts, ExchangeAccount::PdaAccount, "pda_account")?;
+        let escrow = Self::load_escrow(escrow_account)?;
+
+        require_signer(taker, "taker")?;
+        if !Self::is_top_level_instruction(accounts, program_id)? {
+            msg!("Settlement was invoked through a CPI");
+            return Err(EscrowError::NotTopLevelInstruction.into());
+        }
+        if escrow.status != EscrowStatus::Reserved {
+            return Err(EscrowError::SettlementNotPrepared.into());
+        }
+        if *taker.key != escrow.prepared_taker {
+            return Err(EscrowError::Unauthorized.into());
+        }
+        if !escrow.is_settlement_pending(Self::current_slot()?) {
+            return Err(EscrowError::SettlementExpired.into());
+        }
+
+        if *temp_token_account.key != escrow.temp_token_account_pubkey {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        if *initializer.key != escrow.initializer_pubkey {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        // Prepare checked the payment mint, but the taker may pass a different source account now.
+        let taker_source_token_account_info =
+            assert_token_account(taker_source_token_account, None, None)?;
+        // The taker pays the fee they were quoted at prepare, whatever the config says by now.
+        let config = Self::exchange_config(accounts, program_id)?;
+        let (fee, secondary_fee) = (escrow.prepared_fee, escrow.prepared_secondary_fee);
+        Self::assert_max_amount_in(escrow.expected_amount, fee, escrow.prepared_max_amount_in)?;
+        Self::assert_fee_accounts(
+            accounts,
+            &config,
+            fee,
+            secondary_fee,
+            &taker_source_token_account_info.mint,
+        )?;
+        Self::assert_taker_balance(
+            &taker_source_token_account_info,
+            escrow.expected_amount,
+            fee,
+        )?;
+        Self::assert_payment_destination(
+            &escrow,
+            initializer_dest_token_account,
+            &taker_source_token_account_info.mint,
+        )?;
+        Self::assert_taker_dest_not_frozen(accounts)?;
+
+        let (pda, bump_seed) = Self::escrow_authority(accounts, program_id)?;
+        Self::assert_pda_account(pda_account, &pda)?;
+        Self::assert_temp_account_open(temp_token_account)?;
+        let temp_token_account_info = assert_token_account(temp_token_account, None, None)?;
+        if temp_token_account_info.owner != pda {
+            return Err(EscrowError::AuthorityNotTransferred.into());
+        }
+        Self::assert_temp_mint(&escrow, &temp_token_account_info)?;
+
+        // The taker's dest account isn't re-checked: the token program rejects transfers between
+        // accounts of different mints.
+        let fill = Fill {
+            amount: temp_token_account_info.amount,
+            payment: escrow.expected_amount,
+            fee,
+            secondary_fee,
+            closes_escrow: true,
+        };
+        Self::settle_exchange(accounts, escrow, fill, false, &pda, bump_seed, program_id)
+    }
+
+    /// Checks the `Exchange` accounts against `escrow` and works out what filling `amount` (the
+    /// whole deposit unless `partial`) of it moves, as long as the taker pays at most
+    /// `max_amount_in` for it and `taker_proof` shows it's in the escrow's allow-list.
+    #[allow(clippy::too_many_arguments)]
+    fn validate_exchange(
+        accounts: &[AccountInfo],
+        escrow: &Escrow,
+        amount: u64,
+        partial: bool,
+        reference_preimage: &[u8],
+        max_amount_in: u64,
+        taker_proof: &[[u8; 32]],
+        pda: &Pubkey,
+        program_id: &Pubkey,
+    ) -> Result<Fill, ProgramError> {
+        let taker = Self::exchange_account(accounts, ExchangeAccount::Taker, "taker")?;
+        let taker_source_token_account = Self::exchange_account(
+            accounts,
+            ExchangeAccount::TakerSourceTokenAccount,
+            "taker_source_token_account",
+        )?;
+        let temp_token_account = Self::exchange_account(
+            accounts,
+            ExchangeAccount::TempTokenAccount,
+            "temp_token_account",
+        )?;
+        let initializer =
+            Self::exchange_account(accounts, ExchangeAccount::Initializer, "initializer")?;
+        let initializer_dest_token_account = Self::exchange_account(
+            accounts,
+            ExchangeAccount::InitializerDestTokenAccount,
+            "initializer_dest_token_account",
+        )?;
+        let pda_account =
+            Self::exchange_account(accounts, ExchangeAccount::PdaAccount, "pda_account")?;
+        let taker_source_mint_account = Self::exchange_account(
+            accounts,
+            ExchangeAccount::TakerSourceMint,
+            "taker_source_mint_account",
+        )?;
 
         // I think we check this because we never explicitly transfer out of taker, so we need to
         // check that taker is authorized(?)
-        if !taker.is_signer {
-            return Err(ProgramError::MissingRequiredSignature);
-        }
+        require_signer(taker, "taker")?;
 
         // Check everything matches up with our escrow.
 
@@ -141,14 +915,233 @@ impl Processor {
         if *initializer.key != escrow.initializer_pubkey {
             return Err(ProgramError::InvalidAccountData);
         }
-        if *initializer_dest_token_account.key != escrow.initializer_dest_token_account_pubkey {
-            return Err(ProgramError::InvalidAccountData);
+        let slot = Self::current_slot()?;
+        if !escrow.is_active(slot) {
+            msg!(
+                "Escrow opens at slot {}, current slot is {}",
+                escrow.start_slot,
+                slot
+            );
+            return Err(EscrowError::EscrowNotYetActive.into());
+        }
+        if escrow.is_expired(slot) {
+            msg!("Escrow expired at slot {}", escrow.expiry_slot);
+            return Err(EscrowError::EscrowExpired.into());
+        }
+        if escrow.reference_mode == ReferenceMode::HashedRevealOnFill
+            && keccak::hash(reference_preimage).to_bytes() != escrow.reference
+        {
+            return Err(EscrowError::ReferenceMismatch.into());
+        }
+        if !escrow.allows_taker(taker.key, taker_proof) {
+            msg!("Taker {} isn't in the escrow's allow-list", taker.key);
+            return Err(EscrowError::NotInAllowlist.into());
         }
+        if let COption::Some(maker) = escrow.maker {
+            let escrow_account =
+                Self::exchange_account(accounts, ExchangeAccount::EscrowAccount, "escrow_account")?;
+            let message = instruction::maker_approval_message(
+                escrow_account.key,
+                taker.key,
+                amount,
+                escrow.fills_done,
+                escrow.expected_amount,
+            );
+            Self::assert_maker_approval(accounts, &maker, &message)?;
+        }
+
+        Self::assert_pda_account(pda_account, pda)?;
 
-        let temp_token_account_info = TokenAccount::unpack(&temp_token_account.try_borrow_data()?)?;
-        if temp_token_account_info.amount != amount {
+        Self::assert_temp_account_open(temp_token_account)?;
+        let temp_token_account_info = assert_token_account(temp_token_account, None, None)?;
+        // If init's `set_authority` didn't take effect, the initializer could still pull the
+        // deposit out from under the taker.
+        if temp_token_account_info.owner != *pda {
+            return Err(EscrowError::AuthorityNotTransferred.into());
+        }
+        Self::assert_temp_mint(escrow, &temp_token_account_info)?;
+        let deposit = temp_token_account_info.amount;
+        // A drained temp account or a zeroed expected amount would otherwise let a full exchange
+        // go through for nothing.
+        if escrow.expected_amount == 0 || deposit == 0 {
+            msg!(
+                "Escrow expects {} for a deposit of {}",
+                escrow.expected_amount,
+                deposit
+            );
+            return Err(EscrowError::InvalidEscrowState.into());
+        }
+        if partial && !escrow.basket.is_empty() {
+            msg!("Basket escrows can only be filled in full");
+            return Err(ProgramError::InvalidArgument);
+        }
+        // A full exchange takes the whole deposit, a partial one any nonzero part of it.
+        if partial {
+            if amount == 0 || amount > deposit {
+                return Err(EscrowError::ExpectedAmountMismatch.into());
+            }
+        } else if amount != deposit {
             return Err(EscrowError::ExpectedAmountMismatch.into());
         }
+        if escrow.fills_exhausted() {
+            return Err(EscrowError::MaxFillsReached.into());
+        }
+        let closes_escrow = amount == deposit;
+        // Only the fill taking what's left may be smaller, or a dust remainder could never close.
+        if !closes_escrow && amount < escrow.min_fill {
+            msg!(
+                "Fill of {} is below the escrow's minimum fill of {}",
+                amount,
+                escrow.min_fill
+            );
+            return Err(EscrowError::FillTooSmall.into());
+        }
+        let payment = escrow.fill_payment(amount, deposit)?;
+
+        let taker_source_token_account_info =
+            assert_token_account(taker_source_token_account, None, None)?;
+        if *taker_source_mint_account.key != taker_source_token_account_info.mint {
+            return Err(EscrowError::MintMismatch.into());
+        }
+        let (fee, secondary_fee) = Self::exchange_fee(
+            accounts,
+            payment,
+            &taker_source_token_account_info.mint,
+            program_id,
+        )?;
+        Self::assert_max_amount_in(payment, fee, max_amount_in)?;
+        Self::assert_taker_balance(&taker_source_token_account_info, payment, fee)?;
+        let taker_source_mint = assert_mint(taker_source_mint_account)?;
+        if taker_source_mint.decimals != escrow.expected_amount_decimals {
+            return Err(EscrowError::DecimalsMismatch.into());
+        }
+        Self::assert_payment_destination(
+            escrow,
+            initializer_dest_token_account,
+            &taker_source_token_account_info.mint,
+        )?;
+        Self::assert_taker_dest_not_frozen(accounts)?;
+
+        #[cfg(feature = "oracle")]
+        if let COption::Some(oracle_key) = escrow.oracle {
+            let oracle_account =
+                Self::exchange_account(accounts, ExchangeAccount::Oracle, "oracle_account")?;
+            if *oracle_account.key != oracle_key {
+                return Err(ProgramError::InvalidAccountData);
+            }
+            let feed = PriceFeed::unpack(&oracle_account.try_borrow_data()?)?;
+            check_price_deviation(
+                &feed,
+                deposit,
+                escrow.expected_amount,
+                escrow.max_deviation_bps,
+            )?;
+        }
+
+        Ok(Fill {
+            amount,
+            payment,
+            fee,
+            secondary_fee,
+            closes_escrow,
+        })
+    }
+
+    /// Moves the tokens of a validated `fill` and updates or closes the escrow. With
+    /// `unwrap_native_sol` the taker paid from their native SOL wrapper, which is closed once paid.
+    #[allow(clippy::too_many_arguments)]
+    fn settle_exchange(
+        accounts: &[AccountInfo],
+        mut escrow: Escrow,
+        fill: Fill,
+        unwrap_native_sol: bool,
+        pda: &Pubkey,
+        bump_seed: u8,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let taker = Self::exchange_account(accounts, ExchangeAccount::Taker, "taker")?;
+        let taker_source_token_account = Self::exchange_account(
+            accounts,
+            ExchangeAccount::TakerSourceTokenAccount,
+            "taker_source_token_account",
+        )?;
+        let taker_dest_token_account = Self::exchange_account(
+            accounts,
+            ExchangeAccount::TakerDestTokenAccount,
+            "taker_dest_token_account",
+        )?;
+        let temp_token_account = Self::exchange_account(
+            accounts,
+            ExchangeAccount::TempTokenAccount,
+            "temp_token_account",
+        )?;
+        let initializer =
+            Self::exchange_account(accounts, ExchangeAccount::Initializer, "initializer")?;
+        let initializer_dest_token_account = Self::exchange_account(
+            accounts,
+            ExchangeAccount::InitializerDestTokenAccount,
+            "initializer_dest_token_account",
+        )?;
+        let escrow_account =
+            Self::exchange_account(accounts, ExchangeAccount::EscrowAccount, "escrow_account")?;
+        let token_program =
+            Self::exchange_account(accounts, ExchangeAccount::TokenProgram, "token_program")?;
+        assert_token_program(token_program)?;
+        let pda_account =
+            Self::exchange_account(accounts, ExchangeAccount::PdaAccount, "pda_account")?;
+        let escrow_index_account = Self::exchange_account(
+            accounts,
+            ExchangeAccount::EscrowIndex,
+            "escrow_index_account",
+        )?;
+
+        // Checked against the fee when the fill was worked out.
+        let main_fee = fill.fee - fill.secondary_fee;
+        if main_fee > 0 {
+            let treasury_token_account = Self::exchange_account(
+                accounts,
+                ExchangeAccount::TreasuryTokenAccount,
+                "treasury_token_account",
+            )?;
+            let fee_ix = spl_token::instruction::transfer(
+                token_program.key,
+                taker_source_token_account.key,
+                treasury_token_account.key,
+                taker.key,
+                &[taker.key],
+                main_fee,
+            )?;
+            log_debug!("Calling token program to pay the exchange fee...");
+            invoke(
+                &fee_ix,
+                &[
+                    taker_source_token_account.clone(),
+                    treasury_token_account.clone(),
+                    taker.clone(),
+                ],
+            )?;
+        }
+        if fill.secondary_fee > 0 {
+            let secondary_treasury_token_account =
+                Self::secondary_treasury_token_account(accounts)?;
+            let fee_ix = spl_token::instruction::transfer(
+                token_program.key,
+                taker_source_token_account.key,
+                secondary_treasury_token_account.key,
+                taker.key,
+                &[taker.key],
+                fill.secondary_fee,
+            )?;
+            log_debug!("Calling token program to pay the secondary treasury's share of the fee...");
+            invoke(
+                &fee_ix,
+                &[
+                    taker_source_token_account.clone(),
+                    secondary_treasury_token_account.clone(),
+                    taker.clone(),
+                ],
+            )?;
+        }
 
         // Transfer tokens from taker to initializer.
 
@@ -158,9 +1151,9 @@ impl Processor {
             initializer_dest_token_account.key,
             taker.key,
             &[taker.key],
-            escrow.expected_amount,
+            fill.payment,
         )?;
-        msg!("Calling token program to transfer tokens to escrow's initializer...");
+        log_debug!("Calling token program to transfer tokens to escrow's initializer...");
         invoke(
             &transfer_to_initializer,
             &[
@@ -171,8 +1164,11 @@ impl Processor {
                 // token_program.clone(),
             ],
         )?;
-
-        let (pda, bump_seed) = Pubkey::find_program_address(&[b"escrow"], program_id);
+        // The taker is done paying. Closing the wrapper after the escrow instead would have its
+        // CPI clear the close reason's return data.
+        if unwrap_native_sol {
+            Self::unwrap_native_sol(accounts)?;
+        }
 
         // Transfer tokens from initializer's temp account to taker.
 
@@ -181,56 +1177,2796 @@ impl Processor {
             temp_token_account.key,
             taker_dest_token_account.key,
             // Do we need to generate a
-            &pda,
-            &[&pda],
+            pda,
+            &[pda],
             // pda_account.key,
             // &[pda_account],
-            amount,
+            fill.amount,
         )?;
-        msg!("Calling token program to transfer tokens to the taker...");
+        log_debug!("Calling token program to transfer tokens to the taker...");
         invoke_signed(
             &transfer_to_taker_ix,
             &[
                 temp_token_account.clone(),
                 taker_dest_token_account.clone(),
-                // I think this will implicitly check that pda == pda_account(?)
                 pda_account.clone(),
                 // NB: this is not necessary it seems.
                 // token_program.clone(),
             ],
-            &[&[&b"escrow"[..], &[bump_seed]]],
+            &[&escrow_signer_seeds(&bump_seed)],
+        )?;
+
+        Self::record_fill(accounts, escrow_account, taker, fill.amount, program_id)?;
+
+        if !fill.closes_escrow {
+            escrow.expected_amount = escrow
+                .expected_amount
+                .checked_sub(fill.payment)
+                .ok_or(EscrowError::Overflow)?;
+            escrow.fills_done = escrow
+                .fills_done
+                .checked_add(1)
+                .ok_or(EscrowError::Overflow)?;
+            // Whatever was prepared before has expired, or we wouldn't have gotten here.
+            escrow.status = EscrowStatus::PartiallyFilled;
+            Escrow::pack(escrow, &mut escrow_account.try_borrow_mut_data()?)?;
+            return Ok(());
+        }
+
+        // The one payment buys the whole basket.
+        Self::release_basket(
+            &Self::exchange_basket_accounts(Self::trailing_exchange_accounts(accounts), &escrow)?,
+            token_program,
+            None,
+            initializer,
+            pda_account,
+            pda,
+            bump_seed,
+        )?;
+
+        let rent_destination = Self::rent_destination(accounts, &escrow, initializer)?;
+        // Never close the temp account over tokens still locked under the PDA.
+        Self::return_residual(
+            accounts,
+            token_program,
+            temp_token_account,
+            initializer,
+            pda_account,
+            pda,
+            bump_seed,
         )?;
 
         // Close temp token account created when escrow was initialized.
+        Self::close_temp_token_account(
+            token_program,
+            temp_token_account,
+            initializer,
+            pda_account,
+            pda,
+            bump_seed,
+            true,
+        )?;
 
-        let close_account_ix = spl_token::instruction::close_account(
+        Self::close_escrow_account(
+            escrow_account,
+            rent_destination,
+            escrow.stake_lamports,
+            initializer,
+            CloseReason::Exchanged,
+        )?;
+        Self::remove_from_escrow_index(
+            escrow_index_account,
+            initializer,
+            escrow_account.key,
+            program_id,
+        )?;
+
+        Ok(())
+    }
+
+    /// Sends whatever the closing fill left in the temp account (e.g. rounding dust) to the
+    /// initializer's refund token account. A wrapped SOL residual needs no transfer, closing the
+    /// temp account unwraps it to the initializer.
+    fn return_residual<'a>(
+        accounts: &[AccountInfo<'a>],
+        token_program: &AccountInfo<'a>,
+        temp_token_account: &AccountInfo<'a>,
+        initializer: &AccountInfo<'a>,
+        pda_account: &AccountInfo<'a>,
+        pda: &Pubkey,
+        bump_seed: u8,
+    ) -> ProgramResult {
+        let temp_token_account_info = assert_token_account(temp_token_account, None, None)?;
+        if temp_token_account_info.amount == 0 || temp_token_account_info.is_native() {
+            return Ok(());
+        }
+
+        let refund_token_account = Self::exchange_account(
+            accounts,
+            ExchangeAccount::InitializerRefundTokenAccount,
+            "initializer_refund_token_account",
+        )?;
+        assert_token_account(
+            refund_token_account,
+            Some(&temp_token_account_info.mint),
+            Some(initializer.key),
+        )?;
+        let refund_ix = spl_token::instruction::transfer(
             token_program.key,
             temp_token_account.key,
-            initializer.key,
-            &pda,
-            &[&pda],
+            refund_token_account.key,
+            pda,
+            &[pda],
+            temp_token_account_info.amount,
         )?;
-        msg!("Calling token program to close pda's temp account...");
+        log_debug!(
+            "Calling token program to return {} residual tokens to the initializer...",
+            temp_token_account_info.amount
+        );
         invoke_signed(
-            &close_account_ix,
+            &refund_ix,
             &[
                 temp_token_account.clone(),
-                initializer.clone(),
+                refund_token_account.clone(),
                 pda_account.clone(),
-                // NB: this is not necessary it seems.
-                // token_program.clone(),
             ],
-            &[&[&b"escrow"[..], &[bump_seed]]],
-        )?;
+            &[&escrow_signer_seeds(&bump_seed)],
+        )
+    }
 
-        msg!("Closing the escrow account...");
-        **initializer.lamports.borrow_mut() = initializer
-            .lamports()
-            .checked_add(escrow_account.lamports())
-            .ok_or(EscrowError::Overflow)?;
-        **escrow_account.lamports.borrow_mut() = 0;
-        *escrow_account.try_borrow_mut_data()? = &mut [];
+    /// Works out the fee a fill paying `payment` in `payment_mint` owes under the current config
+    /// and the secondary treasury's share of it, checking the treasury token accounts they go to.
+    /// Fails if the program is paused or the fill is relayed by a relayer the config doesn't
+    /// allow.
+    fn exchange_fee(
+        accounts: &[AccountInfo],
+        payment: u64,
+        payment_mint: &Pubkey,
+        program_id: &Pubkey,
+    ) -> Result<(u64, u64), ProgramError> {
+        let config = Self::exchange_config(accounts, program_id)?;
+        let fee = config.fee_for(payment, payment_mint)?;
+        let secondary_fee = config.secondary_fee(fee)?;
+        Self::assert_fee_accounts(accounts, &config, fee, secondary_fee, payment_mint)?;
+        Ok((fee, secondary_fee))
+    }
+
+    /// The config a fill goes by, as long as it isn't paused and lets the fill's relayer through.
+    fn exchange_config(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+    ) -> Result<Config, ProgramError> {
+        let config_account =
+            Self::exchange_account(accounts, ExchangeAccount::Config, "config_account")?;
+        let config = Self::load_config(config_account, program_id)?;
+        if config.paused {
+            return Err(EscrowError::Paused.into());
+        }
+        Self::assert_relayer(accounts, &config)?;
+        Ok(config)
+    }
+
+    /// Checks the treasuries' token accounts a fill pays `fee` to, `secondary_fee` of it going to
+    /// the secondary treasury.
+    fn assert_fee_accounts(
+        accounts: &[AccountInfo],
+        config: &Config,
+        fee: u64,
+        secondary_fee: u64,
+        payment_mint: &Pubkey,
+    ) -> ProgramResult {
+        if fee > secondary_fee {
+            let treasury_token_account = Self::exchange_account(
+                accounts,
+                ExchangeAccount::TreasuryTokenAccount,
+                "treasury_token_account",
+            )?;
+            assert_token_account(
+                treasury_token_account,
+                Some(payment_mint),
+                Some(&config.treasury),
+            )?;
+        }
+        if secondary_fee > 0 {
+            assert_token_account(
+                Self::secondary_treasury_token_account(accounts)?,
+                Some(payment_mint),
+                Some(&config.secondary_treasury),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn secondary_treasury_token_account<'a, 'b>(
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<&'a AccountInfo<'b>, ProgramError> {
+        Self::exchange_account(
+            accounts,
+            ExchangeAccount::SecondaryTreasuryTokenAccount,
+            "secondary_treasury_token_account",
+        )
+    }
 
+    /// Protects the taker from paying more than they signed up for, e.g. after a fee change.
+    fn assert_max_amount_in(payment: u64, fee: u64, max_amount_in: u64) -> ProgramResult {
+        let cost = payment.checked_add(fee).ok_or(EscrowError::Overflow)?;
+        if cost > max_amount_in {
+            msg!(
+                "Fill costs {} but the taker pays at most {}",
+                cost,
+                max_amount_in
+            );
+            return Err(EscrowError::AmountInExceedsMax.into());
+        }
+        Ok(())
+    }
+
+    /// Fails before any transfer if the taker can't pay, instead of inside the token program.
+    fn assert_taker_balance(taker_source: &TokenAccount, payment: u64, fee: u64) -> ProgramResult {
+        let cost = payment.checked_add(fee).ok_or(EscrowError::Overflow)?;
+        if taker_source.amount < cost {
+            msg!(
+                "Taker holds {} tokens but the fill costs {}",
+                taker_source.amount,
+                cost
+            );
+            return Err(EscrowError::InsufficientTakerBalance.into());
+        }
         Ok(())
     }
+
+    /// Checks `initializer_dest_token_account` can take a payment in `payment_mint`: it's either
+    /// the escrow's dest account, or for one of the escrow's other accepted mints, an account of
+    /// that mint the initializer owns.
+    fn assert_payment_destination(
+        escrow: &Escrow,
+        initializer_dest_token_account: &AccountInfo,
+        payment_mint: &Pubkey,
+    ) -> ProgramResult {
+        Self::assert_initializer_dest_available(initializer_dest_token_account)?;
+        let dest_info = assert_token_account(initializer_dest_token_account, None, None)?;
+        if dest_info.mint != *payment_mint {
+            return Err(EscrowError::MintNotAccepted.into());
+        }
+        if *initializer_dest_token_account.key != escrow.initializer_dest_token_account_pubkey {
+            if !escrow.accepted_mints.contains(payment_mint) {
+                return Err(EscrowError::MintNotAccepted.into());
+            }
+            if dest_info.owner != escrow.initializer_pubkey {
+                return Err(EscrowError::OwnerMismatch.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether this instruction is the transaction's own rather than invoked through a CPI, going
+    /// by the program of the current instruction in the instructions sysvar among the trailing
+    /// accounts. solana-program 1.9 has no stack height, so how deep a CPI runs can't be told. This
+    /// program only invokes the token, system and associated token account programs, so its own
+    /// instruction never reenters it.
+    fn is_top_level_instruction(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+    ) -> Result<bool, ProgramError> {
+        let instructions_sysvar = Self::trailing_exchange_accounts(accounts)
+            .iter()
+            .find(|info| *info.key == sysvar::instructions::id())
+            .ok_or_else(|| {
+                msg!("Missing the instructions sysvar");
+                ProgramError::from(EscrowError::NotEnoughAccounts)
+            })?;
+        let current_index = load_current_index_checked(instructions_sysvar)?;
+        let current_ix = load_instruction_at_checked(current_index as usize, instructions_sysvar)?;
+        Ok(current_ix.program_id == *program_id)
+    }
+
+    /// Checks the instruction right before this one is the ed25519 program verifying `maker`'s
+    /// signature over `message`, as `instruction::verify_maker_approval` builds it. The ed25519
+    /// program fails the transaction if the signature is wrong, so its presence is all that's
+    /// left to check.
+    fn assert_maker_approval(
+        accounts: &[AccountInfo],
+        maker: &Pubkey,
+        message: &[u8],
+    ) -> ProgramResult {
+        let missing = |reason: &str| -> ProgramResult {
+            msg!("Missing the maker's signature: {}", reason);
+            Err(EscrowError::MissingMakerSignature.into())
+        };
+        let instructions_sysvar = match Self::trailing_exchange_accounts(accounts)
+            .iter()
+            .find(|info| *info.key == sysvar::instructions::id())
+        {
+            Some(instructions_sysvar) => instructions_sysvar,
+            None => return missing("no instructions sysvar"),
+        };
+        let current_index = load_current_index_checked(instructions_sysvar)?;
+        if current_index == 0 {
+            return missing("no instruction before the fill");
+        }
+        let verify_ix =
+            load_instruction_at_checked(current_index as usize - 1, instructions_sysvar)?;
+        if verify_ix.program_id != ed25519_program::id() {
+            return missing("the instruction before the fill isn't an ed25519 verify");
+        }
+        let data = &verify_ix.data;
+        let offsets = |index: usize| {
+            data.get(2 + 2 * index..4 + 2 * index)
+                .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+        };
+        let expected_offsets = [
+            instruction::ED25519_SIGNATURE_OFFSET as u16,
+            u16::MAX,
+            instruction::ED25519_PUBKEY_OFFSET as u16,
+            u16::MAX,
+            instruction::ED25519_MESSAGE_OFFSET as u16,
+            message.len() as u16,
+            u16::MAX,
+        ];
+        let layout_matches = data.first() == Some(&1)
+            && expected_offsets
+                .iter()
+                .enumerate()
+                .all(|(index, expected)| offsets(index) == Some(*expected));
+        let signed_pubkey =
+            data.get(instruction::ED25519_PUBKEY_OFFSET..instruction::ED25519_PUBKEY_OFFSET + 32);
+        let signed_message = data.get(instruction::ED25519_MESSAGE_OFFSET..);
+        if !layout_matches
+            || signed_pubkey != Some(maker.as_ref())
+            || signed_message != Some(message)
+        {
+            return missing("the ed25519 verify is for another key or other terms");
+        }
+        Ok(())
+    }
+
+    /// Creates the initializer's associated token account an escrow initialized with
+    /// `deliver_to_ata` pays into, funded by the taker, if it doesn't exist yet and the fill is
+    /// paid in the escrow's expected mint. Without the accounts `with_ata_creation` appends, the
+    /// fill fails like for any other unavailable dest account.
+    fn create_initializer_ata(accounts: &[AccountInfo], escrow: &Escrow) -> ProgramResult {
+        let initializer_dest_token_account = Self::exchange_account(
+            accounts,
+            ExchangeAccount::InitializerDestTokenAccount,
+            "initializer_dest_token_account",
+        )?;
+        let taker_source_mint_account = Self::exchange_account(
+            accounts,
+            ExchangeAccount::TakerSourceMint,
+            "taker_source_mint_account",
+        )?;
+        if !initializer_dest_token_account.data_is_empty()
+            || *initializer_dest_token_account.key != escrow.initializer_dest_token_account_pubkey
+            || *taker_source_mint_account.key != escrow.expected_mint
+        {
+            return Ok(());
+        }
+        let trailing_account = |key: &Pubkey| {
+            Self::trailing_exchange_accounts(accounts)
+                .iter()
+                .find(|info| info.key == key)
+        };
+        let (system_program, ata_program, rent_sysvar) = match (
+            trailing_account(&system_program::id()),
+            trailing_account(&spl_associated_token_account::id()),
+            trailing_account(&sysvar::rent::id()),
+        ) {
+            (Some(system_program), Some(ata_program), Some(rent_sysvar)) => {
+                (system_program, ata_program, rent_sysvar)
+            }
+            _ => {
+                msg!("Initializer's associated token account doesn't exist yet");
+                return Err(EscrowError::InitializerDestUnavailable.into());
+            }
+        };
+        let taker = Self::exchange_account(accounts, ExchangeAccount::Taker, "taker")?;
+        let initializer =
+            Self::exchange_account(accounts, ExchangeAccount::Initializer, "initializer")?;
+        let token_program =
+            Self::exchange_account(accounts, ExchangeAccount::TokenProgram, "token_program")?;
+        assert_token_program(token_program)?;
+        if *initializer.key != escrow.initializer_pubkey {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        log_debug!("Creating the initializer's associated token account");
+        invoke(
+            &create_associated_token_account(taker.key, initializer.key, &escrow.expected_mint),
+            &[
+                taker.clone(),
+                initializer_dest_token_account.clone(),
+                initializer.clone(),
+                taker_source_mint_account.clone(),
+                system_program.clone(),
+                token_program.clone(),
+                rent_sysvar.clone(),
+                ata_program.clone(),
+            ],
+        )
+    }
+
+    /// Fails clearly if the taker's dest token account is frozen, rather than deep inside the
+    /// token program's transfer to it. The initializer's is covered by
+    /// `assert_initializer_dest_available`.
+    fn assert_taker_dest_not_frozen(accounts: &[AccountInfo]) -> ProgramResult {
+        let taker_dest_token_account = Self::exchange_account(
+            accounts,
+            ExchangeAccount::TakerDestTokenAccount,
+            "taker_dest_token_account",
+        )?;
+        if assert_token_account(taker_dest_token_account, None, None)?.is_frozen() {
+            msg!(
+                "Taker's dest token account {} is frozen",
+                taker_dest_token_account.key
+            );
+            return Err(EscrowError::AccountFrozen.into());
+        }
+        Ok(())
+    }
+
+    /// Fails clearly if the escrow's temp account was closed from under it, rather than as an
+    /// obscure unpack or transfer failure.
+    fn assert_temp_account_open(temp_token_account: &AccountInfo) -> ProgramResult {
+        if temp_token_account.lamports() == 0 || temp_token_account.data_is_empty() {
+            msg!("Temp token account {} was closed", temp_token_account.key);
+            return Err(EscrowError::TempAccountClosed.into());
+        }
+        Ok(())
+    }
+
+    /// The temp account is matched by key already, but the taker is owed the mint the escrow was
+    /// set up with, so check that too rather than trust the key alone.
+    fn assert_temp_mint(escrow: &Escrow, temp_token_account_info: &TokenAccount) -> ProgramResult {
+        if temp_token_account_info.mint != escrow.deposit_mint {
+            msg!(
+                "Temp account holds {}, the escrow was set up with {}",
+                temp_token_account_info.mint,
+                escrow.deposit_mint
+            );
+            return Err(EscrowError::TempMintMismatch.into());
+        }
+        Ok(())
+    }
+
+    /// Fails cleanly, before anything moves, if the initializer closed or froze the account their
+    /// proceeds go to, rather than deep inside the token program's transfer.
+    fn assert_initializer_dest_available(
+        initializer_dest_token_account: &AccountInfo,
+    ) -> ProgramResult {
+        if !can_receive_tokens(initializer_dest_token_account) {
+            msg!("Initializer's dest token account is closed or frozen");
+            return Err(EscrowError::InitializerDestUnavailable.into());
+        }
+        Ok(())
+    }
+
+    /// The escrow authority PDA and its bump for a fill, read from the `AuthorityCache` when
+    /// that's among the `accounts` past the fixed ones, derived otherwise.
+    fn escrow_authority(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+    ) -> Result<(Pubkey, u8), ProgramError> {
+        let cache_account = Self::trailing_exchange_accounts(accounts)
+            .iter()
+            .find(|info| info.owner == program_id && info.data_len() == AuthorityCache::LEN);
+        if let Some(cache_account) = cache_account {
+            let cache = AuthorityCache::unpack(&cache_account.try_borrow_data()?)?;
+            Self::assert_authority_bump(&cache.authority, cache.bump_seed, "cached", program_id)?;
+            return Ok((cache.authority, cache.bump_seed));
+        }
+        Ok(find_escrow_authority_address(program_id))
+    }
+
+    /// Checks `bump` (read from an account, as described by `source`) derives the escrow
+    /// authority `pda`, so a corrupt one fails here with `InvalidSeeds` rather than obscurely in
+    /// the signed CPI it'd be used for.
+    fn assert_authority_bump(
+        pda: &Pubkey,
+        bump: u8,
+        source: &str,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let result = verify_escrow_pda(program_id, pda, bump);
+        if result.is_err() {
+            msg!(
+                "The {} bump {} doesn't derive the escrow authority {}",
+                source,
+                bump,
+                pda
+            );
+        }
+        result
+    }
+
+    /// The escrow authority PDA passed as `pda_account` and its bump, checked against the bump
+    /// stored in `escrow` instead of derived.
+    fn stored_escrow_authority(
+        pda_account: &AccountInfo,
+        escrow: &Escrow,
+        program_id: &Pubkey,
+    ) -> Result<(Pubkey, u8), ProgramError> {
+        Self::assert_authority_bump(pda_account.key, escrow.bump_seed, "stored", program_id)?;
+        Self::assert_pda_account(pda_account, pda_account.key)?;
+        Ok((*pda_account.key, escrow.bump_seed))
+    }
+
+    /// The optional accounts a fill was passed after its fixed ones: the oracle, the authority
+    /// cache, the relayer, the audit log, the secondary treasury's token account, the escrow's
+    /// rent beneficiary and the instructions sysvar, each at most once and told apart by what
+    /// they are.
+    fn trailing_exchange_accounts<'a, 'b>(
+        accounts: &'a [AccountInfo<'b>],
+    ) -> &'a [AccountInfo<'b>] {
+        accounts
+            .get(ExchangeAccount::Oracle as usize..)
+            .unwrap_or(&[])
+    }
+
+    /// Records the fill in the `AuditLog` if it was passed among the trailing accounts.
+    fn record_fill(
+        accounts: &[AccountInfo],
+        escrow_account: &AccountInfo,
+        taker: &AccountInfo,
+        amount: u64,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let audit_log_account = Self::trailing_exchange_accounts(accounts)
+            .iter()
+            .find(|info| info.owner == program_id && info.data_len() == AuditLog::LEN);
+        if let Some(audit_log_account) = audit_log_account {
+            let entry = AuditEntry {
+                escrow: *escrow_account.key,
+                taker: *taker.key,
+                amount,
+                slot: Self::current_slot()?,
+            };
+            AuditLog::append(&mut audit_log_account.try_borrow_mut_data()?, &entry)?;
+        }
+        Ok(())
+    }
+
+    /// Checks the relayer of a fill, the only signer among its trailing accounts, is the one the
+    /// config allows, if it has one.
+    fn assert_relayer(accounts: &[AccountInfo], config: &Config) -> ProgramResult {
+        let relayer = Self::trailing_exchange_accounts(accounts)
+            .iter()
+            .find(|info| info.is_signer);
+        if let (Some(relayer), COption::Some(allowed_relayer)) = (relayer, config.allowed_relayer) {
+            if *relayer.key != allowed_relayer {
+                msg!("Relayer {} isn't the allowed one", relayer.key);
+                return Err(EscrowError::Unauthorized.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Account `index` of the accounts `Exchange` and the settlement instructions expect.
+    fn exchange_account<'a, 'b>(
+        accounts: &'a [AccountInfo<'b>],
+        index: ExchangeAccount,
+        name: &str,
+    ) -> Result<&'a AccountInfo<'b>, ProgramError> {
+        named_account_at(accounts, index as usize, name)
+    }
+
+    fn process_cancel_escrow(accounts: &[AccountInfo], program_id: &Pubkey) -> ProgramResult {
+        Self::cancel_escrow(accounts, program_id, true)
+    }
+
+    /// Cancels the escrow, except for closing the escrow account itself unless
+    /// `close_escrow_account`, which is then up to the caller (see `close_and_unindex`).
+    fn cancel_escrow(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+        close_escrow_account: bool,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let initializer = next_named_account_info(account_info_iter, "initializer")?;
+        let temp_token_account = next_named_account_info(account_info_iter, "temp_token_account")?;
+        let initializer_refund_token_account =
+            next_named_account_info(account_info_iter, "initializer_refund_token_account")?;
+        let escrow_account = next_named_account_info(account_info_iter, "escrow_account")?;
+        let token_program = next_named_account_info(account_info_iter, "token_program")?;
+        assert_token_program(token_program)?;
+        let pda_account = next_named_account_info(account_info_iter, "pda_account")?;
+        let escrow_index_account =
+            next_named_account_info(account_info_iter, "escrow_index_account")?;
+        let escrow = Self::load_escrow(escrow_account)?;
+
+        require_signer(initializer, "initializer")?;
+        if *initializer.key != escrow.initializer_pubkey {
+            return Err(EscrowError::Unauthorized.into());
+        }
+        if *temp_token_account.key != escrow.temp_token_account_pubkey {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        if !escrow.is_cancellable(Self::current_slot()?) {
+            msg!(
+                "Escrow can be cancelled from slot {}",
+                escrow.cancellable_slot
+            );
+            return Err(EscrowError::TooEarlyToCancel.into());
+        }
+
+        Self::assert_temp_account_open(temp_token_account)?;
+        let temp_token_account_info = assert_token_account(temp_token_account, None, None)?;
+        assert_token_account(
+            initializer_refund_token_account,
+            Some(&temp_token_account_info.mint),
+            Some(initializer.key),
+        )?;
+
+        let (pda, bump_seed) = Self::stored_escrow_authority(pda_account, &escrow, program_id)?;
+
+        let penalty = proportional_amount(
+            temp_token_account_info.amount,
+            escrow.cancel_penalty_bps as u64,
+            10_000,
+            Rounding::Down,
+        )?;
+        if penalty > 0 {
+            let config_account = next_named_account_info(account_info_iter, "config_account")?;
+            let treasury_token_account =
+                next_named_account_info(account_info_iter, "treasury_token_account")?;
+            let config = Self::load_config(config_account, program_id)?;
+            assert_token_account(
+                treasury_token_account,
+                Some(&temp_token_account_info.mint),
+                Some(&config.treasury),
+            )?;
+            let penalty_ix = spl_token::instruction::transfer(
+                token_program.key,
+                temp_token_account.key,
+                treasury_token_account.key,
+                &pda,
+                &[&pda],
+                penalty,
+            )?;
+            log_debug!("Calling token program to pay the cancel penalty...");
+            invoke_signed(
+                &penalty_ix,
+                &[
+                    temp_token_account.clone(),
+                    treasury_token_account.clone(),
+                    pda_account.clone(),
+                ],
+                &[&escrow_signer_seeds(&bump_seed)],
+            )?;
+        }
+        // The penalty only ever takes a share of the main deposit.
+        Self::release_basket(
+            &Self::cancel_basket_accounts(accounts, &escrow)?,
+            token_program,
+            Some(initializer.key),
+            initializer,
+            pda_account,
+            &pda,
+            bump_seed,
+        )?;
+
+        Self::refund_temp_account(
+            token_program,
+            temp_token_account,
+            temp_token_account_info.amount - penalty,
+            initializer_refund_token_account,
+            initializer,
+            pda_account,
+            &pda,
+            bump_seed,
+        )?;
+        if !close_escrow_account {
+            return Ok(());
+        }
+        Self::close_and_unindex(
+            escrow_account,
+            Self::rent_destination(accounts, &escrow, initializer)?,
+            escrow.stake_lamports,
+            initializer,
+            escrow_index_account,
+            initializer,
+            CloseReason::Cancelled,
+            program_id,
+        )
+    }
+
+    /// Reclaims each expired escrow of the batch for its initializer like a penalty-free cancel,
+    /// but forfeiting its stake, skipping those that haven't expired (or are already closed)
+    /// instead of failing the batch.
+    fn process_reclaim_expired_batch(
+        accounts: &[AccountInfo],
+        count: u8,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let token_program = next_named_account_info(account_info_iter, "token_program")?;
+        assert_token_program(token_program)?;
+        let pda_account = next_named_account_info(account_info_iter, "pda_account")?;
+
+        if count == 0 || count as usize > MAX_RECLAIM_BATCH {
+            msg!("Batch size must be between 1 and {}", MAX_RECLAIM_BATCH);
+            return Err(ProgramError::InvalidArgument);
+        }
+        let (pda, bump_seed) = find_escrow_authority_address(program_id);
+        Self::assert_pda_account(pda_account, &pda)?;
+        let slot = Self::current_slot()?;
+
+        for _ in 0..count {
+            let escrow_account = next_named_account_info(account_info_iter, "escrow_account")?;
+            let temp_token_account =
+                next_named_account_info(account_info_iter, "temp_token_account")?;
+            let initializer = next_named_account_info(account_info_iter, "initializer")?;
+            let initializer_refund_token_account =
+                next_named_account_info(account_info_iter, "initializer_refund_token_account")?;
+            let escrow_index_account =
+                next_named_account_info(account_info_iter, "escrow_index_account")?;
+
+            // Another keeper may have gotten to it first, or it's listed twice.
+            if escrow_account.data_len() == 0
+                || escrow_account
+                    .try_borrow_data()?
+                    .iter()
+                    .all(|byte| *byte == 0)
+            {
+                log_info!("Skipping closed escrow {}", escrow_account.key);
+                continue;
+            }
+            if escrow_account.owner != program_id {
+                return Err(ProgramError::IncorrectProgramId);
+            }
+            let escrow = Self::load_escrow(escrow_account)?;
+            if !escrow.is_expired(slot) {
+                log_info!("Skipping escrow {}, not expired", escrow_account.key);
+                continue;
+            }
+            if escrow.is_settlement_pending(slot) {
+                log_info!("Skipping escrow {}, settlement pending", escrow_account.key);
+                continue;
+            }
+            // The batch has no room for the basket's accounts.
+            if !escrow.basket.is_empty() {
+                log_info!("Skipping escrow {}, basket escrow", escrow_account.key);
+                continue;
+            }
+
+            if *initializer.key != escrow.initializer_pubkey
+                || *temp_token_account.key != escrow.temp_token_account_pubkey
+            {
+                return Err(ProgramError::InvalidAccountData);
+            }
+            let temp_token_account_info = assert_token_account(temp_token_account, None, None)?;
+            assert_token_account(
+                initializer_refund_token_account,
+                Some(&temp_token_account_info.mint),
+                Some(initializer.key),
+            )?;
+            // Expiring unfilled is what the stake was put up against.
+            let stake_destination = if escrow.stake_lamports > 0 {
+                Self::stake_treasury(accounts, 2 + 5 * count as usize, program_id)?
+            } else {
+                initializer
+            };
+
+            Self::refund_and_close(
+                token_program,
+                temp_token_account,
+                temp_token_account_info.amount,
+                initializer_refund_token_account,
+                initializer,
+                Self::rent_destination(accounts, &escrow, initializer)?,
+                escrow.stake_lamports,
+                stake_destination,
+                pda_account,
+                &pda,
+                bump_seed,
+                escrow_account,
+                escrow_index_account,
+                CloseReason::Expired,
+                program_id,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns `refund` of the deposit to the initializer and closes the escrow for `reason`:
+    /// its temp account, the escrow account (its rent going to `rent_destination` and its `stake`
+    /// to `stake_destination`) and its entry in the escrow index.
+    #[allow(clippy::too_many_arguments)]
+    fn refund_and_close<'a>(
+        token_program: &AccountInfo<'a>,
+        temp_token_account: &AccountInfo<'a>,
+        refund: u64,
+        initializer_refund_token_account: &AccountInfo<'a>,
+        initializer: &AccountInfo<'a>,
+        rent_destination: &AccountInfo<'a>,
+        stake: u64,
+        stake_destination: &AccountInfo<'a>,
+        pda_account: &AccountInfo<'a>,
+        pda: &Pubkey,
+        bump_seed: u8,
+        escrow_account: &AccountInfo<'a>,
+        escrow_index_account: &AccountInfo<'a>,
+        reason: CloseReason,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        Self::refund_temp_account(
+            token_program,
+            temp_token_account,
+            refund,
+            initializer_refund_token_account,
+            initializer,
+            pda_account,
+            pda,
+            bump_seed,
+        )?;
+        Self::close_and_unindex(
+            escrow_account,
+            rent_destination,
+            stake,
+            stake_destination,
+            escrow_index_account,
+            initializer,
+            reason,
+            program_id,
+        )
+    }
+
+    /// Returns `refund` of the deposit to the initializer and closes the temp account.
+    #[allow(clippy::too_many_arguments)]
+    fn refund_temp_account<'a>(
+        token_program: &AccountInfo<'a>,
+        temp_token_account: &AccountInfo<'a>,
+        refund: u64,
+        initializer_refund_token_account: &AccountInfo<'a>,
+        initializer: &AccountInfo<'a>,
+        pda_account: &AccountInfo<'a>,
+        pda: &Pubkey,
+        bump_seed: u8,
+    ) -> ProgramResult {
+        // Return the deposited tokens, unless something already drained the temp account. Wrapped
+        // SOL is returned unwrapped instead, by closing the temp account below.
+        let temp_token_account_info = assert_token_account(temp_token_account, None, None)?;
+        if refund > 0 && !temp_token_account_info.is_native() {
+            let refund_ix = spl_token::instruction::transfer(
+                token_program.key,
+                temp_token_account.key,
+                initializer_refund_token_account.key,
+                pda,
+                &[pda],
+                refund,
+            )?;
+            log_debug!("Calling token program to return tokens to the initializer...");
+            invoke_signed(
+                &refund_ix,
+                &[
+                    temp_token_account.clone(),
+                    initializer_refund_token_account.clone(),
+                    pda_account.clone(),
+                ],
+                &[&escrow_signer_seeds(&bump_seed)],
+            )?;
+        }
+
+        Self::close_temp_token_account(
+            token_program,
+            temp_token_account,
+            initializer,
+            pda_account,
+            pda,
+            bump_seed,
+            true,
+        )
+    }
+
+    /// Closes the escrow account for `reason`, its rent going to `rent_destination` and its
+    /// `stake` to `stake_destination`, and removes it from the initializer's escrow index.
+    #[allow(clippy::too_many_arguments)]
+    fn close_and_unindex(
+        escrow_account: &AccountInfo,
+        rent_destination: &AccountInfo,
+        stake: u64,
+        stake_destination: &AccountInfo,
+        escrow_index_account: &AccountInfo,
+        initializer: &AccountInfo,
+        reason: CloseReason,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        Self::close_escrow_account(
+            escrow_account,
+            rent_destination,
+            stake,
+            stake_destination,
+            reason,
+        )?;
+        Self::remove_from_escrow_index(
+            escrow_index_account,
+            initializer,
+            escrow_account.key,
+            program_id,
+        )
+    }
+
+    /// The account at `index`, which has to be the basket temp token account `basket_key`.
+    fn basket_account_at<'a, 'b>(
+        accounts: &'a [AccountInfo<'b>],
+        index: usize,
+        basket_key: &Pubkey,
+    ) -> Result<&'a AccountInfo<'b>, ProgramError> {
+        let basket_account = named_account_at(accounts, index, "basket account")?;
+        if basket_account.key != basket_key {
+            msg!(
+                "Expected basket account {} at position {}, got {}",
+                basket_key,
+                index,
+                basket_account.key
+            );
+            return Err(ProgramError::InvalidAccountData);
+        }
+        Ok(basket_account)
+    }
+
+    /// The escrow's basket accounts of a `CancelEscrow`, each with the account it's refunded to,
+    /// from their positions after the instruction's own accounts.
+    fn cancel_basket_accounts<'a, 'b>(
+        accounts: &'a [AccountInfo<'b>],
+        escrow: &Escrow,
+    ) -> Result<Vec<(&'a AccountInfo<'b>, &'a AccountInfo<'b>)>, ProgramError> {
+        require_account_count(
+            accounts,
+            CANCEL_ESCROW_BASKET_OFFSET + 2 * escrow.basket.len(),
+        )?;
+        escrow
+            .basket
+            .iter()
+            .enumerate()
+            .map(|(index, basket_key)| {
+                let position = CANCEL_ESCROW_BASKET_OFFSET + 2 * index;
+                Ok((
+                    Self::basket_account_at(accounts, position, basket_key)?,
+                    named_account_at(accounts, position + 1, "basket destination")?,
+                ))
+            })
+            .collect()
+    }
+
+    /// The escrow's basket accounts of a full fill, each with the account it's paid out to, from
+    /// among the fill's `trailing_accounts`, which come in any order.
+    fn exchange_basket_accounts<'a, 'b>(
+        trailing_accounts: &'a [AccountInfo<'b>],
+        escrow: &Escrow,
+    ) -> Result<Vec<(&'a AccountInfo<'b>, &'a AccountInfo<'b>)>, ProgramError> {
+        escrow
+            .basket
+            .iter()
+            .map(|basket_key| {
+                let position = trailing_accounts
+                    .iter()
+                    .position(|info| info.key == basket_key)
+                    .ok_or_else(|| {
+                        msg!("Missing basket account {}", basket_key);
+                        EscrowError::NotEnoughAccounts
+                    })?;
+                Ok((
+                    &trailing_accounts[position],
+                    named_account_at(trailing_accounts, position + 1, "basket destination")?,
+                ))
+            })
+            .collect()
+    }
+
+    /// Empties each of the escrow's `basket_accounts` into the account paired with it (see
+    /// `instruction::with_basket_accounts`) and closes it, its rent going to the initializer.
+    /// `destination_owner` is who those accounts must belong to, if anyone: a cancel's refunds
+    /// have to go to the initializer, while a fill's go wherever the taker wants. Refunded wrapped
+    /// SOL is unwrapped to the initializer instead.
+    #[allow(clippy::too_many_arguments)]
+    fn release_basket<'a>(
+        basket_accounts: &[(&AccountInfo<'a>, &AccountInfo<'a>)],
+        token_program: &AccountInfo<'a>,
+        destination_owner: Option<&Pubkey>,
+        initializer: &AccountInfo<'a>,
+        pda_account: &AccountInfo<'a>,
+        pda: &Pubkey,
+        bump_seed: u8,
+    ) -> ProgramResult {
+        for &(basket_account, destination) in basket_accounts {
+            let basket_account_info = assert_token_account(basket_account, None, None)?;
+            if basket_account_info.owner != *pda {
+                return Err(EscrowError::AuthorityNotTransferred.into());
+            }
+            assert_token_account(
+                destination,
+                Some(&basket_account_info.mint),
+                destination_owner,
+            )?;
+            let unwrap = destination_owner.is_some() && basket_account_info.is_native();
+            if basket_account_info.amount > 0 && !unwrap {
+                let transfer_ix = spl_token::instruction::transfer(
+                    token_program.key,
+                    basket_account.key,
+                    destination.key,
+                    pda,
+                    &[pda],
+                    basket_account_info.amount,
+                )?;
+                log_debug!(
+                    "Calling token program to release {} basket tokens...",
+                    basket_account_info.amount
+                );
+                invoke_signed(
+                    &transfer_ix,
+                    &[
+                        basket_account.clone(),
+                        destination.clone(),
+                        pda_account.clone(),
+                    ],
+                    &[&escrow_signer_seeds(&bump_seed)],
+                )?;
+            }
+            Self::close_temp_token_account(
+                token_program,
+                basket_account,
+                initializer,
+                pda_account,
+                pda,
+                bump_seed,
+                unwrap,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn process_sweep_dust(accounts: &[AccountInfo], program_id: &Pubkey) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let admin = next_named_account_info(account_info_iter, "admin")?;
+        let dust_account = next_named_account_info(account_info_iter, "dust_account")?;
+        let treasury = next_named_account_info(account_info_iter, "treasury")?;
+        let system_program = next_named_account_info(account_info_iter, "system_program")?;
+        let config_account = next_named_account_info(account_info_iter, "config_account")?;
+
+        require_signer(admin, "admin")?;
+        let config = Self::load_config(config_account, program_id)?;
+        if *admin.key != config.admin {
+            return Err(EscrowError::Unauthorized.into());
+        }
+        if *treasury.key != config.treasury {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let rent_exempt_minimum = Self::rent().minimum_balance(dust_account.data_len());
+        let dust = dust_account.lamports().saturating_sub(rent_exempt_minimum);
+        if dust == 0 {
+            log_info!("No dust to sweep");
+            return Ok(());
+        }
+
+        let (pda, bump_seed) = find_escrow_authority_address(program_id);
+        if *dust_account.key == pda {
+            // The PDA is a system account, so it has to sign a system transfer for its lamports.
+            let transfer_ix = system_instruction::transfer(&pda, treasury.key, dust);
+            log_debug!("Calling system program to sweep dust from the pda...");
+            invoke_signed(
+                &transfer_ix,
+                &[
+                    dust_account.clone(),
+                    treasury.clone(),
+                    system_program.clone(),
+                ],
+                &[&escrow_signer_seeds(&bump_seed)],
+            )?;
+            return Ok(());
+        }
+
+        if dust_account.owner != program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        // Anything large enough to be an escrow must not be one that's still live.
+        if dust_account.data_len() >= Escrow::LEN {
+            let escrow_info = Escrow::unpack_unchecked(&dust_account.try_borrow_data()?)?;
+            if escrow_info.is_initialized() && escrow_info.status != EscrowStatus::Closed {
+                return Err(EscrowError::ActiveEscrow.into());
+            }
+        }
+
+        log_debug!("Sweeping dust to the treasury...");
+        **treasury.lamports.borrow_mut() = treasury
+            .lamports()
+            .checked_add(dust)
+            .ok_or(EscrowError::Overflow)?;
+        **dust_account.lamports.borrow_mut() = dust_account
+            .lamports()
+            .checked_sub(dust)
+            .ok_or(EscrowError::Overflow)?;
+
+        Ok(())
+    }
+
+    fn process_split_escrow(
+        accounts: &[AccountInfo],
+        count: u8,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let initializer = next_named_account_info(account_info_iter, "initializer")?;
+        let escrow_account = next_named_account_info(account_info_iter, "escrow_account")?;
+        let temp_token_account = next_named_account_info(account_info_iter, "temp_token_account")?;
+        let temp_mint_account = next_named_account_info(account_info_iter, "temp_mint_account")?;
+        let token_program = next_named_account_info(account_info_iter, "token_program")?;
+        assert_token_program(token_program)?;
+        let pda_account = next_named_account_info(account_info_iter, "pda_account")?;
+        let escrow_index_account =
+            next_named_account_info(account_info_iter, "escrow_index_account")?;
+        let system_program = next_named_account_info(account_info_iter, "system_program")?;
+
+        require_signer(initializer, "initializer")?;
+        if count < 2 {
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        let escrow = Self::load_escrow(escrow_account)?;
+        if *initializer.key != escrow.initializer_pubkey {
+            return Err(EscrowError::Unauthorized.into());
+        }
+        if *temp_token_account.key != escrow.temp_token_account_pubkey {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        if escrow.is_settlement_pending(Self::current_slot()?) {
+            return Err(EscrowError::SettlementPending.into());
+        }
+        if !escrow.basket.is_empty() {
+            msg!("Basket escrows can't be split");
+            return Err(EscrowError::InvalidEscrowState.into());
+        }
+        // Each child would start its own count of fills, getting around `max_fills`.
+        if escrow.fills_done > 0 {
+            msg!("Partially filled escrows can't be split");
+            return Err(EscrowError::InvalidEscrowState.into());
+        }
+
+        let (pda, bump_seed) = Self::stored_escrow_authority(pda_account, &escrow, program_id)?;
+        let temp_token_account_info = assert_token_account(temp_token_account, None, None)?;
+        if *temp_mint_account.key != temp_token_account_info.mint {
+            return Err(EscrowError::MintMismatch.into());
+        }
+
+        // Every child needs something to hand out and something to ask for.
+        let deposit = temp_token_account_info.amount;
+        let deposit_share = deposit / count as u64;
+        let expected_share = proportional_amount(
+            deposit_share,
+            escrow.expected_amount,
+            deposit,
+            Rounding::Down,
+        )?;
+        if deposit_share == 0 || expected_share == 0 {
+            msg!("Escrow is too small to split {} ways", count);
+            return Err(EscrowError::AmountTooSmall.into());
+        }
+
+        // The stake keeps backing the deposit, split like it.
+        let stake_share = escrow.stake_lamports / count as u64;
+        let mut deposit_left = deposit;
+        let mut expected_left = escrow.expected_amount;
+        let mut stake_left = escrow.stake_lamports;
+        let mut children = Vec::with_capacity(count as usize);
+        for index in 0..count {
+            let child_account = next_named_account_info(account_info_iter, "child_escrow_account")?;
+            let child_temp_account =
+                next_named_account_info(account_info_iter, "child_temp_token_account")?;
+            let is_last = index == count - 1;
+            let (child_deposit, child_expected, child_stake) = if is_last {
+                (deposit_left, expected_left, stake_left)
+            } else {
+                (deposit_share, expected_share, stake_share)
+            };
+            deposit_left -= child_deposit;
+            expected_left -= child_expected;
+            stake_left -= child_stake;
+
+            let (child_key, child_bump) =
+                find_split_escrow_address(program_id, escrow_account.key, index);
+            let (child_temp_key, child_temp_bump) =
+                find_split_temp_address(program_id, escrow_account.key, index);
+            if *child_account.key != child_key || *child_temp_account.key != child_temp_key {
+                return Err(ProgramError::InvalidSeeds);
+            }
+
+            log_debug!("Calling system program to create split escrow {}...", index);
+            Self::create_pda_account(
+                child_temp_account,
+                initializer,
+                system_program,
+                TokenAccount::LEN,
+                &[
+                    SPLIT_TEMP_SEED,
+                    escrow_account.key.as_ref(),
+                    &[index],
+                    &[child_temp_bump],
+                ],
+                &spl_token::id(),
+            )?;
+            invoke(
+                &spl_token::instruction::initialize_account3(
+                    token_program.key,
+                    child_temp_account.key,
+                    temp_mint_account.key,
+                    &pda,
+                )?,
+                &[child_temp_account.clone(), temp_mint_account.clone()],
+            )?;
+            invoke_signed(
+                &spl_token::instruction::transfer(
+                    token_program.key,
+                    temp_token_account.key,
+                    child_temp_account.key,
+                    &pda,
+                    &[&pda],
+                    child_deposit,
+                )?,
+                &[
+                    temp_token_account.clone(),
+                    child_temp_account.clone(),
+                    pda_account.clone(),
+                ],
+                &[&escrow_signer_seeds(&bump_seed)],
+            )?;
+
+            Self::create_pda_account(
+                child_account,
+                initializer,
+                system_program,
+                Escrow::LEN,
+                &[
+                    SPLIT_ESCROW_SEED,
+                    escrow_account.key.as_ref(),
+                    &[index],
+                    &[child_bump],
+                ],
+                program_id,
+            )?;
+            let mut child = escrow.clone();
+            child.temp_token_account_pubkey = child_temp_key;
+            child.expected_amount = child_expected;
+            child.fills_done = 0;
+            child.status = EscrowStatus::Open;
+            // The initializer paid for the children.
+            child.rent_beneficiary = COption::None;
+            child.stake_lamports = child_stake;
+            Escrow::pack(child, &mut child_account.try_borrow_mut_data()?)?;
+            children.push((child_account, child_stake));
+
+            // Children are keyed by their parent, whose address init already held to the cooldown.
+            Self::add_to_escrow_index(
+                escrow_index_account,
+                initializer,
+                system_program,
+                child_account.key,
+                0,
+                program_id,
+            )?;
+        }
+
+        Self::close_temp_token_account(
+            token_program,
+            temp_token_account,
+            initializer,
+            pda_account,
+            &pda,
+            bump_seed,
+            false,
+        )?;
+        // Only once the CPIs are done, as the runtime checks each CPI's accounts kept their
+        // lamports, which the initializer wouldn't after the index refunds it.
+        Self::remove_from_escrow_index(
+            escrow_index_account,
+            initializer,
+            escrow_account.key,
+            program_id,
+        )?;
+        for (child_account, child_stake) in children {
+            Self::move_lamports(escrow_account, child_account, child_stake)?;
+        }
+        Self::close_escrow_account(
+            escrow_account,
+            Self::rent_destination(accounts, &escrow, initializer)?,
+            0,
+            initializer,
+            CloseReason::Split,
+        )?;
+
+        Ok(())
+    }
+
+    fn process_merge_escrows(accounts: &[AccountInfo], program_id: &Pubkey) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let initializer = next_named_account_info(account_info_iter, "initializer")?;
+        let escrow_account = next_named_account_info(account_info_iter, "escrow_account")?;
+        let temp_token_account = next_named_account_info(account_info_iter, "temp_token_account")?;
+        let merged_escrow_account =
+            next_named_account_info(account_info_iter, "merged_escrow_account")?;
+        let merged_temp_token_account =
+            next_named_account_info(account_info_iter, "merged_temp_token_account")?;
+        let token_program = next_named_account_info(account_info_iter, "token_program")?;
+        assert_token_program(token_program)?;
+        let pda_account = next_named_account_info(account_info_iter, "pda_account")?;
+        let escrow_index_account =
+            next_named_account_info(account_info_iter, "escrow_index_account")?;
+
+        require_signer(initializer, "initializer")?;
+        if escrow_account.key == merged_escrow_account.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let mut escrow = Self::load_escrow(escrow_account)?;
+        let merged_escrow = Self::load_escrow(merged_escrow_account)?;
+        // Merging changes what a prepared taker would pay and receive.
+        let slot = Self::current_slot()?;
+        if escrow.is_settlement_pending(slot) || merged_escrow.is_settlement_pending(slot) {
+            return Err(EscrowError::SettlementPending.into());
+        }
+
+        if *initializer.key != escrow.initializer_pubkey
+            || *initializer.key != merged_escrow.initializer_pubkey
+        {
+            return Err(EscrowError::Unauthorized.into());
+        }
+        if *temp_token_account.key != escrow.temp_token_account_pubkey
+            || *merged_temp_token_account.key != merged_escrow.temp_token_account_pubkey
+        {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        if escrow.initializer_dest_token_account_pubkey
+            != merged_escrow.initializer_dest_token_account_pubkey
+            || escrow.rounding_mode != merged_escrow.rounding_mode
+            || escrow.accepted_mints != merged_escrow.accepted_mints
+            || escrow.cancel_penalty_bps != merged_escrow.cancel_penalty_bps
+            || escrow.expiry_slot != merged_escrow.expiry_slot
+            || escrow.oracle != merged_escrow.oracle
+            || escrow.taker_merkle_root != merged_escrow.taker_merkle_root
+            || escrow.maker != merged_escrow.maker
+            || escrow.min_fill != merged_escrow.min_fill
+            || escrow.max_fills != merged_escrow.max_fills
+            || escrow.delivers_to_ata != merged_escrow.delivers_to_ata
+        {
+            msg!("Escrows with different terms can't be merged");
+            return Err(ProgramError::InvalidAccountData);
+        }
+        if !escrow.basket.is_empty() || !merged_escrow.basket.is_empty() {
+            msg!("Basket escrows can't be merged");
+            return Err(EscrowError::InvalidEscrowState.into());
+        }
+
+        let temp_token_account_info = assert_token_account(temp_token_account, None, None)?;
+        let merged_temp_token_account_info = assert_token_account(
+            merged_temp_token_account,
+            Some(&temp_token_account_info.mint),
+            None,
+        )?;
+        let (pda, bump_seed) = Self::stored_escrow_authority(pda_account, &escrow, program_id)?;
+
+        escrow.expected_amount = escrow
+            .expected_amount
+            .checked_add(merged_escrow.expected_amount)
+            .ok_or(EscrowError::Overflow)?;
+        // The merged escrow's stake moves along with its deposit.
+        escrow.stake_lamports = escrow
+            .stake_lamports
+            .checked_add(merged_escrow.stake_lamports)
+            .ok_or(EscrowError::Overflow)?;
+        Escrow::pack(escrow, &mut escrow_account.try_borrow_mut_data()?)?;
+
+        if merged_temp_token_account_info.amount > 0 {
+            let transfer_ix = spl_token::instruction::transfer(
+                token_program.key,
+                merged_temp_token_account.key,
+                temp_token_account.key,
+                &pda,
+                &[&pda],
+                merged_temp_token_account_info.amount,
+            )?;
+            log_debug!("Calling token program to move tokens into the merged escrow...");
+            invoke_signed(
+                &transfer_ix,
+                &[
+                    merged_temp_token_account.clone(),
+                    temp_token_account.clone(),
+                    pda_account.clone(),
+                ],
+                &[&escrow_signer_seeds(&bump_seed)],
+            )?;
+        }
+
+        Self::close_temp_token_account(
+            token_program,
+            merged_temp_token_account,
+            initializer,
+            pda_account,
+            &pda,
+            bump_seed,
+            false,
+        )?;
+
+        Self::close_escrow_account(
+            merged_escrow_account,
+            Self::rent_destination(accounts, &merged_escrow, initializer)?,
+            merged_escrow.stake_lamports,
+            escrow_account,
+            CloseReason::Merged,
+        )?;
+        Self::remove_from_escrow_index(
+            escrow_index_account,
+            initializer,
+            merged_escrow_account.key,
+            program_id,
+        )?;
+
+        Ok(())
+    }
+
+    fn process_top_up_escrow(
+        accounts: &[AccountInfo],
+        amount: u64,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let initializer = next_named_account_info(account_info_iter, "initializer")?;
+        let escrow_account = next_named_account_info(account_info_iter, "escrow_account")?;
+        let source_token_account =
+            next_named_account_info(account_info_iter, "source_token_account")?;
+        let temp_token_account = next_named_account_info(account_info_iter, "temp_token_account")?;
+        let token_program = next_named_account_info(account_info_iter, "token_program")?;
+        assert_token_program(token_program)?;
+        let config_account = next_named_account_info(account_info_iter, "config_account")?;
+
+        require_signer(initializer, "initializer")?;
+        if amount == 0 {
+            return Err(ProgramError::InvalidArgument);
+        }
+        if Self::load_config(config_account, program_id)?.paused {
+            return Err(EscrowError::Paused.into());
+        }
+
+        let mut escrow = Self::load_escrow(escrow_account)?;
+        if *initializer.key != escrow.initializer_pubkey {
+            return Err(EscrowError::Unauthorized.into());
+        }
+        if *temp_token_account.key != escrow.temp_token_account_pubkey {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let slot = Self::current_slot()?;
+        // A prepared taker already agreed to the current amounts.
+        if escrow.is_settlement_pending(slot) {
+            return Err(EscrowError::SettlementPending.into());
+        }
+        if escrow.is_expired(slot) {
+            return Err(EscrowError::EscrowExpired.into());
+        }
+
+        let temp_token_account_info = assert_token_account(temp_token_account, None, None)?;
+        Self::assert_temp_mint(&escrow, &temp_token_account_info)?;
+        assert_token_account(
+            source_token_account,
+            Some(&temp_token_account_info.mint),
+            None,
+        )?;
+
+        let deposit = temp_token_account_info.amount;
+        if deposit == 0 {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let new_deposit = deposit.checked_add(amount).ok_or(EscrowError::Overflow)?;
+        escrow.expected_amount = escrow.fill_payment(new_deposit, deposit)?;
+        Escrow::pack(escrow, &mut escrow_account.try_borrow_mut_data()?)?;
+
+        let transfer_ix = spl_token::instruction::transfer(
+            token_program.key,
+            source_token_account.key,
+            temp_token_account.key,
+            initializer.key,
+            &[],
+            amount,
+        )?;
+        log_debug!("Calling token program to top up the deposit...");
+        invoke(
+            &transfer_ix,
+            &[
+                source_token_account.clone(),
+                temp_token_account.clone(),
+                initializer.clone(),
+                token_program.clone(),
+            ],
+        )
+    }
+
+    /// Cancels the escrow like `CancelEscrow` and initializes a new one like `InitEscrow` with the
+    /// same terms but `new_expected_amount`, so each half runs all of its own checks.
+    fn process_replace_escrow(
+        accounts: &[AccountInfo],
+        new_expected_amount: u64,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let initializer = named_account_at(accounts, 0, "initializer")?;
+        let escrow_account = named_account_at(accounts, 3, "escrow_account")?;
+        let new_initializer =
+            named_account_at(accounts, REPLACE_ESCROW_INIT_OFFSET, "initializer")?;
+
+        let escrow = Self::load_escrow(escrow_account)?;
+        let slot = Self::current_slot()?;
+        if escrow.is_settlement_pending(slot) {
+            return Err(EscrowError::SettlementPending.into());
+        }
+        if escrow.is_expired(slot) {
+            return Err(EscrowError::EscrowExpired.into());
+        }
+        // Both halves would need the basket's accounts after their own.
+        if !escrow.basket.is_empty() {
+            msg!("Basket escrows can't be replaced");
+            return Err(EscrowError::InvalidEscrowState.into());
+        }
+        if new_initializer.key != initializer.key {
+            return Err(EscrowError::Unauthorized.into());
+        }
+
+        let escrow_index_account = named_account_at(accounts, 6, "escrow_index_account")?;
+        // The cancel finds the rent beneficiary the init is passed, if any, among all accounts.
+        let rent_destination = Self::rent_destination(accounts, &escrow, initializer)?;
+        let stake = escrow.stake_lamports;
+
+        // The old escrow account is only closed once the init's CPIs are done: the runtime checks
+        // that each CPI's accounts still hold the lamports they did, which the initializer
+        // wouldn't after being refunded the account's rent and stake.
+        Self::cancel_escrow(accounts, program_id, false)?;
+        Self::process_init_escrow(
+            &accounts[REPLACE_ESCROW_INIT_OFFSET..],
+            new_expected_amount,
+            escrow.oracle,
+            escrow.max_deviation_bps,
+            escrow.max_fills,
+            escrow.rounding_mode,
+            escrow.accepted_mints,
+            escrow.reference,
+            escrow.reference_mode,
+            escrow.start_slot,
+            escrow.cancel_penalty_bps,
+            escrow.expiry_slot,
+            // The old escrow's window was over for the cancel to go through.
+            0,
+            escrow.category,
+            escrow.taker_merkle_root,
+            escrow.delivers_to_ata,
+            escrow.min_fill,
+            escrow.stake_lamports,
+            escrow.maker,
+            vec![],
+            program_id,
+        )?;
+        Self::close_and_unindex(
+            escrow_account,
+            rent_destination,
+            stake,
+            initializer,
+            escrow_index_account,
+            initializer,
+            CloseReason::Cancelled,
+            program_id,
+        )
+    }
+
+    fn process_top_up_rent(accounts: &[AccountInfo], program_id: &Pubkey) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let payer = next_named_account_info(account_info_iter, "payer")?;
+        let escrow_account = next_named_account_info(account_info_iter, "escrow_account")?;
+        let system_program = next_named_account_info(account_info_iter, "system_program")?;
+
+        require_signer(payer, "payer")?;
+        if escrow_account.owner != program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        Self::load_escrow(escrow_account)?;
+
+        log_debug!("Calling system program to top up the escrow's rent...");
+        Self::top_up_rent(
+            escrow_account,
+            payer,
+            system_program,
+            escrow_account.data_len(),
+        )
+    }
+
+    fn process_quote_exchange(
+        accounts: &[AccountInfo],
+        amount: u64,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let escrow_account = next_named_account_info(account_info_iter, "escrow_account")?;
+        let temp_token_account = next_named_account_info(account_info_iter, "temp_token_account")?;
+        let config_account = next_named_account_info(account_info_iter, "config_account")?;
+        let payment_mint_account =
+            next_named_account_info(account_info_iter, "payment_mint_account")?;
+
+        if escrow_account.owner != program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let escrow = Self::load_escrow(escrow_account)?;
+        if *temp_token_account.key != escrow.temp_token_account_pubkey {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let config = Self::load_config(config_account, program_id)?;
+        if config.paused {
+            return Err(EscrowError::Paused.into());
+        }
+
+        let slot = Self::current_slot()?;
+        if !escrow.is_active(slot) {
+            return Err(EscrowError::EscrowNotYetActive.into());
+        }
+        if escrow.is_expired(slot) {
+            return Err(EscrowError::EscrowExpired.into());
+        }
+        if escrow.fills_exhausted() {
+            return Err(EscrowError::MaxFillsReached.into());
+        }
+
+        let payment_mint = payment_mint_account.key;
+        if *payment_mint != escrow.expected_mint && !escrow.accepted_mints.contains(payment_mint) {
+            return Err(EscrowError::MintNotAccepted.into());
+        }
+        let deposit = assert_token_account(temp_token_account, None, None)?.amount;
+        if amount == 0 || amount > deposit {
+            return Err(EscrowError::ExpectedAmountMismatch.into());
+        }
+
+        let payment = escrow.fill_payment(amount, deposit)?;
+        let fee = config.fee_for(payment, payment_mint)?;
+        let amount_in = payment.checked_add(fee).ok_or(EscrowError::Overflow)?;
+
+        let mut quote = [0; 16];
+        quote[..8].copy_from_slice(&amount_in.to_le_bytes());
+        quote[8..].copy_from_slice(&amount.to_le_bytes());
+        set_return_data(&quote);
+        Ok(())
+    }
+
+    fn process_check_eligibility(
+        accounts: &[AccountInfo],
+        taker: &Pubkey,
+        taker_proof: &[[u8; 32]],
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let escrow_account = next_named_account_info(account_info_iter, "escrow_account")?;
+
+        if escrow_account.owner != program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let escrow = Self::load_escrow(escrow_account)?;
+        let eligible = escrow.allows_taker(taker, taker_proof);
+        log_info!(
+            "Taker {} is {}eligible",
+            taker,
+            if eligible { "" } else { "not " }
+        );
+        set_return_data(&[eligible as u8]);
+        Ok(())
+    }
+
+    fn process_describe_exchange(accounts: &[AccountInfo], program_id: &Pubkey) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let escrow_account = next_named_account_info(account_info_iter, "escrow_account")?;
+        if escrow_account.owner != program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let escrow = Self::load_escrow(escrow_account)?;
+
+        // Build the exchange the same way clients do, so the description can't drift from it.
+        let taker_supplied = Pubkey::default();
+        let oracle = match &escrow.oracle {
+            COption::Some(oracle) => Some(oracle),
+            COption::None => None,
+        };
+        let exchange = instruction::exchange(
+            program_id,
+            &taker_supplied,
+            &taker_supplied,
+            &taker_supplied,
+            &taker_supplied,
+            &escrow.temp_token_account_pubkey,
+            &escrow.initializer_pubkey,
+            &escrow.initializer_dest_token_account_pubkey,
+            escrow_account.key,
+            &taker_supplied,
+            &taker_supplied,
+            oracle,
+            0,
+            &[],
+            u64::MAX,
+        )?;
+
+        let mut description = Vec::with_capacity(exchange.accounts.len() * 33);
+        for meta in &exchange.accounts {
+            let mut flags = 0;
+            if meta.is_signer {
+                flags |= EXCHANGE_ACCOUNT_SIGNER;
+            }
+            if meta.is_writable {
+                flags |= EXCHANGE_ACCOUNT_WRITABLE;
+            }
+            description.extend_from_slice(meta.pubkey.as_ref());
+            description.push(flags);
+        }
+        set_return_data(&description);
+        Ok(())
+    }
+
+    fn process_init_config(
+        accounts: &[AccountInfo],
+        args: ConfigArgs,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let deployer = next_named_account_info(account_info_iter, "deployer")?;
+        let config_account = next_named_account_info(account_info_iter, "config_account")?;
+        let system_program = next_named_account_info(account_info_iter, "system_program")?;
+        let program_data = next_named_account_info(account_info_iter, "program_data")?;
+
+        require_signer(deployer, "deployer")?;
+        if Self::upgrade_authority(program_data, program_id)? != Some(*deployer.key) {
+            return Err(EscrowError::Unauthorized.into());
+        }
+        let (config_pda, bump_seed) = find_config_address(program_id);
+        if *config_account.key != config_pda {
+            return Err(ProgramError::InvalidSeeds);
+        }
+        if config_account.data_len() != 0 {
+            return Err(ProgramError::AccountAlreadyInitialized);
+        }
+        Self::assert_config_fees(&args)?;
+
+        log_debug!("Calling system program to create the config...");
+        Self::create_pda_account(
+            config_account,
+            deployer,
+            system_program,
+            Config::LEN,
+            &[CONFIG_SEED, &[bump_seed]],
+            program_id,
+        )?;
+
+        let config = Config {
+            is_initialized: true,
+            admin: args.admin,
+            fee_bps: args.fee_bps,
+            paused: args.paused,
+            treasury: args.treasury,
+            init_fee: args.init_fee,
+            pending_admin: Pubkey::default(),
+            fee_overrides: args.fee_overrides,
+            allowed_relayer: args.allowed_relayer,
+            address_cooldown_slots: args.address_cooldown_slots,
+            secondary_treasury: args.secondary_treasury,
+            secondary_treasury_bps: args.secondary_treasury_bps,
+        };
+        Config::pack(config, &mut config_account.try_borrow_mut_data()?)?;
+
+        Ok(())
+    }
+
+    /// Checks every fee of `args` and the secondary treasury's share is at most 100% and that the
+    /// overrides fit in the config, with no mint overridden twice.
+    fn assert_config_fees(args: &ConfigArgs) -> ProgramResult {
+        if args.fee_bps > 10_000 || args.secondary_treasury_bps > 10_000 {
+            return Err(ProgramError::InvalidArgument);
+        }
+        if args.fee_overrides.len() > MAX_FEE_OVERRIDES {
+            msg!(
+                "{} fee overrides, at most {} allowed",
+                args.fee_overrides.len(),
+                MAX_FEE_OVERRIDES
+            );
+            return Err(EscrowError::ListTooLong.into());
+        }
+        for (index, fee_override) in args.fee_overrides.iter().enumerate() {
+            if fee_override.fee_bps > 10_000 {
+                return Err(ProgramError::InvalidArgument);
+            }
+            if args.fee_overrides[..index]
+                .iter()
+                .any(|other| other.mint == fee_override.mint)
+            {
+                msg!("Fee of {} overridden twice", fee_override.mint);
+                return Err(ProgramError::InvalidArgument);
+            }
+        }
+        Ok(())
+    }
+
+    fn process_init_audit_log(accounts: &[AccountInfo], program_id: &Pubkey) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let admin = next_named_account_info(account_info_iter, "admin")?;
+        let audit_log_account = next_named_account_info(account_info_iter, "audit_log_account")?;
+        let system_program = next_named_account_info(account_info_iter, "system_program")?;
+        let config_account = next_named_account_info(account_info_iter, "config_account")?;
+
+        require_signer(admin, "admin")?;
+        let config = Self::load_config(config_account, program_id)?;
+        if *admin.key != config.admin {
+            return Err(EscrowError::Unauthorized.into());
+        }
+        let (audit_log_pda, bump_seed) = find_audit_log_address(program_id);
+        if *audit_log_account.key != audit_log_pda {
+            return Err(ProgramError::InvalidSeeds);
+        }
+        if audit_log_account.data_len() != 0 {
+            return Err(ProgramError::AccountAlreadyInitialized);
+        }
+
+        // Allocated zeroed, which is an empty log.
+        log_debug!("Calling system program to create the audit log...");
+        Self::create_pda_account(
+            audit_log_account,
+            admin,
+            system_program,
+            AuditLog::LEN,
+            &[AUDIT_LOG_SEED, &[bump_seed]],
+            program_id,
+        )
+    }
+
+    fn process_cache_authority(accounts: &[AccountInfo], program_id: &Pubkey) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let payer = next_named_account_info(account_info_iter, "payer")?;
+        let cache_account = next_named_account_info(account_info_iter, "cache_account")?;
+        let system_program = next_named_account_info(account_info_iter, "system_program")?;
+
+        require_signer(payer, "payer")?;
+        let (cache_pda, cache_bump_seed) = find_authority_cache_address(program_id);
+        if *cache_account.key != cache_pda {
+            return Err(ProgramError::InvalidSeeds);
+        }
+        if cache_account.data_len() != 0 {
+            return Err(ProgramError::AccountAlreadyInitialized);
+        }
+
+        log_debug!("Calling system program to create the authority cache...");
+        Self::create_pda_account(
+            cache_account,
+            payer,
+            system_program,
+            AuthorityCache::LEN,
+            &[AUTHORITY_CACHE_SEED, &[cache_bump_seed]],
+            program_id,
+        )?;
+
+        let (authority, bump_seed) = find_escrow_authority_address(program_id);
+        let cache = AuthorityCache {
+            is_initialized: true,
+            authority,
+            bump_seed,
+        };
+        AuthorityCache::pack(cache, &mut cache_account.try_borrow_mut_data()?)?;
+
+        Ok(())
+    }
+
+    fn process_update_config(
+        accounts: &[AccountInfo],
+        args: ConfigArgs,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let admin = next_named_account_info(account_info_iter, "admin")?;
+        let config_account = next_named_account_info(account_info_iter, "config_account")?;
+
+        require_signer(admin, "admin")?;
+        let mut config = Self::load_config(config_account, program_id)?;
+        if *admin.key != config.admin {
+            return Err(EscrowError::Unauthorized.into());
+        }
+        Self::assert_config_fees(&args)?;
+        if args.admin != config.admin {
+            msg!("The admin can only be changed with ProposeAdmin");
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        config.fee_bps = args.fee_bps;
+        config.fee_overrides = args.fee_overrides;
+        config.allowed_relayer = args.allowed_relayer;
+        config.address_cooldown_slots = args.address_cooldown_slots;
+        config.secondary_treasury = args.secondary_treasury;
+        config.secondary_treasury_bps = args.secondary_treasury_bps;
+        config.paused = args.paused;
+        config.treasury = args.treasury;
+        config.init_fee = args.init_fee;
+        Config::pack(config, &mut config_account.try_borrow_mut_data()?)?;
+
+        Ok(())
+    }
+
+    fn process_propose_admin(
+        accounts: &[AccountInfo],
+        new_admin: Pubkey,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let admin = next_named_account_info(account_info_iter, "admin")?;
+        let config_account = next_named_account_info(account_info_iter, "config_account")?;
+
+        require_signer(admin, "admin")?;
+        let mut config = Self::load_config(config_account, program_id)?;
+        if *admin.key != config.admin {
+            return Err(EscrowError::Unauthorized.into());
+        }
+
+        log_info!("Proposing {} as admin", new_admin);
+        config.pending_admin = new_admin;
+        Config::pack(config, &mut config_account.try_borrow_mut_data()?)?;
+
+        Ok(())
+    }
+
+    fn process_accept_admin(accounts: &[AccountInfo], program_id: &Pubkey) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let new_admin = next_named_account_info(account_info_iter, "new_admin")?;
+        let config_account = next_named_account_info(account_info_iter, "config_account")?;
+
+        require_signer(new_admin, "new_admin")?;
+        let mut config = Self::load_config(config_account, program_id)?;
+        // Nobody is pending as the default key, since no one can sign for it.
+        if *new_admin.key != config.pending_admin {
+            return Err(EscrowError::Unauthorized.into());
+        }
+
+        config.admin = config.pending_admin;
+        config.pending_admin = Pubkey::default();
+        Config::pack(config, &mut config_account.try_borrow_mut_data()?)?;
+
+        Ok(())
+    }
+
+    /// Unpacks an escrow about to be filled, cancelled or otherwise changed, failing with
+    /// `UninitializedAccount` (and saying so) on one that was never initialized.
+    fn load_escrow(escrow_account: &AccountInfo) -> Result<Escrow, ProgramError> {
+        match Escrow::unpack(&escrow_account.try_borrow_data()?) {
+            Err(ProgramError::UninitializedAccount) => {
+                msg!("Escrow {} is not initialized", escrow_account.key);
+                Err(ProgramError::UninitializedAccount)
+            }
+            Ok(escrow) if escrow.status == EscrowStatus::Closed => {
+                msg!("Escrow {} is closed", escrow_account.key);
+                Err(EscrowError::EscrowClosed.into())
+            }
+            result => result,
+        }
+    }
+
+    /// Reads the upgrade authority of the program from `program_data`, checking it's the program's
+    /// ProgramData account. `None` once the program was made immutable.
+    fn upgrade_authority(
+        program_data: &AccountInfo,
+        program_id: &Pubkey,
+    ) -> Result<Option<Pubkey>, ProgramError> {
+        let (program_data_address, _) =
+            Pubkey::find_program_address(&[program_id.as_ref()], &bpf_loader_upgradeable::id());
+        if *program_data.key != program_data_address {
+            return Err(ProgramError::InvalidSeeds);
+        }
+        if *program_data.owner != bpf_loader_upgradeable::id() {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        // `UpgradeableLoaderState::ProgramData` as bincode lays it out: the variant index, the
+        // slot it was last deployed in and the optional authority.
+        let data = program_data.try_borrow_data()?;
+        let header = data
+            .get(..PROGRAM_DATA_HEADER_LEN)
+            .ok_or(ProgramError::InvalidAccountData)?;
+        if header[..4] != PROGRAM_DATA_VARIANT.to_le_bytes() {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        match header[12] {
+            0 => Ok(None),
+            1 => Ok(Some(Pubkey::new(&header[13..]))),
+            _ => Err(ProgramError::InvalidAccountData),
+        }
+    }
+
+    /// Unpacks the program's config, checking `config_account` is its PDA.
+    fn load_config(
+        config_account: &AccountInfo,
+        program_id: &Pubkey,
+    ) -> Result<Config, ProgramError> {
+        if *config_account.key != find_config_address(program_id).0 {
+            return Err(ProgramError::InvalidSeeds);
+        }
+        if config_account.owner != program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        Config::unpack(&config_account.try_borrow_data()?)
+    }
+
+    fn process_verify(
+        accounts: &[AccountInfo],
+        repair: bool,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let escrow_account = next_named_account_info(account_info_iter, "escrow_account")?;
+        let temp_token_account = next_named_account_info(account_info_iter, "temp_token_account")?;
+        let config_account = next_named_account_info(account_info_iter, "config_account")?;
+
+        if escrow_account.owner != program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let mut escrow = Self::load_escrow(escrow_account)?;
+        if *temp_token_account.key != escrow.temp_token_account_pubkey {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let (pda, bump_seed) = find_escrow_authority_address(program_id);
+        let mut mismatches = 0;
+        if escrow.bump_seed != bump_seed {
+            msg!("Stored bump {} should be {}", escrow.bump_seed, bump_seed);
+            mismatches |= VERIFY_BUMP_MISMATCH;
+        }
+        if escrow.version != PROGRAM_VERSION {
+            msg!(
+                "Stored version {} should be {}",
+                escrow.version,
+                PROGRAM_VERSION
+            );
+            mismatches |= VERIFY_VERSION_MISMATCH;
+        }
+        if assert_token_account(temp_token_account, None, Some(&pda)).is_err() {
+            msg!("Temp token account isn't owned by the escrow authority");
+            mismatches |= VERIFY_TEMP_OWNER_MISMATCH;
+        }
+
+        if repair {
+            let admin = next_named_account_info(account_info_iter, "admin")?;
+            require_signer(admin, "admin")?;
+            let config = Self::load_config(config_account, program_id)?;
+            if *admin.key != config.admin {
+                return Err(EscrowError::Unauthorized.into());
+            }
+
+            if mismatches & (VERIFY_BUMP_MISMATCH | VERIFY_VERSION_MISMATCH) != 0 {
+                log_debug!("Rewriting bump and version...");
+                escrow.bump_seed = bump_seed;
+                escrow.version = PROGRAM_VERSION;
+                Escrow::pack(escrow, &mut escrow_account.try_borrow_mut_data()?)?;
+            }
+        }
+
+        set_return_data(&[mismatches]);
+        Ok(())
+    }
+
+    #[cfg(feature = "test-harness")]
+    fn process_set_escrow_state_for_testing(
+        accounts: &[AccountInfo],
+        escrow: Escrow,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let escrow_account = next_named_account_info(account_info_iter, "escrow_account")?;
+        if escrow_account.owner != program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        Escrow::pack(escrow, &mut escrow_account.try_borrow_mut_data()?)
+    }
+
+    fn process_get_version() -> ProgramResult {
+        msg!("Program version: {}", PROGRAM_VERSION);
+        set_return_data(&PROGRAM_VERSION.to_le_bytes());
+        Ok(())
+    }
+
+    /// What an escrow stores for `reference`: the reference itself, or the hash of it as a
+    /// preimage.
+    fn reference_commitment(
+        reference: &[u8],
+        reference_mode: ReferenceMode,
+    ) -> Result<[u8; 32], ProgramError> {
+        match reference_mode {
+            ReferenceMode::Plain => {
+                let mut stored = [0; 32];
+                stored
+                    .get_mut(..reference.len())
+                    .ok_or(ProgramError::InvalidArgument)?
+                    .copy_from_slice(reference);
+                Ok(stored)
+            }
+            ReferenceMode::Hashed | ReferenceMode::HashedRevealOnFill => {
+                Ok(keccak::hash(reference).to_bytes())
+            }
+        }
+    }
+
+    /// Lists an escrow stores have room for `MAX_LIST_LEN` entries.
+    fn assert_list_len(len: usize, name: &str) -> ProgramResult {
+        if len > MAX_LIST_LEN {
+            msg!("{} {} is more than the {} allowed", len, name, MAX_LIST_LEN);
+            return Err(EscrowError::ListTooLong.into());
+        }
+        Ok(())
+    }
+
+    fn assert_min_expected_amount(amount: u64, min_expected_amount: u64) -> ProgramResult {
+        if amount < min_expected_amount {
+            msg!(
+                "Expected amount {} is below the minimum of {}",
+                amount,
+                min_expected_amount
+            );
+            return Err(EscrowError::AmountTooSmall.into());
+        }
+        Ok(())
+    }
+
+    /// Transfers `fee` lamports from the initializer to the treasury, as long as the initializer
+    /// stays rent-exempt afterwards.
+    fn collect_init_fee<'a>(
+        initializer: &AccountInfo<'a>,
+        treasury: &AccountInfo<'a>,
+        system_program: &AccountInfo<'a>,
+        fee: u64,
+        expected_treasury: &Pubkey,
+        rent: &Rent,
+    ) -> ProgramResult {
+        if fee == 0 {
+            return Ok(());
+        }
+        if treasury.key != expected_treasury {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let spendable = initializer
+            .lamports()
+            .saturating_sub(rent.minimum_balance(initializer.data_len()));
+        if spendable < fee {
+            msg!("Initializer can't cover the {} lamport init fee", fee);
+            return Err(EscrowError::InsufficientFunds.into());
+        }
+
+        log_debug!("Calling system program to pay the init fee...");
+        invoke(
+            &system_instruction::transfer(initializer.key, treasury.key, fee),
+            &[
+                initializer.clone(),
+                treasury.clone(),
+                system_program.clone(),
+            ],
+        )
+    }
+
+    /// Moves the initializer's `stake` into the escrow account, on top of its rent.
+    fn collect_stake<'a>(
+        initializer: &AccountInfo<'a>,
+        escrow_account: &AccountInfo<'a>,
+        system_program: &AccountInfo<'a>,
+        stake: u64,
+    ) -> ProgramResult {
+        if stake == 0 {
+            return Ok(());
+        }
+        log_debug!("Calling system program to stake {} lamports...", stake);
+        invoke(
+            &system_instruction::transfer(initializer.key, escrow_account.key, stake),
+            &[
+                initializer.clone(),
+                escrow_account.clone(),
+                system_program.clone(),
+            ],
+        )
+    }
+
+    /// The config's treasury a `ReclaimExpiredBatch` forfeits stakes to, passed at `index` right
+    /// after the config PDA.
+    fn stake_treasury<'a, 'b>(
+        accounts: &'a [AccountInfo<'b>],
+        index: usize,
+        program_id: &Pubkey,
+    ) -> Result<&'a AccountInfo<'b>, ProgramError> {
+        let config_account = named_account_at(accounts, index, "config_account")?;
+        let treasury = named_account_at(accounts, index + 1, "treasury")?;
+        let config = Self::load_config(config_account, program_id)?;
+        if *treasury.key != config.treasury {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        Ok(treasury)
+    }
+
+    /// Appends `escrow` to the initializer's escrow index, creating or growing the index account as
+    /// needed (paid for by the initializer). Refuses an escrow at the address the initializer last
+    /// closed until `cooldown_slots` have passed since, as the runtime can trip over an account
+    /// recreated right after being closed.
+    fn add_to_escrow_index<'a>(
+        escrow_index_account: &AccountInfo<'a>,
+        initializer: &AccountInfo<'a>,
+        system_program: &AccountInfo<'a>,
+        escrow: &Pubkey,
+        cooldown_slots: u64,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let (escrow_index_pda, bump_seed) = find_escrow_index_address(program_id, initializer.key);
+        if *escrow_index_account.key != escrow_index_pda {
+            return Err(ProgramError::InvalidSeeds);
+        }
+        let signer_seeds: &[&[u8]] = &[ESCROW_INDEX_SEED, initializer.key.as_ref(), &[bump_seed]];
+
+        let is_new = escrow_index_account.data_len() == 0;
+        let mut escrow_index = if is_new {
+            EscrowIndex::default()
+        } else {
+            if escrow_index_account.owner != program_id {
+                return Err(ProgramError::IncorrectProgramId);
+            }
+            EscrowIndex::unpack(&escrow_index_account.try_borrow_data()?)?
+        };
+        if cooldown_slots > 0
+            && escrow_index.last_removed == *escrow
+            && Self::current_slot()?
+                < escrow_index
+                    .last_removed_slot
+                    .saturating_add(cooldown_slots)
+        {
+            msg!(
+                "Escrow {} can be reused from slot {}",
+                escrow,
+                escrow_index
+                    .last_removed_slot
+                    .saturating_add(cooldown_slots)
+            );
+            return Err(EscrowError::AddressCooldown.into());
+        }
+        escrow_index.escrows.push(*escrow);
+        let new_len = EscrowIndex::packed_len(escrow_index.escrows.len());
+
+        if is_new {
+            log_debug!("Calling system program to create the escrow index...");
+            Self::create_pda_account(
+                escrow_index_account,
+                initializer,
+                system_program,
+                new_len,
+                signer_seeds,
+                program_id,
+            )?;
+        } else {
+            Self::top_up_rent(escrow_index_account, initializer, system_program, new_len)?;
+            escrow_index_account.realloc(new_len, false)?;
+        }
+
+        escrow_index.pack_into_slice(&mut escrow_index_account.try_borrow_mut_data()?);
+        Ok(())
+    }
+
+    /// Creates a `len` byte account owned by `owner` at the PDA `account` signs for with
+    /// `signer_seeds`, with `payer` funding its rent. Same as create_account, but still works if
+    /// someone already sent lamports to the PDA.
+    fn create_pda_account<'a>(
+        account: &AccountInfo<'a>,
+        payer: &AccountInfo<'a>,
+        system_program: &AccountInfo<'a>,
+        len: usize,
+        signer_seeds: &[&[u8]],
+        owner: &Pubkey,
+    ) -> ProgramResult {
+        Self::top_up_rent(account, payer, system_program, len)?;
+        invoke_signed(
+            &system_instruction::allocate(account.key, len as u64),
+            &[account.clone(), system_program.clone()],
+            &[signer_seeds],
+        )?;
+        invoke_signed(
+            &system_instruction::assign(account.key, owner),
+            &[account.clone(), system_program.clone()],
+            &[signer_seeds],
+        )
+    }
+
+    /// Has `payer` send `account` whatever it's missing to be rent-exempt at `len` bytes.
+    fn top_up_rent<'a>(
+        account: &AccountInfo<'a>,
+        payer: &AccountInfo<'a>,
+        system_program: &AccountInfo<'a>,
+        len: usize,
+    ) -> ProgramResult {
+        let top_up = Self::rent()
+            .minimum_balance(len)
+            .saturating_sub(account.lamports());
+        if top_up > 0 {
+            invoke(
+                &system_instruction::transfer(payer.key, account.key, top_up),
+                &[payer.clone(), account.clone(), system_program.clone()],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Removes `escrow` from the initializer's escrow index, shrinking the index account and
+    /// refunding the rent it no longer needs to the initializer.
+    fn remove_from_escrow_index(
+        escrow_index_account: &AccountInfo,
+        initializer: &AccountInfo,
+        escrow: &Pubkey,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let (escrow_index_pda, _bump_seed) = find_escrow_index_address(program_id, initializer.key);
+        if *escrow_index_account.key != escrow_index_pda {
+            return Err(ProgramError::InvalidSeeds);
+        }
+        if escrow_index_account.owner != program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        let mut escrow_index = EscrowIndex::unpack(&escrow_index_account.try_borrow_data()?)?;
+        let position = match escrow_index.escrows.iter().position(|key| key == escrow) {
+            Some(position) => position,
+            None => {
+                msg!("Escrow {} is not in the escrow index", escrow);
+                return Ok(());
+            }
+        };
+        escrow_index.escrows.remove(position);
+        escrow_index.last_removed = *escrow;
+        escrow_index.last_removed_slot = Self::current_slot()?;
+        let new_len = EscrowIndex::packed_len(escrow_index.escrows.len());
+
+        escrow_index.pack_into_slice(&mut escrow_index_account.try_borrow_mut_data()?);
+        escrow_index_account.realloc(new_len, false)?;
+
+        let refund = escrow_index_account
+            .lamports()
+            .saturating_sub(Self::rent().minimum_balance(new_len));
+        Self::credit_lamports(initializer, refund)?;
+        **escrow_index_account.lamports.borrow_mut() = escrow_index_account
+            .lamports()
+            .checked_sub(refund)
+            .ok_or(EscrowError::Overflow)?;
+
+        Ok(())
+    }
+
+    /// Reads the current slot via the sysvar syscall, failing with a clear error rather than a
+    /// bare `UnsupportedSysvar` when that's unavailable.
+    fn current_slot() -> Result<u64, ProgramError> {
+        match Clock::get() {
+            Ok(clock) => Ok(clock.slot),
+            Err(err) => {
+                msg!("Clock sysvar unavailable: {}", err);
+                Err(EscrowError::ClockUnavailable.into())
+            }
+        }
+    }
+
+    /// Reads the live rent via the sysvar syscall. Only when that's unavailable (e.g. when running
+    /// natively without a runtime) do we fall back to `Rent::default()`, which matches mainnet's
+    /// parameters today but would make rent-exemption checks wrong on a cluster configured
+    /// differently.
+    fn rent() -> Rent {
+        Rent::get().unwrap_or_else(|_| {
+            log_info!("Rent sysvar unavailable, falling back to default rent");
+            Rent::default()
+        })
+    }
+
+    /// The signed CPIs only ever sign for `pda`, so make sure the account passed along is actually
+    /// it rather than letting the token program fail with a less helpful error. Nothing writes to
+    /// it, so it's documented read-only and a writable one means a hand-crafted transaction.
+    fn assert_pda_account(pda_account: &AccountInfo, pda: &Pubkey) -> ProgramResult {
+        if pda_account.key != pda {
+            msg!("PDA account {} does not match {}", pda_account.key, pda);
+            return Err(EscrowError::InvalidPdaAccount.into());
+        }
+        if pda_account.is_writable {
+            msg!("PDA account passed as writable");
+            return Err(ProgramError::InvalidAccountData);
+        }
+        Ok(())
+    }
+
+    /// Closes the drained temp token account owned by the PDA, sending its rent to `destination`.
+    ///
+    /// The token program only refuses to close token accounts still holding tokens when they're
+    /// not wrapped SOL, which it would silently unwrap to `destination` instead, so every caller
+    /// moving the tokens out first is checked here rather than trusted, unless it closes with
+    /// `unwrap` to pay out what's left of a wrapped SOL deposit that way. The destination's credit
+    /// is checked against all of the temp's lamports.
+    #[allow(clippy::too_many_arguments)]
+    fn close_temp_token_account<'a>(
+        token_program: &AccountInfo<'a>,
+        temp_token_account: &AccountInfo<'a>,
+        destination: &AccountInfo<'a>,
+        pda_account: &AccountInfo<'a>,
+        pda: &Pubkey,
+        bump_seed: u8,
+        unwrap: bool,
+    ) -> ProgramResult {
+        Self::assert_pda_account(pda_account, pda)?;
+
+        let temp_token_account_info = assert_token_account(temp_token_account, None, None)?;
+        let unwraps = unwrap && temp_token_account_info.is_native();
+        if unwraps {
+            // Reported apart so the initializer can tell their deposit back from the rent; any
+            // lamports sent to the account without a sync count as rent here.
+            log_info!(
+                "Unwrapping {} lamports of deposit and {} of rent to {}",
+                temp_token_account_info.amount,
+                temp_token_account
+                    .lamports()
+                    .saturating_sub(temp_token_account_info.amount),
+                destination.key
+            );
+        }
+        if temp_token_account_info.amount != 0 && !unwraps {
+            msg!(
+                "Temp account still holds {} tokens",
+                temp_token_account_info.amount
+            );
+            return Err(EscrowError::EscrowNotEmpty.into());
+        }
+        let expected_destination_lamports = destination
+            .lamports()
+            .checked_add(temp_token_account.lamports())
+            .ok_or(EscrowError::Overflow)?;
+
+        let close_account_ix = spl_token::instruction::close_account(
+            token_program.key,
+            temp_token_account.key,
+            destination.key,
+            pda,
+            &[pda],
+        )?;
+        log_debug!("Calling token program to close pda's temp account...");
+        invoke_signed(
+            &close_account_ix,
+            &[
+                temp_token_account.clone(),
+                destination.clone(),
+                pda_account.clone(),
+                // NB: this is not necessary it seems.
+                // token_program.clone(),
+            ],
+            &[&escrow_signer_seeds(&bump_seed)],
+        )?;
+
+        if destination.lamports() != expected_destination_lamports {
+            msg!("Temp account close credited an unexpected amount");
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        Ok(())
+    }
+
+    /// Closes the escrow account, sending its `stake` to `stake_destination` and its rent (all
+    /// other lamports) to `destination`, and reports `reason` in the logs and as return data (one
+    /// byte).
+    fn close_escrow_account(
+        escrow_account: &AccountInfo,
+        destination: &AccountInfo,
+        stake: u64,
+        stake_destination: &AccountInfo,
+        reason: CloseReason,
+    ) -> ProgramResult {
+        log_debug!("Closing the escrow account...");
+        let rent = escrow_account
+            .lamports()
+            .checked_sub(stake)
+            .ok_or(EscrowError::InsufficientFunds)?;
+        if stake > 0 {
+            Self::credit_lamports(stake_destination, stake)?;
+        }
+        Self::credit_lamports(destination, rent)?;
+        **escrow_account.lamports.borrow_mut() = 0;
+        // Should a later instruction of this transaction fund the account again, it must not be
+        // read as the live escrow it was, so it's handed back to the system program empty.
+        escrow_account.realloc(0, false)?;
+        escrow_account.assign(&system_program::id());
+        log_info!("Escrow {} closed: {:?}", escrow_account.key, reason);
+        set_return_data(&[reason as u8]);
+        Ok(())
+    }
+
+    /// Where the escrow account's rent goes when it closes: its rent beneficiary if it has one,
+    /// which must then be among `accounts`, the initializer otherwise.
+    fn rent_destination<'a, 'b>(
+        accounts: &'a [AccountInfo<'b>],
+        escrow: &Escrow,
+        initializer: &'a AccountInfo<'b>,
+    ) -> Result<&'a AccountInfo<'b>, ProgramError> {
+        match escrow.rent_beneficiary {
+            COption::Some(rent_beneficiary) => accounts
+                .iter()
+                .find(|info| *info.key == rent_beneficiary)
+                .ok_or_else(|| {
+                    msg!("Missing the escrow's rent beneficiary {}", rent_beneficiary);
+                    EscrowError::NotEnoughAccounts.into()
+                }),
+            COption::None => Ok(initializer),
+        }
+    }
+
+    /// Moves `amount` lamports from `source`, an account of this program, to `destination`.
+    fn move_lamports(
+        source: &AccountInfo,
+        destination: &AccountInfo,
+        amount: u64,
+    ) -> ProgramResult {
+        **source.lamports.borrow_mut() = source
+            .lamports()
+            .checked_sub(amount)
+            .ok_or(EscrowError::InsufficientFunds)?;
+        Self::credit_lamports(destination, amount)
+    }
+
+    /// Adds `amount` lamports to `destination` for a rent refund.
+    ///
+    /// Total supply is far below `u64::MAX`, so this can't overflow on a real cluster. If it ever
+    /// did, the whole transaction reverts, taker transfers included, and the escrow stays open;
+    /// refunding only part of the rent would instead strand lamports in a closed account, so we
+    /// still fail, but log why so the revert isn't a mystery.
+    fn credit_lamports(destination: &AccountInfo, amount: u64) -> ProgramResult {
+        let balance = destination.lamports();
+        match balance.checked_add(amount) {
+            Some(total) => {
+                **destination.lamports.borrow_mut() = total;
+                Ok(())
+            }
+            None => {
+                msg!(
+                    "Refunding {} lamports to {} would overflow its balance of {}",
+                    amount,
+                    destination.key,
+                    balance
+                );
+                Err(EscrowError::Overflow.into())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        solana_program::program_stubs::{self, SyscallStubs},
+        std::{cell::RefCell, sync::Once},
+    };
+
+    thread_local! {
+        /// What the program last set as return data on this test's thread.
+        // Older clippy mistakes the `const` initializer for a `const` item.
+        #[allow(clippy::declare_interior_mutable_const)]
+        static RETURN_DATA: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+    }
+
+    struct CaptureReturnData;
+
+    impl SyscallStubs for CaptureReturnData {
+        fn sol_set_return_data(&mut self, data: &[u8]) {
+            RETURN_DATA.with(|return_data| *return_data.borrow_mut() = data.to_vec());
+        }
+    }
+
+    /// Processes `data` against `accounts` and returns the return data it set. The stubs are
+    /// process wide, so they're installed once and keep the data per thread, i.e. per test.
+    fn process_returning(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        data: &[u8],
+    ) -> Result<Vec<u8>, ProgramError> {
+        static STUBS: Once = Once::new();
+        STUBS.call_once(|| {
+            program_stubs::set_syscall_stubs(Box::new(CaptureReturnData));
+        });
+        RETURN_DATA.with(|return_data| return_data.borrow_mut().clear());
+        Processor::process(program_id, accounts, data)?;
+        Ok(RETURN_DATA.with(|return_data| return_data.borrow().clone()))
+    }
+
+    #[test]
+    fn get_version_returns_program_version() {
+        let program_id = Pubkey::new_unique();
+        let ix = instruction::get_version(&program_id).unwrap();
+        assert_eq!(
+            process_returning(&program_id, &[], &ix.data).unwrap(),
+            PROGRAM_VERSION.to_le_bytes()
+        );
+    }
+
+    fn check_eligibility(
+        program_id: &Pubkey,
+        escrow_key: &Pubkey,
+        data: &mut [u8],
+        taker: &Pubkey,
+        taker_proof: Vec<[u8; 32]>,
+    ) -> Vec<u8> {
+        let ix =
+            instruction::check_eligibility(program_id, escrow_key, taker, taker_proof).unwrap();
+        let mut lamports = 0;
+        let escrow_account = AccountInfo::new(
+            escrow_key,
+            false,
+            false,
+            &mut lamports,
+            data,
+            program_id,
+            false,
+            0,
+        );
+        process_returning(program_id, &[escrow_account], &ix.data).unwrap()
+    }
+
+    #[test]
+    fn check_eligibility_reports_allow_list_membership() {
+        let program_id = Pubkey::new_unique();
+        let escrow_key = Pubkey::new_unique();
+        let allowed = Pubkey::new_unique();
+        let other_allowed = Pubkey::new_unique();
+
+        // Allow-list of two takers: the root hashes both leaves, the smaller one first.
+        let leaf = keccak::hash(allowed.as_ref()).to_bytes();
+        let other_leaf = keccak::hash(other_allowed.as_ref()).to_bytes();
+        let mut escrow = Escrow::unpack_unchecked(&[0; Escrow::LEN]).unwrap();
+        escrow.status = EscrowStatus::Open;
+        escrow.taker_merkle_root =
+            keccak::hashv(&[&leaf.min(other_leaf), &leaf.max(other_leaf)]).to_bytes();
+        let mut data = vec![0; Escrow::LEN];
+        Escrow::pack(escrow, &mut data).unwrap();
+
+        assert_eq!(
+            check_eligibility(
+                &program_id,
+                &escrow_key,
+                &mut data,
+                &allowed,
+                vec![other_leaf]
+            ),
+            [1]
+        );
+        // Someone else can't borrow an allowed taker's proof.
+        assert_eq!(
+            check_eligibility(
+                &program_id,
+                &escrow_key,
+                &mut data,
+                &Pubkey::new_unique(),
+                vec![other_leaf]
+            ),
+            [0]
+        );
+        assert_eq!(
+            check_eligibility(&program_id, &escrow_key, &mut data, &allowed, vec![]),
+            [0]
+        );
+    }
+
+    /// Has `admin_key` sweep an account of `program_id` holding `dust` lamports above rent
+    /// exemption and `escrow`'s data, under a config whose admin is `config_admin`, and returns
+    /// how many lamports the treasury got.
+    fn sweep_dust(
+        program_id: &Pubkey,
+        admin_key: Pubkey,
+        config_admin: Pubkey,
+        escrow: Escrow,
+        dust: u64,
+    ) -> Result<u64, ProgramError> {
+        let dust_key = Pubkey::new_unique();
+        let treasury_key = Pubkey::new_unique();
+        let system_program_key = system_program::id();
+        let config_key = find_config_address(program_id).0;
+        let mut data = vec![0; Escrow::LEN];
+        Escrow::pack(escrow, &mut data).unwrap();
+        let mut config = Config::unpack_unchecked(&[0; Config::LEN]).unwrap();
+        config.is_initialized = true;
+        config.admin = config_admin;
+        config.treasury = treasury_key;
+        let mut config_data = vec![0; Config::LEN];
+        Config::pack(config, &mut config_data).unwrap();
+        let (mut admin_lamports, mut treasury_lamports, mut system_program_lamports) = (0, 0, 0);
+        let mut config_lamports = 0;
+        let mut dust_lamports = Rent::default().minimum_balance(Escrow::LEN) + dust;
+        let accounts = [
+            AccountInfo::new(
+                &admin_key,
+                true,
+                false,
+                &mut admin_lamports,
+                &mut [],
+                &system_program_key,
+                false,
+                0,
+            ),
+            AccountInfo::new(
+                &dust_key,
+                false,
+                true,
+                &mut dust_lamports,
+                &mut data,
+                program_id,
+                false,
+                0,
+            ),
+            AccountInfo::new(
+                &treasury_key,
+                false,
+                true,
+                &mut treasury_lamports,
+                &mut [],
+                &system_program_key,
+                false,
+                0,
+            ),
+            AccountInfo::new(
+                &system_program_key,
+                false,
+                false,
+                &mut system_program_lamports,
+                &mut [],
+                &system_program_key,
+                true,
+                0,
+            ),
+            AccountInfo::new(
+                &config_key,
+                false,
+                false,
+                &mut config_lamports,
+                &mut config_data,
+                program_id,
+                false,
+                0,
+            ),
+        ];
+        let ix = instruction::sweep_dust(program_id, &admin_key, &dust_key, &treasury_key).unwrap();
+        Processor::process(program_id, &accounts, &ix.data)?;
+        Ok(accounts[2].lamports())
+    }
+
+    #[test]
+    fn sweep_dust_rejects_active_escrow() {
+        let admin = Pubkey::new_unique();
+        let mut escrow = Escrow::unpack_unchecked(&[0; Escrow::LEN]).unwrap();
+        escrow.status = EscrowStatus::Open;
+        assert_eq!(
+            sweep_dust(&Pubkey::new_unique(), admin, admin, escrow, 50).unwrap_err(),
+            EscrowError::ActiveEscrow.into()
+        );
+    }
+
+    #[test]
+    fn sweep_dust_sweeps_stale_account() {
+        let admin = Pubkey::new_unique();
+        let stale = Escrow::unpack_unchecked(&[0; Escrow::LEN]).unwrap();
+        assert_eq!(
+            sweep_dust(&Pubkey::new_unique(), admin, admin, stale, 50),
+            Ok(50)
+        );
+
+        let mut closed = Escrow::unpack_unchecked(&[0; Escrow::LEN]).unwrap();
+        closed.status = EscrowStatus::Closed;
+        assert_eq!(
+            sweep_dust(&Pubkey::new_unique(), admin, admin, closed, 50),
+            Ok(50)
+        );
+    }
+
+    #[test]
+    fn sweep_dust_requires_config_admin() {
+        let stale = Escrow::unpack_unchecked(&[0; Escrow::LEN]).unwrap();
+        assert_eq!(
+            sweep_dust(
+                &Pubkey::new_unique(),
+                Pubkey::new_unique(),
+                Pubkey::new_unique(),
+                stale,
+                50
+            )
+            .unwrap_err(),
+            EscrowError::Unauthorized.into()
+        );
+    }
 }
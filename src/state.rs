@@ -1,77 +1,1072 @@
 use arrayref::{array_mut_ref, array_ref, array_refs, mut_array_refs};
 use solana_program::{
+    keccak,
     program_error::ProgramError,
+    program_option::COption,
     program_pack::{IsInitialized, Pack, Sealed},
     pubkey::Pubkey,
 };
 
+use crate::{
+    math::{proportional_amount, Rounding},
+    SETTLEMENT_EXPIRY_SLOTS,
+};
+
+#[derive(Clone)]
 pub struct Escrow {
-    pub is_initialized: bool,
+    pub status: EscrowStatus,
     pub initializer_pubkey: Pubkey,
     pub temp_token_account_pubkey: Pubkey,
     pub initializer_dest_token_account_pubkey: Pubkey,
     pub expected_amount: u64,
+    /// Decimals of the mint `expected_amount` is denominated in.
+    pub expected_amount_decimals: u8,
+    /// Price feed the exchange price is checked against, if any.
+    pub oracle: COption<Pubkey>,
+    /// Max allowed deviation (in basis points) of the escrow's implied price from the oracle price.
+    pub max_deviation_bps: u16,
+    /// Max number of fills (partial or full) allowed, 0 for no limit.
+    pub max_fills: u8,
+    /// Number of fills so far.
+    pub fills_done: u8,
+    /// Who absorbs the rounding dust of partial fills.
+    pub rounding_mode: RoundingMode,
+    /// Taker of the prepared settlement, only meaningful while `status` is `Reserved`.
+    pub prepared_taker: Pubkey,
+    /// Slot the settlement was prepared in.
+    pub prepared_slot: u64,
+    /// Mints the initializer accepts payment in besides the dest token account's, 1:1 since
+    /// they must share its decimals. At most `MAX_ACCEPTED_MINTS`.
+    pub accepted_mints: Vec<Pubkey>,
+    /// Off-chain reference of the trade, or its keccak hash depending on `reference_mode`.
+    pub reference: [u8; 32],
+    pub reference_mode: ReferenceMode,
+    /// Bump of the escrow authority PDA, as derived at init.
+    pub bump_seed: u8,
+    /// `PROGRAM_VERSION` of the program that initialized the escrow.
+    pub version: u32,
+    /// First slot the escrow can be filled in, 0 if it can be right away.
+    pub start_slot: u64,
+    /// Share of the deposit (in basis points) a cancel sends to the treasury instead of refunding.
+    pub cancel_penalty_bps: u16,
+    /// First slot the escrow can no longer be filled in and anyone can reclaim it for the
+    /// initializer. 0 only on escrows initialized before expiries were defaulted, which never
+    /// expire.
+    pub expiry_slot: u64,
+    /// Mint of the deposit held in the temp token account.
+    pub deposit_mint: Pubkey,
+    /// Mint of the initializer's dest token account, the one `expected_amount` is paid in.
+    pub expected_mint: Pubkey,
+    /// First slot the initializer can cancel the escrow in, so takers get a window their fills
+    /// can't be cancelled out from under. 0 if it can be cancelled right away.
+    pub cancellable_slot: u64,
+    /// Kind of trade for clients to filter escrows by, e.g. 0 for generic, 1 for NFTs and 2 for
+    /// OTC deals. Means nothing to the program. Stored at `CATEGORY_OFFSET`.
+    pub category: u8,
+    /// Account the escrow account's rent goes to when it closes, e.g. a sponsor who funded it,
+    /// instead of the initializer. Only the initializer can set it, when signing the init.
+    pub rent_beneficiary: COption<Pubkey>,
+    /// Root of a merkle tree of the takers allowed to fill the escrow, all zeroes to allow
+    /// anyone. See `allows_taker`.
+    pub taker_merkle_root: [u8; 32],
+    /// Whether `initializer_dest_token_account_pubkey` is the initializer's associated token
+    /// account for `expected_mint`, which a full `Exchange` creates if it doesn't exist yet.
+    pub delivers_to_ata: bool,
+    /// Fewest deposited tokens a partial fill can take, except the one closing the escrow. 0 for
+    /// no minimum.
+    pub min_fill: u64,
+    /// Lamports the initializer staked at init on top of the escrow account's rent. They get them
+    /// back when the escrow closes in any way but expiring unfilled, which forfeits them to the
+    /// config's treasury. Splitting or merging the escrow moves them to the escrows it leaves.
+    pub stake_lamports: u64,
+    /// Key whose ed25519 signature over each fill's terms (see
+    /// `instruction::maker_approval_message`) the fill needs, verified by an ed25519 program
+    /// instruction right before it. For RFQ flows where the maker quotes takers off-chain.
+    pub maker: COption<Pubkey>,
+    /// Temp token accounts holding the rest of a basket deposit besides the one of
+    /// `temp_token_account_pubkey`, each of another mint and owned by the escrow authority. A full
+    /// fill pays out all of them for the one `expected_amount`, and a basket escrow can't be
+    /// filled any other way. At most `MAX_BASKET_LEN`.
+    pub basket: Vec<Pubkey>,
+    /// Fee (the secondary treasury's share included) quoted to the prepared settlement's taker,
+    /// which the finalize charges whatever the config says by then. Only meaningful while
+    /// `status` is `Reserved`, like the next two.
+    pub prepared_fee: u64,
+    /// Secondary treasury's share of `prepared_fee`.
+    pub prepared_secondary_fee: u64,
+    /// Most the prepared settlement's taker agreed to pay, fee included.
+    pub prepared_max_amount_in: u64,
+}
+
+impl Escrow {
+    pub const MAX_ACCEPTED_MINTS: usize = MAX_LIST_LEN;
+    pub const MAX_BASKET_LEN: usize = MAX_LIST_LEN;
+
+    /// Offset of `category` in the packed escrow, to filter `getProgramAccounts` on with a
+    /// memcmp. Fields are only ever appended, so it won't move.
+    pub const CATEGORY_OFFSET: usize = 412;
+
+    /// Whether the escrow can be filled at `slot`.
+    pub fn is_active(&self, slot: u64) -> bool {
+        slot >= self.start_slot
+    }
+
+    /// Whether `taker` may fill the escrow, proving with `proof` that it's a leaf of the tree
+    /// `taker_merkle_root` is the root of if there is one. Leaves are the keccak hash of the
+    /// taker's key and each node the keccak hash of its children, the smaller one first.
+    pub fn allows_taker(&self, taker: &Pubkey, proof: &[[u8; 32]]) -> bool {
+        if self.taker_merkle_root == [0; 32] {
+            return true;
+        }
+        let root = proof
+            .iter()
+            .fold(keccak::hash(taker.as_ref()).to_bytes(), |node, sibling| {
+                let (first, second) = if node <= *sibling {
+                    (&node, sibling)
+                } else {
+                    (sibling, &node)
+                };
+                keccak::hashv(&[first, second]).to_bytes()
+            });
+        root == self.taker_merkle_root
+    }
+
+    /// Whether the escrow has used up its max fills, so it can only be cancelled.
+    pub fn fills_exhausted(&self) -> bool {
+        self.max_fills != 0 && self.fills_done >= self.max_fills
+    }
+
+    /// Whether the initializer can cancel the escrow at `slot`.
+    pub fn is_cancellable(&self, slot: u64) -> bool {
+        slot >= self.cancellable_slot
+    }
+
+    /// Whether the escrow has expired at `slot`.
+    pub fn is_expired(&self, slot: u64) -> bool {
+        self.expiry_slot != 0 && slot >= self.expiry_slot
+    }
+
+    /// What the taker pays the initializer for `amount` of the escrow's `deposit`. The last fill
+    /// pays whatever is left, so the dust of earlier fills always settles on the side the
+    /// escrow's rounding mode picked.
+    pub fn fill_payment(&self, amount: u64, deposit: u64) -> Result<u64, ProgramError> {
+        if amount == deposit {
+            Ok(self.expected_amount)
+        } else {
+            proportional_amount(
+                amount,
+                self.expected_amount,
+                deposit,
+                self.rounding_mode.payment_rounding(),
+            )
+        }
+    }
+
+    /// Whether a prepared settlement still reserves the escrow at `slot`.
+    pub fn is_settlement_pending(&self, slot: u64) -> bool {
+        self.status == EscrowStatus::Reserved
+            && slot < self.prepared_slot.saturating_add(SETTLEMENT_EXPIRY_SLOTS)
+    }
+}
+
+/// Most entries any list an escrow stores (e.g. its accepted mints) can hold, so that
+/// `Escrow::LEN` and with it the escrow's rent stay fixed.
+pub const MAX_LIST_LEN: usize = 3;
+
+/// Packed length of a list of keys: a count byte followed by `MAX_LIST_LEN` key slots. The
+/// escrow layout spells it out, and the list (un)packing below only type checks if they agree.
+const KEY_LIST_LEN: usize = 1 + 32 * MAX_LIST_LEN;
+
+/// Longest reference preimage `InitEscrow` hashes.
+pub const MAX_REFERENCE_PREIMAGE_LEN: usize = 64;
+
+/// Most hashes a taker's allow-list proof can have, enough for a million takers.
+pub const MAX_TAKER_PROOF_LEN: usize = 20;
+
+/// How an escrow's `reference` is stored.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReferenceMode {
+    /// As given, zero-padded to 32 bytes.
+    Plain = 0,
+    /// Keccak hash of the given preimage, so only the commitment is on chain.
+    Hashed = 1,
+    /// Like `Hashed`, but fills must reveal the preimage.
+    HashedRevealOnFill = 2,
+}
+
+impl ReferenceMode {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(ReferenceMode::Plain),
+            1 => Some(ReferenceMode::Hashed),
+            2 => Some(ReferenceMode::HashedRevealOnFill),
+            _ => None,
+        }
+    }
+}
+
+/// Where an escrow is in its life, the first byte of its account. `Open` is 1 so escrows from
+/// before there was more to it than being initialized read as open.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EscrowStatus {
+    /// Account allocated for an escrow, not yet initialized.
+    Uninitialized = 0,
+    /// Initialized and never filled.
+    Open = 1,
+    /// Reserved for a taker by `PrepareSettlement`, until `FinalizeSettlement` or the
+    /// reservation lapses after `SETTLEMENT_EXPIRY_SLOTS`.
+    Reserved = 2,
+    /// Filled in part, the rest still open.
+    PartiallyFilled = 3,
+    /// Closed, only ever seen if the account is revived in the transaction that closed it.
+    Closed = 4,
+}
+
+impl EscrowStatus {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(EscrowStatus::Uninitialized),
+            1 => Some(EscrowStatus::Open),
+            2 => Some(EscrowStatus::Reserved),
+            3 => Some(EscrowStatus::PartiallyFilled),
+            4 => Some(EscrowStatus::Closed),
+            _ => None,
+        }
+    }
+}
+
+/// Why an escrow account was closed, reported when it is so indexers can tell closures apart.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CloseReason {
+    /// Filled in full by a taker.
+    Exchanged = 0,
+    /// Cancelled by the initializer.
+    Cancelled = 1,
+    /// Merged into another escrow by `MergeEscrows`.
+    Merged = 2,
+    /// Replaced by its children by `SplitEscrow`.
+    Split = 3,
+    /// Reclaimed for the initializer after expiring.
+    Expired = 4,
+}
+
+impl CloseReason {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(CloseReason::Exchanged),
+            1 => Some(CloseReason::Cancelled),
+            2 => Some(CloseReason::Merged),
+            3 => Some(CloseReason::Split),
+            4 => Some(CloseReason::Expired),
+            _ => None,
+        }
+    }
+}
+
+/// Which side of a partial fill gets the rounded-down share when its price isn't a whole amount.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RoundingMode {
+    /// The taker pays at least the exact price, the dust goes to the initializer.
+    FloorToTaker = 0,
+    /// The taker pays at most the exact price, the initializer gives up the dust.
+    FloorToInitializer = 1,
+}
+
+impl RoundingMode {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(RoundingMode::FloorToTaker),
+            1 => Some(RoundingMode::FloorToInitializer),
+            _ => None,
+        }
+    }
+
+    /// How to round the amount the taker pays for a partial fill.
+    pub fn payment_rounding(self) -> Rounding {
+        match self {
+            RoundingMode::FloorToTaker => Rounding::Up,
+            RoundingMode::FloorToInitializer => Rounding::Down,
+        }
+    }
 }
 
 impl Sealed for Escrow {}
 impl IsInitialized for Escrow {
     fn is_initialized(&self) -> bool {
-        self.is_initialized
+        self.status != EscrowStatus::Uninitialized
     }
 }
 
 impl Pack for Escrow {
-    const LEN: usize = 105;
+    const LEN: usize = 655;
 
     fn pack_into_slice(&self, dst: &mut [u8]) {
         let dst = array_mut_ref![dst, 0, Escrow::LEN];
         let (
-            is_initialized_dst,
+            status_dst,
             initializer_pubkey_dst,
             temp_token_account_pubkey_dst,
             initializer_dest_token_account_pubkey_dst,
             expected_amount_dst,
-        ) = mut_array_refs![dst, 1, 32, 32, 32, 8];
+            expected_amount_decimals_dst,
+            oracle_dst,
+            max_deviation_bps_dst,
+            max_fills_dst,
+            fills_done_dst,
+            rounding_mode_dst,
+            prepared_taker_dst,
+            prepared_slot_dst,
+            accepted_mints_dst,
+            reference_dst,
+            reference_mode_dst,
+            bump_seed_dst,
+            version_dst,
+            start_slot_dst,
+            cancel_penalty_bps_dst,
+            expiry_slot_dst,
+            deposit_mint_dst,
+            expected_mint_dst,
+            cancellable_slot_dst,
+            category_dst,
+            rent_beneficiary_dst,
+            taker_merkle_root_dst,
+            delivers_to_ata_dst,
+            min_fill_dst,
+            stake_lamports_dst,
+            maker_dst,
+            basket_dst,
+            prepared_fee_dst,
+            prepared_secondary_fee_dst,
+            prepared_max_amount_in_dst,
+        ) = mut_array_refs![
+            dst, 1, 32, 32, 32, 8, 1, 36, 2, 1, 1, 1, 32, 8, 97, 32, 1, 1, 4, 8, 2, 8, 32, 32, 8,
+            1, 36, 32, 1, 8, 8, 36, 97, 8, 8, 8
+        ];
 
         let Escrow {
-            is_initialized,
+            status,
             initializer_pubkey,
             temp_token_account_pubkey,
             initializer_dest_token_account_pubkey,
             expected_amount,
+            expected_amount_decimals,
+            oracle,
+            max_deviation_bps,
+            max_fills,
+            fills_done,
+            rounding_mode,
+            prepared_taker,
+            prepared_slot,
+            accepted_mints,
+            reference,
+            reference_mode,
+            bump_seed,
+            version,
+            start_slot,
+            cancel_penalty_bps,
+            expiry_slot,
+            deposit_mint,
+            expected_mint,
+            cancellable_slot,
+            category,
+            rent_beneficiary,
+            taker_merkle_root,
+            delivers_to_ata,
+            min_fill,
+            stake_lamports,
+            maker,
+            basket,
+            prepared_fee,
+            prepared_secondary_fee,
+            prepared_max_amount_in,
         } = self;
 
-        is_initialized_dst[0] = *is_initialized as u8;
+        status_dst[0] = *status as u8;
         initializer_pubkey_dst.copy_from_slice(initializer_pubkey.as_ref());
         temp_token_account_pubkey_dst.copy_from_slice(temp_token_account_pubkey.as_ref());
         initializer_dest_token_account_pubkey_dst
             .copy_from_slice(initializer_dest_token_account_pubkey.as_ref());
         *expected_amount_dst = expected_amount.to_le_bytes();
+        expected_amount_decimals_dst[0] = *expected_amount_decimals;
+        pack_coption_key(oracle, oracle_dst);
+        *max_deviation_bps_dst = max_deviation_bps.to_le_bytes();
+        max_fills_dst[0] = *max_fills;
+        fills_done_dst[0] = *fills_done;
+        rounding_mode_dst[0] = *rounding_mode as u8;
+        prepared_taker_dst.copy_from_slice(prepared_taker.as_ref());
+        *prepared_slot_dst = prepared_slot.to_le_bytes();
+        pack_key_list(accepted_mints, accepted_mints_dst);
+        *reference_dst = *reference;
+        reference_mode_dst[0] = *reference_mode as u8;
+        bump_seed_dst[0] = *bump_seed;
+        *version_dst = version.to_le_bytes();
+        *start_slot_dst = start_slot.to_le_bytes();
+        *cancel_penalty_bps_dst = cancel_penalty_bps.to_le_bytes();
+        *expiry_slot_dst = expiry_slot.to_le_bytes();
+        deposit_mint_dst.copy_from_slice(deposit_mint.as_ref());
+        expected_mint_dst.copy_from_slice(expected_mint.as_ref());
+        *cancellable_slot_dst = cancellable_slot.to_le_bytes();
+        category_dst[0] = *category;
+        pack_coption_key(rent_beneficiary, rent_beneficiary_dst);
+        *taker_merkle_root_dst = *taker_merkle_root;
+        delivers_to_ata_dst[0] = *delivers_to_ata as u8;
+        *min_fill_dst = min_fill.to_le_bytes();
+        *stake_lamports_dst = stake_lamports.to_le_bytes();
+        pack_coption_key(maker, maker_dst);
+        pack_key_list(basket, basket_dst);
+        *prepared_fee_dst = prepared_fee.to_le_bytes();
+        *prepared_secondary_fee_dst = prepared_secondary_fee.to_le_bytes();
+        *prepared_max_amount_in_dst = prepared_max_amount_in.to_le_bytes();
     }
 
     fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
         let src = array_ref![src, 0, Escrow::LEN];
         let (
-            is_initialized,
+            status,
             initializer_pubkey,
             temp_token_account_pubkey,
             initializer_dest_token_account_pubkey,
             expected_amount,
-        ) = array_refs![src, 1, 32, 32, 32, 8];
-        let is_initialized = match is_initialized {
-            [0] => false,
-            [1] => true,
-            _ => return Err(ProgramError::InvalidAccountData),
-        };
-
+            expected_amount_decimals,
+            oracle,
+            max_deviation_bps,
+            max_fills,
+            fills_done,
+            rounding_mode,
+            prepared_taker,
+            prepared_slot,
+            accepted_mints,
+            reference,
+            reference_mode,
+            bump_seed,
+            version,
+            start_slot,
+            cancel_penalty_bps,
+            expiry_slot,
+            deposit_mint,
+            expected_mint,
+            cancellable_slot,
+            category,
+            rent_beneficiary,
+            taker_merkle_root,
+            delivers_to_ata,
+            min_fill,
+            stake_lamports,
+            maker,
+            basket,
+            prepared_fee,
+            prepared_secondary_fee,
+            prepared_max_amount_in,
+        ) = array_refs![
+            src, 1, 32, 32, 32, 8, 1, 36, 2, 1, 1, 1, 32, 8, 97, 32, 1, 1, 4, 8, 2, 8, 32, 32, 8,
+            1, 36, 32, 1, 8, 8, 36, 97, 8, 8, 8
+        ];
         Ok(Escrow {
-            is_initialized,
+            status: EscrowStatus::from_u8(status[0]).ok_or(ProgramError::InvalidAccountData)?,
             initializer_pubkey: Pubkey::new_from_array(*initializer_pubkey),
             temp_token_account_pubkey: Pubkey::new_from_array(*temp_token_account_pubkey),
             initializer_dest_token_account_pubkey: Pubkey::new_from_array(
                 *initializer_dest_token_account_pubkey,
             ),
             expected_amount: u64::from_le_bytes(*expected_amount),
+            expected_amount_decimals: expected_amount_decimals[0],
+            oracle: unpack_coption_key(oracle)?,
+            max_deviation_bps: u16::from_le_bytes(*max_deviation_bps),
+            max_fills: max_fills[0],
+            fills_done: fills_done[0],
+            rounding_mode: RoundingMode::from_u8(rounding_mode[0])
+                .ok_or(ProgramError::InvalidAccountData)?,
+            prepared_taker: Pubkey::new_from_array(*prepared_taker),
+            prepared_slot: u64::from_le_bytes(*prepared_slot),
+            accepted_mints: unpack_key_list(accepted_mints)?,
+            reference: *reference,
+            reference_mode: ReferenceMode::from_u8(reference_mode[0])
+                .ok_or(ProgramError::InvalidAccountData)?,
+            bump_seed: bump_seed[0],
+            version: u32::from_le_bytes(*version),
+            start_slot: u64::from_le_bytes(*start_slot),
+            cancel_penalty_bps: u16::from_le_bytes(*cancel_penalty_bps),
+            expiry_slot: u64::from_le_bytes(*expiry_slot),
+            deposit_mint: Pubkey::new_from_array(*deposit_mint),
+            expected_mint: Pubkey::new_from_array(*expected_mint),
+            cancellable_slot: u64::from_le_bytes(*cancellable_slot),
+            category: category[0],
+            rent_beneficiary: unpack_coption_key(rent_beneficiary)?,
+            taker_merkle_root: *taker_merkle_root,
+            delivers_to_ata: match delivers_to_ata {
+                [0] => false,
+                [1] => true,
+                _ => return Err(ProgramError::InvalidAccountData),
+            },
+            min_fill: u64::from_le_bytes(*min_fill),
+            stake_lamports: u64::from_le_bytes(*stake_lamports),
+            maker: unpack_coption_key(maker)?,
+            basket: unpack_key_list(basket)?,
+            prepared_fee: u64::from_le_bytes(*prepared_fee),
+            prepared_secondary_fee: u64::from_le_bytes(*prepared_secondary_fee),
+            prepared_max_amount_in: u64::from_le_bytes(*prepared_max_amount_in),
         })
     }
 }
+
+// Same layout as spl-token's: a 4-byte tag followed by the key.
+fn pack_coption_key(src: &COption<Pubkey>, dst: &mut [u8; 36]) {
+    let (tag, body) = mut_array_refs![dst, 4, 32];
+    match src {
+        COption::Some(key) => {
+            *tag = [1, 0, 0, 0];
+            body.copy_from_slice(key.as_ref());
+        }
+        COption::None => {
+            *tag = [0; 4];
+        }
+    }
+}
+
+fn unpack_coption_key(src: &[u8; 36]) -> Result<COption<Pubkey>, ProgramError> {
+    let (tag, body) = array_refs![src, 4, 32];
+    match *tag {
+        [0, 0, 0, 0] => Ok(COption::None),
+        [1, 0, 0, 0] => Ok(COption::Some(Pubkey::new_from_array(*body))),
+        _ => Err(ProgramError::InvalidAccountData),
+    }
+}
+
+// A count byte followed by `MAX_LIST_LEN` key slots, unused ones zeroed.
+fn pack_key_list(src: &[Pubkey], dst: &mut [u8; KEY_LIST_LEN]) {
+    let (count, keys) = mut_array_refs![dst, 1, KEY_LIST_LEN - 1];
+    count[0] = src.len() as u8;
+    keys.fill(0);
+    for (dst, key) in keys.chunks_exact_mut(32).zip(src) {
+        dst.copy_from_slice(key.as_ref());
+    }
+}
+
+// Older clippy flags `array_refs!` with a computed length.
+#[allow(clippy::ptr_offset_with_cast)]
+fn unpack_key_list(src: &[u8; KEY_LIST_LEN]) -> Result<Vec<Pubkey>, ProgramError> {
+    let (count, keys) = array_refs![src, 1, KEY_LIST_LEN - 1];
+    let count = count[0] as usize;
+    if count > MAX_LIST_LEN {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    Ok(keys.chunks_exact(32).take(count).map(Pubkey::new).collect())
+}
+
+/// Seed of the singleton `Config` PDA.
+pub const CONFIG_SEED: &[u8] = b"config";
+
+/// Derives the address of the program's `Config`.
+pub fn find_config_address(program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[CONFIG_SEED], program_id)
+}
+
+/// Most mints the config can override the fee of.
+pub const MAX_FEE_OVERRIDES: usize = 4;
+
+/// Packed length of the fee overrides: a count byte followed by `MAX_FEE_OVERRIDES` slots of a
+/// mint and its fee.
+const FEE_OVERRIDES_LEN: usize = 1 + 34 * MAX_FEE_OVERRIDES;
+
+/// Fee charged instead of the config's `fee_bps` on fills paid in `mint`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FeeOverride {
+    pub mint: Pubkey,
+    /// In basis points of the payment, like `Config::fee_bps`.
+    pub fee_bps: u16,
+}
+
+/// Parameters operators can tune without redeploying, set by `InitConfig` and `UpdateConfig`.
+pub struct Config {
+    pub is_initialized: bool,
+    /// Key allowed to update the config.
+    pub admin: Pubkey,
+    /// Fee takers pay on top of each fill's payment, in basis points of it.
+    pub fee_bps: u16,
+    /// Whether new escrows and fills are refused. Cancels always go through.
+    pub paused: bool,
+    /// Wallet the init fee goes to, and owner of the token accounts exchange fees go to.
+    pub treasury: Pubkey,
+    /// Lamports the initializer pays the treasury for each escrow created, 0 for no fee.
+    pub init_fee: u64,
+    /// Admin proposed by `ProposeAdmin` until they accept, `Pubkey::default()` if none.
+    pub pending_admin: Pubkey,
+    /// Per payment mint fees taking precedence over `fee_bps`, at most `MAX_FEE_OVERRIDES`.
+    pub fee_overrides: Vec<FeeOverride>,
+    /// Only relayer fills may be relayed by, if set. Fills without a relayer are always allowed.
+    pub allowed_relayer: COption<Pubkey>,
+    /// Slots an initializer has to wait before reusing the address of the escrow they last
+    /// closed, 0 for no wait.
+    pub address_cooldown_slots: u64,
+    /// Owner of the token accounts the secondary treasury's share of exchange fees goes to.
+    pub secondary_treasury: Pubkey,
+    /// Share of each exchange fee (in basis points) going to `secondary_treasury` instead of
+    /// `treasury`, 0 to send it all to `treasury`.
+    pub secondary_treasury_bps: u16,
+}
+
+impl Config {
+    /// Fee in basis points of fills paid in `mint`.
+    pub fn fee_bps_for(&self, mint: &Pubkey) -> u16 {
+        self.fee_overrides
+            .iter()
+            .find(|fee_override| fee_override.mint == *mint)
+            .map_or(self.fee_bps, |fee_override| fee_override.fee_bps)
+    }
+
+    /// Part of an exchange `fee` going to the secondary treasury, rounded down so any dust stays
+    /// with the main one.
+    pub fn secondary_fee(&self, fee: u64) -> Result<u64, ProgramError> {
+        proportional_amount(
+            fee,
+            self.secondary_treasury_bps as u64,
+            10_000,
+            Rounding::Down,
+        )
+    }
+
+    /// Fee the taker pays the treasury on top of a `payment` in `mint`, rounded down.
+    pub fn fee_for(&self, payment: u64, mint: &Pubkey) -> Result<u64, ProgramError> {
+        proportional_amount(
+            payment,
+            self.fee_bps_for(mint) as u64,
+            10_000,
+            Rounding::Down,
+        )
+    }
+}
+
+impl Sealed for Config {}
+impl IsInitialized for Config {
+    fn is_initialized(&self) -> bool {
+        self.is_initialized
+    }
+}
+
+impl Pack for Config {
+    const LEN: usize = 323;
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let dst = array_mut_ref![dst, 0, Config::LEN];
+        let (
+            is_initialized_dst,
+            admin_dst,
+            fee_bps_dst,
+            paused_dst,
+            treasury_dst,
+            init_fee_dst,
+            pending_admin_dst,
+            fee_overrides_dst,
+            allowed_relayer_dst,
+            address_cooldown_slots_dst,
+            secondary_treasury_dst,
+            secondary_treasury_bps_dst,
+        ) = mut_array_refs![dst, 1, 32, 2, 1, 32, 8, 32, 137, 36, 8, 32, 2];
+        is_initialized_dst[0] = self.is_initialized as u8;
+        admin_dst.copy_from_slice(self.admin.as_ref());
+        *fee_bps_dst = self.fee_bps.to_le_bytes();
+        paused_dst[0] = self.paused as u8;
+        treasury_dst.copy_from_slice(self.treasury.as_ref());
+        *init_fee_dst = self.init_fee.to_le_bytes();
+        pending_admin_dst.copy_from_slice(self.pending_admin.as_ref());
+        pack_fee_overrides(&self.fee_overrides, fee_overrides_dst);
+        pack_coption_key(&self.allowed_relayer, allowed_relayer_dst);
+        *address_cooldown_slots_dst = self.address_cooldown_slots.to_le_bytes();
+        secondary_treasury_dst.copy_from_slice(self.secondary_treasury.as_ref());
+        *secondary_treasury_bps_dst = self.secondary_treasury_bps.to_le_bytes();
+    }
+
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        let src = array_ref![src, 0, Config::LEN];
+        let (
+            is_initialized,
+            admin,
+            fee_bps,
+            paused,
+            treasury,
+            init_fee,
+            pending_admin,
+            fee_overrides,
+            allowed_relayer,
+            address_cooldown_slots,
+            secondary_treasury,
+            secondary_treasury_bps,
+        ) = array_refs![src, 1, 32, 2, 1, 32, 8, 32, 137, 36, 8, 32, 2];
+        let is_initialized = match is_initialized {
+            [0] => false,
+            [1] => true,
+            _ => return Err(ProgramError::InvalidAccountData),
+        };
+        let paused = match paused {
+            [0] => false,
+            [1] => true,
+            _ => return Err(ProgramError::InvalidAccountData),
+        };
+        Ok(Config {
+            is_initialized,
+            admin: Pubkey::new_from_array(*admin),
+            fee_bps: u16::from_le_bytes(*fee_bps),
+            paused,
+            treasury: Pubkey::new_from_array(*treasury),
+            init_fee: u64::from_le_bytes(*init_fee),
+            pending_admin: Pubkey::new_from_array(*pending_admin),
+            fee_overrides: unpack_fee_overrides(fee_overrides)?,
+            allowed_relayer: unpack_coption_key(allowed_relayer)?,
+            address_cooldown_slots: u64::from_le_bytes(*address_cooldown_slots),
+            secondary_treasury: Pubkey::new_from_array(*secondary_treasury),
+            secondary_treasury_bps: u16::from_le_bytes(*secondary_treasury_bps),
+        })
+    }
+}
+
+// A count byte followed by `MAX_FEE_OVERRIDES` slots of a mint and its fee, unused ones zeroed.
+fn pack_fee_overrides(src: &[FeeOverride], dst: &mut [u8; FEE_OVERRIDES_LEN]) {
+    let (count, slots) = mut_array_refs![dst, 1, FEE_OVERRIDES_LEN - 1];
+    count[0] = src.len() as u8;
+    slots.fill(0);
+    for (dst, fee_override) in slots.chunks_exact_mut(34).zip(src) {
+        dst[..32].copy_from_slice(fee_override.mint.as_ref());
+        dst[32..].copy_from_slice(&fee_override.fee_bps.to_le_bytes());
+    }
+}
+
+// Older clippy flags `array_refs!` with a computed length.
+#[allow(clippy::ptr_offset_with_cast)]
+fn unpack_fee_overrides(src: &[u8; FEE_OVERRIDES_LEN]) -> Result<Vec<FeeOverride>, ProgramError> {
+    let (count, slots) = array_refs![src, 1, FEE_OVERRIDES_LEN - 1];
+    let count = count[0] as usize;
+    if count > MAX_FEE_OVERRIDES {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    Ok(slots
+        .chunks_exact(34)
+        .take(count)
+        .map(|slot| FeeOverride {
+            mint: Pubkey::new(&slot[..32]),
+            fee_bps: u16::from_le_bytes([slot[32], slot[33]]),
+        })
+        .collect())
+}
+
+/// Seed of the PDA that owns every escrow's temp token account.
+pub const ESCROW_AUTHORITY_SEED: &[u8] = b"escrow";
+
+/// Derives the escrow authority PDA and its bump.
+pub fn find_escrow_authority_address(program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[ESCROW_AUTHORITY_SEED], program_id)
+}
+
+/// Seeds to sign for the escrow authority with, e.g. when composing programs pass it to
+/// `invoke_signed`.
+pub fn escrow_signer_seeds(bump: &u8) -> [&[u8]; 2] {
+    [ESCROW_AUTHORITY_SEED, std::slice::from_ref(bump)]
+}
+
+/// Checks `expected` is the escrow authority PDA for `bump` (e.g. one stored in an escrow) with a
+/// single `create_program_address`, rather than the search `find_escrow_authority_address` does.
+pub fn verify_escrow_pda(
+    program_id: &Pubkey,
+    expected: &Pubkey,
+    bump: u8,
+) -> Result<(), ProgramError> {
+    match Pubkey::create_program_address(&escrow_signer_seeds(&bump), program_id) {
+        Ok(pda) if pda == *expected => Ok(()),
+        _ => Err(ProgramError::InvalidSeeds),
+    }
+}
+
+/// Seed of the `AuthorityCache` PDA.
+pub const AUTHORITY_CACHE_SEED: &[u8] = b"authority-cache";
+
+/// Derives the address of the program's `AuthorityCache`.
+pub fn find_authority_cache_address(program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[AUTHORITY_CACHE_SEED], program_id)
+}
+
+/// The escrow authority PDA and its bump, written once by `CacheAuthority` so fills passing it
+/// can skip `find_program_address`. Only this program writes accounts it owns, so a program-owned
+/// account of this size holds the right values wherever it lives.
+pub struct AuthorityCache {
+    pub is_initialized: bool,
+    pub authority: Pubkey,
+    pub bump_seed: u8,
+}
+
+impl Sealed for AuthorityCache {}
+impl IsInitialized for AuthorityCache {
+    fn is_initialized(&self) -> bool {
+        self.is_initialized
+    }
+}
+
+impl Pack for AuthorityCache {
+    const LEN: usize = 34;
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let dst = array_mut_ref![dst, 0, AuthorityCache::LEN];
+        let (is_initialized_dst, authority_dst, bump_seed_dst) = mut_array_refs![dst, 1, 32, 1];
+        is_initialized_dst[0] = self.is_initialized as u8;
+        authority_dst.copy_from_slice(self.authority.as_ref());
+        bump_seed_dst[0] = self.bump_seed;
+    }
+
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        let src = array_ref![src, 0, AuthorityCache::LEN];
+        let (is_initialized, authority, bump_seed) = array_refs![src, 1, 32, 1];
+        let is_initialized = match is_initialized {
+            [0] => false,
+            [1] => true,
+            _ => return Err(ProgramError::InvalidAccountData),
+        };
+        Ok(AuthorityCache {
+            is_initialized,
+            authority: Pubkey::new_from_array(*authority),
+            bump_seed: bump_seed[0],
+        })
+    }
+}
+
+/// Seed prefix of the escrow accounts `SplitEscrow` creates, followed by the split escrow's key
+/// and the child's index.
+pub const SPLIT_ESCROW_SEED: &[u8] = b"split-escrow";
+/// Same as `SPLIT_ESCROW_SEED`, for the children's temp token accounts.
+pub const SPLIT_TEMP_SEED: &[u8] = b"split-temp";
+
+/// Derives the address of child `index` of `escrow` created by `SplitEscrow`.
+pub fn find_split_escrow_address(program_id: &Pubkey, escrow: &Pubkey, index: u8) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[SPLIT_ESCROW_SEED, escrow.as_ref(), &[index]], program_id)
+}
+
+/// Derives the address of the temp token account of child `index` of `escrow`.
+pub fn find_split_temp_address(program_id: &Pubkey, escrow: &Pubkey, index: u8) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[SPLIT_TEMP_SEED, escrow.as_ref(), &[index]], program_id)
+}
+
+/// Seed prefix of the `EscrowIndex` PDA, followed by the initializer's key.
+pub const ESCROW_INDEX_SEED: &[u8] = b"escrow-index";
+
+/// Derives the address of the initializer's `EscrowIndex`.
+pub fn find_escrow_index_address(program_id: &Pubkey, initializer: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[ESCROW_INDEX_SEED, initializer.as_ref()], program_id)
+}
+
+/// Seed prefix of the wrapped SOL account an `Exchange` pays from for a taker paying in native
+/// SOL, followed by the taker's key.
+pub const NATIVE_SOL_WRAPPER_SEED: &[u8] = b"native-sol-wrapper";
+
+/// Derives the address of the wrapped SOL account a taker's native SOL payment goes through (see
+/// `instruction::with_native_sol_payment`). It only exists during the exchange.
+pub fn find_native_sol_wrapper_address(program_id: &Pubkey, taker: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[NATIVE_SOL_WRAPPER_SEED, taker.as_ref()], program_id)
+}
+
+/// Escrows created by an initializer that are still open, in creation order. Lives in a PDA
+/// (see `find_escrow_index_address`) that grows and shrinks with the list, so wallets can list
+/// their escrows without `getProgramAccounts` filters.
+#[derive(Default)]
+pub struct EscrowIndex {
+    pub escrows: Vec<Pubkey>,
+    /// Escrow last removed from the index, `Pubkey::default()` if none yet.
+    pub last_removed: Pubkey,
+    /// Slot `last_removed` was removed at.
+    pub last_removed_slot: u64,
+}
+
+impl EscrowIndex {
+    /// A little-endian u32 count of the keys that follow, then `last_removed` and
+    /// `last_removed_slot`.
+    const HEADER_LEN: usize = 44;
+
+    /// Length of an index holding `count` escrows.
+    pub fn packed_len(count: usize) -> usize {
+        Self::HEADER_LEN + count * 32
+    }
+
+    pub fn unpack(src: &[u8]) -> Result<Self, ProgramError> {
+        if src.len() < Self::HEADER_LEN {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let (header, keys) = src.split_at(Self::HEADER_LEN);
+        let (count, last_removed, last_removed_slot) =
+            array_refs![array_ref![header, 0, EscrowIndex::HEADER_LEN], 4, 32, 8];
+        let count = u32::from_le_bytes(*count) as usize;
+        if keys.len() < count * 32 {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let escrows = keys.chunks_exact(32).take(count).map(Pubkey::new).collect();
+        Ok(EscrowIndex {
+            escrows,
+            last_removed: Pubkey::new_from_array(*last_removed),
+            last_removed_slot: u64::from_le_bytes(*last_removed_slot),
+        })
+    }
+
+    /// Packs into `dst`, which must be at least `packed_len` of the current list.
+    pub fn pack_into_slice(&self, dst: &mut [u8]) {
+        let (header, keys) = dst.split_at_mut(Self::HEADER_LEN);
+        let (count, last_removed, last_removed_slot) =
+            mut_array_refs![array_mut_ref![header, 0, EscrowIndex::HEADER_LEN], 4, 32, 8];
+        *count = (self.escrows.len() as u32).to_le_bytes();
+        last_removed.copy_from_slice(self.last_removed.as_ref());
+        *last_removed_slot = self.last_removed_slot.to_le_bytes();
+        for (dst, key) in keys.chunks_exact_mut(32).zip(&self.escrows) {
+            dst.copy_from_slice(key.as_ref());
+        }
+    }
+}
+
+/// Seed of the `AuditLog` PDA.
+pub const AUDIT_LOG_SEED: &[u8] = b"audit-log";
+
+/// Fills the `AuditLog` holds before overwriting the oldest.
+pub const AUDIT_LOG_CAPACITY: usize = 32;
+
+/// Derives the address of the program's `AuditLog`.
+pub fn find_audit_log_address(program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[AUDIT_LOG_SEED], program_id)
+}
+
+/// A fill recorded in the `AuditLog`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AuditEntry {
+    pub escrow: Pubkey,
+    pub taker: Pubkey,
+    /// Deposited tokens the taker received.
+    pub amount: u64,
+    pub slot: u64,
+}
+
+impl AuditEntry {
+    pub const LEN: usize = 80;
+
+    fn pack_into(&self, dst: &mut [u8; AuditEntry::LEN]) {
+        let (escrow_dst, taker_dst, amount_dst, slot_dst) = mut_array_refs![dst, 32, 32, 8, 8];
+        escrow_dst.copy_from_slice(self.escrow.as_ref());
+        taker_dst.copy_from_slice(self.taker.as_ref());
+        *amount_dst = self.amount.to_le_bytes();
+        *slot_dst = self.slot.to_le_bytes();
+    }
+
+    fn unpack_from(src: &[u8; AuditEntry::LEN]) -> Self {
+        let (escrow, taker, amount, slot) = array_refs![src, 32, 32, 8, 8];
+        AuditEntry {
+            escrow: Pubkey::new_from_array(*escrow),
+            taker: Pubkey::new_from_array(*taker),
+            amount: u64::from_le_bytes(*amount),
+            slot: u64::from_le_bytes(*slot),
+        }
+    }
+}
+
+/// Ring buffer of the last `AUDIT_LOG_CAPACITY` fills sent along with it (see
+/// `instruction::with_audit_log`), for deployments that want an on-chain trail of fills without
+/// running an indexer. Lives in a PDA (see `find_audit_log_address`) and is appended to in place
+/// rather than unpacked whole, since it's large.
+pub struct AuditLog;
+
+impl AuditLog {
+    /// Two little-endian u32s: the slot the next entry goes in, then how many slots are used.
+    const HEADER_LEN: usize = 8;
+
+    pub const LEN: usize = Self::HEADER_LEN + AUDIT_LOG_CAPACITY * AuditEntry::LEN;
+
+    /// Reads the next slot and the used slot count of a log's `data`, checking they're in range.
+    fn header(data: &[u8]) -> Result<(usize, usize), ProgramError> {
+        if data.len() != Self::LEN {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let (next, used) = array_refs![array_ref![data, 0, AuditLog::HEADER_LEN], 4, 4];
+        let next = u32::from_le_bytes(*next) as usize;
+        let used = u32::from_le_bytes(*used) as usize;
+        if next >= AUDIT_LOG_CAPACITY || used > AUDIT_LOG_CAPACITY {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        Ok((next, used))
+    }
+
+    /// Records `entry`, over the oldest one once the log is full.
+    pub fn append(data: &mut [u8], entry: &AuditEntry) -> Result<(), ProgramError> {
+        let (next, used) = Self::header(data)?;
+        let (header, entries) = data.split_at_mut(Self::HEADER_LEN);
+        entry.pack_into(array_mut_ref![
+            entries,
+            next * AuditEntry::LEN,
+            AuditEntry::LEN
+        ]);
+
+        let (next_dst, used_dst) = mut_array_refs![array_mut_ref![header, 0, 8], 4, 4];
+        *next_dst = (((next + 1) % AUDIT_LOG_CAPACITY) as u32).to_le_bytes();
+        *used_dst = ((used + 1).min(AUDIT_LOG_CAPACITY) as u32).to_le_bytes();
+        Ok(())
+    }
+
+    /// The recorded entries, oldest first.
+    pub fn entries(data: &[u8]) -> Result<Vec<AuditEntry>, ProgramError> {
+        let (next, used) = Self::header(data)?;
+        let entries = &data[Self::HEADER_LEN..];
+        // Until the log first wraps, the oldest entry is the first one; after, it's the one
+        // about to be overwritten.
+        let oldest = if used < AUDIT_LOG_CAPACITY { 0 } else { next };
+        Ok((0..used)
+            .map(|offset| {
+                let index = (oldest + offset) % AUDIT_LOG_CAPACITY;
+                AuditEntry::unpack_from(array_ref![
+                    entries,
+                    index * AuditEntry::LEN,
+                    AuditEntry::LEN
+                ])
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unpack_rejects_out_of_range_flag_bytes() {
+        // An escrow's first byte is its status, which goes up to `Closed`.
+        let mut data = vec![0; Escrow::LEN];
+        data[0] = EscrowStatus::Closed as u8 + 1;
+        assert!(matches!(
+            Escrow::unpack_unchecked(&data),
+            Err(ProgramError::InvalidAccountData)
+        ));
+
+        // The others start with an `is_initialized` byte that's either 0 or 1, not any nonzero
+        // value.
+        let mut data = vec![0; Config::LEN];
+        data[0] = 2;
+        assert!(matches!(
+            Config::unpack_unchecked(&data),
+            Err(ProgramError::InvalidAccountData)
+        ));
+        let mut data = vec![0; AuthorityCache::LEN];
+        data[0] = 0xff;
+        assert!(matches!(
+            AuthorityCache::unpack_unchecked(&data),
+            Err(ProgramError::InvalidAccountData)
+        ));
+    }
+
+    #[test]
+    fn audit_log_overwrites_oldest_entries_once_full() {
+        let entry = |slot: u64| AuditEntry {
+            escrow: Pubkey::new_from_array([1; 32]),
+            taker: Pubkey::new_from_array([2; 32]),
+            amount: 100,
+            slot,
+        };
+        let mut data = vec![0; AuditLog::LEN];
+        let appended = AUDIT_LOG_CAPACITY as u64 + 3;
+        for slot in 0..appended {
+            AuditLog::append(&mut data, &entry(slot)).unwrap();
+        }
+
+        // The first 3 were overwritten, the rest come oldest first.
+        let entries = AuditLog::entries(&data).unwrap();
+        assert_eq!(entries.len(), AUDIT_LOG_CAPACITY);
+        assert_eq!(entries, (3..appended).map(entry).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,242 @@
+use solana_program::{
+    account_info::AccountInfo, entrypoint::ProgramResult, msg, program_error::ProgramError,
+    program_pack::Pack, pubkey::Pubkey,
+};
+use spl_token::state::{Account as TokenAccount, Mint};
+
+use crate::error::EscrowError;
+
+/// Token-2022 accounts carrying extensions have a type byte right after the base state (mints
+/// being padded up to it), followed by the extensions.
+const ACCOUNT_TYPE_OFFSET: usize = TokenAccount::LEN;
+const ACCOUNT_TYPE_MINT: u8 = 1;
+const ACCOUNT_TYPE_ACCOUNT: u8 = 2;
+
+/// Unpacks the base state of a token account going by the size of `data` rather than assuming
+/// the legacy layout, so accounts with Token-2022 extensions unpack too. The extensions
+/// themselves are skipped. Only accounts of the SPL Token program get here for now (see
+/// `assert_token_program`), this just keeps the unpacking from being what holds Token-2022 back.
+pub fn unpack_token_account_data(data: &[u8]) -> Result<TokenAccount, ProgramError> {
+    if data.len() == Mint::LEN {
+        return Err(EscrowError::ExpectedTokenAccountGotMint.into());
+    }
+    if data.len() <= ACCOUNT_TYPE_OFFSET {
+        return TokenAccount::unpack(data);
+    }
+    match data[ACCOUNT_TYPE_OFFSET] {
+        ACCOUNT_TYPE_ACCOUNT => TokenAccount::unpack(&data[..ACCOUNT_TYPE_OFFSET]),
+        ACCOUNT_TYPE_MINT => Err(EscrowError::ExpectedTokenAccountGotMint.into()),
+        _ => Err(ProgramError::InvalidAccountData),
+    }
+}
+
+/// Unpacks `info` as an SPL token account, checking that it's owned by the token program and,
+/// if given, that it holds `expected_mint` and is owned by `expected_owner`.
+pub fn assert_token_account(
+    info: &AccountInfo,
+    expected_mint: Option<&Pubkey>,
+    expected_owner: Option<&Pubkey>,
+) -> Result<TokenAccount, ProgramError> {
+    if *info.owner != spl_token::id() {
+        msg!(
+            "{} is owned by {}, not the token program",
+            info.key,
+            info.owner
+        );
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    // Mints are owned by the token program too, and otherwise just fail to unpack.
+    if info.data_len() == Mint::LEN {
+        msg!("{} is a mint, not a token account", info.key);
+        return Err(EscrowError::ExpectedTokenAccountGotMint.into());
+    }
+    let token_account = unpack_token_account_data(&info.try_borrow_data()?)?;
+
+    if let Some(mint) = expected_mint {
+        if token_account.mint != *mint {
+            return Err(EscrowError::MintMismatch.into());
+        }
+    }
+    if let Some(owner) = expected_owner {
+        if token_account.owner != *owner {
+            return Err(EscrowError::OwnerMismatch.into());
+        }
+    }
+
+    Ok(token_account)
+}
+
+/// Whether `info` is an initialized, unfrozen SPL token account, i.e. one the token program will
+/// still credit.
+pub fn can_receive_tokens(info: &AccountInfo) -> bool {
+    if *info.owner != spl_token::id() {
+        return false;
+    }
+    match info.try_borrow_data() {
+        Ok(data) => match unpack_token_account_data(&data) {
+            Ok(account) => !account.is_frozen(),
+            Err(_) => false,
+        },
+        Err(_) => false,
+    }
+}
+
+/// Unpacks `info` as an SPL mint, checking that it's owned by the token program.
+pub fn assert_mint(info: &AccountInfo) -> Result<Mint, ProgramError> {
+    if *info.owner != spl_token::id() {
+        msg!(
+            "{} is owned by {}, not the token program",
+            info.key,
+            info.owner
+        );
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    if info.data_len() == TokenAccount::LEN {
+        msg!("{} is a token account, not a mint", info.key);
+        return Err(EscrowError::ExpectedMintGotTokenAccount.into());
+    }
+    Mint::unpack(&info.try_borrow_data()?)
+}
+
+/// Checks `info` is the SPL token program, so every CPI an instruction makes through it targets
+/// the one program the token accounts it checked are owned by, and that it's passed read-only as
+/// documented.
+pub fn assert_token_program(info: &AccountInfo) -> ProgramResult {
+    if *info.key != spl_token::id() {
+        msg!("{} is not the token program", info.key);
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    if info.is_writable {
+        msg!("Token program passed as writable");
+        return Err(ProgramError::InvalidAccountData);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, solana_program::program_option::COption, spl_token::state::AccountState};
+
+    fn token_account_data(mint: Pubkey, owner: Pubkey) -> Vec<u8> {
+        let mut data = vec![0; TokenAccount::LEN];
+        TokenAccount::pack(
+            TokenAccount {
+                mint,
+                owner,
+                amount: 100,
+                delegate: COption::None,
+                state: AccountState::Initialized,
+                is_native: COption::None,
+                delegated_amount: 0,
+                close_authority: COption::None,
+            },
+            &mut data,
+        )
+        .unwrap();
+        data
+    }
+
+    /// Runs `assert_token_account` on an account at a fresh address with the given owner and
+    /// data.
+    fn check(
+        owner: &Pubkey,
+        data: &mut [u8],
+        expected_mint: Option<&Pubkey>,
+        expected_owner: Option<&Pubkey>,
+    ) -> Result<TokenAccount, ProgramError> {
+        let key = Pubkey::new_unique();
+        let mut lamports = 0;
+        let info = AccountInfo::new(&key, false, false, &mut lamports, data, owner, false, 0);
+        assert_token_account(&info, expected_mint, expected_owner)
+    }
+
+    #[test]
+    fn assert_token_account_accepts_matching_account() {
+        let (mint, owner) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut data = token_account_data(mint, owner);
+        let account = check(&spl_token::id(), &mut data, Some(&mint), Some(&owner)).unwrap();
+        assert_eq!(account.amount, 100);
+    }
+
+    #[test]
+    fn assert_token_account_rejects_account_of_another_program() {
+        let mut data = token_account_data(Pubkey::new_unique(), Pubkey::new_unique());
+        assert_eq!(
+            check(&Pubkey::new_unique(), &mut data, None, None).unwrap_err(),
+            ProgramError::IncorrectProgramId
+        );
+    }
+
+    #[test]
+    fn assert_token_account_rejects_mint() {
+        let mut data = vec![0; Mint::LEN];
+        assert_eq!(
+            check(&spl_token::id(), &mut data, None, None).unwrap_err(),
+            EscrowError::ExpectedTokenAccountGotMint.into()
+        );
+    }
+
+    #[test]
+    fn assert_token_account_rejects_uninitialized_account() {
+        let mut data = vec![0; TokenAccount::LEN];
+        assert_eq!(
+            check(&spl_token::id(), &mut data, None, None).unwrap_err(),
+            ProgramError::UninitializedAccount
+        );
+    }
+
+    #[test]
+    fn assert_token_account_rejects_wrong_mint() {
+        let mut data = token_account_data(Pubkey::new_unique(), Pubkey::new_unique());
+        assert_eq!(
+            check(
+                &spl_token::id(),
+                &mut data,
+                Some(&Pubkey::new_unique()),
+                None
+            )
+            .unwrap_err(),
+            EscrowError::MintMismatch.into()
+        );
+    }
+
+    #[test]
+    fn assert_token_account_rejects_wrong_owner() {
+        let mut data = token_account_data(Pubkey::new_unique(), Pubkey::new_unique());
+        assert_eq!(
+            check(
+                &spl_token::id(),
+                &mut data,
+                None,
+                Some(&Pubkey::new_unique())
+            )
+            .unwrap_err(),
+            EscrowError::OwnerMismatch.into()
+        );
+    }
+
+    #[test]
+    fn unpack_token_account_data_skips_transfer_fee_extension() {
+        let (mint, owner) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut data = token_account_data(mint, owner);
+        // Token-2022's account type, then a `TransferFeeAmount` extension: its type, length and
+        // withheld amount.
+        data.push(ACCOUNT_TYPE_ACCOUNT);
+        data.extend_from_slice(&2u16.to_le_bytes());
+        data.extend_from_slice(&8u16.to_le_bytes());
+        data.extend_from_slice(&7u64.to_le_bytes());
+
+        let account = unpack_token_account_data(&data).unwrap();
+        assert_eq!(
+            (account.mint, account.owner, account.amount),
+            (mint, owner, 100)
+        );
+
+        // The same extended account claiming to be a mint.
+        data[ACCOUNT_TYPE_OFFSET] = ACCOUNT_TYPE_MINT;
+        assert_eq!(
+            unpack_token_account_data(&data).unwrap_err(),
+            EscrowError::ExpectedTokenAccountGotMint.into()
+        );
+    }
+}
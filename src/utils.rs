@@ -0,0 +1,60 @@
+use solana_program::{
+    account_info::AccountInfo, entrypoint::ProgramResult, msg, program_error::ProgramError,
+};
+
+use crate::error::EscrowError;
+
+/// Fails with `NotEnoughAccounts` if fewer than `required` accounts were passed, before any of
+/// them is looked at, so a short account list isn't misreported as a wrong account.
+pub fn require_account_count(accounts: &[AccountInfo], required: usize) -> ProgramResult {
+    if accounts.len() < required {
+        msg!(
+            "Expected at least {} accounts, got {}",
+            required,
+            accounts.len()
+        );
+        return Err(EscrowError::NotEnoughAccounts.into());
+    }
+    Ok(())
+}
+
+/// Returns the account at `index`, failing with `NotEnoughAccounts` and logging `name` if there
+/// aren't enough accounts.
+pub fn named_account_at<'a, 'b>(
+    accounts: &'a [AccountInfo<'b>],
+    index: usize,
+    name: &str,
+) -> Result<&'a AccountInfo<'b>, ProgramError> {
+    match accounts.get(index) {
+        Some(info) => Ok(info),
+        None => {
+            msg!("Missing account: {}", name);
+            Err(EscrowError::NotEnoughAccounts.into())
+        }
+    }
+}
+
+/// Same as `next_account_info`, but fails with `NotEnoughAccounts` and logs which account was
+/// missing so integrators don't have to count positions to figure out what they forgot.
+pub fn next_named_account_info<'a, 'b, I: Iterator<Item = &'a AccountInfo<'b>>>(
+    iter: &mut I,
+    name: &str,
+) -> Result<I::Item, ProgramError> {
+    match iter.next() {
+        Some(info) => Ok(info),
+        None => {
+            msg!("Missing account: {}", name);
+            Err(EscrowError::NotEnoughAccounts.into())
+        }
+    }
+}
+
+/// Fails with `MissingRequiredSignature` unless `info` signed, logging the `role` it was expected
+/// to sign as.
+pub fn require_signer(info: &AccountInfo, role: &str) -> ProgramResult {
+    if !info.is_signer {
+        msg!("Missing signature: {} {}", role, info.key);
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    Ok(())
+}
@@ -0,0 +1,102 @@
+#![cfg(feature = "test-bpf")]
+
+mod common;
+
+use {
+    bpf_program_template::{
+        instruction,
+        state::{ReferenceMode, RoundingMode},
+    },
+    common::*,
+    solana_program::{program_option::COption, program_pack::Pack, pubkey::Pubkey},
+    solana_sdk::signature::Signer,
+    spl_associated_token_account::get_associated_token_address,
+    spl_token::state::Account as TokenAccount,
+};
+
+#[tokio::test]
+async fn exchange_creates_initializer_associated_token_account() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let fixture = setup(&mut context, &program_id).await;
+    let initializer_ata =
+        get_associated_token_address(&fixture.initializer.pubkey(), &fixture.mint_y);
+
+    let init_escrow = instruction::init_escrow(
+        &program_id,
+        &fixture.initializer.pubkey(),
+        &fixture.temp_token_account.pubkey(),
+        &initializer_ata,
+        &fixture.mint_y,
+        &fixture.escrow_account.pubkey(),
+        &TREASURY,
+        EXPECTED_AMOUNT,
+        COption::None,
+        0,
+        0,
+        RoundingMode::FloorToTaker,
+        vec![],
+        vec![],
+        ReferenceMode::Plain,
+        0,
+        0,
+        0,
+        0,
+        0,
+        [0; 32],
+        true,
+        0,
+        0,
+        COption::None,
+        vec![],
+    )
+    .unwrap();
+    let exchange = instruction::exchange(
+        &program_id,
+        &fixture.taker.pubkey(),
+        &fixture.taker_send_account.pubkey(),
+        &fixture.mint_y,
+        &fixture.taker_receive_account.pubkey(),
+        &fixture.temp_token_account.pubkey(),
+        &fixture.initializer.pubkey(),
+        &initializer_ata,
+        &fixture.escrow_account.pubkey(),
+        // Unchecked while the fee is 0.
+        &Pubkey::new_unique(),
+        // Unchecked while the exchange leaves no residual.
+        &Pubkey::new_unique(),
+        None,
+        DEPOSIT_AMOUNT,
+        &[],
+        EXPECTED_AMOUNT,
+    )
+    .unwrap();
+
+    process(&mut context, &[init_escrow], &[&fixture.initializer])
+        .await
+        .unwrap();
+    assert!(context
+        .banks_client
+        .get_account(initializer_ata)
+        .await
+        .unwrap()
+        .is_none());
+
+    process(
+        &mut context,
+        &[instruction::with_ata_creation(exchange)],
+        &[&fixture.taker],
+    )
+    .await
+    .unwrap();
+
+    let account = context
+        .banks_client
+        .get_account(initializer_ata)
+        .await
+        .unwrap()
+        .unwrap();
+    let initializer_ata_info = TokenAccount::unpack(&account.data).unwrap();
+    assert_eq!(initializer_ata_info.owner, fixture.initializer.pubkey());
+    assert_eq!(initializer_ata_info.amount, EXPECTED_AMOUNT);
+}
@@ -0,0 +1,64 @@
+#![cfg(feature = "test-bpf")]
+
+mod common;
+
+use {
+    bpf_program_template::{
+        instruction,
+        state::{find_audit_log_address, AuditEntry, AuditLog},
+    },
+    common::*,
+    solana_program::{pubkey::Pubkey, rent::Rent},
+    solana_sdk::{account::Account, signature::Signer},
+};
+
+#[tokio::test]
+async fn exchange_records_fill_in_audit_log() {
+    let program_id = Pubkey::new_unique();
+    let mut program_test = program_test(program_id);
+    // Stand-in for `InitAuditLog`, which only the config's admin can sign.
+    let audit_log = find_audit_log_address(&program_id).0;
+    program_test.add_account(
+        audit_log,
+        Account {
+            lamports: Rent::default().minimum_balance(AuditLog::LEN),
+            data: vec![0; AuditLog::LEN],
+            owner: program_id,
+            ..Account::default()
+        },
+    );
+    let mut context = program_test.start_with_context().await;
+    let fixture = setup(&mut context, &program_id).await;
+    process(
+        &mut context,
+        &[init_escrow_instruction(&program_id, &fixture)],
+        &[&fixture.initializer],
+    )
+    .await
+    .unwrap();
+
+    let exchange =
+        instruction::with_audit_log(exchange_instruction(&program_id, &fixture), &program_id);
+    process(&mut context, &[exchange], &[&fixture.taker])
+        .await
+        .unwrap();
+
+    let data = context
+        .banks_client
+        .get_account(audit_log)
+        .await
+        .unwrap()
+        .unwrap()
+        .data;
+    let entries = AuditLog::entries(&data).unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(
+        entries[0],
+        AuditEntry {
+            escrow: fixture.escrow_account.pubkey(),
+            taker: fixture.taker.pubkey(),
+            amount: DEPOSIT_AMOUNT,
+            slot: entries[0].slot,
+        }
+    );
+}
@@ -0,0 +1,74 @@
+#![cfg(feature = "test-bpf")]
+
+mod common;
+
+use {
+    bpf_program_template::{instruction, state::Escrow},
+    common::*,
+    solana_program::{instruction::InstructionError, program_pack::Pack, pubkey::Pubkey},
+    solana_sdk::{
+        account::AccountSharedData, signature::Signer, transaction::TransactionError,
+        transport::TransportError,
+    },
+    spl_associated_token_account::{create_associated_token_account, get_associated_token_address},
+};
+
+#[tokio::test]
+async fn cancel_rejects_corrupt_stored_bump_before_signing() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let fixture = setup(&mut context, &program_id).await;
+    let initializer_refund_account =
+        get_associated_token_address(&fixture.initializer.pubkey(), &fixture.mint_x);
+
+    let create_refund_account = create_associated_token_account(
+        &context.payer.pubkey(),
+        &fixture.initializer.pubkey(),
+        &fixture.mint_x,
+    );
+    process(
+        &mut context,
+        &[
+            create_refund_account,
+            init_escrow_instruction(&program_id, &fixture),
+        ],
+        &[&fixture.initializer],
+    )
+    .await
+    .unwrap();
+
+    let mut escrow_account = context
+        .banks_client
+        .get_account(fixture.escrow_account.pubkey())
+        .await
+        .unwrap()
+        .unwrap();
+    let mut escrow = Escrow::unpack(&escrow_account.data).unwrap();
+    escrow.bump_seed = escrow.bump_seed.wrapping_sub(1);
+    Escrow::pack(escrow, &mut escrow_account.data).unwrap();
+    context.set_account(
+        &fixture.escrow_account.pubkey(),
+        &AccountSharedData::from(escrow_account),
+    );
+
+    let cancel = instruction::cancel_escrow(
+        &program_id,
+        &fixture.initializer.pubkey(),
+        &fixture.temp_token_account.pubkey(),
+        &initializer_refund_account,
+        &fixture.escrow_account.pubkey(),
+        // Unchecked without a cancel penalty.
+        &Pubkey::new_unique(),
+    )
+    .unwrap();
+    let error = process(&mut context, &[cancel], &[&fixture.initializer])
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        error,
+        TransportError::TransactionError(TransactionError::InstructionError(
+            0,
+            InstructionError::InvalidSeeds,
+        ))
+    ));
+}
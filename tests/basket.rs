@@ -0,0 +1,217 @@
+#![cfg(feature = "test-bpf")]
+
+mod common;
+
+use {
+    bpf_program_template::{
+        instruction::{self, EscrowInstruction},
+        state::Escrow,
+    },
+    common::*,
+    solana_program::{
+        instruction::{AccountMeta, Instruction},
+        program_option::COption,
+        program_pack::Pack,
+        pubkey::Pubkey,
+    },
+    solana_program_test::*,
+    solana_sdk::signature::{Keypair, Signer},
+};
+
+const BASKET_AMOUNT: u64 = 300;
+
+/// A basket temp token account of the initializer's holding `BASKET_AMOUNT` of a new mint, and an
+/// empty token account of that mint owned by `destination_owner`.
+async fn create_basket_accounts(
+    context: &mut ProgramTestContext,
+    fixture: &Fixture,
+    destination_owner: &Pubkey,
+) -> (Keypair, Keypair) {
+    let mint_z = Keypair::new();
+    let basket_temp_account = Keypair::new();
+    let basket_destination = Keypair::new();
+    create_mint(context, &mint_z, &fixture.mint_authority.pubkey())
+        .await
+        .unwrap();
+    create_token_account(
+        context,
+        &basket_temp_account,
+        &mint_z.pubkey(),
+        &fixture.initializer.pubkey(),
+        &fixture.mint_authority,
+        BASKET_AMOUNT,
+    )
+    .await
+    .unwrap();
+    create_token_account(
+        context,
+        &basket_destination,
+        &mint_z.pubkey(),
+        destination_owner,
+        &fixture.mint_authority,
+        0,
+    )
+    .await
+    .unwrap();
+    (basket_temp_account, basket_destination)
+}
+
+/// Initializes the fixture's escrow with `basket_temp_account` as its basket.
+fn init_basket_escrow_instruction(
+    program_id: &Pubkey,
+    fixture: &Fixture,
+    basket_temp_account: &Pubkey,
+) -> Instruction {
+    let mut init_escrow = init_escrow_instruction_with(program_id, fixture, |data| {
+        if let EscrowInstruction::InitEscrow { basket, .. } = data {
+            *basket = vec![*basket_temp_account];
+        }
+    });
+    init_escrow
+        .accounts
+        .push(AccountMeta::new(*basket_temp_account, false));
+    init_escrow
+}
+
+async fn get_escrow(context: &mut ProgramTestContext, fixture: &Fixture) -> Escrow {
+    Escrow::unpack(
+        &context
+            .banks_client
+            .get_account(fixture.escrow_account.pubkey())
+            .await
+            .unwrap()
+            .unwrap()
+            .data,
+    )
+    .unwrap()
+}
+
+async fn assert_closed(context: &mut ProgramTestContext, accounts: &[Pubkey]) {
+    for closed in accounts {
+        assert!(context
+            .banks_client
+            .get_account(*closed)
+            .await
+            .unwrap()
+            .is_none());
+    }
+}
+
+#[tokio::test]
+async fn exchange_pays_out_whole_basket() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let fixture = setup(&mut context, &program_id).await;
+    let (basket_temp_account, taker_basket_account) =
+        create_basket_accounts(&mut context, &fixture, &fixture.taker.pubkey()).await;
+
+    let init_escrow =
+        init_basket_escrow_instruction(&program_id, &fixture, &basket_temp_account.pubkey());
+    process(&mut context, &[init_escrow], &[&fixture.initializer])
+        .await
+        .unwrap();
+    let escrow = get_escrow(&mut context, &fixture).await;
+    assert_eq!(escrow.basket, vec![basket_temp_account.pubkey()]);
+    assert_eq!(escrow.rent_beneficiary, COption::None);
+
+    let exchange = instruction::with_basket_accounts(
+        exchange_instruction(&program_id, &fixture),
+        &[(basket_temp_account.pubkey(), taker_basket_account.pubkey())],
+    );
+    process(&mut context, &[exchange], &[&fixture.taker])
+        .await
+        .unwrap();
+
+    for (account, expected_amount) in [
+        (fixture.taker_receive_account.pubkey(), DEPOSIT_AMOUNT),
+        (taker_basket_account.pubkey(), BASKET_AMOUNT),
+        (
+            fixture.initializer_receive_account.pubkey(),
+            EXPECTED_AMOUNT,
+        ),
+    ] {
+        assert_eq!(token_balance(&mut context, account).await, expected_amount);
+    }
+    assert_closed(
+        &mut context,
+        &[
+            fixture.temp_token_account.pubkey(),
+            basket_temp_account.pubkey(),
+            fixture.escrow_account.pubkey(),
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn cancel_refunds_basket_from_its_position() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let fixture = setup(&mut context, &program_id).await;
+    let (basket_temp_account, initializer_basket_account) =
+        create_basket_accounts(&mut context, &fixture, &fixture.initializer.pubkey()).await;
+    let initializer_refund_account = Keypair::new();
+    create_token_account(
+        &mut context,
+        &initializer_refund_account,
+        &fixture.mint_x,
+        &fixture.initializer.pubkey(),
+        &fixture.mint_authority,
+        0,
+    )
+    .await
+    .unwrap();
+
+    // The rent beneficiary goes right after the basket accounts.
+    let rent_beneficiary = Pubkey::new_unique();
+    let init_escrow = instruction::with_rent_beneficiary(
+        init_basket_escrow_instruction(&program_id, &fixture, &basket_temp_account.pubkey()),
+        &rent_beneficiary,
+    );
+    process(&mut context, &[init_escrow], &[&fixture.initializer])
+        .await
+        .unwrap();
+    let escrow = get_escrow(&mut context, &fixture).await;
+    assert_eq!(escrow.basket, vec![basket_temp_account.pubkey()]);
+    assert_eq!(escrow.rent_beneficiary, COption::Some(rent_beneficiary));
+
+    let cancel_escrow = instruction::cancel_escrow(
+        &program_id,
+        &fixture.initializer.pubkey(),
+        &fixture.temp_token_account.pubkey(),
+        &initializer_refund_account.pubkey(),
+        &fixture.escrow_account.pubkey(),
+        // Unchecked without a cancel penalty.
+        &Pubkey::new_unique(),
+    )
+    .unwrap();
+    let cancel_escrow = instruction::with_rent_beneficiary(
+        instruction::with_basket_accounts(
+            cancel_escrow,
+            &[(
+                basket_temp_account.pubkey(),
+                initializer_basket_account.pubkey(),
+            )],
+        ),
+        &rent_beneficiary,
+    );
+    process(&mut context, &[cancel_escrow], &[&fixture.initializer])
+        .await
+        .unwrap();
+
+    for (account, expected_amount) in [
+        (initializer_refund_account.pubkey(), DEPOSIT_AMOUNT),
+        (initializer_basket_account.pubkey(), BASKET_AMOUNT),
+    ] {
+        assert_eq!(token_balance(&mut context, account).await, expected_amount);
+    }
+    assert_closed(
+        &mut context,
+        &[
+            fixture.temp_token_account.pubkey(),
+            basket_temp_account.pubkey(),
+            fixture.escrow_account.pubkey(),
+        ],
+    )
+    .await;
+}
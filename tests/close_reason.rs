@@ -0,0 +1,213 @@
+#![cfg(feature = "test-bpf")]
+
+mod common;
+
+use {
+    bpf_program_template::{
+        instruction::{self, EscrowInstruction, ExpiredEscrow},
+        state::{CloseReason, Escrow},
+    },
+    common::*,
+    solana_program::{
+        instruction::Instruction, program_pack::Pack, pubkey::Pubkey, rent::Rent,
+        system_instruction,
+    },
+    solana_program_test::*,
+    solana_sdk::signature::{Keypair, Signer},
+    spl_associated_token_account::{create_associated_token_account, get_associated_token_address},
+};
+
+/// Far enough out that escrows initialized at different slots still share it.
+const EXPIRY_SLOT: u64 = 1_000_000;
+
+fn init_expiring_escrow_instruction(
+    program_id: &Pubkey,
+    fixture: &Fixture,
+    expiry: u64,
+) -> Instruction {
+    init_escrow_instruction_with(program_id, fixture, |data| {
+        if let EscrowInstruction::InitEscrow { expiry_slot, .. } = data {
+            *expiry_slot = expiry;
+        }
+    })
+}
+
+/// Starts a context with return data kept, and initializes the fixture's escrow expiring at
+/// `expiry`.
+async fn setup_escrow(expiry: u64) -> (ProgramTestContext, Pubkey, Fixture) {
+    let program_id = Pubkey::new_unique();
+    let mut context = start_with_return_data(program_id).await;
+    let fixture = setup(&mut context, &program_id).await;
+    process(
+        &mut context,
+        &[init_expiring_escrow_instruction(
+            &program_id,
+            &fixture,
+            expiry,
+        )],
+        &[&fixture.initializer],
+    )
+    .await
+    .unwrap();
+    (context, program_id, fixture)
+}
+
+#[tokio::test]
+async fn exchange_returns_exchanged() {
+    let (mut context, program_id, fixture) = setup_escrow(EXPIRY_SLOT).await;
+
+    process(
+        &mut context,
+        &expect_return_data(
+            &[exchange_instruction(&program_id, &fixture)],
+            &[CloseReason::Exchanged as u8],
+        ),
+        &[&fixture.taker],
+    )
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn cancel_returns_cancelled() {
+    let (mut context, program_id, fixture) = setup_escrow(EXPIRY_SLOT).await;
+    let initializer_refund_account = Keypair::new();
+    create_token_account(
+        &mut context,
+        &initializer_refund_account,
+        &fixture.mint_x,
+        &fixture.initializer.pubkey(),
+        &fixture.mint_authority,
+        0,
+    )
+    .await
+    .unwrap();
+
+    let cancel_escrow = instruction::cancel_escrow(
+        &program_id,
+        &fixture.initializer.pubkey(),
+        &fixture.temp_token_account.pubkey(),
+        &initializer_refund_account.pubkey(),
+        &fixture.escrow_account.pubkey(),
+        // Unchecked without a cancel penalty.
+        &Pubkey::new_unique(),
+    )
+    .unwrap();
+    process(
+        &mut context,
+        &expect_return_data(&[cancel_escrow], &[CloseReason::Cancelled as u8]),
+        &[&fixture.initializer],
+    )
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn reclaim_returns_expired() {
+    let (mut context, program_id, fixture) = setup_escrow(100).await;
+    let initializer_refund_account =
+        get_associated_token_address(&fixture.initializer.pubkey(), &fixture.mint_x);
+    let create_refund_account = create_associated_token_account(
+        &context.payer.pubkey(),
+        &fixture.initializer.pubkey(),
+        &fixture.mint_x,
+    );
+    process(&mut context, &[create_refund_account], &[])
+        .await
+        .unwrap();
+    context.warp_to_slot(200).unwrap();
+
+    let reclaim = instruction::reclaim_expired_batch(
+        &program_id,
+        &[ExpiredEscrow {
+            escrow_account: fixture.escrow_account.pubkey(),
+            temp_token_account: fixture.temp_token_account.pubkey(),
+            initializer: fixture.initializer.pubkey(),
+            initializer_refund_token_account: initializer_refund_account,
+        }],
+        &TREASURY,
+    )
+    .unwrap();
+    process(
+        &mut context,
+        &expect_return_data(&[reclaim], &[CloseReason::Expired as u8]),
+        &[],
+    )
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn split_returns_split() {
+    let (mut context, program_id, fixture) = setup_escrow(EXPIRY_SLOT).await;
+
+    let split_escrow = instruction::split_escrow(
+        &program_id,
+        &fixture.initializer.pubkey(),
+        &fixture.escrow_account.pubkey(),
+        &fixture.temp_token_account.pubkey(),
+        &fixture.mint_x,
+        2,
+    )
+    .unwrap();
+    process(
+        &mut context,
+        &expect_return_data(&[split_escrow], &[CloseReason::Split as u8]),
+        &[&fixture.initializer],
+    )
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn merge_returns_merged() {
+    let (mut context, program_id, fixture) = setup_escrow(EXPIRY_SLOT).await;
+    let merged_temp_token_account = Keypair::new();
+    let merged_escrow_account = Keypair::new();
+    create_token_account(
+        &mut context,
+        &merged_temp_token_account,
+        &fixture.mint_x,
+        &fixture.initializer.pubkey(),
+        &fixture.mint_authority,
+        DEPOSIT_AMOUNT,
+    )
+    .await
+    .unwrap();
+    let create_escrow_account = system_instruction::create_account(
+        &context.payer.pubkey(),
+        &merged_escrow_account.pubkey(),
+        Rent::default().minimum_balance(Escrow::LEN),
+        Escrow::LEN as u64,
+        &program_id,
+    );
+    // The fixture's escrow, with its temp and escrow accounts swapped for the new ones.
+    let mut init_merged_escrow =
+        init_expiring_escrow_instruction(&program_id, &fixture, EXPIRY_SLOT);
+    init_merged_escrow.accounts[1].pubkey = merged_temp_token_account.pubkey();
+    init_merged_escrow.accounts[3].pubkey = merged_escrow_account.pubkey();
+    process(
+        &mut context,
+        &[create_escrow_account, init_merged_escrow],
+        &[&fixture.initializer, &merged_escrow_account],
+    )
+    .await
+    .unwrap();
+
+    let merge_escrows = instruction::merge_escrows(
+        &program_id,
+        &fixture.initializer.pubkey(),
+        &fixture.escrow_account.pubkey(),
+        &fixture.temp_token_account.pubkey(),
+        &merged_escrow_account.pubkey(),
+        &merged_temp_token_account.pubkey(),
+    )
+    .unwrap();
+    process(
+        &mut context,
+        &expect_return_data(&[merge_escrows], &[CloseReason::Merged as u8]),
+        &[&fixture.initializer],
+    )
+    .await
+    .unwrap();
+}
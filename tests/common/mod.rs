@@ -0,0 +1,556 @@
+//! Accounts and instructions the BPF tests share. Each test file only uses some of them.
+#![allow(dead_code)]
+
+use {
+    bpf_program_template::{
+        error::EscrowError,
+        instruction::{self, EscrowInstruction},
+        processor::Processor,
+        state::{find_config_address, Config, Escrow, ReferenceMode, RoundingMode},
+    },
+    solana_program::{
+        account_info::AccountInfo,
+        entrypoint::ProgramResult,
+        instruction::{Instruction, InstructionError},
+        msg,
+        native_token::LAMPORTS_PER_SOL,
+        program::{get_return_data, set_return_data},
+        program_error::ProgramError,
+        program_option::COption,
+        program_pack::Pack,
+        program_stubs::{self, SyscallStubs},
+        pubkey::Pubkey,
+        rent::Rent,
+        system_instruction,
+    },
+    solana_program_test::*,
+    solana_sdk::{
+        account::{Account, AccountSharedData},
+        signature::{Keypair, Signer},
+        transaction::{Transaction, TransactionError},
+        transport::TransportError,
+    },
+    spl_token::state::{Account as TokenAccount, Mint},
+    std::{
+        cell::RefCell,
+        ptr,
+        sync::{
+            atomic::{AtomicPtr, Ordering},
+            Once,
+        },
+    },
+};
+
+pub const DEPOSIT_AMOUNT: u64 = 1_000;
+pub const EXPECTED_AMOUNT: u64 = 500;
+/// Fee of the config `setup_with_fee` sets.
+pub const FEE_BPS: u16 = 100;
+/// `FEE_BPS` of `EXPECTED_AMOUNT`.
+pub const FEE: u64 = 5;
+/// Treasury set in the config.
+pub const TREASURY: Pubkey = Pubkey::new_from_array([7; 32]);
+
+/// The program with a config that charges no fees.
+pub fn program_test(program_id: Pubkey) -> ProgramTest {
+    let mut program_test = ProgramTest::new(
+        "bpf_program_template",
+        program_id,
+        processor!(Processor::process),
+    );
+
+    // Stand-in for `InitConfig`, which only the deployer key can sign.
+    let config = Config {
+        is_initialized: true,
+        admin: Pubkey::new_unique(),
+        fee_bps: 0,
+        paused: false,
+        treasury: TREASURY,
+        init_fee: 0,
+        pending_admin: Pubkey::default(),
+        fee_overrides: vec![],
+        allowed_relayer: COption::None,
+        address_cooldown_slots: 0,
+        secondary_treasury: Pubkey::default(),
+        secondary_treasury_bps: 0,
+    };
+    let mut data = vec![0; Config::LEN];
+    Config::pack(config, &mut data).unwrap();
+    program_test.add_account(
+        find_config_address(&program_id).0,
+        Account {
+            lamports: Rent::default().minimum_balance(Config::LEN),
+            data,
+            owner: program_id,
+            ..Account::default()
+        },
+    );
+    program_test
+}
+
+pub async fn create_mint(
+    context: &mut ProgramTestContext,
+    mint: &Keypair,
+    authority: &Pubkey,
+) -> Result<(), TransportError> {
+    let rent = Rent::default();
+    let transaction = Transaction::new_signed_with_payer(
+        &[
+            system_instruction::create_account(
+                &context.payer.pubkey(),
+                &mint.pubkey(),
+                rent.minimum_balance(Mint::LEN),
+                Mint::LEN as u64,
+                &spl_token::id(),
+            ),
+            spl_token::instruction::initialize_mint(
+                &spl_token::id(),
+                &mint.pubkey(),
+                authority,
+                None,
+                0,
+            )
+            .unwrap(),
+        ],
+        Some(&context.payer.pubkey()),
+        &[&context.payer, mint],
+        context.last_blockhash,
+    );
+    context.banks_client.process_transaction(transaction).await
+}
+
+pub async fn create_token_account(
+    context: &mut ProgramTestContext,
+    account: &Keypair,
+    mint: &Pubkey,
+    owner: &Pubkey,
+    mint_authority: &Keypair,
+    amount: u64,
+) -> Result<(), TransportError> {
+    let rent = Rent::default();
+    let mut instructions = vec![
+        system_instruction::create_account(
+            &context.payer.pubkey(),
+            &account.pubkey(),
+            rent.minimum_balance(TokenAccount::LEN),
+            TokenAccount::LEN as u64,
+            &spl_token::id(),
+        ),
+        spl_token::instruction::initialize_account(
+            &spl_token::id(),
+            &account.pubkey(),
+            mint,
+            owner,
+        )
+        .unwrap(),
+    ];
+    let mut signers = vec![&context.payer, account];
+    if amount > 0 {
+        signers.push(mint_authority);
+        instructions.push(
+            spl_token::instruction::mint_to(
+                &spl_token::id(),
+                mint,
+                &account.pubkey(),
+                &mint_authority.pubkey(),
+                &[],
+                amount,
+            )
+            .unwrap(),
+        );
+    }
+    let transaction = Transaction::new_signed_with_payer(
+        &instructions,
+        Some(&context.payer.pubkey()),
+        &signers,
+        context.last_blockhash,
+    );
+    context.banks_client.process_transaction(transaction).await
+}
+
+pub async fn token_balance(context: &mut ProgramTestContext, account: Pubkey) -> u64 {
+    let account = context
+        .banks_client
+        .get_account(account)
+        .await
+        .unwrap()
+        .unwrap();
+    TokenAccount::unpack(&account.data).unwrap().amount
+}
+
+/// An initializer depositing `DEPOSIT_AMOUNT` of X for `EXPECTED_AMOUNT` of Y, and a taker with
+/// just enough Y to fill it.
+pub struct Fixture {
+    pub mint_x: Pubkey,
+    pub mint_y: Pubkey,
+    pub mint_authority: Keypair,
+    pub initializer: Keypair,
+    pub taker: Keypair,
+    pub temp_token_account: Keypair,
+    pub initializer_receive_account: Keypair,
+    pub taker_send_account: Keypair,
+    pub taker_receive_account: Keypair,
+    pub escrow_account: Keypair,
+}
+
+pub async fn setup(context: &mut ProgramTestContext, program_id: &Pubkey) -> Fixture {
+    let mint_authority = Keypair::new();
+    let mint_x = Keypair::new();
+    let mint_y = Keypair::new();
+    create_mint(context, &mint_x, &mint_authority.pubkey())
+        .await
+        .unwrap();
+    create_mint(context, &mint_y, &mint_authority.pubkey())
+        .await
+        .unwrap();
+
+    let fixture = Fixture {
+        mint_x: mint_x.pubkey(),
+        mint_y: mint_y.pubkey(),
+        mint_authority,
+        initializer: Keypair::new(),
+        taker: Keypair::new(),
+        temp_token_account: Keypair::new(),
+        initializer_receive_account: Keypair::new(),
+        taker_send_account: Keypair::new(),
+        taker_receive_account: Keypair::new(),
+        escrow_account: Keypair::new(),
+    };
+
+    let accounts = [
+        (
+            &fixture.temp_token_account,
+            &mint_x,
+            &fixture.initializer,
+            DEPOSIT_AMOUNT,
+        ),
+        (
+            &fixture.initializer_receive_account,
+            &mint_y,
+            &fixture.initializer,
+            0,
+        ),
+        (
+            &fixture.taker_send_account,
+            &mint_y,
+            &fixture.taker,
+            EXPECTED_AMOUNT,
+        ),
+        (&fixture.taker_receive_account, &mint_x, &fixture.taker, 0),
+    ];
+    for (account, mint, owner, amount) in accounts {
+        create_token_account(
+            context,
+            account,
+            &mint.pubkey(),
+            &owner.pubkey(),
+            &fixture.mint_authority,
+            amount,
+        )
+        .await
+        .unwrap();
+    }
+
+    let rent = Rent::default();
+    let mut instructions = vec![system_instruction::create_account(
+        &context.payer.pubkey(),
+        &fixture.escrow_account.pubkey(),
+        rent.minimum_balance(Escrow::LEN),
+        Escrow::LEN as u64,
+        program_id,
+    )];
+    for wallet in [&fixture.initializer, &fixture.taker] {
+        instructions.push(system_instruction::transfer(
+            &context.payer.pubkey(),
+            &wallet.pubkey(),
+            LAMPORTS_PER_SOL,
+        ));
+    }
+    let transaction = Transaction::new_signed_with_payer(
+        &instructions,
+        Some(&context.payer.pubkey()),
+        &[&context.payer, &fixture.escrow_account],
+        context.last_blockhash,
+    );
+    context
+        .banks_client
+        .process_transaction(transaction)
+        .await
+        .unwrap();
+
+    fixture
+}
+
+/// Initializes the fixture's escrow with every optional feature left off.
+pub fn init_escrow_instruction(program_id: &Pubkey, fixture: &Fixture) -> Instruction {
+    instruction::init_escrow(
+        program_id,
+        &fixture.initializer.pubkey(),
+        &fixture.temp_token_account.pubkey(),
+        &fixture.initializer_receive_account.pubkey(),
+        &fixture.mint_y,
+        &fixture.escrow_account.pubkey(),
+        &TREASURY,
+        EXPECTED_AMOUNT,
+        COption::None,
+        0,
+        0,
+        RoundingMode::FloorToTaker,
+        vec![],
+        vec![],
+        ReferenceMode::Plain,
+        0,
+        0,
+        0,
+        0,
+        0,
+        [0; 32],
+        false,
+        0,
+        0,
+        COption::None,
+        vec![],
+    )
+    .unwrap()
+}
+
+/// `init_escrow_instruction` with its data changed by `edit`, for turning on a feature that
+/// doesn't take accounts of its own.
+pub fn init_escrow_instruction_with(
+    program_id: &Pubkey,
+    fixture: &Fixture,
+    edit: impl FnOnce(&mut EscrowInstruction),
+) -> Instruction {
+    let mut init_escrow = init_escrow_instruction(program_id, fixture);
+    let mut data = EscrowInstruction::unpack(&init_escrow.data).unwrap();
+    edit(&mut data);
+    init_escrow.data = data.pack();
+    init_escrow
+}
+
+/// Fills the fixture's escrow in one go.
+pub fn exchange_instruction(program_id: &Pubkey, fixture: &Fixture) -> Instruction {
+    instruction::exchange(
+        program_id,
+        &fixture.taker.pubkey(),
+        &fixture.taker_send_account.pubkey(),
+        &fixture.mint_y,
+        &fixture.taker_receive_account.pubkey(),
+        &fixture.temp_token_account.pubkey(),
+        &fixture.initializer.pubkey(),
+        &fixture.initializer_receive_account.pubkey(),
+        &fixture.escrow_account.pubkey(),
+        // Unchecked while the fee is 0.
+        &Pubkey::new_unique(),
+        // Unchecked while the exchange leaves no residual.
+        &Pubkey::new_unique(),
+        None,
+        DEPOSIT_AMOUNT,
+        &[],
+        EXPECTED_AMOUNT,
+    )
+    .unwrap()
+}
+
+/// Signs and processes `instructions`, paid for by the context's payer.
+pub async fn process(
+    context: &mut ProgramTestContext,
+    instructions: &[Instruction],
+    signers: &[&Keypair],
+) -> Result<(), TransportError> {
+    let mut all_signers = vec![&context.payer];
+    all_signers.extend_from_slice(signers);
+    let transaction = Transaction::new_signed_with_payer(
+        instructions,
+        Some(&context.payer.pubkey()),
+        &all_signers,
+        context.last_blockhash,
+    );
+    context.banks_client.process_transaction(transaction).await
+}
+
+pub fn assert_escrow_error(error: TransportError, expected: EscrowError) {
+    assert!(matches!(
+        error,
+        TransportError::TransactionError(TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(code),
+        )) if code == expected as u32
+    ));
+}
+
+/// Stand-in for an `UpdateConfig` of the fee, which only the config's admin can sign.
+pub async fn set_fee_bps(context: &mut ProgramTestContext, program_id: &Pubkey, fee_bps: u16) {
+    let config_address = find_config_address(program_id).0;
+    let mut account = context
+        .banks_client
+        .get_account(config_address)
+        .await
+        .unwrap()
+        .unwrap();
+    let mut config = Config::unpack(&account.data).unwrap();
+    config.fee_bps = fee_bps;
+    Config::pack(config, &mut account.data).unwrap();
+    context.set_account(&config_address, &AccountSharedData::from(account));
+}
+
+/// Opens the fixture's escrow with a `FEE_BPS` fee, funds the taker for it and returns the
+/// treasury's token account the fee goes to.
+pub async fn setup_with_fee(
+    context: &mut ProgramTestContext,
+    program_id: &Pubkey,
+) -> (Fixture, Keypair) {
+    let fixture = setup(context, program_id).await;
+    set_fee_bps(context, program_id, FEE_BPS).await;
+    let treasury_token_account = Keypair::new();
+    create_token_account(
+        context,
+        &treasury_token_account,
+        &fixture.mint_y,
+        &TREASURY,
+        &fixture.mint_authority,
+        0,
+    )
+    .await
+    .unwrap();
+    let mint_fee = spl_token::instruction::mint_to(
+        &spl_token::id(),
+        &fixture.mint_y,
+        &fixture.taker_send_account.pubkey(),
+        &fixture.mint_authority.pubkey(),
+        &[],
+        FEE,
+    )
+    .unwrap();
+    process(
+        context,
+        &[mint_fee, init_escrow_instruction(program_id, &fixture)],
+        &[&fixture.mint_authority, &fixture.initializer],
+    )
+    .await
+    .unwrap();
+    (fixture, treasury_token_account)
+}
+
+/// Program checking the return data the instructions before it in the transaction left, see
+/// `expect_return_data`.
+pub const RETURN_DATA_PROBE: Pubkey = Pubkey::new_from_array([9; 32]);
+
+thread_local! {
+    /// Return data set on this thread, i.e. by the transaction it runs.
+    // Older clippy mistakes the `const` initializer for a `const` item.
+    #[allow(clippy::declare_interior_mutable_const)]
+    static RETURN_DATA: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+/// The syscall stubs `solana-program-test` installed, which drop return data. Leaked once set.
+static PROGRAM_TEST_STUBS: AtomicPtr<Box<dyn SyscallStubs>> = AtomicPtr::new(ptr::null_mut());
+
+/// `solana-program-test`'s stubs, keeping return data like the runtime: the last the program set,
+/// cleared by each CPI.
+struct ReturnDataStubs;
+
+impl ReturnDataStubs {
+    fn inner(&self) -> &dyn SyscallStubs {
+        // Only unset while being swapped in.
+        loop {
+            let stubs = PROGRAM_TEST_STUBS.load(Ordering::Acquire);
+            if !stubs.is_null() {
+                // SAFETY: Set once, from a box that's never freed.
+                return unsafe { (*stubs).as_ref() };
+            }
+            std::hint::spin_loop();
+        }
+    }
+}
+
+impl SyscallStubs for ReturnDataStubs {
+    fn sol_log(&self, message: &str) {
+        self.inner().sol_log(message)
+    }
+    fn sol_log_compute_units(&self) {
+        self.inner().sol_log_compute_units()
+    }
+    fn sol_invoke_signed(
+        &self,
+        instruction: &Instruction,
+        account_infos: &[AccountInfo],
+        signers_seeds: &[&[&[u8]]],
+    ) -> ProgramResult {
+        RETURN_DATA.with(|return_data| return_data.borrow_mut().clear());
+        self.inner()
+            .sol_invoke_signed(instruction, account_infos, signers_seeds)
+    }
+    fn sol_get_clock_sysvar(&self, var_addr: *mut u8) -> u64 {
+        self.inner().sol_get_clock_sysvar(var_addr)
+    }
+    fn sol_get_epoch_schedule_sysvar(&self, var_addr: *mut u8) -> u64 {
+        self.inner().sol_get_epoch_schedule_sysvar(var_addr)
+    }
+    fn sol_get_fees_sysvar(&self, var_addr: *mut u8) -> u64 {
+        self.inner().sol_get_fees_sysvar(var_addr)
+    }
+    fn sol_get_rent_sysvar(&self, var_addr: *mut u8) -> u64 {
+        self.inner().sol_get_rent_sysvar(var_addr)
+    }
+    fn sol_get_return_data(&self) -> Option<(Pubkey, Vec<u8>)> {
+        let data = RETURN_DATA.with(|return_data| return_data.borrow().clone());
+        (!data.is_empty()).then(|| (Pubkey::default(), data))
+    }
+    fn sol_set_return_data(&mut self, data: &[u8]) {
+        RETURN_DATA.with(|return_data| *return_data.borrow_mut() = data.to_vec());
+    }
+    fn sol_log_data(&self, fields: &[&[u8]]) {
+        self.inner().sol_log_data(fields)
+    }
+}
+
+/// With an empty `data`, clears the return data. Otherwise fails unless the return data is
+/// `data`.
+fn check_return_data(
+    _program_id: &Pubkey,
+    _accounts: &[AccountInfo],
+    data: &[u8],
+) -> ProgramResult {
+    if !data.is_empty() {
+        let return_data = get_return_data().map(|(_, return_data)| return_data);
+        if return_data.as_deref() != Some(data) {
+            msg!("Expected return data {:?}, got {:?}", data, return_data);
+            return Err(ProgramError::InvalidInstructionData);
+        }
+    }
+    set_return_data(&[]);
+    Ok(())
+}
+
+/// The program with `RETURN_DATA_PROBE`, starting with the return data kept (see
+/// `expect_return_data`).
+pub async fn start_with_return_data(program_id: Pubkey) -> ProgramTestContext {
+    let mut program_test = program_test(program_id);
+    program_test.add_program(
+        "return_data_probe",
+        RETURN_DATA_PROBE,
+        processor!(check_return_data),
+    );
+    // Starting installs `solana-program-test`'s stubs, which are then wrapped.
+    let context = program_test.start_with_context().await;
+    static WRAP_STUBS: Once = Once::new();
+    WRAP_STUBS.call_once(|| {
+        let stubs = program_stubs::set_syscall_stubs(Box::new(ReturnDataStubs));
+        PROGRAM_TEST_STUBS.store(Box::into_raw(Box::new(stubs)), Ordering::Release);
+    });
+    context
+}
+
+/// `instructions`, followed by a check that they leave `expected` as return data. Needs a context
+/// from `start_with_return_data`.
+pub fn expect_return_data(instructions: &[Instruction], expected: &[u8]) -> Vec<Instruction> {
+    let probe = |data: &[u8]| Instruction {
+        program_id: RETURN_DATA_PROBE,
+        accounts: vec![],
+        data: data.to_vec(),
+    };
+    let mut checked = vec![probe(&[])];
+    checked.extend_from_slice(instructions);
+    checked.push(probe(expected));
+    checked
+}
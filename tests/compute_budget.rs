@@ -0,0 +1,94 @@
+#![cfg(feature = "test-bpf")]
+
+mod common;
+
+use {
+    bpf_program_template::instruction, common::*, solana_program::pubkey::Pubkey,
+    solana_program_test::*, solana_sdk::signature::Signer,
+};
+
+/// Compute unit budgets per instruction. These are upper bounds with some headroom over what the
+/// instructions currently consume; a change that pushes an instruction over its budget fails
+/// here and should either be optimized or come with a deliberate bump of the constant (and the
+/// README table).
+const INIT_ESCROW_MAX_UNITS: u64 = 20_000;
+const EXCHANGE_MAX_UNITS: u64 = 50_000;
+
+async fn start(program_id: Pubkey, max_units: u64) -> ProgramTestContext {
+    let mut program_test = program_test(program_id);
+    program_test.set_compute_max_units(max_units);
+    program_test.start_with_context().await
+}
+
+#[tokio::test]
+async fn init_escrow_stays_within_compute_budget() {
+    let program_id = Pubkey::new_unique();
+    let mut context = start(program_id, INIT_ESCROW_MAX_UNITS).await;
+    let fixture = setup(&mut context, &program_id).await;
+
+    process(
+        &mut context,
+        &[init_escrow_instruction(&program_id, &fixture)],
+        &[&fixture.initializer],
+    )
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn exchange_stays_within_compute_budget() {
+    let program_id = Pubkey::new_unique();
+    let mut context = start(program_id, EXCHANGE_MAX_UNITS).await;
+    let fixture = setup(&mut context, &program_id).await;
+
+    process(
+        &mut context,
+        &[init_escrow_instruction(&program_id, &fixture)],
+        &[&fixture.initializer],
+    )
+    .await
+    .unwrap();
+    process(
+        &mut context,
+        &[exchange_instruction(&program_id, &fixture)],
+        &[&fixture.taker],
+    )
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn exchange_with_authority_cache_stays_within_compute_budget() {
+    let program_id = Pubkey::new_unique();
+    let mut context = start(program_id, EXCHANGE_MAX_UNITS).await;
+    let fixture = setup(&mut context, &program_id).await;
+
+    let cache_authority =
+        instruction::cache_authority(&program_id, &context.payer.pubkey()).unwrap();
+    process(
+        &mut context,
+        &[
+            cache_authority,
+            init_escrow_instruction(&program_id, &fixture),
+        ],
+        &[&fixture.initializer],
+    )
+    .await
+    .unwrap();
+    process(
+        &mut context,
+        &[instruction::with_authority_cache(
+            exchange_instruction(&program_id, &fixture),
+            &program_id,
+        )],
+        &[&fixture.taker],
+    )
+    .await
+    .unwrap();
+
+    // Same outcome as the derived path: the taker got the whole deposit.
+    assert_eq!(
+        token_balance(&mut context, fixture.taker_receive_account.pubkey()).await,
+        DEPOSIT_AMOUNT
+    );
+}
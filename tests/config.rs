@@ -0,0 +1,154 @@
+#![cfg(feature = "test-bpf")]
+
+mod common;
+
+use {
+    bpf_program_template::{
+        error::EscrowError,
+        instruction::{self, ConfigArgs},
+        processor::Processor,
+        state::{find_config_address, Config},
+    },
+    common::*,
+    solana_program::{
+        bpf_loader_upgradeable, program_option::COption, program_pack::Pack, pubkey::Pubkey,
+        rent::Rent,
+    },
+    solana_program_test::*,
+    solana_sdk::{
+        account::Account,
+        signature::{Keypair, Signer},
+    },
+};
+
+/// The program without a config yet, deployed with `upgrade_authority`.
+fn program_test_deployed_by(program_id: Pubkey, upgrade_authority: &Pubkey) -> ProgramTest {
+    let mut program_test = ProgramTest::new(
+        "bpf_program_template",
+        program_id,
+        processor!(Processor::process),
+    );
+    // `UpgradeableLoaderState::ProgramData`: the variant, the slot and the authority, with no
+    // program bits after it.
+    let mut data = 3u32.to_le_bytes().to_vec();
+    data.extend_from_slice(&0u64.to_le_bytes());
+    data.push(1);
+    data.extend_from_slice(upgrade_authority.as_ref());
+    program_test.add_account(
+        Pubkey::find_program_address(&[program_id.as_ref()], &bpf_loader_upgradeable::id()).0,
+        Account {
+            lamports: Rent::default().minimum_balance(data.len()),
+            data,
+            owner: bpf_loader_upgradeable::id(),
+            ..Account::default()
+        },
+    );
+    program_test
+}
+
+fn config_args(admin: Pubkey) -> ConfigArgs {
+    ConfigArgs {
+        admin,
+        fee_bps: 0,
+        paused: false,
+        treasury: TREASURY,
+        init_fee: 0,
+        fee_overrides: vec![],
+        allowed_relayer: COption::None,
+        address_cooldown_slots: 0,
+        secondary_treasury: Pubkey::default(),
+        secondary_treasury_bps: 0,
+    }
+}
+
+#[tokio::test]
+async fn upgrade_authority_inits_config() {
+    let program_id = Pubkey::new_unique();
+    let upgrade_authority = Keypair::new();
+    let admin = Pubkey::new_unique();
+    let mut context = program_test_deployed_by(program_id, &upgrade_authority.pubkey())
+        .start_with_context()
+        .await;
+    let fund_upgrade_authority = solana_program::system_instruction::transfer(
+        &context.payer.pubkey(),
+        &upgrade_authority.pubkey(),
+        Rent::default().minimum_balance(Config::LEN) + 1_000_000,
+    );
+
+    process(
+        &mut context,
+        &[
+            fund_upgrade_authority,
+            instruction::init_config(&program_id, &upgrade_authority.pubkey(), config_args(admin))
+                .unwrap(),
+        ],
+        &[&upgrade_authority],
+    )
+    .await
+    .unwrap();
+
+    let config = context
+        .banks_client
+        .get_account(find_config_address(&program_id).0)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(Config::unpack(&config.data).unwrap().admin, admin);
+}
+
+#[tokio::test]
+async fn only_upgrade_authority_inits_config() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test_deployed_by(program_id, &Pubkey::new_unique())
+        .start_with_context()
+        .await;
+    let payer = context.payer.pubkey();
+
+    let error = process(
+        &mut context,
+        &[instruction::init_config(&program_id, &payer, config_args(payer)).unwrap()],
+        &[],
+    )
+    .await
+    .unwrap_err();
+    assert_escrow_error(error, EscrowError::Unauthorized);
+}
+
+#[tokio::test]
+async fn only_admin_updates_config() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let impostor = Keypair::new();
+    let config_address = find_config_address(&program_id).0;
+    let config = context
+        .banks_client
+        .get_account(config_address)
+        .await
+        .unwrap()
+        .unwrap();
+    let admin = Config::unpack(&config.data).unwrap().admin;
+
+    let error = process(
+        &mut context,
+        &[instruction::update_config(
+            &program_id,
+            &impostor.pubkey(),
+            ConfigArgs {
+                fee_bps: FEE_BPS,
+                ..config_args(admin)
+            },
+        )
+        .unwrap()],
+        &[&impostor],
+    )
+    .await
+    .unwrap_err();
+    assert_escrow_error(error, EscrowError::Unauthorized);
+    let config = context
+        .banks_client
+        .get_account(config_address)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(Config::unpack(&config.data).unwrap().fee_bps, 0);
+}
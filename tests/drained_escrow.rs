@@ -0,0 +1,91 @@
+#![cfg(feature = "test-bpf")]
+
+mod common;
+
+use {
+    bpf_program_template::{error::EscrowError, state::Escrow},
+    common::*,
+    solana_program::{program_pack::Pack, pubkey::Pubkey},
+    solana_program_test::*,
+    solana_sdk::{account::AccountSharedData, signature::Signer},
+    spl_token::state::Account as TokenAccount,
+};
+
+/// Initializes the fixture's escrow, then rewrites `account` with `edit`, as no instruction would.
+async fn init_and_corrupt<T: Pack>(
+    context: &mut ProgramTestContext,
+    program_id: &Pubkey,
+    fixture: &Fixture,
+    account: Pubkey,
+    edit: impl FnOnce(&mut T),
+) {
+    process(
+        context,
+        &[init_escrow_instruction(program_id, fixture)],
+        &[&fixture.initializer],
+    )
+    .await
+    .unwrap();
+    let mut stored = context
+        .banks_client
+        .get_account(account)
+        .await
+        .unwrap()
+        .unwrap();
+    let mut state = T::unpack_unchecked(&stored.data).unwrap();
+    edit(&mut state);
+    T::pack(state, &mut stored.data).unwrap();
+    context.set_account(&account, &AccountSharedData::from(stored));
+}
+
+#[tokio::test]
+async fn exchange_rejects_zero_expected_amount() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let fixture = setup(&mut context, &program_id).await;
+    init_and_corrupt(
+        &mut context,
+        &program_id,
+        &fixture,
+        fixture.escrow_account.pubkey(),
+        |escrow: &mut Escrow| escrow.expected_amount = 0,
+    )
+    .await;
+
+    let error = process(
+        &mut context,
+        &[exchange_instruction(&program_id, &fixture)],
+        &[&fixture.taker],
+    )
+    .await
+    .unwrap_err();
+    assert_escrow_error(error, EscrowError::InvalidEscrowState);
+}
+
+#[tokio::test]
+async fn exchange_rejects_empty_deposit() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let fixture = setup(&mut context, &program_id).await;
+    init_and_corrupt(
+        &mut context,
+        &program_id,
+        &fixture,
+        fixture.temp_token_account.pubkey(),
+        |temp_token_account: &mut TokenAccount| temp_token_account.amount = 0,
+    )
+    .await;
+
+    let error = process(
+        &mut context,
+        &[exchange_instruction(&program_id, &fixture)],
+        &[&fixture.taker],
+    )
+    .await
+    .unwrap_err();
+    assert_escrow_error(error, EscrowError::InvalidEscrowState);
+    assert_eq!(
+        token_balance(&mut context, fixture.taker_send_account.pubkey()).await,
+        EXPECTED_AMOUNT
+    );
+}
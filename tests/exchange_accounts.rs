@@ -0,0 +1,36 @@
+#![cfg(feature = "test-bpf")]
+
+mod common;
+
+use {bpf_program_template::error::EscrowError, common::*, solana_program::pubkey::Pubkey};
+
+#[tokio::test]
+async fn exchange_reports_missing_accounts_before_wrong_ones() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let fixture = setup(&mut context, &program_id).await;
+
+    process(
+        &mut context,
+        &[init_escrow_instruction(&program_id, &fixture)],
+        &[&fixture.initializer],
+    )
+    .await
+    .unwrap();
+
+    // A mint where the taker's source token account goes, and the accounts cut short after it.
+    let mut exchange = exchange_instruction(&program_id, &fixture);
+    exchange.accounts[1].pubkey = fixture.mint_y;
+    let mut truncated_exchange = exchange.clone();
+    truncated_exchange.accounts.truncate(2);
+
+    let error = process(&mut context, &[truncated_exchange], &[&fixture.taker])
+        .await
+        .unwrap_err();
+    assert_escrow_error(error, EscrowError::NotEnoughAccounts);
+
+    let error = process(&mut context, &[exchange], &[&fixture.taker])
+        .await
+        .unwrap_err();
+    assert_escrow_error(error, EscrowError::ExpectedTokenAccountGotMint);
+}
@@ -0,0 +1,59 @@
+#![cfg(feature = "test-bpf")]
+
+mod common;
+
+use {
+    bpf_program_template::{error::EscrowError, state::find_escrow_authority_address},
+    common::*,
+    solana_program::{program_pack::Pack, pubkey::Pubkey},
+    solana_sdk::signature::Signer,
+    spl_token::state::Account as TokenAccount,
+};
+
+#[tokio::test]
+async fn init_escrow_hands_temp_account_to_escrow_authority() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let fixture = setup(&mut context, &program_id).await;
+
+    process(
+        &mut context,
+        &[init_escrow_instruction(&program_id, &fixture)],
+        &[&fixture.initializer],
+    )
+    .await
+    .unwrap();
+
+    let temp_token_account = context
+        .banks_client
+        .get_account(fixture.temp_token_account.pubkey())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        TokenAccount::unpack(&temp_token_account.data)
+            .unwrap()
+            .owner,
+        find_escrow_authority_address(&program_id).0
+    );
+}
+
+#[tokio::test]
+async fn init_escrow_rejects_unfunded_escrow_account() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let fixture = setup(&mut context, &program_id).await;
+
+    // An escrow account that was never created, so it has no lamports at all.
+    let unfunded_escrow_account = Pubkey::new_unique();
+    let mut init_escrow = init_escrow_instruction(&program_id, &fixture);
+    for account in &mut init_escrow.accounts {
+        if account.pubkey == fixture.escrow_account.pubkey() {
+            account.pubkey = unfunded_escrow_account;
+        }
+    }
+    let error = process(&mut context, &[init_escrow], &[&fixture.initializer])
+        .await
+        .unwrap_err();
+    assert_escrow_error(error, EscrowError::NotRentExempt);
+}
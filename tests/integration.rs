@@ -0,0 +1,31 @@
+#![cfg(feature = "test-bpf")]
+
+use {
+    assert_matches::*,
+    bpf_program_template::{instruction, processor::Processor},
+    solana_program::pubkey::Pubkey,
+    solana_program_test::*,
+    solana_sdk::{signature::Signer, transaction::Transaction},
+};
+
+#[tokio::test]
+async fn test_transaction() {
+    solana_logger::setup_with_default("solana_program_runtime=debug");
+    let program_id = Pubkey::new_unique();
+
+    let (mut banks_client, payer, recent_blockhash) = ProgramTest::new(
+        "bpf_program_template",
+        program_id,
+        processor!(Processor::process),
+    )
+    .start()
+    .await;
+
+    let mut transaction = Transaction::new_with_payer(
+        &[instruction::get_version(&program_id).unwrap()],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+
+    assert_matches!(banks_client.process_transaction(transaction).await, Ok(()));
+}
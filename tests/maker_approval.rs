@@ -0,0 +1,185 @@
+#![cfg(feature = "test-bpf")]
+
+mod common;
+
+use {
+    bpf_program_template::{
+        error::EscrowError,
+        instruction::{self, EscrowInstruction},
+    },
+    common::*,
+    solana_program::{
+        instruction::{Instruction, InstructionError},
+        program_option::COption,
+        pubkey::Pubkey,
+    },
+    solana_program_test::*,
+    solana_sdk::{
+        signature::{Keypair, Signer},
+        transaction::{Transaction, TransactionError},
+        transport::TransportError,
+    },
+};
+
+/// Opens the fixture's escrow with `maker_keypair` as its maker.
+async fn setup_with_maker(
+    context: &mut ProgramTestContext,
+    program_id: &Pubkey,
+    maker_keypair: &Keypair,
+) -> Fixture {
+    let fixture = setup(context, program_id).await;
+    let init_escrow = init_escrow_instruction_with(program_id, &fixture, |data| {
+        if let EscrowInstruction::InitEscrow { maker, .. } = data {
+            *maker = COption::Some(maker_keypair.pubkey());
+        }
+    });
+    process(context, &[init_escrow], &[&fixture.initializer])
+        .await
+        .unwrap();
+    fixture
+}
+
+/// The maker's approval of the taker filling `amount`, followed by the fill.
+fn approved_fill(
+    program_id: &Pubkey,
+    fixture: &Fixture,
+    maker_keypair: &Keypair,
+    amount: u64,
+    fills_done: u8,
+    expected_amount: u64,
+) -> [Instruction; 2] {
+    let message = instruction::maker_approval_message(
+        &fixture.escrow_account.pubkey(),
+        &fixture.taker.pubkey(),
+        amount,
+        fills_done,
+        expected_amount,
+    );
+    let signature = maker_keypair.sign_message(&message);
+    let signature: [u8; 64] = signature.as_ref().try_into().unwrap();
+    let exchange = instruction::partial_exchange(
+        program_id,
+        &fixture.taker.pubkey(),
+        &fixture.taker_send_account.pubkey(),
+        &fixture.mint_y,
+        &fixture.taker_receive_account.pubkey(),
+        &fixture.temp_token_account.pubkey(),
+        &fixture.initializer.pubkey(),
+        &fixture.initializer_receive_account.pubkey(),
+        &fixture.escrow_account.pubkey(),
+        // Unchecked while the fee is 0.
+        &Pubkey::new_unique(),
+        // Unchecked while the exchange leaves no residual.
+        &Pubkey::new_unique(),
+        None,
+        amount,
+        &[],
+        EXPECTED_AMOUNT,
+    )
+    .unwrap();
+    [
+        instruction::verify_maker_approval(&maker_keypair.pubkey(), &signature, &message),
+        instruction::with_maker_approval(exchange),
+    ]
+}
+
+#[tokio::test]
+async fn exchange_requires_maker_approval_when_escrow_has_a_maker() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let maker_keypair = Keypair::new();
+    let fixture = setup_with_maker(&mut context, &program_id, &maker_keypair).await;
+
+    let exchange = instruction::with_maker_approval(exchange_instruction(&program_id, &fixture));
+    let error = process(
+        &mut context,
+        std::slice::from_ref(&exchange),
+        &[&fixture.taker],
+    )
+    .await
+    .unwrap_err();
+    assert_escrow_error(error, EscrowError::MissingMakerSignature);
+
+    let message = instruction::maker_approval_message(
+        &fixture.escrow_account.pubkey(),
+        &fixture.taker.pubkey(),
+        DEPOSIT_AMOUNT,
+        0,
+        EXPECTED_AMOUNT,
+    );
+    let signature = maker_keypair.sign_message(&message);
+    let signature: [u8; 64] = signature.as_ref().try_into().unwrap();
+    process(
+        &mut context,
+        &[
+            instruction::verify_maker_approval(&maker_keypair.pubkey(), &signature, &message),
+            exchange,
+        ],
+        &[&fixture.taker],
+    )
+    .await
+    .unwrap();
+    assert!(context
+        .banks_client
+        .get_account(fixture.escrow_account.pubkey())
+        .await
+        .unwrap()
+        .is_none());
+}
+
+#[tokio::test]
+async fn maker_approval_covers_a_single_fill() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let maker_keypair = Keypair::new();
+    let fixture = setup_with_maker(&mut context, &program_id, &maker_keypair).await;
+
+    let half = DEPOSIT_AMOUNT / 2;
+    let first_fill = approved_fill(
+        &program_id,
+        &fixture,
+        &maker_keypair,
+        half,
+        0,
+        EXPECTED_AMOUNT,
+    );
+    process(&mut context, &first_fill, &[&fixture.taker])
+        .await
+        .unwrap();
+
+    // The same approval again, paid for by the taker so the transaction isn't a duplicate.
+    let replay = Transaction::new_signed_with_payer(
+        &first_fill,
+        Some(&fixture.taker.pubkey()),
+        &[&fixture.taker],
+        context.last_blockhash,
+    );
+    let error = context
+        .banks_client
+        .process_transaction(replay)
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        error,
+        TransportError::TransactionError(TransactionError::InstructionError(
+            1,
+            InstructionError::Custom(code),
+        )) if code == EscrowError::MissingMakerSignature as u32
+    ));
+
+    let second_fill = approved_fill(
+        &program_id,
+        &fixture,
+        &maker_keypair,
+        half,
+        1,
+        EXPECTED_AMOUNT / 2,
+    );
+    process(&mut context, &second_fill, &[&fixture.taker])
+        .await
+        .unwrap();
+    assert_eq!(
+        token_balance(&mut context, fixture.taker_receive_account.pubkey()).await,
+        DEPOSIT_AMOUNT
+    );
+}
@@ -0,0 +1,87 @@
+#![cfg(feature = "test-bpf")]
+
+mod common;
+
+use {
+    bpf_program_template::{
+        error::EscrowError,
+        instruction::{self, EscrowInstruction},
+    },
+    common::*,
+    solana_program::{instruction::Instruction, pubkey::Pubkey},
+    solana_program_test::*,
+    solana_sdk::signature::Signer,
+};
+
+const MAX_FILLS: u8 = 2;
+
+/// Small enough that `MAX_FILLS` of them leave part of the deposit.
+const FILL: u64 = 300;
+
+fn partial_exchange_instruction(program_id: &Pubkey, fixture: &Fixture) -> Instruction {
+    instruction::partial_exchange(
+        program_id,
+        &fixture.taker.pubkey(),
+        &fixture.taker_send_account.pubkey(),
+        &fixture.mint_y,
+        &fixture.taker_receive_account.pubkey(),
+        &fixture.temp_token_account.pubkey(),
+        &fixture.initializer.pubkey(),
+        &fixture.initializer_receive_account.pubkey(),
+        &fixture.escrow_account.pubkey(),
+        // Unchecked while the fee is 0.
+        &Pubkey::new_unique(),
+        // Unchecked while the exchange leaves no residual.
+        &Pubkey::new_unique(),
+        None,
+        FILL,
+        &[],
+        EXPECTED_AMOUNT,
+    )
+    .unwrap()
+}
+
+#[tokio::test]
+async fn partial_exchange_stops_at_max_fills() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let fixture = setup(&mut context, &program_id).await;
+
+    let init_escrow = init_escrow_instruction_with(&program_id, &fixture, |data| {
+        if let EscrowInstruction::InitEscrow { max_fills, .. } = data {
+            *max_fills = MAX_FILLS;
+        }
+    });
+    process(&mut context, &[init_escrow], &[&fixture.initializer])
+        .await
+        .unwrap();
+
+    for _ in 0..MAX_FILLS {
+        process(
+            &mut context,
+            &[partial_exchange_instruction(&program_id, &fixture)],
+            &[&fixture.taker],
+        )
+        .await
+        .unwrap();
+        // A new blockhash, or the same fill again would be a duplicate transaction.
+        context.last_blockhash = context
+            .banks_client
+            .get_new_latest_blockhash(&context.last_blockhash)
+            .await
+            .unwrap();
+    }
+
+    let error = process(
+        &mut context,
+        &[partial_exchange_instruction(&program_id, &fixture)],
+        &[&fixture.taker],
+    )
+    .await
+    .unwrap_err();
+    assert_escrow_error(error, EscrowError::MaxFillsReached);
+    assert_eq!(
+        token_balance(&mut context, fixture.temp_token_account.pubkey()).await,
+        DEPOSIT_AMOUNT - u64::from(MAX_FILLS) * FILL
+    );
+}
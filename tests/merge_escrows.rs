@@ -0,0 +1,260 @@
+#![cfg(feature = "test-bpf")]
+
+mod common;
+
+use {
+    bpf_program_template::{
+        error::EscrowError,
+        instruction::{self, EscrowInstruction},
+        state::Escrow,
+    },
+    common::*,
+    solana_program::{
+        instruction::{Instruction, InstructionError},
+        program_pack::Pack,
+        pubkey::Pubkey,
+        rent::Rent,
+        system_instruction,
+    },
+    solana_program_test::*,
+    solana_sdk::{
+        signature::{Keypair, Signer},
+        transaction::TransactionError,
+        transport::TransportError,
+    },
+};
+
+const MERGED_DEPOSIT_AMOUNT: u64 = 200;
+
+/// Far enough out that escrows initialized at different slots still share it, as they have to to
+/// be merged.
+const EXPIRY_SLOT: u64 = 1_000_000;
+
+/// The fixture's `InitEscrow`, expiring at `EXPIRY_SLOT` and otherwise changed by `edit`.
+fn init_escrow_instruction_expiring(
+    program_id: &Pubkey,
+    fixture: &Fixture,
+    edit: impl FnOnce(&mut EscrowInstruction),
+) -> Instruction {
+    init_escrow_instruction_with(program_id, fixture, |data| {
+        if let EscrowInstruction::InitEscrow { expiry_slot, .. } = data {
+            *expiry_slot = EXPIRY_SLOT;
+        }
+        edit(data);
+    })
+}
+
+/// A second escrow of the fixture's initializer, depositing `MERGED_DEPOSIT_AMOUNT` of `mint`
+/// for `EXPECTED_AMOUNT` into the same receiving account, initialized by `init_escrow`.
+struct MergedEscrow {
+    temp_token_account: Keypair,
+    escrow_account: Keypair,
+}
+
+async fn init_merged_escrow(
+    context: &mut ProgramTestContext,
+    program_id: &Pubkey,
+    fixture: &Fixture,
+    mint: &Pubkey,
+    mut init_escrow: Instruction,
+) -> MergedEscrow {
+    let merged = MergedEscrow {
+        temp_token_account: Keypair::new(),
+        escrow_account: Keypair::new(),
+    };
+    create_token_account(
+        context,
+        &merged.temp_token_account,
+        mint,
+        &fixture.initializer.pubkey(),
+        &fixture.mint_authority,
+        MERGED_DEPOSIT_AMOUNT,
+    )
+    .await
+    .unwrap();
+
+    let create_escrow_account = system_instruction::create_account(
+        &context.payer.pubkey(),
+        &merged.escrow_account.pubkey(),
+        Rent::default().minimum_balance(Escrow::LEN),
+        Escrow::LEN as u64,
+        program_id,
+    );
+    // The fixture's escrow, with its temp and escrow accounts swapped for the new ones.
+    init_escrow.accounts[1].pubkey = merged.temp_token_account.pubkey();
+    init_escrow.accounts[3].pubkey = merged.escrow_account.pubkey();
+    process(
+        context,
+        &[create_escrow_account, init_escrow],
+        &[&merged.escrow_account, &fixture.initializer],
+    )
+    .await
+    .unwrap();
+
+    merged
+}
+
+fn merge_escrows_instruction(
+    program_id: &Pubkey,
+    fixture: &Fixture,
+    merged: &MergedEscrow,
+) -> Instruction {
+    instruction::merge_escrows(
+        program_id,
+        &fixture.initializer.pubkey(),
+        &fixture.escrow_account.pubkey(),
+        &fixture.temp_token_account.pubkey(),
+        &merged.escrow_account.pubkey(),
+        &merged.temp_token_account.pubkey(),
+    )
+    .unwrap()
+}
+
+#[tokio::test]
+async fn merge_escrows_moves_deposit_and_expected_amount() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let fixture = setup(&mut context, &program_id).await;
+    process(
+        &mut context,
+        &[init_escrow_instruction_expiring(
+            &program_id,
+            &fixture,
+            |_| {},
+        )],
+        &[&fixture.initializer],
+    )
+    .await
+    .unwrap();
+    let merged = init_merged_escrow(
+        &mut context,
+        &program_id,
+        &fixture,
+        &fixture.mint_x,
+        init_escrow_instruction_expiring(&program_id, &fixture, |_| {}),
+    )
+    .await;
+
+    process(
+        &mut context,
+        &[merge_escrows_instruction(&program_id, &fixture, &merged)],
+        &[&fixture.initializer],
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        token_balance(&mut context, fixture.temp_token_account.pubkey()).await,
+        DEPOSIT_AMOUNT + MERGED_DEPOSIT_AMOUNT
+    );
+    let escrow_account = context
+        .banks_client
+        .get_account(fixture.escrow_account.pubkey())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        Escrow::unpack(&escrow_account.data)
+            .unwrap()
+            .expected_amount,
+        2 * EXPECTED_AMOUNT
+    );
+    for closed in [
+        merged.escrow_account.pubkey(),
+        merged.temp_token_account.pubkey(),
+    ] {
+        assert_eq!(
+            context.banks_client.get_account(closed).await.unwrap(),
+            None
+        );
+    }
+}
+
+#[tokio::test]
+async fn merge_escrows_rejects_different_deposit_mint() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let fixture = setup(&mut context, &program_id).await;
+    process(
+        &mut context,
+        &[init_escrow_instruction_expiring(
+            &program_id,
+            &fixture,
+            |_| {},
+        )],
+        &[&fixture.initializer],
+    )
+    .await
+    .unwrap();
+    let mint_z = Keypair::new();
+    create_mint(&mut context, &mint_z, &fixture.mint_authority.pubkey())
+        .await
+        .unwrap();
+    let merged = init_merged_escrow(
+        &mut context,
+        &program_id,
+        &fixture,
+        &mint_z.pubkey(),
+        init_escrow_instruction_expiring(&program_id, &fixture, |_| {}),
+    )
+    .await;
+
+    let error = process(
+        &mut context,
+        &[merge_escrows_instruction(&program_id, &fixture, &merged)],
+        &[&fixture.initializer],
+    )
+    .await
+    .unwrap_err();
+    assert_escrow_error(error, EscrowError::MintMismatch);
+}
+
+#[tokio::test]
+async fn merge_escrows_rejects_different_terms() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let fixture = setup(&mut context, &program_id).await;
+    process(
+        &mut context,
+        &[init_escrow_instruction_expiring(
+            &program_id,
+            &fixture,
+            |_| {},
+        )],
+        &[&fixture.initializer],
+    )
+    .await
+    .unwrap();
+    // Merged, the second escrow's minimum fill would no longer hold.
+    let merged = init_merged_escrow(
+        &mut context,
+        &program_id,
+        &fixture,
+        &fixture.mint_x,
+        init_escrow_instruction_expiring(&program_id, &fixture, |data| {
+            if let EscrowInstruction::InitEscrow { min_fill, .. } = data {
+                *min_fill = MERGED_DEPOSIT_AMOUNT;
+            }
+        }),
+    )
+    .await;
+
+    let error = process(
+        &mut context,
+        &[merge_escrows_instruction(&program_id, &fixture, &merged)],
+        &[&fixture.initializer],
+    )
+    .await
+    .unwrap_err();
+    assert!(matches!(
+        error,
+        TransportError::TransactionError(TransactionError::InstructionError(
+            0,
+            InstructionError::InvalidAccountData,
+        ))
+    ));
+    assert_eq!(
+        token_balance(&mut context, merged.temp_token_account.pubkey()).await,
+        MERGED_DEPOSIT_AMOUNT
+    );
+}
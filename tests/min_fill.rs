@@ -0,0 +1,85 @@
+#![cfg(feature = "test-bpf")]
+
+mod common;
+
+use {
+    bpf_program_template::{
+        error::EscrowError,
+        instruction::{self, EscrowInstruction},
+    },
+    common::*,
+    solana_program::{instruction::Instruction, pubkey::Pubkey},
+    solana_sdk::signature::Signer,
+};
+
+const MIN_FILL: u64 = 400;
+
+fn partial_exchange_instruction(
+    program_id: &Pubkey,
+    fixture: &Fixture,
+    amount: u64,
+) -> Instruction {
+    instruction::partial_exchange(
+        program_id,
+        &fixture.taker.pubkey(),
+        &fixture.taker_send_account.pubkey(),
+        &fixture.mint_y,
+        &fixture.taker_receive_account.pubkey(),
+        &fixture.temp_token_account.pubkey(),
+        &fixture.initializer.pubkey(),
+        &fixture.initializer_receive_account.pubkey(),
+        &fixture.escrow_account.pubkey(),
+        // Unchecked while the fee is 0.
+        &Pubkey::new_unique(),
+        // Unchecked while the exchange leaves no residual.
+        &Pubkey::new_unique(),
+        None,
+        amount,
+        &[],
+        EXPECTED_AMOUNT,
+    )
+    .unwrap()
+}
+
+#[tokio::test]
+async fn partial_exchange_enforces_min_fill_except_for_the_closing_fill() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let fixture = setup(&mut context, &program_id).await;
+
+    let init_escrow = init_escrow_instruction_with(&program_id, &fixture, |data| {
+        if let EscrowInstruction::InitEscrow { min_fill, .. } = data {
+            *min_fill = MIN_FILL;
+        }
+    });
+    process(&mut context, &[init_escrow], &[&fixture.initializer])
+        .await
+        .unwrap();
+
+    // Too small, with plenty left after it.
+    let error = process(
+        &mut context,
+        &[partial_exchange_instruction(&program_id, &fixture, 100)],
+        &[&fixture.taker],
+    )
+    .await
+    .unwrap_err();
+    assert_escrow_error(error, EscrowError::FillTooSmall);
+
+    // Leaves 300, below the minimum but closing the escrow.
+    for amount in [700, DEPOSIT_AMOUNT - 700] {
+        process(
+            &mut context,
+            &[partial_exchange_instruction(&program_id, &fixture, amount)],
+            &[&fixture.taker],
+        )
+        .await
+        .unwrap();
+    }
+    assert!(context
+        .banks_client
+        .get_account(fixture.temp_token_account.pubkey())
+        .await
+        .unwrap()
+        .is_none());
+}
@@ -0,0 +1,123 @@
+#![cfg(all(feature = "test-bpf", feature = "oracle"))]
+
+mod common;
+
+use {
+    bpf_program_template::{
+        error::EscrowError,
+        instruction::{self, EscrowInstruction},
+        oracle::{PriceFeed, PRICE_FEED_MAGIC},
+    },
+    common::*,
+    solana_program::{
+        instruction::Instruction, program_option::COption, program_pack::Pack, pubkey::Pubkey,
+        rent::Rent,
+    },
+    solana_sdk::{account::Account, signature::Signer, transport::TransportError},
+};
+
+const MAX_DEVIATION_BPS: u16 = 100;
+
+/// A price account of some oracle program with `price * 10^expo` in it.
+fn mock_oracle(price: i64, expo: i32) -> Account {
+    let mut data = vec![0; PriceFeed::LEN];
+    PriceFeed::pack(
+        PriceFeed {
+            magic: PRICE_FEED_MAGIC,
+            price,
+            expo,
+        },
+        &mut data,
+    )
+    .unwrap();
+    Account {
+        lamports: Rent::default().minimum_balance(PriceFeed::LEN),
+        data,
+        owner: Pubkey::new_unique(),
+        ..Account::default()
+    }
+}
+
+fn oracle_exchange_instruction(
+    program_id: &Pubkey,
+    fixture: &Fixture,
+    oracle: &Pubkey,
+) -> Instruction {
+    instruction::exchange(
+        program_id,
+        &fixture.taker.pubkey(),
+        &fixture.taker_send_account.pubkey(),
+        &fixture.mint_y,
+        &fixture.taker_receive_account.pubkey(),
+        &fixture.temp_token_account.pubkey(),
+        &fixture.initializer.pubkey(),
+        &fixture.initializer_receive_account.pubkey(),
+        &fixture.escrow_account.pubkey(),
+        // Unchecked while the fee is 0.
+        &Pubkey::new_unique(),
+        // Unchecked while the exchange leaves no residual.
+        &Pubkey::new_unique(),
+        Some(oracle),
+        DEPOSIT_AMOUNT,
+        &[],
+        EXPECTED_AMOUNT,
+    )
+    .unwrap()
+}
+
+/// Starts the program with an oracle quoting `price * 10^expo`, and fills an escrow checked
+/// against it.
+async fn exchange_against_oracle(price: i64, expo: i32) -> Result<(), TransportError> {
+    let program_id = Pubkey::new_unique();
+    let oracle = Pubkey::new_unique();
+    let mut program_test = program_test(program_id);
+    program_test.add_account(oracle, mock_oracle(price, expo));
+    let mut context = program_test.start_with_context().await;
+    let fixture = setup(&mut context, &program_id).await;
+
+    let init_escrow = init_escrow_instruction_with(&program_id, &fixture, |data| {
+        if let EscrowInstruction::InitEscrow {
+            oracle: escrow_oracle,
+            max_deviation_bps,
+            ..
+        } = data
+        {
+            *escrow_oracle = COption::Some(oracle);
+            *max_deviation_bps = MAX_DEVIATION_BPS;
+        }
+    });
+    process(&mut context, &[init_escrow], &[&fixture.initializer])
+        .await
+        .unwrap();
+
+    process(
+        &mut context,
+        &[oracle_exchange_instruction(&program_id, &fixture, &oracle)],
+        &[&fixture.taker],
+    )
+    .await?;
+    assert_eq!(
+        token_balance(&mut context, fixture.taker_receive_account.pubkey()).await,
+        DEPOSIT_AMOUNT
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn exchange_at_the_oracle_price_succeeds() {
+    // 500 Y for 1000 X is exactly the quoted 0.5 Y per X.
+    exchange_against_oracle(5, -1).await.unwrap();
+}
+
+#[tokio::test]
+async fn exchange_within_the_deviation_succeeds() {
+    // 0.505 Y per X puts the escrow's price just under 1% below the oracle's.
+    exchange_against_oracle(505, -3).await.unwrap();
+}
+
+#[tokio::test]
+async fn exchange_away_from_the_oracle_price_fails() {
+    // At 1 Y per X the initializer would be getting half of what the tokens are worth.
+    let error = exchange_against_oracle(1, 0).await.unwrap_err();
+    assert_escrow_error(error, EscrowError::PriceOutOfBounds);
+}
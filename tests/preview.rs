@@ -0,0 +1,92 @@
+#![cfg(all(feature = "test-bpf", feature = "client"))]
+
+mod common;
+
+use {
+    bpf_program_template::{
+        client::preview_exchange,
+        instruction,
+        state::{find_config_address, Config, Escrow},
+    },
+    common::*,
+    solana_program::{program_pack::Pack, pubkey::Pubkey},
+    solana_sdk::signature::Signer,
+};
+
+#[tokio::test]
+async fn preview_matches_exchange() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let (fixture, treasury_token_account) = setup_with_fee(&mut context, &program_id).await;
+
+    let escrow_account = context
+        .banks_client
+        .get_account(fixture.escrow_account.pubkey())
+        .await
+        .unwrap()
+        .unwrap();
+    let config_account = context
+        .banks_client
+        .get_account(find_config_address(&program_id).0)
+        .await
+        .unwrap()
+        .unwrap();
+    let deposit = token_balance(&mut context, fixture.temp_token_account.pubkey()).await;
+    let taker_source_balance =
+        token_balance(&mut context, fixture.taker_send_account.pubkey()).await;
+    let preview = preview_exchange(
+        &Escrow::unpack(&escrow_account.data).unwrap(),
+        &Config::unpack(&config_account.data).unwrap(),
+        deposit,
+        &fixture.mint_y,
+        taker_source_balance,
+    )
+    .unwrap();
+
+    let exchange = instruction::exchange(
+        &program_id,
+        &fixture.taker.pubkey(),
+        &fixture.taker_send_account.pubkey(),
+        &fixture.mint_y,
+        &fixture.taker_receive_account.pubkey(),
+        &fixture.temp_token_account.pubkey(),
+        &fixture.initializer.pubkey(),
+        &fixture.initializer_receive_account.pubkey(),
+        &fixture.escrow_account.pubkey(),
+        &treasury_token_account.pubkey(),
+        // Unchecked while the exchange leaves no residual.
+        &Pubkey::new_unique(),
+        None,
+        deposit,
+        &[],
+        preview.amount_in,
+    )
+    .unwrap();
+    process(&mut context, &[exchange], &[&fixture.taker])
+        .await
+        .unwrap();
+
+    assert_eq!(
+        taker_source_balance
+            - token_balance(&mut context, fixture.taker_send_account.pubkey()).await,
+        preview.amount_in
+    );
+    assert_eq!(
+        token_balance(&mut context, fixture.taker_receive_account.pubkey()).await,
+        preview.amount_out
+    );
+    assert_eq!(
+        token_balance(&mut context, treasury_token_account.pubkey()).await,
+        preview.fee
+    );
+    assert_eq!(preview.fee, FEE);
+    assert_eq!(
+        context
+            .banks_client
+            .get_account(fixture.escrow_account.pubkey())
+            .await
+            .unwrap()
+            .is_none(),
+        preview.escrow_will_close
+    );
+}
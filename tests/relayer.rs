@@ -0,0 +1,91 @@
+#![cfg(feature = "test-bpf")]
+
+mod common;
+
+use {
+    bpf_program_template::{
+        error::EscrowError,
+        instruction,
+        state::{find_config_address, Config},
+    },
+    common::*,
+    solana_program::{program_option::COption, program_pack::Pack, pubkey::Pubkey},
+    solana_program_test::*,
+    solana_sdk::{
+        account::AccountSharedData,
+        signature::{Keypair, Signer},
+    },
+};
+
+/// Stand-in for an `UpdateConfig` of the allowed relayer, which only the config's admin can sign.
+async fn set_allowed_relayer(
+    context: &mut ProgramTestContext,
+    program_id: &Pubkey,
+    relayer: &Pubkey,
+) {
+    let config_address = find_config_address(program_id).0;
+    let mut account = context
+        .banks_client
+        .get_account(config_address)
+        .await
+        .unwrap()
+        .unwrap();
+    let mut config = Config::unpack(&account.data).unwrap();
+    config.allowed_relayer = COption::Some(*relayer);
+    Config::pack(config, &mut account.data).unwrap();
+    context.set_account(&config_address, &AccountSharedData::from(account));
+}
+
+#[tokio::test]
+async fn exchange_accepts_allowed_relayer() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let fixture = setup(&mut context, &program_id).await;
+    let relayer = Keypair::new();
+    set_allowed_relayer(&mut context, &program_id, &relayer.pubkey()).await;
+    process(
+        &mut context,
+        &[init_escrow_instruction(&program_id, &fixture)],
+        &[&fixture.initializer],
+    )
+    .await
+    .unwrap();
+
+    let exchange = instruction::with_relayer(
+        exchange_instruction(&program_id, &fixture),
+        &relayer.pubkey(),
+    );
+    process(&mut context, &[exchange], &[&fixture.taker, &relayer])
+        .await
+        .unwrap();
+
+    assert_eq!(
+        token_balance(&mut context, fixture.taker_receive_account.pubkey()).await,
+        DEPOSIT_AMOUNT
+    );
+}
+
+#[tokio::test]
+async fn exchange_rejects_other_relayer() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let fixture = setup(&mut context, &program_id).await;
+    set_allowed_relayer(&mut context, &program_id, &Pubkey::new_unique()).await;
+    process(
+        &mut context,
+        &[init_escrow_instruction(&program_id, &fixture)],
+        &[&fixture.initializer],
+    )
+    .await
+    .unwrap();
+
+    let relayer = Keypair::new();
+    let exchange = instruction::with_relayer(
+        exchange_instruction(&program_id, &fixture),
+        &relayer.pubkey(),
+    );
+    let error = process(&mut context, &[exchange], &[&fixture.taker, &relayer])
+        .await
+        .unwrap_err();
+    assert_escrow_error(error, EscrowError::Unauthorized);
+}
@@ -0,0 +1,213 @@
+#![cfg(feature = "test-bpf")]
+
+mod common;
+
+use {
+    bpf_program_template::{
+        error::EscrowError,
+        instruction::{self, EscrowInstruction},
+        state::Escrow,
+    },
+    common::*,
+    solana_program::{
+        instruction::Instruction, program_pack::Pack, pubkey::Pubkey, rent::Rent,
+        system_instruction,
+    },
+    solana_program_test::*,
+    solana_sdk::signature::{Keypair, Signer},
+    spl_token::state::Account as TokenAccount,
+};
+
+const NEW_EXPECTED_AMOUNT: u64 = 600;
+
+/// The accounts of the escrow replacing the fixture's.
+struct Replacement {
+    temp_token_account: Keypair,
+    escrow_account: Keypair,
+}
+
+async fn create_replacement(
+    context: &mut ProgramTestContext,
+    program_id: &Pubkey,
+    fixture: &Fixture,
+) -> Replacement {
+    let replacement = Replacement {
+        temp_token_account: Keypair::new(),
+        escrow_account: Keypair::new(),
+    };
+    create_token_account(
+        context,
+        &replacement.temp_token_account,
+        &fixture.mint_x,
+        &fixture.initializer.pubkey(),
+        &fixture.mint_authority,
+        0,
+    )
+    .await
+    .unwrap();
+    let create_escrow_account = system_instruction::create_account(
+        &context.payer.pubkey(),
+        &replacement.escrow_account.pubkey(),
+        Rent::default().minimum_balance(Escrow::LEN),
+        Escrow::LEN as u64,
+        program_id,
+    );
+    process(
+        context,
+        &[create_escrow_account],
+        &[&replacement.escrow_account],
+    )
+    .await
+    .unwrap();
+    replacement
+}
+
+fn replace_escrow_instruction(
+    program_id: &Pubkey,
+    fixture: &Fixture,
+    replacement: &Replacement,
+) -> Instruction {
+    let cancel_escrow = instruction::cancel_escrow(
+        program_id,
+        &fixture.initializer.pubkey(),
+        &fixture.temp_token_account.pubkey(),
+        &replacement.temp_token_account.pubkey(),
+        &fixture.escrow_account.pubkey(),
+        // Unchecked without a cancel penalty.
+        &Pubkey::new_unique(),
+    )
+    .unwrap();
+    // The fixture's escrow, with its temp and escrow accounts swapped for the new ones.
+    let mut init_escrow = init_escrow_instruction(program_id, fixture);
+    init_escrow.accounts[1].pubkey = replacement.temp_token_account.pubkey();
+    init_escrow.accounts[3].pubkey = replacement.escrow_account.pubkey();
+    instruction::replace_escrow(program_id, cancel_escrow, init_escrow, NEW_EXPECTED_AMOUNT)
+        .unwrap()
+}
+
+#[tokio::test]
+async fn replace_escrow_moves_deposit_into_new_escrow() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let fixture = setup(&mut context, &program_id).await;
+    process(
+        &mut context,
+        &[init_escrow_instruction(&program_id, &fixture)],
+        &[&fixture.initializer],
+    )
+    .await
+    .unwrap();
+    let replacement = create_replacement(&mut context, &program_id, &fixture).await;
+
+    process(
+        &mut context,
+        &[replace_escrow_instruction(
+            &program_id,
+            &fixture,
+            &replacement,
+        )],
+        &[&fixture.initializer],
+    )
+    .await
+    .unwrap();
+
+    let escrow_account = context
+        .banks_client
+        .get_account(replacement.escrow_account.pubkey())
+        .await
+        .unwrap()
+        .unwrap();
+    let escrow = Escrow::unpack(&escrow_account.data).unwrap();
+    assert_eq!(escrow.expected_amount, NEW_EXPECTED_AMOUNT);
+    assert_eq!(escrow.fills_done, 0);
+    assert_eq!(
+        escrow.temp_token_account_pubkey,
+        replacement.temp_token_account.pubkey()
+    );
+    let temp_token_account = context
+        .banks_client
+        .get_account(replacement.temp_token_account.pubkey())
+        .await
+        .unwrap()
+        .unwrap();
+    let temp_token_account = TokenAccount::unpack(&temp_token_account.data).unwrap();
+    assert_eq!(temp_token_account.amount, DEPOSIT_AMOUNT);
+    assert_ne!(temp_token_account.owner, fixture.initializer.pubkey());
+    for closed in [
+        fixture.escrow_account.pubkey(),
+        fixture.temp_token_account.pubkey(),
+    ] {
+        assert_eq!(
+            context.banks_client.get_account(closed).await.unwrap(),
+            None
+        );
+    }
+}
+
+#[tokio::test]
+async fn replace_escrow_is_blocked_by_prepared_settlement() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let fixture = setup(&mut context, &program_id).await;
+    let mut prepare_settlement = exchange_instruction(&program_id, &fixture);
+    prepare_settlement.data = EscrowInstruction::PrepareSettlement {
+        amount: DEPOSIT_AMOUNT,
+        reference_preimage: vec![],
+        max_amount_in: EXPECTED_AMOUNT,
+        taker_proof: vec![],
+    }
+    .pack();
+    process(
+        &mut context,
+        &[init_escrow_instruction(&program_id, &fixture)],
+        &[&fixture.initializer],
+    )
+    .await
+    .unwrap();
+    process(&mut context, &[prepare_settlement], &[&fixture.taker])
+        .await
+        .unwrap();
+    let replacement = create_replacement(&mut context, &program_id, &fixture).await;
+
+    let error = process(
+        &mut context,
+        &[replace_escrow_instruction(
+            &program_id,
+            &fixture,
+            &replacement,
+        )],
+        &[&fixture.initializer],
+    )
+    .await
+    .unwrap_err();
+    assert_escrow_error(error, EscrowError::SettlementPending);
+}
+
+#[tokio::test]
+async fn replace_escrow_respects_cancel_window() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let fixture = setup(&mut context, &program_id).await;
+    let init_escrow = init_escrow_instruction_with(&program_id, &fixture, |data| {
+        if let EscrowInstruction::InitEscrow { min_open_slots, .. } = data {
+            *min_open_slots = 1_000;
+        }
+    });
+    process(&mut context, &[init_escrow], &[&fixture.initializer])
+        .await
+        .unwrap();
+    let replacement = create_replacement(&mut context, &program_id, &fixture).await;
+
+    let error = process(
+        &mut context,
+        &[replace_escrow_instruction(
+            &program_id,
+            &fixture,
+            &replacement,
+        )],
+        &[&fixture.initializer],
+    )
+    .await
+    .unwrap_err();
+    assert_escrow_error(error, EscrowError::TooEarlyToCancel);
+}
@@ -0,0 +1,100 @@
+#![cfg(feature = "test-bpf")]
+
+mod common;
+
+use {
+    bpf_program_template::{
+        instruction,
+        state::{find_config_address, Config},
+    },
+    common::*,
+    solana_program::{instruction::AccountMeta, program_pack::Pack, pubkey::Pubkey},
+    solana_program_test::*,
+    solana_sdk::{
+        account::AccountSharedData,
+        signature::{Keypair, Signer},
+    },
+};
+
+const SECONDARY_TREASURY: Pubkey = Pubkey::new_from_array([8; 32]);
+/// Half of the fee, rounded down: 2 of `FEE`.
+const SECONDARY_TREASURY_BPS: u16 = 5_000;
+const SECONDARY_FEE: u64 = 2;
+
+/// Stand-in for an `UpdateConfig` splitting the fee with `SECONDARY_TREASURY`.
+async fn set_secondary_treasury(context: &mut ProgramTestContext, program_id: &Pubkey) {
+    let config_address = find_config_address(program_id).0;
+    let mut account = context
+        .banks_client
+        .get_account(config_address)
+        .await
+        .unwrap()
+        .unwrap();
+    let mut config = Config::unpack(&account.data).unwrap();
+    config.secondary_treasury = SECONDARY_TREASURY;
+    config.secondary_treasury_bps = SECONDARY_TREASURY_BPS;
+    Config::pack(config, &mut account.data).unwrap();
+    context.set_account(&config_address, &AccountSharedData::from(account));
+}
+
+#[tokio::test]
+async fn exchange_pays_secondary_treasury_at_its_position() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let (fixture, treasury_token_account) = setup_with_fee(&mut context, &program_id).await;
+    set_secondary_treasury(&mut context, &program_id).await;
+    let secondary_treasury_token_account = Keypair::new();
+    create_token_account(
+        &mut context,
+        &secondary_treasury_token_account,
+        &fixture.mint_y,
+        &SECONDARY_TREASURY,
+        &fixture.mint_authority,
+        0,
+    )
+    .await
+    .unwrap();
+
+    let mut exchange = instruction::with_secondary_treasury(
+        instruction::exchange(
+            &program_id,
+            &fixture.taker.pubkey(),
+            &fixture.taker_send_account.pubkey(),
+            &fixture.mint_y,
+            &fixture.taker_receive_account.pubkey(),
+            &fixture.temp_token_account.pubkey(),
+            &fixture.initializer.pubkey(),
+            &fixture.initializer_receive_account.pubkey(),
+            &fixture.escrow_account.pubkey(),
+            &treasury_token_account.pubkey(),
+            // Unchecked while the exchange leaves no residual.
+            &Pubkey::new_unique(),
+            None,
+            DEPOSIT_AMOUNT,
+            &[],
+            EXPECTED_AMOUNT + FEE,
+        )
+        .unwrap(),
+        &secondary_treasury_token_account.pubkey(),
+    );
+    // A token account among the trailing ones, like a basket's, isn't taken for the secondary
+    // treasury's.
+    exchange.accounts.push(AccountMeta::new(
+        fixture.initializer_receive_account.pubkey(),
+        false,
+    ));
+    process(&mut context, &[exchange], &[&fixture.taker])
+        .await
+        .unwrap();
+
+    for (account, expected_amount) in [
+        (treasury_token_account.pubkey(), FEE - SECONDARY_FEE),
+        (secondary_treasury_token_account.pubkey(), SECONDARY_FEE),
+        (
+            fixture.initializer_receive_account.pubkey(),
+            EXPECTED_AMOUNT,
+        ),
+    ] {
+        assert_eq!(token_balance(&mut context, account).await, expected_amount);
+    }
+}
@@ -0,0 +1,233 @@
+#![cfg(feature = "test-bpf")]
+
+mod common;
+
+use {
+    bpf_program_template::{error::EscrowError, instruction},
+    common::*,
+    solana_program::{
+        account_info::AccountInfo,
+        entrypoint::ProgramResult,
+        instruction::{AccountMeta, Instruction},
+        program::invoke,
+        pubkey::Pubkey,
+    },
+    solana_program_test::*,
+    solana_sdk::signature::Signer,
+};
+
+fn prepare_settlement_instruction(
+    program_id: &Pubkey,
+    fixture: &Fixture,
+    treasury_token_account: &Pubkey,
+    max_amount_in: u64,
+) -> Instruction {
+    instruction::prepare_settlement(
+        program_id,
+        &fixture.taker.pubkey(),
+        &fixture.taker_send_account.pubkey(),
+        &fixture.mint_y,
+        &fixture.taker_receive_account.pubkey(),
+        &fixture.temp_token_account.pubkey(),
+        &fixture.initializer.pubkey(),
+        &fixture.initializer_receive_account.pubkey(),
+        &fixture.escrow_account.pubkey(),
+        treasury_token_account,
+        // Unchecked while the exchange leaves no residual.
+        &Pubkey::new_unique(),
+        None,
+        DEPOSIT_AMOUNT,
+        &[],
+        max_amount_in,
+    )
+    .unwrap()
+}
+
+fn finalize_settlement_instruction(
+    program_id: &Pubkey,
+    fixture: &Fixture,
+    treasury_token_account: &Pubkey,
+) -> Instruction {
+    instruction::finalize_settlement(
+        program_id,
+        &fixture.taker.pubkey(),
+        &fixture.taker_send_account.pubkey(),
+        &fixture.mint_y,
+        &fixture.taker_receive_account.pubkey(),
+        &fixture.temp_token_account.pubkey(),
+        &fixture.initializer.pubkey(),
+        &fixture.initializer_receive_account.pubkey(),
+        &fixture.escrow_account.pubkey(),
+        treasury_token_account,
+        &Pubkey::new_unique(),
+    )
+    .unwrap()
+}
+
+#[tokio::test]
+async fn finalize_charges_the_fee_quoted_at_prepare() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let (fixture, treasury_token_account) = setup_with_fee(&mut context, &program_id).await;
+
+    // Exactly what the fill costs.
+    process(
+        &mut context,
+        &[prepare_settlement_instruction(
+            &program_id,
+            &fixture,
+            &treasury_token_account.pubkey(),
+            EXPECTED_AMOUNT + FEE,
+        )],
+        &[&fixture.taker],
+    )
+    .await
+    .unwrap();
+    // Would cost the taker 45 more than they agreed to if the finalize went by it.
+    set_fee_bps(&mut context, &program_id, 10 * FEE_BPS).await;
+    process(
+        &mut context,
+        &[finalize_settlement_instruction(
+            &program_id,
+            &fixture,
+            &treasury_token_account.pubkey(),
+        )],
+        &[&fixture.taker],
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        token_balance(&mut context, treasury_token_account.pubkey()).await,
+        FEE
+    );
+    assert_eq!(
+        token_balance(&mut context, fixture.initializer_receive_account.pubkey()).await,
+        EXPECTED_AMOUNT
+    );
+    assert_eq!(
+        token_balance(&mut context, fixture.taker_receive_account.pubkey()).await,
+        DEPOSIT_AMOUNT
+    );
+}
+
+#[tokio::test]
+async fn prepare_rejects_max_amount_in_below_the_cost() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let (fixture, treasury_token_account) = setup_with_fee(&mut context, &program_id).await;
+
+    let error = process(
+        &mut context,
+        &[prepare_settlement_instruction(
+            &program_id,
+            &fixture,
+            &treasury_token_account.pubkey(),
+            EXPECTED_AMOUNT + FEE - 1,
+        )],
+        &[&fixture.taker],
+    )
+    .await
+    .unwrap_err();
+    assert_escrow_error(error, EscrowError::AmountInExceedsMax);
+}
+
+#[tokio::test]
+async fn finalize_rejects_unprepared_escrow() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let (fixture, treasury_token_account) = setup_with_fee(&mut context, &program_id).await;
+
+    let error = process(
+        &mut context,
+        &[finalize_settlement_instruction(
+            &program_id,
+            &fixture,
+            &treasury_token_account.pubkey(),
+        )],
+        &[&fixture.taker],
+    )
+    .await
+    .unwrap_err();
+    assert_escrow_error(error, EscrowError::SettlementNotPrepared);
+    assert_eq!(
+        token_balance(&mut context, fixture.temp_token_account.pubkey()).await,
+        DEPOSIT_AMOUNT
+    );
+}
+
+/// A program that invokes the program passed as its first account with its own data and the
+/// rest of its accounts, for running an instruction one CPI deep.
+fn forward(_program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    let instruction = Instruction {
+        program_id: *accounts[0].key,
+        accounts: accounts[1..]
+            .iter()
+            .map(|info| AccountMeta {
+                pubkey: *info.key,
+                is_signer: info.is_signer,
+                is_writable: info.is_writable,
+            })
+            .collect(),
+        data: data.to_vec(),
+    };
+    invoke(&instruction, accounts)
+}
+
+#[tokio::test]
+async fn finalize_rejects_settling_through_cpi() {
+    let program_id = Pubkey::new_unique();
+    let forwarder_id = Pubkey::new_unique();
+    let mut program_test = program_test(program_id);
+    program_test.add_program("forwarder", forwarder_id, processor!(forward));
+    let mut context = program_test.start_with_context().await;
+    let fixture = setup(&mut context, &program_id).await;
+    // Unchecked while the fee is 0.
+    let treasury_token_account = Pubkey::new_unique();
+    process(
+        &mut context,
+        &[
+            init_escrow_instruction(&program_id, &fixture),
+            prepare_settlement_instruction(
+                &program_id,
+                &fixture,
+                &treasury_token_account,
+                EXPECTED_AMOUNT,
+            ),
+        ],
+        &[&fixture.initializer, &fixture.taker],
+    )
+    .await
+    .unwrap();
+
+    let finalize_settlement =
+        finalize_settlement_instruction(&program_id, &fixture, &treasury_token_account);
+    let mut accounts = vec![AccountMeta::new_readonly(program_id, false)];
+    accounts.extend(finalize_settlement.accounts);
+    let forwarded = Instruction {
+        program_id: forwarder_id,
+        accounts,
+        data: finalize_settlement.data,
+    };
+    let error = process(&mut context, &[forwarded], &[&fixture.taker])
+        .await
+        .unwrap_err();
+    assert_escrow_error(error, EscrowError::NotTopLevelInstruction);
+
+    // The same instruction goes through at the top level.
+    process(
+        &mut context,
+        &[finalize_settlement_instruction(
+            &program_id,
+            &fixture,
+            &treasury_token_account,
+        )],
+        &[&fixture.taker],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        token_balance(&mut context, fixture.taker_receive_account.pubkey()).await,
+        DEPOSIT_AMOUNT
+    );
+}
@@ -0,0 +1,166 @@
+#![cfg(feature = "test-bpf")]
+
+mod common;
+
+use {
+    bpf_program_template::{
+        error::EscrowError,
+        instruction,
+        state::{find_split_escrow_address, find_split_temp_address, Escrow},
+    },
+    common::*,
+    solana_program::{
+        instruction::{Instruction, InstructionError},
+        program_pack::Pack,
+        pubkey::Pubkey,
+    },
+    solana_sdk::{signature::Signer, transaction::TransactionError, transport::TransportError},
+};
+
+fn split_escrow_instruction(program_id: &Pubkey, fixture: &Fixture, count: u8) -> Instruction {
+    instruction::split_escrow(
+        program_id,
+        &fixture.initializer.pubkey(),
+        &fixture.escrow_account.pubkey(),
+        &fixture.temp_token_account.pubkey(),
+        &fixture.mint_x,
+        count,
+    )
+    .unwrap()
+}
+
+#[tokio::test]
+async fn split_children_add_up_to_split_escrow() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let fixture = setup(&mut context, &program_id).await;
+    process(
+        &mut context,
+        &[init_escrow_instruction(&program_id, &fixture)],
+        &[&fixture.initializer],
+    )
+    .await
+    .unwrap();
+
+    // Doesn't divide evenly, the last child takes the leftovers.
+    let count = 3;
+    process(
+        &mut context,
+        &[split_escrow_instruction(&program_id, &fixture, count)],
+        &[&fixture.initializer],
+    )
+    .await
+    .unwrap();
+
+    let mut deposits = vec![];
+    let mut expected_amounts = vec![];
+    for index in 0..count {
+        let child =
+            find_split_escrow_address(&program_id, &fixture.escrow_account.pubkey(), index).0;
+        let child_temp =
+            find_split_temp_address(&program_id, &fixture.escrow_account.pubkey(), index).0;
+        let escrow = Escrow::unpack(
+            &context
+                .banks_client
+                .get_account(child)
+                .await
+                .unwrap()
+                .unwrap()
+                .data,
+        )
+        .unwrap();
+        assert_eq!(escrow.temp_token_account_pubkey, child_temp);
+        deposits.push(token_balance(&mut context, child_temp).await);
+        expected_amounts.push(escrow.expected_amount);
+    }
+    assert_eq!(deposits, [333, 333, 334]);
+    assert_eq!(deposits.iter().sum::<u64>(), DEPOSIT_AMOUNT);
+    assert_eq!(expected_amounts, [166, 166, 168]);
+    assert_eq!(expected_amounts.iter().sum::<u64>(), EXPECTED_AMOUNT);
+    for closed in [
+        fixture.escrow_account.pubkey(),
+        fixture.temp_token_account.pubkey(),
+    ] {
+        assert!(context
+            .banks_client
+            .get_account(closed)
+            .await
+            .unwrap()
+            .is_none());
+    }
+}
+
+#[tokio::test]
+async fn split_rejects_a_single_child() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let fixture = setup(&mut context, &program_id).await;
+    process(
+        &mut context,
+        &[init_escrow_instruction(&program_id, &fixture)],
+        &[&fixture.initializer],
+    )
+    .await
+    .unwrap();
+
+    let error = process(
+        &mut context,
+        &[split_escrow_instruction(&program_id, &fixture, 1)],
+        &[&fixture.initializer],
+    )
+    .await
+    .unwrap_err();
+    assert!(matches!(
+        error,
+        TransportError::TransactionError(TransactionError::InstructionError(
+            0,
+            InstructionError::InvalidArgument,
+        ))
+    ));
+}
+
+#[tokio::test]
+async fn split_rejects_partially_filled_escrow() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let fixture = setup(&mut context, &program_id).await;
+    process(
+        &mut context,
+        &[init_escrow_instruction(&program_id, &fixture)],
+        &[&fixture.initializer],
+    )
+    .await
+    .unwrap();
+    let partial_exchange = instruction::partial_exchange(
+        &program_id,
+        &fixture.taker.pubkey(),
+        &fixture.taker_send_account.pubkey(),
+        &fixture.mint_y,
+        &fixture.taker_receive_account.pubkey(),
+        &fixture.temp_token_account.pubkey(),
+        &fixture.initializer.pubkey(),
+        &fixture.initializer_receive_account.pubkey(),
+        &fixture.escrow_account.pubkey(),
+        // Unchecked while the fee is 0.
+        &Pubkey::new_unique(),
+        // Unchecked while the exchange leaves no residual.
+        &Pubkey::new_unique(),
+        None,
+        DEPOSIT_AMOUNT / 2,
+        &[],
+        EXPECTED_AMOUNT,
+    )
+    .unwrap();
+    process(&mut context, &[partial_exchange], &[&fixture.taker])
+        .await
+        .unwrap();
+
+    let error = process(
+        &mut context,
+        &[split_escrow_instruction(&program_id, &fixture, 2)],
+        &[&fixture.initializer],
+    )
+    .await
+    .unwrap_err();
+    assert_escrow_error(error, EscrowError::InvalidEscrowState);
+}
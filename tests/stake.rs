@@ -0,0 +1,328 @@
+#![cfg(feature = "test-bpf")]
+
+mod common;
+
+use {
+    bpf_program_template::{
+        instruction::{self, EscrowInstruction, ExpiredEscrow},
+        state::{find_escrow_index_address, find_split_escrow_address, Escrow},
+    },
+    common::*,
+    solana_program::{
+        instruction::Instruction, native_token::LAMPORTS_PER_SOL, program_pack::Pack,
+        pubkey::Pubkey, rent::Rent, system_instruction,
+    },
+    solana_program_test::*,
+    solana_sdk::signature::{Keypair, Signer},
+    spl_associated_token_account::{create_associated_token_account, get_associated_token_address},
+};
+
+/// Enough for the treasury to be rent exempt on it alone, should it not exist yet.
+const STAKE: u64 = LAMPORTS_PER_SOL / 10;
+
+fn init_staked_escrow_instruction(
+    program_id: &Pubkey,
+    fixture: &Fixture,
+    expiry: u64,
+) -> Instruction {
+    init_escrow_instruction_with(program_id, fixture, |data| {
+        if let EscrowInstruction::InitEscrow {
+            stake, expiry_slot, ..
+        } = data
+        {
+            *stake = STAKE;
+            *expiry_slot = expiry;
+        }
+    })
+}
+
+/// Far enough out that escrows initialized at different slots still share it.
+const EXPIRY_SLOT: u64 = 1_000_000;
+
+async fn get_escrow(context: &mut ProgramTestContext, escrow_account: Pubkey) -> (Escrow, u64) {
+    let account = context
+        .banks_client
+        .get_account(escrow_account)
+        .await
+        .unwrap()
+        .unwrap();
+    (Escrow::unpack(&account.data).unwrap(), account.lamports)
+}
+
+#[tokio::test]
+async fn exchange_returns_stake_to_initializer() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let fixture = setup(&mut context, &program_id).await;
+
+    process(
+        &mut context,
+        &[init_staked_escrow_instruction(&program_id, &fixture, 0)],
+        &[&fixture.initializer],
+    )
+    .await
+    .unwrap();
+    let escrow_lamports = context
+        .banks_client
+        .get_balance(fixture.escrow_account.pubkey())
+        .await
+        .unwrap();
+    assert_eq!(
+        escrow_lamports,
+        Rent::default().minimum_balance(Escrow::LEN) + STAKE
+    );
+    let temp_lamports = context
+        .banks_client
+        .get_balance(fixture.temp_token_account.pubkey())
+        .await
+        .unwrap();
+    let initializer_lamports = context
+        .banks_client
+        .get_balance(fixture.initializer.pubkey())
+        .await
+        .unwrap();
+    let escrow_index = find_escrow_index_address(&program_id, &fixture.initializer.pubkey()).0;
+    let escrow_index_lamports = context
+        .banks_client
+        .get_balance(escrow_index)
+        .await
+        .unwrap();
+
+    process(
+        &mut context,
+        &[exchange_instruction(&program_id, &fixture)],
+        &[&fixture.taker],
+    )
+    .await
+    .unwrap();
+
+    // Along with the rent of the escrow index's entry for the escrow.
+    let escrow_index_refund = escrow_index_lamports
+        - context
+            .banks_client
+            .get_balance(escrow_index)
+            .await
+            .unwrap();
+    assert_eq!(
+        context
+            .banks_client
+            .get_balance(fixture.initializer.pubkey())
+            .await
+            .unwrap(),
+        initializer_lamports + escrow_lamports + temp_lamports + escrow_index_refund
+    );
+}
+
+#[tokio::test]
+async fn reclaiming_expired_escrow_forfeits_stake_to_treasury() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let fixture = setup(&mut context, &program_id).await;
+    let treasury = TREASURY;
+    let initializer_refund_account =
+        get_associated_token_address(&fixture.initializer.pubkey(), &fixture.mint_x);
+
+    let create_refund_account = create_associated_token_account(
+        &context.payer.pubkey(),
+        &fixture.initializer.pubkey(),
+        &fixture.mint_x,
+    );
+    process(
+        &mut context,
+        &[
+            create_refund_account,
+            init_staked_escrow_instruction(&program_id, &fixture, 100),
+        ],
+        &[&fixture.initializer],
+    )
+    .await
+    .unwrap();
+    context.warp_to_slot(200).unwrap();
+    let treasury_lamports = context.banks_client.get_balance(treasury).await.unwrap();
+
+    let reclaim = instruction::reclaim_expired_batch(
+        &program_id,
+        &[ExpiredEscrow {
+            escrow_account: fixture.escrow_account.pubkey(),
+            temp_token_account: fixture.temp_token_account.pubkey(),
+            initializer: fixture.initializer.pubkey(),
+            initializer_refund_token_account: initializer_refund_account,
+        }],
+        &treasury,
+    )
+    .unwrap();
+    process(&mut context, &[reclaim], &[]).await.unwrap();
+
+    assert_eq!(
+        context.banks_client.get_balance(treasury).await.unwrap(),
+        treasury_lamports + STAKE
+    );
+    assert!(context
+        .banks_client
+        .get_account(fixture.escrow_account.pubkey())
+        .await
+        .unwrap()
+        .is_none());
+}
+
+#[tokio::test]
+async fn reclaim_batch_skips_escrow_it_already_closed() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let fixture = setup(&mut context, &program_id).await;
+    let treasury = TREASURY;
+    let initializer_refund_account =
+        get_associated_token_address(&fixture.initializer.pubkey(), &fixture.mint_x);
+
+    let create_refund_account = create_associated_token_account(
+        &context.payer.pubkey(),
+        &fixture.initializer.pubkey(),
+        &fixture.mint_x,
+    );
+    process(
+        &mut context,
+        &[
+            create_refund_account,
+            init_staked_escrow_instruction(&program_id, &fixture, 100),
+        ],
+        &[&fixture.initializer],
+    )
+    .await
+    .unwrap();
+    context.warp_to_slot(200).unwrap();
+
+    // The second entry finds the account the first one closed.
+    let expired_escrow = || ExpiredEscrow {
+        escrow_account: fixture.escrow_account.pubkey(),
+        temp_token_account: fixture.temp_token_account.pubkey(),
+        initializer: fixture.initializer.pubkey(),
+        initializer_refund_token_account: initializer_refund_account,
+    };
+    let reclaim = instruction::reclaim_expired_batch(
+        &program_id,
+        &[expired_escrow(), expired_escrow()],
+        &treasury,
+    )
+    .unwrap();
+    process(&mut context, &[reclaim], &[]).await.unwrap();
+
+    assert_eq!(
+        token_balance(&mut context, initializer_refund_account).await,
+        DEPOSIT_AMOUNT
+    );
+    assert!(context
+        .banks_client
+        .get_account(fixture.escrow_account.pubkey())
+        .await
+        .unwrap()
+        .is_none());
+}
+
+#[tokio::test]
+async fn split_moves_stake_into_children() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let fixture = setup(&mut context, &program_id).await;
+    process(
+        &mut context,
+        &[init_staked_escrow_instruction(
+            &program_id,
+            &fixture,
+            EXPIRY_SLOT,
+        )],
+        &[&fixture.initializer],
+    )
+    .await
+    .unwrap();
+
+    // Doesn't divide evenly, the last child takes the remainder.
+    let count = 3;
+    let split_escrow = instruction::split_escrow(
+        &program_id,
+        &fixture.initializer.pubkey(),
+        &fixture.escrow_account.pubkey(),
+        &fixture.temp_token_account.pubkey(),
+        &fixture.mint_x,
+        count,
+    )
+    .unwrap();
+    process(&mut context, &[split_escrow], &[&fixture.initializer])
+        .await
+        .unwrap();
+
+    let rent = Rent::default().minimum_balance(Escrow::LEN);
+    for index in 0..count {
+        let child =
+            find_split_escrow_address(&program_id, &fixture.escrow_account.pubkey(), index).0;
+        let (escrow, lamports) = get_escrow(&mut context, child).await;
+        let expected_stake = if index == count - 1 {
+            STAKE - 2 * (STAKE / 3)
+        } else {
+            STAKE / 3
+        };
+        assert_eq!(escrow.stake_lamports, expected_stake);
+        assert_eq!(lamports, rent + expected_stake);
+    }
+}
+
+#[tokio::test]
+async fn merge_moves_stake_into_remaining_escrow() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let fixture = setup(&mut context, &program_id).await;
+    let merged_temp_token_account = Keypair::new();
+    let merged_escrow_account = Keypair::new();
+    create_token_account(
+        &mut context,
+        &merged_temp_token_account,
+        &fixture.mint_x,
+        &fixture.initializer.pubkey(),
+        &fixture.mint_authority,
+        DEPOSIT_AMOUNT,
+    )
+    .await
+    .unwrap();
+
+    let create_escrow_account = system_instruction::create_account(
+        &context.payer.pubkey(),
+        &merged_escrow_account.pubkey(),
+        Rent::default().minimum_balance(Escrow::LEN),
+        Escrow::LEN as u64,
+        &program_id,
+    );
+    // The fixture's escrow, with its temp and escrow accounts swapped for the new ones.
+    let mut init_merged_escrow = init_staked_escrow_instruction(&program_id, &fixture, EXPIRY_SLOT);
+    init_merged_escrow.accounts[1].pubkey = merged_temp_token_account.pubkey();
+    init_merged_escrow.accounts[3].pubkey = merged_escrow_account.pubkey();
+    process(
+        &mut context,
+        &[
+            init_staked_escrow_instruction(&program_id, &fixture, EXPIRY_SLOT),
+            create_escrow_account,
+            init_merged_escrow,
+        ],
+        &[&fixture.initializer, &merged_escrow_account],
+    )
+    .await
+    .unwrap();
+
+    let merge_escrows = instruction::merge_escrows(
+        &program_id,
+        &fixture.initializer.pubkey(),
+        &fixture.escrow_account.pubkey(),
+        &fixture.temp_token_account.pubkey(),
+        &merged_escrow_account.pubkey(),
+        &merged_temp_token_account.pubkey(),
+    )
+    .unwrap();
+    process(&mut context, &[merge_escrows], &[&fixture.initializer])
+        .await
+        .unwrap();
+
+    let (escrow, lamports) = get_escrow(&mut context, fixture.escrow_account.pubkey()).await;
+    assert_eq!(escrow.stake_lamports, 2 * STAKE);
+    assert_eq!(
+        lamports,
+        Rent::default().minimum_balance(Escrow::LEN) + 2 * STAKE
+    );
+}
@@ -0,0 +1,226 @@
+#![cfg(feature = "test-bpf")]
+
+mod common;
+
+use {
+    bpf_program_template::{
+        instruction,
+        state::{find_escrow_index_address, find_native_sol_wrapper_address, CloseReason},
+    },
+    common::*,
+    solana_program::{program_option::COption, program_pack::Pack, pubkey::Pubkey, rent::Rent},
+    solana_program_test::*,
+    solana_sdk::{
+        account::{Account, AccountSharedData},
+        signature::{Keypair, Signer},
+    },
+    spl_associated_token_account::{create_associated_token_account, get_associated_token_address},
+    spl_token::state::{Account as TokenAccount, AccountState, Mint},
+};
+
+/// The program-test genesis has no native mint.
+fn add_native_mint(context: &mut ProgramTestContext) {
+    let mut data = vec![0; Mint::LEN];
+    Mint::pack(
+        Mint {
+            decimals: spl_token::native_mint::DECIMALS,
+            is_initialized: true,
+            ..Mint::default()
+        },
+        &mut data,
+    )
+    .unwrap();
+    context.set_account(
+        &spl_token::native_mint::id(),
+        &AccountSharedData::from(Account {
+            lamports: Rent::default().minimum_balance(Mint::LEN),
+            data,
+            owner: spl_token::id(),
+            ..Account::default()
+        }),
+    );
+}
+
+#[tokio::test]
+async fn cancel_unwraps_wrapped_sol_deposit_to_initializer() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let fixture = setup(&mut context, &program_id).await;
+    let native_mint = spl_token::native_mint::id();
+    let temp_rent = Rent::default().minimum_balance(TokenAccount::LEN);
+
+    // Turn the fixture's temp account into a wrapped SOL one holding the deposit.
+    add_native_mint(&mut context);
+    let mut data = vec![0; TokenAccount::LEN];
+    TokenAccount::pack(
+        TokenAccount {
+            mint: native_mint,
+            owner: fixture.initializer.pubkey(),
+            amount: DEPOSIT_AMOUNT,
+            state: AccountState::Initialized,
+            is_native: COption::Some(temp_rent),
+            ..TokenAccount::default()
+        },
+        &mut data,
+    )
+    .unwrap();
+    context.set_account(
+        &fixture.temp_token_account.pubkey(),
+        &AccountSharedData::from(Account {
+            lamports: temp_rent + DEPOSIT_AMOUNT,
+            data,
+            owner: spl_token::id(),
+            ..Account::default()
+        }),
+    );
+    let initializer_refund_account =
+        get_associated_token_address(&fixture.initializer.pubkey(), &native_mint);
+
+    let create_refund_account = create_associated_token_account(
+        &context.payer.pubkey(),
+        &fixture.initializer.pubkey(),
+        &native_mint,
+    );
+    process(
+        &mut context,
+        &[
+            create_refund_account,
+            init_escrow_instruction(&program_id, &fixture),
+        ],
+        &[&fixture.initializer],
+    )
+    .await
+    .unwrap();
+    let escrow_lamports = context
+        .banks_client
+        .get_balance(fixture.escrow_account.pubkey())
+        .await
+        .unwrap();
+    let initializer_lamports = context
+        .banks_client
+        .get_balance(fixture.initializer.pubkey())
+        .await
+        .unwrap();
+    let escrow_index = find_escrow_index_address(&program_id, &fixture.initializer.pubkey()).0;
+    let escrow_index_lamports = context
+        .banks_client
+        .get_balance(escrow_index)
+        .await
+        .unwrap();
+
+    let cancel = instruction::cancel_escrow(
+        &program_id,
+        &fixture.initializer.pubkey(),
+        &fixture.temp_token_account.pubkey(),
+        &initializer_refund_account,
+        &fixture.escrow_account.pubkey(),
+        // Unchecked without a cancel penalty.
+        &Pubkey::new_unique(),
+    )
+    .unwrap();
+    process(&mut context, &[cancel], &[&fixture.initializer])
+        .await
+        .unwrap();
+
+    // The deposit comes back as native SOL along with the rent of both accounts and of the escrow
+    // index's entry for the escrow, not as wrapped SOL.
+    let escrow_index_refund = escrow_index_lamports
+        - context
+            .banks_client
+            .get_balance(escrow_index)
+            .await
+            .unwrap();
+    assert_eq!(
+        context
+            .banks_client
+            .get_balance(fixture.initializer.pubkey())
+            .await
+            .unwrap(),
+        initializer_lamports + DEPOSIT_AMOUNT + temp_rent + escrow_lamports + escrow_index_refund
+    );
+    assert_eq!(
+        token_balance(&mut context, initializer_refund_account).await,
+        0
+    );
+    assert!(context
+        .banks_client
+        .get_account(fixture.temp_token_account.pubkey())
+        .await
+        .unwrap()
+        .is_none());
+}
+
+#[tokio::test]
+async fn exchange_wraps_taker_native_sol_payment() {
+    let program_id = Pubkey::new_unique();
+    let mut context = start_with_return_data(program_id).await;
+    let mut fixture = setup(&mut context, &program_id).await;
+    add_native_mint(&mut context);
+
+    // The initializer expects wrapped SOL.
+    let native_mint = spl_token::native_mint::id();
+    let initializer_receive_account = Keypair::new();
+    create_token_account(
+        &mut context,
+        &initializer_receive_account,
+        &native_mint,
+        &fixture.initializer.pubkey(),
+        &fixture.mint_authority,
+        0,
+    )
+    .await
+    .unwrap();
+    fixture.mint_y = native_mint;
+    fixture.initializer_receive_account = initializer_receive_account;
+    process(
+        &mut context,
+        &[init_escrow_instruction(&program_id, &fixture)],
+        &[&fixture.initializer],
+    )
+    .await
+    .unwrap();
+    let taker_lamports = context
+        .banks_client
+        .get_balance(fixture.taker.pubkey())
+        .await
+        .unwrap();
+
+    let exchange = instruction::with_native_sol_payment(
+        exchange_instruction(&program_id, &fixture),
+        &program_id,
+    );
+    // Closing the wrapper leaves the escrow's close reason in place.
+    process(
+        &mut context,
+        &expect_return_data(&[exchange], &[CloseReason::Exchanged as u8]),
+        &[&fixture.taker],
+    )
+    .await
+    .unwrap();
+
+    // The taker paid exactly the expected amount out of their SOL, and the wrapper is gone with
+    // its rent returned.
+    assert_eq!(
+        context
+            .banks_client
+            .get_balance(fixture.taker.pubkey())
+            .await
+            .unwrap(),
+        taker_lamports - EXPECTED_AMOUNT
+    );
+    let wrapper = find_native_sol_wrapper_address(&program_id, &fixture.taker.pubkey()).0;
+    assert!(context
+        .banks_client
+        .get_account(wrapper)
+        .await
+        .unwrap()
+        .is_none());
+    assert_eq!(
+        token_balance(&mut context, fixture.initializer_receive_account.pubkey()).await,
+        EXPECTED_AMOUNT
+    );
+    assert_eq!(
+        token_balance(&mut context, fixture.taker_receive_account.pubkey()).await,
+        DEPOSIT_AMOUNT
+    );
+}